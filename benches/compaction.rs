@@ -0,0 +1,167 @@
+extern crate kvs;
+
+use criterion::*;
+use rand::{thread_rng, Rng};
+use tempfile::TempDir;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use kvs::{KvStore, KvStoreBuilder};
+
+// Only the workload values and the latencies are used here.
+#[allow(dead_code)]
+mod support;
+
+use support::{Latencies, Mix, Workload};
+
+const KEYS: usize = 1000;
+const READERS: usize = 4;
+const READS_PER_THREAD: usize = 500;
+
+type Summary = Arc<Mutex<HashMap<String, Latencies>>>;
+
+fn name(compacting: bool) -> &'static str {
+    if compacting {
+        "during_compaction"
+    } else {
+        "idle"
+    }
+}
+
+// Read every key from `READERS` threads, checking the values.
+fn iter_reads(store: &KvStore, vals: &Arc<Vec<String>>) -> Latencies {
+    let handles: Vec<_> = (0..READERS)
+        .map(|_| {
+            let store = store.clone();
+            let vals = vals.clone();
+            thread::spawn(move || {
+                let mut rng = thread_rng();
+                let mut lat = Latencies::default();
+                for _ in 0..READS_PER_THREAD {
+                    let i = rng.gen_range(0, KEYS);
+                    let start = Instant::now();
+                    let val = store.get(Workload::key(i)).expect("failed to get");
+                    lat.record(start.elapsed());
+                    assert_eq!(val.as_ref(), Some(&vals[i]), "wrong value");
+                }
+                lat
+            })
+        })
+        .collect();
+    let mut lat = Latencies::default();
+    for h in handles {
+        lat.merge(h.join().expect("reader panicked"));
+    }
+    lat
+}
+
+fn get_compaction(c: &mut Criterion) {
+    let summary: Summary = Arc::new(Mutex::new(HashMap::new()));
+    let sum = summary.clone();
+    c.bench(
+        "get_compaction",
+        ParameterizedBenchmark::new(
+            "kvs",
+            move |b, &compacting| {
+                let dir = TempDir::new().expect("failed to create temporary dir");
+                let store = KvStore::open(dir.path()).expect("failed to open kvs");
+                let mix = Mix {
+                    read: 0,
+                    write: 1,
+                    delete: 0,
+                };
+                let mut wl = Workload::new(mix, KEYS, 0);
+                let vals: Vec<String> = (0..KEYS).map(|_| wl.value()).collect();
+                for (i, val) in vals.iter().enumerate() {
+                    store
+                        .set(Workload::key(i), val.clone())
+                        .expect("failed to set");
+                }
+                let vals = Arc::new(vals);
+
+                // Every compaction rewrites all the records and deletes their files.
+                let stop = Arc::new(AtomicBool::new(false));
+                let compacter = if compacting {
+                    let store = store.clone();
+                    let stop = stop.clone();
+                    Some(thread::spawn(move || {
+                        while !stop.load(Ordering::SeqCst) {
+                            store.compact().expect("failed to compact");
+                        }
+                    }))
+                } else {
+                    None
+                };
+
+                let mut all = Latencies::default();
+                b.iter(|| all.merge(iter_reads(&store, &vals)));
+                stop.store(true, Ordering::SeqCst);
+                if let Some(compacter) = compacter {
+                    compacter.join().expect("compacter panicked");
+                }
+                sum.lock()
+                    .unwrap()
+                    .entry(name(compacting).to_owned())
+                    .or_default()
+                    .merge(all);
+            },
+            vec![false, true],
+        )
+        .throughput(|_| Throughput::Elements((READERS * READS_PER_THREAD) as u32))
+        .sample_size(10),
+    );
+
+    let mut summary = summary.lock().unwrap();
+    println!("\nget latency:");
+    for &compacting in [false, true].iter() {
+        if let Some(lat) = summary.get_mut(name(compacting)) {
+            println!("{:<20} {}", name(compacting), lat.summary());
+        }
+    }
+}
+
+fn scan_after_compaction(c: &mut Criterion) {
+    c.bench(
+        "scan_after_compaction",
+        ParameterizedBenchmark::new(
+            "key_order",
+            |b, &key_order| {
+                let dir = TempDir::new().expect("failed to create temporary dir");
+                let store = KvStoreBuilder::new(dir.path())
+                    .compact_key_order(key_order)
+                    .build()
+                    .expect("failed to open kvs");
+                let mix = Mix {
+                    read: 0,
+                    write: 1,
+                    delete: 0,
+                };
+                let mut wl = Workload::new(mix, KEYS, 0);
+                let mut rng = thread_rng();
+                for _ in 0..KEYS * 4 {
+                    let i = rng.gen_range(0, KEYS);
+                    store
+                        .set(Workload::key(i), wl.value())
+                        .expect("failed to set");
+                }
+                store.compact().expect("failed to compact");
+                b.iter(|| {
+                    let pairs = store
+                        .retain_scan("key", |_, _| true)
+                        .expect("failed to scan");
+                    assert!(!pairs.is_empty());
+                });
+            },
+            vec![false, true],
+        )
+        .throughput(|_| Throughput::Elements(KEYS as u32))
+        .sample_size(10),
+    );
+}
+
+criterion_group!(benches, get_compaction, scan_after_compaction);
+criterion_main!(benches);
@@ -0,0 +1,176 @@
+extern crate kvs;
+
+use criterion::*;
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_derive::{Deserialize, Serialize};
+use tempfile::TempDir;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Cursor, Read, Write};
+use std::path::Path;
+
+use kvs::KvStore;
+
+const RECORDS: usize = 20000;
+const KEYS: usize = 5000;
+const SEED: u64 = 1930;
+
+/// Same shape as the log records of `KvStore`, which is private.
+#[derive(Serialize, Deserialize)]
+enum Record {
+    #[serde(rename = "S")]
+    Set(String, String, u64, u64, u64, u64),
+    #[serde(rename = "R")]
+    Rm(String),
+}
+
+/// A fixed mix of sets and removes over `KEYS` keys,
+/// the same for every run so the numbers can be compared.
+fn dataset() -> Vec<Record> {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let mut versions = vec![0; KEYS];
+    (0..RECORDS)
+        .map(|_| {
+            let k = rng.gen_range(0, KEYS);
+            let key = format!("key{:08}", k);
+            // Only live keys are removed, `KvStore::remove` fails otherwise.
+            if versions[k] > 0 && rng.gen_range(0, 10) == 0 {
+                versions[k] = 0;
+                Record::Rm(key)
+            } else {
+                versions[k] += 1;
+                let len = rng.gen_range(1, 1000);
+                let val: String = rng.sample_iter(&Alphanumeric).take(len).collect();
+                let now = 1_560_000_000_000 + rng.gen_range(0, 1_000_000);
+                Record::Set(key, val, 0, versions[k], now, now)
+            }
+        })
+        .collect()
+}
+
+fn write_json(path: &Path, data: &[Record]) {
+    let mut wtr = BufWriter::new(File::create(path).expect("failed to create file"));
+    for rec in data {
+        serde_json::to_writer(&mut wtr, rec).expect("failed to write record");
+    }
+    wtr.flush().expect("failed to flush");
+}
+
+fn write_bincode(path: &Path, data: &[Record]) {
+    let mut wtr = BufWriter::new(File::create(path).expect("failed to create file"));
+    for rec in data {
+        bincode::serialize_into(&mut wtr, rec).expect("failed to write record");
+    }
+    wtr.flush().expect("failed to flush");
+}
+
+// The loaders build the same offset/length index as `KvStore::load_index`.
+
+fn load_json(path: &Path) -> HashMap<String, (usize, usize)> {
+    let rdr = BufReader::new(File::open(path).expect("failed to open file"));
+    let mut stream = serde_json::Deserializer::from_reader(rdr).into_iter::<Record>();
+    let mut index = HashMap::new();
+    let mut offset = stream.byte_offset();
+    while let Some(rec) = stream.next() {
+        let next_offset = stream.byte_offset();
+        match rec.expect("bad json record") {
+            Record::Set(key, ..) => {
+                index.insert(key, (offset, next_offset - offset));
+            }
+            Record::Rm(key) => {
+                index.remove(&key);
+            }
+        }
+        offset = next_offset;
+    }
+    index
+}
+
+fn load_bincode(path: &Path) -> HashMap<String, (usize, usize)> {
+    let mut buf = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut buf))
+        .expect("failed to read file");
+    let len = buf.len() as u64;
+    let mut rdr = Cursor::new(buf);
+    let mut index = HashMap::new();
+    while rdr.position() < len {
+        let offset = rdr.position() as usize;
+        let rec: Record = bincode::deserialize_from(&mut rdr).expect("bad bincode record");
+        let next_offset = rdr.position() as usize;
+        match rec {
+            Record::Set(key, ..) => {
+                index.insert(key, (offset, next_offset - offset));
+            }
+            Record::Rm(key) => {
+                index.remove(&key);
+            }
+        }
+    }
+    index
+}
+
+fn load_index(c: &mut Criterion) {
+    let data = dataset();
+    let dir = TempDir::new().expect("failed to create temporary dir");
+    let json = dir.path().join("records.json");
+    let bin = dir.path().join("records.bincode");
+    write_json(&json, &data);
+    write_bincode(&bin, &data);
+
+    // Both encodings must load the same keys before their speed is worth
+    // comparing, the offsets differ of course.
+    let mut json_keys: Vec<_> = load_json(&json).into_keys().collect();
+    let mut bin_keys: Vec<_> = load_bincode(&bin).into_keys().collect();
+    json_keys.sort();
+    bin_keys.sort();
+    assert_eq!(json_keys, bin_keys);
+
+    let size = |p: &Path| fs::metadata(p).expect("failed to stat").len();
+    println!(
+        "{} records on disk: json {} bytes, bincode {} bytes",
+        RECORDS,
+        size(&json),
+        size(&bin)
+    );
+
+    c.bench(
+        "load_index",
+        Benchmark::new("json", move |b| b.iter(|| load_json(&json)))
+            .with_function("bincode", move |b| b.iter(|| load_bincode(&bin)))
+            .sample_size(10),
+    );
+}
+
+// Reopening a real store, which is dominated by loading the index.
+fn reopen_kvs(c: &mut Criterion) {
+    let dir = TempDir::new().expect("failed to create temporary dir");
+    {
+        let store = KvStore::open(dir.path()).expect("failed to open kvs");
+        for rec in dataset() {
+            match rec {
+                Record::Set(key, val, ..) => store.set(key, val).expect("failed to set"),
+                Record::Rm(key) => store.remove(key).expect("failed to remove"),
+            }
+        }
+    }
+    let size: u64 = fs::read_dir(dir.path())
+        .expect("failed to list dir")
+        .map(|e| e.and_then(|e| e.metadata()).map_or(0, |m| m.len()))
+        .sum();
+    println!("kvs store of {} records: {} bytes", RECORDS, size);
+
+    c.bench(
+        "reopen",
+        Benchmark::new("kvs_json", move |b| {
+            b.iter(|| KvStore::open(dir.path()).expect("failed to open kvs"))
+        })
+        .sample_size(10),
+    );
+}
+
+criterion_group!(benches, load_index, reopen_kvs);
+criterion_main!(benches);
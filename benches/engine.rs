@@ -5,7 +5,7 @@ use rand::distributions::{Alphanumeric, Uniform};
 use rand::{thread_rng, Rng};
 use tempfile::TempDir;
 
-use kvs::{KvStore, KvsEngine, SledDb};
+use kvs::{IndexHasher, KvStore, KvStoreBuilder, KvsEngine, SledDb};
 
 fn write100_unique(c: &mut Criterion) {
     let mut rng = thread_rng();
@@ -40,7 +40,7 @@ fn write100_repeat(c: &mut Criterion) {
     bench_write(c, "write100_repeat", data);
 }
 
-fn iter_write<E: KvsEngine>(eng: E, data: &Vec<(String, String)>) {
+fn iter_write<E: KvsEngine>(eng: E, data: &[(String, String)]) {
     for kv in data.iter() {
         eng.set(kv.0.clone(), kv.1.clone()).expect("failed to set");
     }
@@ -79,7 +79,7 @@ fn nonrepeat_read(c: &mut Criterion) {
     bench_read(c, "nonrepeat_read_1000_250", 1000, 250);
 }
 
-fn iter_read<E: KvsEngine>(eng: E, data: &Vec<(String, String)>, ord: &Vec<usize>) {
+fn iter_read<E: KvsEngine>(eng: E, data: &[(String, String)], ord: &[usize]) {
     for i in ord.iter() {
         let (key, val) = &data[*i];
         assert_eq!(
@@ -142,11 +142,46 @@ fn bench_read(c: &mut Criterion, name: &str, data_sz: usize, ord_sz: usize) {
     );
 }
 
+// Lookups of short keys in the index alone, `meta` reads no data file.
+fn index_hasher(c: &mut Criterion) {
+    #[cfg_attr(not(feature = "ahash"), allow(unused_mut))]
+    let mut hashers = vec![IndexHasher::Sip];
+    #[cfg(feature = "ahash")]
+    hashers.push(IndexHasher::AHash);
+    c.bench(
+        "index_hasher",
+        ParameterizedBenchmark::new(
+            "meta_1000",
+            |b, hasher| {
+                let dir = TempDir::new().expect("failed to create temporary dir");
+                let store = KvStoreBuilder::new(dir.path())
+                    .index_hasher(*hasher)
+                    .build()
+                    .expect("failed to open kvs");
+                for i in 0..1000 {
+                    store
+                        .set(format!("key{}", i), "value".to_owned())
+                        .expect("kvs failed to set");
+                }
+                b.iter(|| {
+                    for i in 0..1000 {
+                        store
+                            .meta(format!("key{}", i))
+                            .expect("failed to read meta");
+                    }
+                })
+            },
+            hashers,
+        ),
+    );
+}
+
 criterion_group!(
     benches,
     write100_repeat,
     write100_unique,
     repeat_read,
-    nonrepeat_read
+    nonrepeat_read,
+    index_hasher
 );
 criterion_main!(benches);
@@ -0,0 +1,130 @@
+extern crate kvs;
+
+use criterion::*;
+use tempfile::TempDir;
+
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use kvs::{KvStore, KvsEngine, SledDb};
+
+mod support;
+
+use support::{Latencies, Mix, Op, Workload};
+
+const KEYS: usize = 1000;
+const OPS_PER_THREAD: usize = 500;
+
+const THREADS: &[usize] = &[1, 4, 8];
+const MIXES: &[Mix] = &[
+    Mix {
+        read: 90,
+        write: 9,
+        delete: 1,
+    },
+    Mix {
+        read: 50,
+        write: 40,
+        delete: 10,
+    },
+];
+
+#[derive(Clone, Copy)]
+struct Case {
+    kvs: bool,
+    threads: usize,
+    mix: Mix,
+}
+
+impl Debug for Case {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let eng = if self.kvs { "kvs" } else { "sled" };
+        write!(f, "{}/{}t/{}", eng, self.threads, self.mix)
+    }
+}
+
+type Summary = Arc<Mutex<HashMap<String, Latencies>>>;
+
+fn iter_mixed<E: KvsEngine>(eng: &E, ops: &Arc<Vec<Vec<Op>>>) -> Latencies {
+    let handles: Vec<_> = (0..ops.len())
+        .map(|i| {
+            let eng = eng.clone();
+            let ops = ops.clone();
+            thread::spawn(move || {
+                let mut lat = Latencies::default();
+                support::run(&eng, &ops[i], &mut lat);
+                lat
+            })
+        })
+        .collect();
+    let mut lat = Latencies::default();
+    for h in handles {
+        lat.merge(h.join().expect("worker panicked"));
+    }
+    lat
+}
+
+fn bench_case<E: KvsEngine>(b: &mut Bencher, eng: E, case: &Case, summary: &Summary) {
+    let mut wl = Workload::new(case.mix, KEYS, 0);
+    for i in 0..KEYS {
+        let val = wl.value();
+        eng.set(Workload::key(i), val).expect("failed to set");
+    }
+    let ops: Vec<Vec<Op>> = (0..case.threads).map(|_| wl.ops(OPS_PER_THREAD)).collect();
+    let ops = Arc::new(ops);
+    let mut all = Latencies::default();
+    b.iter(|| all.merge(iter_mixed(&eng, &ops)));
+    summary
+        .lock()
+        .unwrap()
+        .entry(format!("{:?}", case))
+        .or_default()
+        .merge(all);
+}
+
+fn mixed(c: &mut Criterion) {
+    let mut cases = Vec::new();
+    for &kvs in [false, true].iter() {
+        for &threads in THREADS.iter() {
+            for &mix in MIXES.iter() {
+                cases.push(Case { kvs, threads, mix });
+            }
+        }
+    }
+
+    let summary: Summary = Arc::new(Mutex::new(HashMap::new()));
+    let sum = summary.clone();
+    c.bench(
+        "mixed",
+        ParameterizedBenchmark::new(
+            "sled_kvs",
+            move |b, case| {
+                let dir = TempDir::new().expect("failed to create temporary dir");
+                if case.kvs {
+                    let eng = KvStore::open(dir.path()).expect("failed to open kvs");
+                    bench_case(b, eng, case, &sum);
+                } else {
+                    let eng = SledDb::open(dir.path()).expect("failed to open sled");
+                    bench_case(b, eng, case, &sum);
+                }
+            },
+            cases,
+        )
+        .throughput(|case| Throughput::Elements((case.threads * OPS_PER_THREAD) as u32))
+        .sample_size(10),
+    );
+
+    let mut summary = summary.lock().unwrap();
+    let mut names: Vec<_> = summary.keys().cloned().collect();
+    names.sort();
+    println!("\nlatency per operation:");
+    for name in names {
+        let lat = summary.get_mut(&name).unwrap();
+        println!("{:<20} {}", name, lat.summary());
+    }
+}
+
+criterion_group!(benches, mixed);
+criterion_main!(benches);
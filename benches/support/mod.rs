@@ -0,0 +1,128 @@
+//! Helpers shared by the benchmarks.
+
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, Instant};
+
+use kvs::KvsEngine;
+
+/// Relative weights of the operations in a mixed workload.
+#[derive(Clone, Copy, Debug)]
+pub struct Mix {
+    pub read: u32,
+    pub write: u32,
+    pub delete: u32,
+}
+
+impl Display for Mix {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "r{}w{}d{}", self.read, self.write, self.delete)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Op {
+    Get(String),
+    Set(String, String),
+    Remove(String),
+}
+
+/// Generate operations on a fixed key space following a `Mix`.
+pub struct Workload {
+    mix: Mix,
+    keys: usize,
+    rng: StdRng,
+}
+
+impl Workload {
+    pub fn new(mix: Mix, keys: usize, seed: u64) -> Self {
+        Workload {
+            mix,
+            keys,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn key(i: usize) -> String {
+        format!("key{:08}", i)
+    }
+
+    pub fn value(&mut self) -> String {
+        let len = self.rng.gen_range(1, 1025);
+        self.rng.sample_iter(&Alphanumeric).take(len).collect()
+    }
+
+    pub fn next_op(&mut self) -> Op {
+        let key = Self::key(self.rng.gen_range(0, self.keys));
+        let total = self.mix.read + self.mix.write + self.mix.delete;
+        let x = self.rng.gen_range(0, total);
+        if x < self.mix.read {
+            Op::Get(key)
+        } else if x < self.mix.read + self.mix.write {
+            Op::Set(key, self.value())
+        } else {
+            Op::Remove(key)
+        }
+    }
+
+    pub fn ops(&mut self, n: usize) -> Vec<Op> {
+        (0..n).map(|_| self.next_op()).collect()
+    }
+}
+
+/// Run the operations and record the latency of each.
+/// Removing a missing key is not an error in a mixed workload.
+pub fn run<E: KvsEngine>(eng: &E, ops: &[Op], lat: &mut Latencies) {
+    for op in ops.iter() {
+        let start = Instant::now();
+        match op {
+            Op::Get(key) => {
+                eng.get(key.clone()).expect("failed to get");
+            }
+            Op::Set(key, val) => {
+                eng.set(key.clone(), val.clone()).expect("failed to set");
+            }
+            Op::Remove(key) => {
+                let _ = eng.remove(key.clone());
+            }
+        }
+        lat.record(start.elapsed());
+    }
+}
+
+/// Collected operation latencies.
+#[derive(Default)]
+pub struct Latencies(Vec<Duration>);
+
+impl Latencies {
+    pub fn record(&mut self, d: Duration) {
+        self.0.push(d);
+    }
+
+    pub fn merge(&mut self, other: Latencies) {
+        self.0.extend(other.0);
+    }
+
+    /// The latency below which `p` percent of the operations completed.
+    pub fn percentile(&mut self, p: f64) -> Duration {
+        if self.0.is_empty() {
+            return Duration::from_secs(0);
+        }
+        self.0.sort_unstable();
+        let idx = ((self.0.len() - 1) as f64 * p / 100.0).round() as usize;
+        self.0[idx]
+    }
+
+    pub fn summary(&mut self) -> String {
+        format!(
+            "{} ops, p50 {:?}, p99 {:?}, max {:?}",
+            self.0.len(),
+            self.percentile(50.0),
+            self.percentile(99.0),
+            self.percentile(100.0)
+        )
+    }
+}
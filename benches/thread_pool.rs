@@ -1,11 +1,13 @@
 extern crate criterion;
 extern crate crossbeam;
-extern crate tempfile;
 extern crate kvs;
+extern crate tempfile;
+extern crate tokio;
 
 use criterion::*;
 use crossbeam::sync::WaitGroup;
 use tempfile::TempDir;
+use tokio::prelude::*;
 //use rand::distributions::{Alphanumeric, Uniform};
 //use rand::{thread_rng, Rng};
 
@@ -15,8 +17,8 @@ use std::thread;
 use std::time::Duration;
 //use std::sync::Arc;
 
-use kvs::thread_pool::{SharedQueueThreadPool, RayonThreadPool, ThreadPool};
-use kvs::{KvsClient, KvsServer, KvStore, SledDb};
+use kvs::thread_pool::{RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsServer, SledDb};
 
 const SZ: usize = 1000;
 const NUMS: [u32; 5] = [1, 2, 4, 6, 8];
@@ -33,9 +35,9 @@ fn write_rayon_sled(c: &mut Criterion) {
                 let dir = TempDir::new().unwrap();
                 let eng = SledDb::open(dir.path()).unwrap();
                 let pool = RayonThreadPool::new(num).unwrap();
-                let adr = addr.clone();
+                let adr = addr;
                 let server = KvsServer::new(eng, pool, adr, None);
-                let handle = server.start().unwrap();
+                let handle = server.spawn_background().unwrap();
 
                 let value = "the-value".to_owned();
                 let keys: Vec<String> = (0..SZ).map(|x| format!("key{:04}", x)).collect();
@@ -45,15 +47,15 @@ fn write_rayon_sled(c: &mut Criterion) {
 
                 b.iter(|| {
                     let wg = WaitGroup::new();
-                    for i in 0..SZ {
-                        let adr = addr.clone();
-                        let k = keys[i].clone();
+                    for key in keys.iter() {
+                        let adr = addr;
+                        let k = key.clone();
                         let v = value.clone();
                         let wg = wg.clone();
                         pool.spawn(move || {
                             match KvsClient::new(adr, None) {
-                                Ok(mut cli) => {
-                                    if let Err(e) = cli.set(k, v) {
+                                Ok(cli) => {
+                                    if let Err(e) = cli.set(k, v).wait() {
                                         eprintln!("11111111111EEEEEEEEEEEEEEE {}", e);
                                     }
                                 }
@@ -90,9 +92,9 @@ fn read_rayon_sled(c: &mut Criterion) {
                 let dir = TempDir::new().unwrap();
                 let eng = SledDb::open(dir.path()).unwrap();
                 let pool = RayonThreadPool::new(num).unwrap();
-                let adr = addr.clone();
+                let adr = addr;
                 let server = KvsServer::new(eng, pool, adr, None);
-                let handle = server.start().unwrap();
+                let handle = server.spawn_background().unwrap();
 
                 let value = "the-value".to_owned();
                 let keys: Vec<String> = (0..SZ).map(|x| format!("key{:04}", x)).collect();
@@ -101,9 +103,10 @@ fn read_rayon_sled(c: &mut Criterion) {
                 thread::sleep(Duration::from_secs(1));
 
                 for k in keys.iter() {
-                    KvsClient::new(addr.clone(), None)
+                    KvsClient::new(addr, None)
                         .unwrap()
                         .set(k.clone(), value.clone())
+                        .wait()
                         .unwrap();
                 }
 
@@ -111,14 +114,14 @@ fn read_rayon_sled(c: &mut Criterion) {
 
                 b.iter(|| {
                     let wg = WaitGroup::new();
-                    for i in 0..SZ {
-                        let adr = addr.clone();
-                        let k = keys[i].clone();
+                    for key in keys.iter() {
+                        let adr = addr;
+                        let k = key.clone();
                         let wg = wg.clone();
                         pool.spawn(move || {
                             match KvsClient::new(adr, None) {
-                                Ok(mut cli) => {
-                                    if let Err(e) = cli.get(k) {
+                                Ok(cli) => {
+                                    if let Err(e) = cli.get(k).wait() {
                                         eprintln!("11111111111EEEEEEEEEEEEEEE {}", e);
                                     }
                                 }
@@ -155,9 +158,9 @@ fn write_rayon_kvstore(c: &mut Criterion) {
                 let dir = TempDir::new().unwrap();
                 let eng = KvStore::open(dir.path()).unwrap();
                 let pool = RayonThreadPool::new(num).unwrap();
-                let adr = addr.clone();
+                let adr = addr;
                 let server = KvsServer::new(eng, pool, adr, None);
-                let handle = server.start().unwrap();
+                let handle = server.spawn_background().unwrap();
 
                 let value = "the-value".to_owned();
                 let keys: Vec<String> = (0..SZ).map(|x| format!("key{:04}", x)).collect();
@@ -167,15 +170,15 @@ fn write_rayon_kvstore(c: &mut Criterion) {
 
                 b.iter(|| {
                     let wg = WaitGroup::new();
-                    for i in 0..SZ {
-                        let adr = addr.clone();
-                        let k = keys[i].clone();
+                    for key in keys.iter() {
+                        let adr = addr;
+                        let k = key.clone();
                         let v = value.clone();
                         let wg = wg.clone();
                         pool.spawn(move || {
                             match KvsClient::new(adr, None) {
-                                Ok(mut cli) => {
-                                    if let Err(e) = cli.set(k, v) {
+                                Ok(cli) => {
+                                    if let Err(e) = cli.set(k, v).wait() {
                                         eprintln!("11111111111EEEEEEEEEEEEEEE {}", e);
                                     }
                                 }
@@ -212,9 +215,9 @@ fn read_rayon_kvstore(c: &mut Criterion) {
                 let dir = TempDir::new().unwrap();
                 let eng = KvStore::open(dir.path()).unwrap();
                 let pool = RayonThreadPool::new(num).unwrap();
-                let adr = addr.clone();
+                let adr = addr;
                 let server = KvsServer::new(eng, pool, adr, None);
-                let handle = server.start().unwrap();
+                let handle = server.spawn_background().unwrap();
 
                 let value = "the-value".to_owned();
                 let keys: Vec<String> = (0..SZ).map(|x| format!("key{:04}", x)).collect();
@@ -223,9 +226,10 @@ fn read_rayon_kvstore(c: &mut Criterion) {
                 thread::sleep(Duration::from_secs(1));
 
                 for k in keys.iter() {
-                    KvsClient::new(addr.clone(), None)
+                    KvsClient::new(addr, None)
                         .unwrap()
                         .set(k.clone(), value.clone())
+                        .wait()
                         .unwrap();
                 }
 
@@ -233,14 +237,14 @@ fn read_rayon_kvstore(c: &mut Criterion) {
 
                 b.iter(|| {
                     let wg = WaitGroup::new();
-                    for i in 0..SZ {
-                        let adr = addr.clone();
-                        let k = keys[i].clone();
+                    for key in keys.iter() {
+                        let adr = addr;
+                        let k = key.clone();
                         let wg = wg.clone();
                         pool.spawn(move || {
                             match KvsClient::new(adr, None) {
-                                Ok(mut cli) => {
-                                    if let Err(e) = cli.get(k) {
+                                Ok(cli) => {
+                                    if let Err(e) = cli.get(k).wait() {
                                         eprintln!("11111111111EEEEEEEEEEEEEEE {}", e);
                                     }
                                 }
@@ -277,9 +281,9 @@ fn write_queued_kvstore(c: &mut Criterion) {
                 let dir = TempDir::new().unwrap();
                 let eng = KvStore::open(dir.path()).unwrap();
                 let pool = SharedQueueThreadPool::new(num).unwrap();
-                let adr = addr.clone();
+                let adr = addr;
                 let server = KvsServer::new(eng, pool, adr, None);
-                let handle = server.start().unwrap();
+                let handle = server.spawn_background().unwrap();
 
                 let value = "the-value".to_owned();
                 let keys: Vec<String> = (0..SZ).map(|x| format!("key{:04}", x)).collect();
@@ -289,15 +293,15 @@ fn write_queued_kvstore(c: &mut Criterion) {
 
                 b.iter(|| {
                     let wg = WaitGroup::new();
-                    for i in 0..SZ {
-                        let adr = addr.clone();
-                        let k = keys[i].clone();
+                    for key in keys.iter() {
+                        let adr = addr;
+                        let k = key.clone();
                         let v = value.clone();
                         let wg = wg.clone();
                         pool.spawn(move || {
                             match KvsClient::new(adr, None) {
-                                Ok(mut cli) => {
-                                    if let Err(e) = cli.set(k, v) {
+                                Ok(cli) => {
+                                    if let Err(e) = cli.set(k, v).wait() {
                                         eprintln!("11111111111EEEEEEEEEEEEEEE {}", e);
                                     }
                                 }
@@ -334,9 +338,9 @@ fn read_queued_kvstore(c: &mut Criterion) {
                 let dir = TempDir::new().unwrap();
                 let eng = KvStore::open(dir.path()).unwrap();
                 let pool = SharedQueueThreadPool::new(num).unwrap();
-                let adr = addr.clone();
+                let adr = addr;
                 let server = KvsServer::new(eng, pool, adr, None);
-                let handle = server.start().unwrap();
+                let handle = server.spawn_background().unwrap();
 
                 let value = "the-value".to_owned();
                 let keys: Vec<String> = (0..SZ).map(|x| format!("key{:04}", x)).collect();
@@ -345,9 +349,10 @@ fn read_queued_kvstore(c: &mut Criterion) {
                 thread::sleep(Duration::from_secs(1));
 
                 for k in keys.iter() {
-                    KvsClient::new(addr.clone(), None)
+                    KvsClient::new(addr, None)
                         .unwrap()
                         .set(k.clone(), value.clone())
+                        .wait()
                         .unwrap();
                 }
 
@@ -355,14 +360,14 @@ fn read_queued_kvstore(c: &mut Criterion) {
 
                 b.iter(|| {
                     let wg = WaitGroup::new();
-                    for i in 0..SZ {
-                        let adr = addr.clone();
-                        let k = keys[i].clone();
+                    for key in keys.iter() {
+                        let adr = addr;
+                        let k = key.clone();
                         let wg = wg.clone();
                         pool.spawn(move || {
                             match KvsClient::new(adr, None) {
-                                Ok(mut cli) => {
-                                    if let Err(e) = cli.get(k) {
+                                Ok(cli) => {
+                                    if let Err(e) = cli.get(k).wait() {
                                         eprintln!("11111111111EEEEEEEEEEEEEEE {}", e);
                                     }
                                 }
@@ -5,13 +5,19 @@ extern crate slog_term;
 extern crate structopt;
 extern crate tokio;
 
-use slog::{o, Drain, Logger};
+use slog::{o, Drain, Level, Logger};
 use structopt::StructOpt;
 use tokio::prelude::*;
 
 use std::net::SocketAddr;
+use std::process;
 
-use kvs::KvsClient;
+use kvs::{KvsClient, KEY_NOT_FOUND};
+
+// Exit codes besides 0, which includes a `get` of a missing key.
+// The errors themselves are logged to stderr.
+const EXIT_FAILURE: i32 = 1;
+const EXIT_NOT_FOUND: i32 = 2;
 
 #[derive(StructOpt)]
 #[structopt(
@@ -53,14 +59,56 @@ enum Operation {
         #[structopt(name = "KEY", help = "The key you want to remove.")]
         key: String,
     },
+    #[structopt(name = "count", about = "Count the keys with a given prefix")]
+    Count {
+        #[structopt(name = "PREFIX", help = "The prefix of keys you want to count.")]
+        prefix: String,
+    },
+    #[structopt(name = "clients", about = "List the connections of the server")]
+    Clients,
+    #[structopt(
+        name = "server-version",
+        about = "Print the version, engine and uptime of the server"
+    )]
+    ServerVersion,
+    #[structopt(
+        name = "log-level",
+        about = "Print the log level of the server, after changing it if given"
+    )]
+    LogLevel {
+        #[structopt(name = "LEVEL", help = "critical, error, warning, info or debug.")]
+        level: Option<String>,
+    },
+    #[structopt(
+        name = "config-get",
+        about = "Print the settings of the server and its engine"
+    )]
+    ConfigGet {
+        #[structopt(
+            name = "PATTERN",
+            help = "Glob pattern of the names of the settings.",
+            default_value = "*"
+        )]
+        pattern: String,
+    },
 }
 
-fn main() -> Result<(), i32> {
+fn main() {
+    let code = match run() {
+        Ok(()) => 0,
+        Err(code) => code,
+    };
+    process::exit(code);
+}
+
+// The logger is dropped on return, flushing it before the exit.
+fn run() -> Result<(), i32> {
     let opt = Opt::from_args();
 
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::FullFormat::new(decorator).build().fuse();
     let drain = slog_async::Async::new(drain).build().fuse();
+    let drain = drain.filter_level(Level::Info).fuse();
     let log = Logger::root(drain, o!());
 
     let mut client = KvsClient::new(opt.addr, log)?;
@@ -76,6 +124,24 @@ fn main() -> Result<(), i32> {
             }
         })),
         Operation::Rmv { key } => Box::new(client.rm(key)),
+        Operation::Count { prefix } => Box::new(client.count(prefix).map(|n| println!("{}", n))),
+        Operation::Clients => Box::new(client.clients().map(|s| print!("{}", s))),
+        Operation::ServerVersion => Box::new(client.server_version().map(|s| println!("{}", s))),
+        Operation::LogLevel { level } => {
+            Box::new(client.log_level(level).map(|s| println!("{}", s)))
+        }
+        Operation::ConfigGet { pattern } => Box::new(client.config_get(pattern).map(|pairs| {
+            for (name, val) in pairs {
+                println!("{} {}", name, val);
+            }
+        })),
     };
-    res.wait()
+    res.wait().map_err(|code| match code {
+        // Only `rm` fails with it.
+        KEY_NOT_FOUND => {
+            eprintln!("Key not found");
+            EXIT_NOT_FOUND
+        }
+        _ => EXIT_FAILURE,
+    })
 }
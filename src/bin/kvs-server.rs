@@ -8,11 +8,14 @@ use structopt::clap::arg_enum;
 use structopt::StructOpt;
 
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::string::String;
+use std::time::Duration;
 
-use kvs::slog::{crit, o, Drain, Logger};
+use kvs::daemon::{self, PidFile};
+use kvs::slog::{crit, o, Drain, Level, Logger};
 use kvs::thread_pool::*;
-use kvs::{KvStore, KvsServer, SledDb};
+use kvs::{parse_level, KvStore, KvsServer, LevelSwitch, SledDb};
 
 const DB_DIR: &str = "./";
 
@@ -41,6 +44,42 @@ struct Opt {
         raw(possible_values = "&Engine::variants()")
     )]
     eng: Engine,
+    #[structopt(
+        name = "BACKLOG",
+        long = "backlog",
+        help = "Length of the queue of pending connections.",
+        default_value = "1024"
+    )]
+    backlog: i32,
+    #[structopt(
+        name = "MS",
+        long = "accept-backoff",
+        help = "Longest pause in milliseconds after failed accepts, e.g. out of file descriptors.",
+        default_value = "1000"
+    )]
+    accept_backoff: u64,
+    #[structopt(
+        name = "LEVEL",
+        long = "log-level",
+        help = "Initial log level, changed at runtime by the LOGLEVEL command.",
+        default_value = "info",
+        parse(try_from_str = "parse_level")
+    )]
+    log_level: Level,
+    #[structopt(long = "nodelay", help = "Set TCP_NODELAY on connections.")]
+    nodelay: bool,
+    #[structopt(
+        name = "PATH",
+        long = "pidfile",
+        help = "Write the process id to the file, refuse to start if it names a running process.",
+        parse(from_os_str)
+    )]
+    pidfile: Option<PathBuf>,
+    #[structopt(
+        long = "daemon",
+        help = "Run in the background, with stdio redirected to /dev/null (Unix only)."
+    )]
+    daemon: bool,
 }
 
 arg_enum! {
@@ -54,6 +93,13 @@ arg_enum! {
 
 fn main() -> Result<(), i32> {
     let opt = Opt::from_args();
+    // Before any thread is spawned.
+    if opt.daemon {
+        if let Err(e) = daemon::daemonize() {
+            eprintln!("failed to daemonize: {}", e);
+            return Err(1);
+        }
+    }
 
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::CompactFormat::new(decorator).build().fuse();
@@ -61,14 +107,25 @@ fn main() -> Result<(), i32> {
         .chan_size(10240)
         .build()
         .fuse();
+    let (drain, level) = LevelSwitch::new(drain, opt.log_level);
     let log = Logger::root(
-        drain,
+        drain.fuse(),
         o!(
             "name" => "kvs-server",
             "version" => env!("CARGO_PKG_VERSION"),
             "address" => opt.addr.to_string(),
         ),
     );
+    let _pidfile = match opt.pidfile {
+        Some(ref path) => match PidFile::create(path) {
+            Ok(f) => Some(f),
+            Err(e) => {
+                crit!(log, "failed to create pid file {:?}: {}", path, e);
+                return Err(1);
+            }
+        },
+        None => None,
+    };
     let pool = match SharedQueueThreadPool::new(0) {
         Ok(pool) => pool,
         Err(e) => {
@@ -78,18 +135,27 @@ fn main() -> Result<(), i32> {
     };
 
     match opt.eng {
-        Engine::kvs => {
-            let eng_log = log.new(o!("engine" => "kvs"));
-            match KvStore::with_logger(DB_DIR, eng_log) {
-                Ok(st) => KvsServer::new(st, pool, opt.addr, log.clone()).run()?,
-                Err(e) => {
-                    crit!(log, "failed to start KvStore in {}: {}", DB_DIR, e);
-                    return Err(1);
-                }
+        Engine::kvs => match KvStore::with_logger(DB_DIR, log.clone()) {
+            Ok(st) => KvsServer::new(st, pool, opt.addr, log.clone())
+                .backlog(opt.backlog)
+                .accept_backoff(Duration::from_millis(opt.accept_backoff))
+                .nodelay(opt.nodelay)
+                .signals(true)
+                .log_level(level)
+                .run()?,
+            Err(e) => {
+                crit!(log, "failed to start KvStore in {}: {}", DB_DIR, e);
+                return Err(1);
             }
-        }
+        },
         Engine::sled => match SledDb::open(DB_DIR) {
-            Ok(st) => KvsServer::new(st, pool, opt.addr, log.clone()).run()?,
+            Ok(st) => KvsServer::new(st, pool, opt.addr, log.clone())
+                .backlog(opt.backlog)
+                .accept_backoff(Duration::from_millis(opt.accept_backoff))
+                .nodelay(opt.nodelay)
+                .signals(true)
+                .log_level(level)
+                .run()?,
             Err(e) => {
                 crit!(log, "failed to start SledDB in {}: {}", DB_DIR, e);
                 return Err(1);
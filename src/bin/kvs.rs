@@ -0,0 +1,225 @@
+extern crate kvs;
+extern crate slog;
+extern crate slog_async;
+extern crate slog_term;
+extern crate structopt;
+
+use slog::{crit, o, Drain, Level, Logger};
+use structopt::clap::arg_enum;
+use structopt::StructOpt;
+
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use kvs::{KvStore, KvStoreBuilder, KvsEngine, SledDb};
+
+// Keys copied between two progress reports of `migrate`.
+const PROGRESS_STEP: usize = 10000;
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "kvs",
+    about = "Offline tools for a key-value store directory.",
+    raw(setting = "structopt::clap::AppSettings::ColoredHelp"),
+    raw(setting = "structopt::clap::AppSettings::VersionlessSubcommands"),
+    raw(setting = "structopt::clap::AppSettings::DisableHelpSubcommand")
+)]
+enum Opt {
+    #[structopt(
+        name = "info",
+        about = "Print the metadata and file layout of a database"
+    )]
+    Info {
+        #[structopt(name = "DIR", help = "The database directory.", parse(from_os_str))]
+        dir: PathBuf,
+    },
+    #[structopt(
+        name = "migrate",
+        about = "Copy all the key-value pairs of a database into another engine"
+    )]
+    Migrate {
+        #[structopt(
+            name = "FROM",
+            long = "from",
+            help = "The source database, which must not be in use.",
+            parse(from_os_str)
+        )]
+        from: PathBuf,
+        #[structopt(
+            name = "FROM-ENGINE",
+            long = "from-engine",
+            default_value = "kvs",
+            raw(possible_values = "&Engine::variants()")
+        )]
+        from_engine: Engine,
+        #[structopt(
+            name = "TO",
+            long = "to",
+            help = "The target database, created if absent.",
+            parse(from_os_str)
+        )]
+        to: PathBuf,
+        #[structopt(
+            name = "TO-ENGINE",
+            long = "to-engine",
+            default_value = "sled",
+            raw(possible_values = "&Engine::variants()")
+        )]
+        to_engine: Engine,
+        #[structopt(
+            long = "force",
+            help = "Delete anything in the target that is not a database of the target engine."
+        )]
+        force: bool,
+    },
+}
+
+arg_enum! {
+    #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+    #[allow(non_camel_case_types)]
+    enum Engine {
+        kvs,
+        sled,
+    }
+}
+
+/// Engine recorded on the first line of the meta file of `dir`, `None`
+/// without meta file.
+fn engine_of(dir: &Path) -> Option<String> {
+    fs::read(dir.join("meta")).ok().map(|meta| {
+        let meta = String::from_utf8_lossy(&meta);
+        meta.lines().next().unwrap_or("").trim().to_owned()
+    })
+}
+
+/// Make sure `to` is empty or a database of `engine`, clear it with `force`.
+fn check_target(to: &Path, engine: Engine, force: bool) -> Result<(), String> {
+    let used = match fs::read_dir(to) {
+        Ok(mut entries) => entries.next().is_some(),
+        Err(_) => false,
+    };
+    if !used || engine_of(to) == Some(engine.to_string()) {
+        return Ok(());
+    }
+    if !force {
+        return Err(format!(
+            "{:?} has data of another engine, use --force to delete it",
+            to
+        ));
+    }
+    fs::remove_dir_all(to).map_err(|e| format!("failed to clear {:?}: {}", to, e))
+}
+
+fn copy_all<S: KvsEngine, T: KvsEngine>(src: &S, dst: &T) -> kvs::Result<usize> {
+    let keys = src.keys()?;
+    let total = keys.len();
+    let mut copied = 0;
+    // Written a progress step at a time, with one flush each.
+    let mut batch = Vec::new();
+    for (i, key) in keys.into_iter().enumerate() {
+        // Nothing else writes the source, but don't fail on a missing key.
+        if let Some(val) = src.get(key.clone())? {
+            batch.push((key, val));
+        }
+        if (i + 1) % PROGRESS_STEP == 0 {
+            copied += batch.len();
+            dst.set_many(mem::take(&mut batch))?;
+            eprintln!("{} of {} keys", i + 1, total);
+        }
+    }
+    copied += batch.len();
+    dst.set_many(batch)?;
+    Ok(copied)
+}
+
+fn copy_into<S: KvsEngine>(src: &S, to: &Path, engine: Engine) -> kvs::Result<usize> {
+    fs::create_dir_all(to)?;
+    match engine {
+        Engine::kvs => copy_all(src, &KvStore::open(to)?),
+        Engine::sled => copy_all(src, &SledDb::open(to)?),
+    }
+}
+
+fn migrate(from: &Path, from_engine: Engine, to: &Path, to_engine: Engine) -> kvs::Result<usize> {
+    match from_engine {
+        Engine::kvs => copy_into(&KvStore::open(from)?, to, to_engine),
+        Engine::sled => copy_into(&SledDb::open(from)?, to, to_engine),
+    }
+}
+
+fn main() -> Result<(), i32> {
+    let opt = Opt::from_args();
+
+    let decorator = slog_term::TermDecorator::new().build();
+    let drain = slog_term::FullFormat::new(decorator).build().fuse();
+    let drain = slog_async::Async::new(drain).build().fuse();
+    let drain = drain.filter_level(Level::Info).fuse();
+    let log = Logger::root(drain, o!());
+
+    match opt {
+        Opt::Info { dir } => {
+            let info = match KvStoreBuilder::new(&dir).inspect() {
+                Ok(info) => info,
+                Err(e) => {
+                    crit!(log, "failed to inspect {:?}: {}", dir, e);
+                    return Err(1);
+                }
+            };
+            println!("engine:      {}", info.engine);
+            println!("version:     {}", info.version);
+            if info.engine != "kvs" {
+                return Ok(());
+            }
+            match info.active_id {
+                Some(id) => println!("active file: {}", id),
+                None => println!("active file: -"),
+            }
+            println!("keys:        {}", info.keys);
+            println!(
+                "garbage:     {:.1}% ({} of {} bytes)",
+                info.garbage_ratio() * 100.0,
+                info.garbage,
+                info.size()
+            );
+            println!();
+            println!("{:>10} {:>12}", "file", "size");
+            for (id, sz) in info.files.iter() {
+                println!("{:>10} {:>12}", id, sz);
+            }
+        }
+        Opt::Migrate {
+            from,
+            from_engine,
+            to,
+            to_engine,
+            force,
+        } => {
+            let same = match (fs::canonicalize(&from), fs::canonicalize(&to)) {
+                (Ok(from), Ok(to)) => from == to,
+                _ => false,
+            };
+            if same {
+                crit!(log, "source and target are the same directory: {:?}", from);
+                return Err(1);
+            }
+            // Opening would initialize an empty directory.
+            if engine_of(&from) != Some(from_engine.to_string()) {
+                crit!(log, "no {} database in {:?}", from_engine, from);
+                return Err(1);
+            }
+            if let Err(e) = check_target(&to, to_engine, force) {
+                crit!(log, "{}", e);
+                return Err(1);
+            }
+            match migrate(&from, from_engine, &to, to_engine) {
+                Ok(n) => println!("migrated {} keys", n),
+                Err(e) => {
+                    crit!(log, "failed to migrate {:?} to {:?}: {}", from, to, e);
+                    return Err(1);
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,88 @@
+extern crate futures03;
+
+use futures03::compat::Future01CompatExt;
+use slog::Logger;
+
+use std::error::Error as StdError;
+use std::fmt::{self, Display, Formatter};
+use std::net::SocketAddr;
+
+use super::KvsClient;
+
+/// A failed request of `AsyncKvsClient`.
+///
+/// The code is the one `KvsClient` fails with, e.g. 666 if the server
+/// can't be reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientError(i32);
+
+impl ClientError {
+    pub fn code(self) -> i32 {
+        self.0
+    }
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut Formatter) -> std::result::Result<(), fmt::Error> {
+        match self.0 {
+            666 => write!(f, "failed to connect the server"),
+            2 => write!(f, "failed to send the command"),
+            998 | 999 => write!(f, "failed to read the reply"),
+            code => write!(f, "request failed with code {}", code),
+        }
+    }
+}
+
+impl StdError for ClientError {}
+
+/// A client for `async` code, speaking the same protocol as `KvsClient`.
+///
+/// The futures run on any executor, the sockets are driven by the
+/// reactor of tokio 0.1 in the background.
+pub struct AsyncKvsClient {
+    inner: KvsClient,
+}
+
+impl AsyncKvsClient {
+    pub fn new<LG>(addr: SocketAddr, log: LG) -> Result<Self, ClientError>
+    where
+        LG: Into<Option<Logger>>,
+    {
+        let inner = KvsClient::new(addr, log).map_err(ClientError)?;
+        Ok(Self { inner })
+    }
+
+    pub async fn set(&self, key: String, val: String) -> Result<(), ClientError> {
+        self.inner.set(key, val).compat().await.map_err(ClientError)
+    }
+
+    /// Set with an idempotency token, see `KvsClient::set_with_token`.
+    pub async fn set_with_token(
+        &self,
+        key: String,
+        val: String,
+        token: String,
+    ) -> Result<(), ClientError> {
+        let req = self.inner.set_with_token(key, val, token);
+        req.compat().await.map_err(ClientError)
+    }
+
+    pub async fn get(&self, key: String) -> Result<Option<String>, ClientError> {
+        self.inner.get(key).compat().await.map_err(ClientError)
+    }
+
+    pub async fn rm(&mut self, key: String) -> Result<(), ClientError> {
+        self.inner.rm(key).compat().await.map_err(ClientError)
+    }
+
+    /// Count the keys starting with `prefix`.
+    pub async fn count(&self, prefix: String) -> Result<usize, ClientError> {
+        self.inner.count(prefix).compat().await.map_err(ClientError)
+    }
+
+    /// Increment the float value of key by `delta`, return the new value.
+    pub async fn incrbyfloat(&self, key: String, delta: f64) -> Result<f64, ClientError> {
+        let req = self.inner.incrbyfloat(key, delta);
+        req.compat().await.map_err(ClientError)
+    }
+}
@@ -0,0 +1,196 @@
+use future::Either;
+use futures::sync::oneshot;
+use slog::Logger;
+use tokio::prelude::*;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::KvsClient;
+use crate::get_logger;
+
+const CAPACITY: usize = 10000;
+const MAX_AGE: Duration = Duration::from_secs(1);
+
+/// A client caching the results of `get`, invalidated by the key events
+/// of the server, see `KvsClient::psubscribe`.
+///
+/// A hit skips the network. The keys outside the `prefix` given to `new`
+/// are never cached. A write by another client is still read from the
+/// cache until its event arrives, about one network hop after the write
+/// is acknowledged. The server drops events for a slow subscriber, so an
+/// entry is also dropped after `max_age`, which bounds the staleness.
+/// Nothing is cached until the subscription is confirmed, nor once it
+/// is lost.
+///
+/// The client reads its own writes: `set` and `rm` drop the entry of the
+/// key both when sent and when acknowledged, so a `get` racing with them
+/// can't cache the value they replace.
+pub struct CachingClient {
+    client: KvsClient,
+    cache: Arc<Mutex<Cache>>,
+    prefix: String,
+    // Dropped with the client, ending the subscription.
+    _stop: oneshot::Sender<()>,
+}
+
+struct Cache {
+    entries: HashMap<String, (Option<String>, Instant)>,
+    capacity: usize,
+    max_age: Duration,
+    /// Counts the invalidations, so that a `get` sent before one doesn't
+    /// cache its result.
+    epoch: u64,
+    /// The subscription is active.
+    live: bool,
+}
+
+impl Cache {
+    fn get(&self, key: &str) -> Option<Option<String>> {
+        match self.entries.get(key) {
+            Some((val, at)) if at.elapsed() < self.max_age => Some(val.clone()),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, epoch: u64, key: String, val: Option<String>) {
+        if !self.live || epoch != self.epoch {
+            return;
+        }
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            let max_age = self.max_age;
+            self.entries.retain(|_, (_, at)| at.elapsed() < max_age);
+            if self.entries.len() >= self.capacity {
+                let victim = self.entries.keys().next().cloned();
+                if let Some(victim) = victim {
+                    self.entries.remove(&victim);
+                }
+            }
+        }
+        self.entries.insert(key, (val, Instant::now()));
+    }
+
+    fn invalidate(&mut self, event: &str, key: &str) {
+        self.epoch += 1;
+        if event == "delprefix" {
+            self.entries.retain(|k, _| !k.starts_with(key));
+        } else {
+            self.entries.remove(key);
+        }
+    }
+}
+
+impl CachingClient {
+    /// Cache the keys starting with `prefix`, "" for all of them.
+    pub fn new<LG>(addr: SocketAddr, prefix: String, log: LG) -> Result<Self, i32>
+    where
+        LG: Into<Option<Logger>>,
+    {
+        let log = get_logger(&mut log.into());
+        let client = KvsClient::new(addr, log.clone())?;
+        let cache = Arc::new(Mutex::new(Cache {
+            entries: HashMap::new(),
+            capacity: CAPACITY,
+            max_age: MAX_AGE,
+            epoch: 0,
+            live: false,
+        }));
+
+        let (stop, stopped) = oneshot::channel();
+        let sub = client.subscribe(prefix.clone());
+        let cache2 = cache.clone();
+        thread::Builder::new()
+            .name("kvs-cache-invalidation".to_owned())
+            .spawn(move || {
+                let events = match sub.wait() {
+                    Ok(events) => events,
+                    Err(code) => {
+                        crit!(log, "failed to subscribe, not caching: {}", code);
+                        return;
+                    }
+                };
+                cache2.lock().unwrap().live = true;
+                // Ends with an error when `stop` is dropped.
+                let stopped = stopped.into_stream().map(|_| None).map_err(|_| 0);
+                for event in events.map(Some).select(stopped).wait() {
+                    match event {
+                        Ok(Some((event, key))) => cache2.lock().unwrap().invalidate(&event, &key),
+                        Ok(None) => break,
+                        Err(code) => {
+                            if code != 0 {
+                                error!(log, "subscription lost, not caching: {}", code);
+                            }
+                            break;
+                        }
+                    }
+                }
+                let mut cache = cache2.lock().unwrap();
+                cache.live = false;
+                cache.entries.clear();
+            })
+            .map_err(|_| 1)?;
+
+        Ok(Self {
+            client,
+            cache,
+            prefix,
+            _stop: stop,
+        })
+    }
+
+    /// Keep at most `capacity` entries, 10000 by default.
+    pub fn capacity(self, capacity: usize) -> Self {
+        self.cache.lock().unwrap().capacity = capacity;
+        self
+    }
+
+    /// Drop the entries older than `max_age`, 1 second by default.
+    pub fn max_age(self, max_age: Duration) -> Self {
+        self.cache.lock().unwrap().max_age = max_age;
+        self
+    }
+
+    /// Number of entries, including the expired ones not dropped yet.
+    pub fn cached(&self) -> usize {
+        self.cache.lock().unwrap().entries.len()
+    }
+
+    pub fn get(&self, key: String) -> impl Future<Item = Option<String>, Error = i32> {
+        if !key.starts_with(self.prefix.as_str()) {
+            return Either::A(self.client.get(key));
+        }
+        let epoch = {
+            let cache = self.cache.lock().unwrap();
+            if let Some(val) = cache.get(&key) {
+                return Either::B(Either::A(future::ok(val)));
+            }
+            cache.epoch
+        };
+        let cache = self.cache.clone();
+        Either::B(Either::B(self.client.get(key.clone()).map(move |val| {
+            cache.lock().unwrap().insert(epoch, key, val.clone());
+            val
+        })))
+    }
+
+    pub fn set(&self, key: String, val: String) -> impl Future<Item = (), Error = i32> {
+        self.cache.lock().unwrap().invalidate("set", &key);
+        let cache = self.cache.clone();
+        self.client.set(key.clone(), val).then(move |res| {
+            cache.lock().unwrap().invalidate("set", &key);
+            res
+        })
+    }
+
+    pub fn rm(&mut self, key: String) -> impl Future<Item = (), Error = i32> {
+        self.cache.lock().unwrap().invalidate("del", &key);
+        let cache = self.cache.clone();
+        self.client.rm(key.clone()).then(move |res| {
+            cache.lock().unwrap().invalidate("del", &key);
+            res
+        })
+    }
+}
@@ -0,0 +1,1032 @@
+extern crate bytes;
+extern crate tokio;
+
+use slog::Logger;
+use tokio::codec::Framed;
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+use std::net::SocketAddr;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::get_logger;
+use crate::protocol::{Proto, ProtoCodec};
+use crate::{KeyMeta, NamespaceUsage, Quota};
+
+#[cfg(feature = "async-client")]
+mod async_client;
+mod caching;
+mod reply;
+
+#[cfg(feature = "async-client")]
+pub use async_client::{AsyncKvsClient, ClientError};
+pub use caching::CachingClient;
+use reply::Reply;
+
+/// Error code of `KvsClient::rm` for a key that doesn't exist.
+pub const KEY_NOT_FOUND: i32 = 8;
+
+/// A client of `KvsServer`, opening a connection per request.
+///
+/// The server replies to a write once the store applied it, so when the
+/// future of a write succeeds, every request sent afterwards, on any
+/// connection, observes it: a client that waits for its writes reads its
+/// writes. Requests sent without waiting for each other may be applied in
+/// any order, since the server runs them on a thread pool. A write that
+/// failed, e.g. on a timeout, may still have been applied or be applied
+/// later.
+pub struct KvsClient {
+    addr: SocketAddr,
+    log: Logger,
+}
+
+impl KvsClient {
+    pub fn new<LG>(addr: SocketAddr, log: LG) -> Result<Self, i32>
+    where
+        LG: Into<Option<Logger>>,
+    {
+        let log = get_logger(&mut log.into());
+        Ok(Self { addr, log })
+    }
+
+    /// Send a command and interpret the reply with `f`.
+    ///
+    /// An error reply fails with `codes.0`. A reply that can't be decoded
+    /// or is given back by `f` fails with `codes.1`.
+    fn command<T, F>(
+        &self,
+        req: Vec<Proto>,
+        codes: (i32, i32),
+        f: F,
+    ) -> impl Future<Item = T, Error = i32>
+    where
+        F: FnOnce(Reply) -> Result<T, Reply>,
+    {
+        let (server, unexpected) = codes;
+        let log = self.log.clone();
+        self.request(Proto::Seq(req))
+            .and_then(move |rep| match Reply::parse(rep) {
+                Ok(Reply::Error(code, msg)) => {
+                    error!(log, "server error: {} {}", code, msg);
+                    Err(server)
+                }
+                Ok(rep) => f(rep).map_err(|rep| {
+                    crit!(log, "unexpected reply: {:?}", rep);
+                    unexpected
+                }),
+                Err(e) => {
+                    crit!(log, "{}", e);
+                    Err(unexpected)
+                }
+            })
+    }
+
+    fn request(&self, req: Proto) -> impl Future<Item = Proto, Error = i32> {
+        let addr = self.addr;
+        let log0 = self.log.clone();
+        let log1 = self.log.clone();
+        let log2 = self.log.clone();
+        TcpStream::connect(&self.addr)
+            .map_err(move |e| {
+                crit!(log0, "failed to connect {}: {}", addr, e);
+                666
+            })
+            .and_then(|sock| {
+                Framed::new(sock, ProtoCodec::new())
+                    .send(req)
+                    .map_err(move |e| {
+                        crit!(log1, "failed to send command: {}", e);
+                        2
+                    })
+            })
+            .and_then(move |frame| {
+                let log = log2.clone();
+                frame
+                    .into_future()
+                    .map_err(move |(e, _)| {
+                        crit!(log2, "failed to decode reply: {}", e);
+                        999
+                    })
+                    .and_then(move |(resp, _)| {
+                        resp.ok_or_else(|| {
+                            crit!(log, "empty reply");
+                            998
+                        })
+                    })
+            })
+    }
+
+    pub fn set(&self, key: String, val: String) -> impl Future<Item = (), Error = i32> {
+        self.set_req(vec![
+            Proto::Str("SET".to_owned()),
+            Proto::Bulk(Vec::from(key)),
+            Proto::Bulk(Vec::from(val)),
+        ])
+    }
+
+    /// Set the value with `SETINFO`, return whether the key was created,
+    /// false if a value was overwritten.
+    pub fn set_created(&self, key: String, val: String) -> impl Future<Item = bool, Error = i32> {
+        let req = vec![
+            Proto::Str("SETINFO".to_owned()),
+            Proto::Bulk(Vec::from(key)),
+            Proto::Bulk(Vec::from(val)),
+        ];
+        self.command(req, (86, 87), |rep| match rep {
+            Reply::Int(n) => Ok(n != 0),
+            rep => Err(rep),
+        })
+    }
+
+    /// Set with an idempotency token, so that a retry with the same token
+    /// within the server's dedup window is not applied twice.
+    pub fn set_with_token(
+        &self,
+        key: String,
+        val: String,
+        token: String,
+    ) -> impl Future<Item = (), Error = i32> {
+        self.set_req(vec![
+            Proto::Str("SET".to_owned()),
+            Proto::Bulk(Vec::from(key)),
+            Proto::Bulk(Vec::from(val)),
+            Proto::Bulk(Vec::from("ID")),
+            Proto::Bulk(Vec::from(token)),
+        ])
+    }
+
+    fn set_req(&self, req: Vec<Proto>) -> impl Future<Item = (), Error = i32> {
+        self.command(req, (3, 4), |rep| match rep {
+            Reply::Ok => Ok(()),
+            rep => Err(rep),
+        })
+    }
+
+    pub fn get(&self, key: String) -> impl Future<Item = Option<String>, Error = i32> {
+        let req = vec![Proto::Str("GET".to_owned()), Proto::Bulk(Vec::from(key))];
+        self.command(req, (6, 7), |rep| match rep {
+            Reply::Value(s) => Ok(Some(s)),
+            Reply::Nil => Ok(None),
+            rep => Err(rep),
+        })
+    }
+
+    /// Get the bytes `start..=end` of the value of key, a negative index
+    /// counting from the end, e.g. -1 for the last byte.
+    pub fn getrange(
+        &self,
+        key: String,
+        start: i64,
+        end: i64,
+    ) -> impl Future<Item = Option<String>, Error = i32> {
+        let req = vec![
+            Proto::Str("GETRANGE".to_owned()),
+            Proto::Bulk(Vec::from(key)),
+            Proto::Bulk(Vec::from(start.to_string())),
+            Proto::Bulk(Vec::from(end.to_string())),
+        ];
+        self.command(req, (54, 55), |rep| match rep {
+            Reply::Value(s) => Ok(Some(s)),
+            Reply::Nil => Ok(None),
+            rep => Err(rep),
+        })
+    }
+
+    /// Get the value of key cut to at most `max` bytes, and whether it was
+    /// cut, see `KvStore::get_capped`.
+    pub fn get_capped(
+        &self,
+        key: String,
+        max: usize,
+    ) -> impl Future<Item = Option<(String, bool)>, Error = i32> {
+        let req = vec![
+            Proto::Str("GET".to_owned()),
+            Proto::Bulk(Vec::from(key)),
+            Proto::Bulk(Vec::from("CAP")),
+            Proto::Bulk(Vec::from(max.to_string())),
+        ];
+        self.command(req, (70, 71), |rep| match rep {
+            Reply::Array(items) => {
+                let found = match items.as_slice() {
+                    [Reply::Value(val), Reply::Int(cut)] => Some((val.clone(), *cut != 0)),
+                    _ => None,
+                };
+                found.map(Some).ok_or(Reply::Array(items))
+            }
+            Reply::Nil => Ok(None),
+            rep => Err(rep),
+        })
+    }
+
+    /// Remove key, fail with `KEY_NOT_FOUND` if it doesn't exist.
+    pub fn rm(&mut self, key: String) -> impl Future<Item = (), Error = i32> {
+        let req = vec![Proto::Str("RM".to_owned()), Proto::Bulk(Vec::from(key))];
+        let log = self.log.clone();
+        self.request(Proto::Seq(req))
+            .and_then(move |rep| match Reply::parse(rep) {
+                Ok(Reply::Ok) => Ok(()),
+                // The message of `KvsError::KeyNotFound`, from any engine.
+                Ok(Reply::Error(_, ref msg)) if msg.starts_with("Key not found") => {
+                    Err(KEY_NOT_FOUND)
+                }
+                Ok(Reply::Error(code, msg)) => {
+                    error!(log, "server error: {} {}", code, msg);
+                    Err(9)
+                }
+                Ok(rep) => {
+                    crit!(log, "unexpected reply: {:?}", rep);
+                    Err(10)
+                }
+                Err(e) => {
+                    crit!(log, "{}", e);
+                    Err(10)
+                }
+            })
+    }
+
+    pub fn count(&self, prefix: String) -> impl Future<Item = usize, Error = i32> {
+        let req = vec![
+            Proto::Str("COUNT".to_owned()),
+            Proto::Bulk(Vec::from(prefix)),
+        ];
+        self.command(req, (11, 12), |rep| match rep {
+            Reply::Int(n) if n >= 0 => Ok(n as usize),
+            rep => Err(rep),
+        })
+    }
+
+    /// Make key expire `ttl` from now, resolve to whether it exists.
+    /// The TTL is sent in milliseconds and must be at least one.
+    pub fn expire(&self, key: String, ttl: Duration) -> impl Future<Item = bool, Error = i32> {
+        let req = vec![
+            Proto::Str("EXPIRE".to_owned()),
+            Proto::Bulk(Vec::from(key)),
+            Proto::Bulk(Vec::from(ttl.as_millis().to_string())),
+        ];
+        self.command(req, (72, 73), |rep| match rep {
+            Reply::Int(n) => Ok(n != 0),
+            rep => Err(rep),
+        })
+    }
+
+    /// Make key never expire, resolve to whether it had a TTL.
+    pub fn persist(&self, key: String) -> impl Future<Item = bool, Error = i32> {
+        let req = vec![
+            Proto::Str("PERSIST".to_owned()),
+            Proto::Bulk(Vec::from(key)),
+        ];
+        self.command(req, (98, 99), |rep| match rep {
+            Reply::Int(n) => Ok(n != 0),
+            rep => Err(rep),
+        })
+    }
+
+    /// Add `delta` to the float value of key, and return the new value.
+    pub fn incrbyfloat(&self, key: String, delta: f64) -> impl Future<Item = f64, Error = i32> {
+        let req = vec![
+            Proto::Str("INCRBYFLOAT".to_owned()),
+            Proto::Bulk(Vec::from(key)),
+            Proto::Bulk(Vec::from(delta.to_string())),
+        ];
+        self.command(req, (14, 15), |rep| match rep {
+            Reply::Value(s) => s.parse().map_err(|_| Reply::Value(s)),
+            rep => Err(rep),
+        })
+    }
+
+    /// Add `delta` to the integer value of key and return the new value,
+    /// creating the key with an expiry `ttl` from now if it is absent, see
+    /// `KvStore::increment_with_ttl`. The TTL is sent in milliseconds and
+    /// must be at least one.
+    pub fn incr_with_ttl(
+        &self,
+        key: String,
+        delta: i64,
+        ttl: Duration,
+    ) -> impl Future<Item = i64, Error = i32> {
+        let req = vec![
+            Proto::Str("INCRTTL".to_owned()),
+            Proto::Bulk(Vec::from(key)),
+            Proto::Bulk(Vec::from(delta.to_string())),
+            Proto::Bulk(Vec::from(ttl.as_millis().to_string())),
+        ];
+        self.command(req, (74, 75), |rep| match rep {
+            Reply::Int(n) => Ok(n),
+            rep => Err(rep),
+        })
+    }
+
+    /// Prepend `val` to the list of key and return its new length. Fails if
+    /// key holds a string.
+    pub fn lpush(&self, key: String, val: String) -> impl Future<Item = usize, Error = i32> {
+        self.push("LPUSH", key, val, (88, 89))
+    }
+
+    /// Append `val` to the list of key and return its new length. Fails if
+    /// key holds a string.
+    pub fn rpush(&self, key: String, val: String) -> impl Future<Item = usize, Error = i32> {
+        self.push("RPUSH", key, val, (90, 91))
+    }
+
+    fn push(
+        &self,
+        cmd: &str,
+        key: String,
+        val: String,
+        codes: (i32, i32),
+    ) -> impl Future<Item = usize, Error = i32> {
+        let req = vec![
+            Proto::Str(cmd.to_owned()),
+            Proto::Bulk(Vec::from(key)),
+            Proto::Bulk(Vec::from(val)),
+        ];
+        self.command(req, codes, |rep| match rep {
+            Reply::Int(n) if n >= 0 => Ok(n as usize),
+            rep => Err(rep),
+        })
+    }
+
+    /// Get the items `start..=stop` of the list of key, a negative index
+    /// counting from the end, empty if key is absent.
+    pub fn lrange(
+        &self,
+        key: String,
+        start: i64,
+        stop: i64,
+    ) -> impl Future<Item = Vec<String>, Error = i32> {
+        let req = vec![
+            Proto::Str("LRANGE".to_owned()),
+            Proto::Bulk(Vec::from(key)),
+            Proto::Bulk(Vec::from(start.to_string())),
+            Proto::Bulk(Vec::from(stop.to_string())),
+        ];
+        self.command(req, (92, 93), |rep| match rep {
+            Reply::Array(items) => {
+                let vals: Option<Vec<_>> = items
+                    .iter()
+                    .map(|item| match item {
+                        Reply::Value(val) => Some(val.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                vals.ok_or(Reply::Array(items))
+            }
+            rep => Err(rep),
+        })
+    }
+
+    /// Get the length of the list of key, 0 if it is absent.
+    pub fn llen(&self, key: String) -> impl Future<Item = usize, Error = i32> {
+        let req = vec![Proto::Str("LLEN".to_owned()), Proto::Bulk(Vec::from(key))];
+        self.command(req, (94, 95), |rep| match rep {
+            Reply::Int(n) if n >= 0 => Ok(n as usize),
+            rep => Err(rep),
+        })
+    }
+
+    /// List the connections of the server, one `addr=<peer> age=<seconds>`
+    /// line each, including this one.
+    pub fn clients(&self) -> impl Future<Item = String, Error = i32> {
+        let req = vec![Proto::Str("CLIENTS".to_owned())];
+        self.command(req, (16, 17), |rep| match rep {
+            Reply::Value(s) => Ok(s),
+            rep => Err(rep),
+        })
+    }
+
+    /// Get the value of key and its version.
+    pub fn get_with_version(
+        &self,
+        key: String,
+    ) -> impl Future<Item = Option<(String, u64)>, Error = i32> {
+        let req = vec![Proto::Str("GETVER".to_owned()), Proto::Bulk(Vec::from(key))];
+        self.command(req, (18, 19), |rep| match rep {
+            Reply::Array(items) => {
+                if let [Reply::Value(val), Reply::Int(ver)] = items.as_slice() {
+                    if *ver >= 0 {
+                        return Ok(Some((val.clone(), *ver as u64)));
+                    }
+                }
+                Err(Reply::Array(items))
+            }
+            Reply::Nil => Ok(None),
+            rep => Err(rep),
+        })
+    }
+
+    /// Get key with the time left until it expires, `None` if it never
+    /// does. With `ttl`, also make it expire `ttl` from now, at once, see
+    /// `KvStore::get_and_expire`. The TTL is sent in milliseconds and must
+    /// be at least one.
+    pub fn getex(
+        &self,
+        key: String,
+        ttl: Option<Duration>,
+    ) -> impl Future<Item = Option<(String, Option<Duration>)>, Error = i32> {
+        let mut req = vec![Proto::Str("GETEX".to_owned()), Proto::Bulk(Vec::from(key))];
+        if let Some(ttl) = ttl {
+            req.push(Proto::Bulk(Vec::from("PX")));
+            req.push(Proto::Bulk(Vec::from(ttl.as_millis().to_string())));
+        }
+        self.command(req, (76, 77), |rep| match rep {
+            Reply::Array(items) => {
+                let found = match items.as_slice() {
+                    [Reply::Value(val), Reply::Int(-1)] => Some((val.clone(), None)),
+                    [Reply::Value(val), Reply::Int(ms)] if *ms >= 0 => {
+                        Some((val.clone(), Some(Duration::from_millis(*ms as u64))))
+                    }
+                    _ => None,
+                };
+                found.map(Some).ok_or(Reply::Array(items))
+            }
+            Reply::Nil => Ok(None),
+            rep => Err(rep),
+        })
+    }
+
+    /// Set the value if the version of key is `expected`, 0 for an absent key.
+    /// Return the new version.
+    pub fn set_if_version(
+        &self,
+        key: String,
+        val: String,
+        expected: u64,
+    ) -> impl Future<Item = u64, Error = i32> {
+        let req = vec![
+            Proto::Str("SET".to_owned()),
+            Proto::Bulk(Vec::from(key)),
+            Proto::Bulk(Vec::from(val)),
+            Proto::Bulk(Vec::from("VERSION")),
+            Proto::Bulk(Vec::from(expected.to_string())),
+        ];
+        self.command(req, (20, 21), |rep| match rep {
+            Reply::Int(ver) if ver >= 0 => Ok(ver as u64),
+            rep => Err(rep),
+        })
+    }
+
+    /// Set the value only if key exists, with `SET XX`. Return whether it
+    /// was set.
+    pub fn set_if_present(
+        &self,
+        key: String,
+        val: String,
+    ) -> impl Future<Item = bool, Error = i32> {
+        self.set_if_exists(key, val, "XX", (56, 57))
+    }
+
+    /// Set the value only if key doesn't exist, with `SET NX`. Return
+    /// whether it was set.
+    pub fn set_if_absent(&self, key: String, val: String) -> impl Future<Item = bool, Error = i32> {
+        self.set_if_exists(key, val, "NX", (58, 59))
+    }
+
+    fn set_if_exists(
+        &self,
+        key: String,
+        val: String,
+        flag: &str,
+        codes: (i32, i32),
+    ) -> impl Future<Item = bool, Error = i32> {
+        let req = vec![
+            Proto::Str("SET".to_owned()),
+            Proto::Bulk(Vec::from(key)),
+            Proto::Bulk(Vec::from(val)),
+            Proto::Bulk(Vec::from(flag)),
+        ];
+        self.command(req, codes, |rep| match rep {
+            Reply::Int(n) => Ok(n != 0),
+            rep => Err(rep),
+        })
+    }
+
+    /// Rename `src` to `dst` if `dst` doesn't exist, return whether it was renamed.
+    pub fn rename_nx(&self, src: String, dst: String) -> impl Future<Item = bool, Error = i32> {
+        let req = vec![
+            Proto::Str("RENAMENX".to_owned()),
+            Proto::Bulk(Vec::from(src)),
+            Proto::Bulk(Vec::from(dst)),
+        ];
+        self.command(req, (22, 23), |rep| match rep {
+            Reply::Int(n) => Ok(n != 0),
+            rep => Err(rep),
+        })
+    }
+
+    /// Copy `src` to `dst`, overwrite `dst` only with `replace`.
+    /// Return whether it was copied.
+    pub fn copy(
+        &self,
+        src: String,
+        dst: String,
+        replace: bool,
+    ) -> impl Future<Item = bool, Error = i32> {
+        let mut req = vec![
+            Proto::Str("COPY".to_owned()),
+            Proto::Bulk(Vec::from(src)),
+            Proto::Bulk(Vec::from(dst)),
+        ];
+        if replace {
+            req.push(Proto::Bulk(Vec::from("REPLACE")));
+        }
+        self.command(req, (24, 25), |rep| match rep {
+            Reply::Int(n) => Ok(n != 0),
+            rep => Err(rep),
+        })
+    }
+
+    /// Exchange the values of two existing keys.
+    pub fn swap(&self, a: String, b: String) -> impl Future<Item = (), Error = i32> {
+        let req = vec![
+            Proto::Str("SWAP".to_owned()),
+            Proto::Bulk(Vec::from(a)),
+            Proto::Bulk(Vec::from(b)),
+        ];
+        self.command(req, (36, 37), |rep| match rep {
+            Reply::Ok => Ok(()),
+            rep => Err(rep),
+        })
+    }
+
+    /// Remove the keys starting with `prefix`, return how many were removed.
+    pub fn del_prefix(&self, prefix: String) -> impl Future<Item = usize, Error = i32> {
+        let req = vec![
+            Proto::Str("DELPREFIX".to_owned()),
+            Proto::Bulk(Vec::from(prefix)),
+        ];
+        self.command(req, (38, 39), |rep| match rep {
+            Reply::Int(n) if n >= 0 => Ok(n as usize),
+            rep => Err(rep),
+        })
+    }
+
+    /// Remove the keys whose TTL has passed, return how many were removed,
+    /// see `KvStore::purge_expired`.
+    pub fn purge_expired(&self) -> impl Future<Item = usize, Error = i32> {
+        let req = vec![Proto::Str("PURGEEXPIRED".to_owned())];
+        self.command(req, (78, 79), |rep| match rep {
+            Reply::Int(n) if n >= 0 => Ok(n as usize),
+            rep => Err(rep),
+        })
+    }
+
+    /// The pairs of the keys starting with `prefix` whose value contains
+    /// `substring`, sorted by key. The filtering is done by the server.
+    pub fn scan_value(
+        &self,
+        prefix: String,
+        substring: String,
+    ) -> impl Future<Item = Vec<(String, String)>, Error = i32> {
+        let req = vec![
+            Proto::Str("SCANVALUE".to_owned()),
+            Proto::Bulk(Vec::from(prefix)),
+            Proto::Bulk(Vec::from(substring)),
+        ];
+        self.command(req, (40, 41), |rep| match rep {
+            Reply::Array(items) => {
+                let pairs: Option<Vec<_>> = items
+                    .chunks(2)
+                    .map(|pair| match pair {
+                        [Reply::Value(key), Reply::Value(val)] => Some((key.clone(), val.clone())),
+                        _ => None,
+                    })
+                    .collect();
+                pairs.ok_or(Reply::Array(items))
+            }
+            rep => Err(rep),
+        })
+    }
+
+    /// Set all the pairs in one atomic batch, and return the previous value
+    /// of each key, `None` for an absent one.
+    pub fn mset_get(
+        &self,
+        pairs: Vec<(String, String)>,
+    ) -> impl Future<Item = Vec<Option<String>>, Error = i32> {
+        let mut req = vec![Proto::Str("MSETGET".to_owned())];
+        for (key, val) in pairs {
+            req.push(Proto::Bulk(Vec::from(key)));
+            req.push(Proto::Bulk(Vec::from(val)));
+        }
+        self.command(req, (42, 43), |rep| match rep {
+            Reply::Array(items) => {
+                let vals: Option<Vec<_>> = items
+                    .iter()
+                    .map(|item| match item {
+                        Reply::Value(val) => Some(Some(val.clone())),
+                        Reply::Nil => Some(None),
+                        _ => None,
+                    })
+                    .collect();
+                vals.ok_or(Reply::Array(items))
+            }
+            rep => Err(rep),
+        })
+    }
+
+    /// Set each pair on its own if its key is absent, not atomically. Return
+    /// the outcome of each pair, in order: whether it was set, or the error
+    /// message if it failed.
+    pub fn mset_nx(
+        &self,
+        pairs: Vec<(String, String)>,
+    ) -> impl Future<Item = Vec<Result<bool, String>>, Error = i32> {
+        let mut req = vec![Proto::Str("MSETNX".to_owned())];
+        for (key, val) in pairs {
+            req.push(Proto::Bulk(Vec::from(key)));
+            req.push(Proto::Bulk(Vec::from(val)));
+        }
+        self.command(req, (62, 63), |rep| match rep {
+            Reply::Array(items) => {
+                let outcomes: Option<Vec<_>> = items
+                    .iter()
+                    .map(|item| match item {
+                        Reply::Int(n) => Some(Ok(*n != 0)),
+                        Reply::Error(_, msg) => Some(Err(msg.clone())),
+                        _ => None,
+                    })
+                    .collect();
+                outcomes.ok_or(Reply::Array(items))
+            }
+            rep => Err(rep),
+        })
+    }
+
+    /// Mark the keys as just used for the eviction of the server, return
+    /// how many exist.
+    pub fn touch(&self, keys: Vec<String>) -> impl Future<Item = usize, Error = i32> {
+        let mut req = vec![Proto::Str("TOUCH".to_owned())];
+        req.extend(keys.into_iter().map(|key| Proto::Bulk(Vec::from(key))));
+        self.command(req, (46, 47), |rep| match rep {
+            Reply::Int(n) if n >= 0 => Ok(n as usize),
+            rep => Err(rep),
+        })
+    }
+
+    /// Get the timestamps, size and version of key.
+    pub fn object(&self, key: String) -> impl Future<Item = Option<KeyMeta>, Error = i32> {
+        let req = vec![Proto::Str("OBJECT".to_owned()), Proto::Bulk(Vec::from(key))];
+        self.command(req, (28, 29), |rep| match rep {
+            Reply::Nil => Ok(None),
+            Reply::Array(items) => {
+                let time = |rep: &Reply| match rep {
+                    Reply::Int(ms) => Some(Some(UNIX_EPOCH + Duration::from_millis(*ms as u64))),
+                    Reply::Nil => Some(None),
+                    _ => None,
+                };
+                if let [created, updated, Reply::Int(size), Reply::Int(version)] = &items[..] {
+                    if let (Some(created_at), Some(updated_at)) = (time(created), time(updated)) {
+                        return Ok(Some(KeyMeta {
+                            created_at,
+                            updated_at,
+                            size: *size as usize,
+                            version: *version as u64,
+                        }));
+                    }
+                }
+                Err(Reply::Array(items))
+            }
+            rep => Err(rep),
+        })
+    }
+
+    /// Get the usage and the quota of `namespace`, see
+    /// `KvStoreBuilder::namespaces`.
+    pub fn quota(
+        &self,
+        namespace: String,
+    ) -> impl Future<Item = (NamespaceUsage, Quota), Error = i32> {
+        let req = vec![
+            Proto::Str("QUOTA".to_owned()),
+            Proto::Bulk(Vec::from("GET")),
+            Proto::Bulk(Vec::from(namespace)),
+        ];
+        self.command(req, (50, 51), parse_quota)
+    }
+
+    /// Set the quota of `namespace`, `Quota::default()` for no limit, and
+    /// get its usage and quota.
+    pub fn set_quota(
+        &self,
+        namespace: String,
+        quota: Quota,
+    ) -> impl Future<Item = (NamespaceUsage, Quota), Error = i32> {
+        let mut req = vec![
+            Proto::Str("QUOTA".to_owned()),
+            Proto::Bulk(Vec::from("SET")),
+            Proto::Bulk(Vec::from(namespace)),
+        ];
+        if let Some(n) = quota.max_keys {
+            req.push(Proto::Bulk(Vec::from("KEYS")));
+            req.push(Proto::Bulk(Vec::from(n.to_string())));
+        }
+        if let Some(n) = quota.max_bytes {
+            req.push(Proto::Bulk(Vec::from("BYTES")));
+            req.push(Proto::Bulk(Vec::from(n.to_string())));
+        }
+        self.command(req, (52, 53), parse_quota)
+    }
+
+    /// Get a key chosen at random, `None` if the store is empty.
+    pub fn random_key(&self) -> impl Future<Item = Option<String>, Error = i32> {
+        let req = vec![Proto::Str("RANDOMKEY".to_owned())];
+        self.command(req, (30, 31), |rep| match rep {
+            Reply::Value(s) => Ok(Some(s)),
+            Reply::Nil => Ok(None),
+            rep => Err(rep),
+        })
+    }
+
+    /// Wait until the writes acknowledged so far, by any client, are
+    /// durable on the server, e.g. at a checkpoint after a burst of writes.
+    pub fn wait(&self) -> impl Future<Item = (), Error = i32> {
+        let req = vec![Proto::Str("WAIT".to_owned())];
+        self.command(req, (60, 61), |rep| match rep {
+            Reply::Ok => Ok(()),
+            rep => Err(rep),
+        })
+    }
+
+    /// The keys matching the glob `pattern`, e.g. `user:*:session`, see
+    /// `KvStore::keys_matching`. It matches every key of the server, prefer
+    /// the commands working on a prefix on a large store.
+    pub fn keys_matching(&self, pattern: String) -> impl Future<Item = Vec<String>, Error = i32> {
+        let req = vec![
+            Proto::Str("KEYS".to_owned()),
+            Proto::Bulk(Vec::from(pattern)),
+        ];
+        self.command(req, (68, 69), |rep| match rep {
+            Reply::Array(items) => {
+                let keys: Option<Vec<_>> = items
+                    .iter()
+                    .map(|item| match item {
+                        Reply::Value(key) => Some(key.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                keys.ok_or(Reply::Array(items))
+            }
+            rep => Err(rep),
+        })
+    }
+
+    /// Count the members of the set `prefix`, the keys starting with it.
+    /// Sets are emulated with keys, e.g. `set:x:m` for the member `m` of
+    /// `set:x:`, see `KvStore::intersect_prefixes`.
+    pub fn scard(&self, prefix: String) -> impl Future<Item = usize, Error = i32> {
+        let req = vec![
+            Proto::Str("SCARD".to_owned()),
+            Proto::Bulk(Vec::from(prefix)),
+        ];
+        self.command(req, (80, 81), |rep| match rep {
+            Reply::Int(n) if n >= 0 => Ok(n as usize),
+            rep => Err(rep),
+        })
+    }
+
+    /// The members of both sets `a` and `b`, sorted, see `scard`. The
+    /// server reads every key, it takes time linear in their number.
+    pub fn sinter(&self, a: String, b: String) -> impl Future<Item = Vec<String>, Error = i32> {
+        let req = vec![
+            Proto::Str("SINTER".to_owned()),
+            Proto::Bulk(Vec::from(a)),
+            Proto::Bulk(Vec::from(b)),
+        ];
+        self.command(req, (82, 83), |rep| match rep {
+            Reply::Array(items) => {
+                let members: Option<Vec<_>> = items
+                    .iter()
+                    .map(|item| match item {
+                        Reply::Value(member) => Some(member.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                members.ok_or(Reply::Array(items))
+            }
+            rep => Err(rep),
+        })
+    }
+
+    /// The `top_n` keys whose writes made the most garbage since the server
+    /// opened the store, with the bytes, most first.
+    pub fn hot_keys(&self, top_n: usize) -> impl Future<Item = Vec<(String, usize)>, Error = i32> {
+        let req = vec![
+            Proto::Str("HOTKEYS".to_owned()),
+            Proto::Bulk(Vec::from(top_n.to_string())),
+        ];
+        self.command(req, (84, 85), |rep| match rep {
+            Reply::Array(items) => {
+                let keys: Option<Vec<_>> = items
+                    .chunks(2)
+                    .map(|pair| match pair {
+                        [Reply::Value(key), Reply::Int(bytes)] if *bytes >= 0 => {
+                            Some((key.clone(), *bytes as usize))
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                keys.ok_or(Reply::Array(items))
+            }
+            rep => Err(rep),
+        })
+    }
+
+    /// Stop the background compactions of the server, e.g. during a
+    /// traffic spike, until `resume_compaction`.
+    pub fn pause_compaction(&self) -> impl Future<Item = (), Error = i32> {
+        self.compact("PAUSE", (64, 65))
+    }
+
+    /// Start the background compactions of the server again.
+    pub fn resume_compaction(&self) -> impl Future<Item = (), Error = i32> {
+        self.compact("RESUME", (66, 67))
+    }
+
+    fn compact(&self, sub: &str, codes: (i32, i32)) -> impl Future<Item = (), Error = i32> {
+        let req = vec![
+            Proto::Str("COMPACT".to_owned()),
+            Proto::Bulk(Vec::from(sub)),
+        ];
+        self.command(req, codes, |rep| match rep {
+            Reply::Ok => Ok(()),
+            rep => Err(rep),
+        })
+    }
+
+    /// End a session, the server replies `OK` and closes the connection.
+    /// Each command of this client has its own connection, so this only
+    /// checks that the server answers.
+    pub fn quit(&self) -> impl Future<Item = (), Error = i32> {
+        let req = vec![Proto::Str("QUIT".to_owned())];
+        self.command(req, (32, 33), |rep| match rep {
+            Reply::Ok => Ok(()),
+            rep => Err(rep),
+        })
+    }
+
+    /// Get `version=<crate version> engine=<engine> uptime=<seconds>` of
+    /// the server.
+    pub fn server_version(&self) -> impl Future<Item = String, Error = i32> {
+        let req = vec![Proto::Str("VERSION".to_owned())];
+        self.command(req, (34, 35), |rep| match rep {
+            Reply::Value(s) => Ok(s),
+            rep => Err(rep),
+        })
+    }
+
+    /// Get the counters of the server, one `<name>=<count>` per line, e.g.
+    /// `errors_decode=0` for the requests that couldn't be decoded, and
+    /// `io_bytes_written=1024` for the bytes the engine wrote, if it counts
+    /// them.
+    pub fn info(&self) -> impl Future<Item = String, Error = i32> {
+        let req = vec![Proto::Str("INFO".to_owned())];
+        self.command(req, (44, 45), |rep| match rep {
+            Reply::Value(s) => Ok(s),
+            rep => Err(rep),
+        })
+    }
+
+    /// Get the log level of the server, after changing it to `level` if
+    /// given, e.g. "debug". The server must be given its `LogLevel`.
+    pub fn log_level(&self, level: Option<String>) -> impl Future<Item = String, Error = i32> {
+        let mut req = vec![Proto::Str("LOGLEVEL".to_owned())];
+        if let Some(level) = level {
+            req.push(Proto::Bulk(Vec::from(level)));
+        }
+        self.command(req, (48, 49), |rep| match rep {
+            Reply::Value(s) => Ok(s),
+            rep => Err(rep),
+        })
+    }
+
+    /// Get the settings of the server and of its engine whose names match
+    /// the glob `pattern`, e.g. "*" or "compact_*", as name-value pairs.
+    pub fn config_get(
+        &self,
+        pattern: String,
+    ) -> impl Future<Item = Vec<(String, String)>, Error = i32> {
+        let req = vec![
+            Proto::Str("CONFIG".to_owned()),
+            Proto::Bulk(Vec::from("GET")),
+            Proto::Bulk(Vec::from(pattern)),
+        ];
+        self.command(req, (96, 97), |rep| match rep {
+            Reply::Array(items) => {
+                let pairs: Option<Vec<_>> = items
+                    .chunks(2)
+                    .map(|pair| match pair {
+                        [Reply::Value(name), Reply::Value(val)] => {
+                            Some((name.clone(), val.clone()))
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                pairs.ok_or(Reply::Array(items))
+            }
+            rep => Err(rep),
+        })
+    }
+
+    /// Receive `(event, key)` of the keys starting with `prefix`:
+    /// `("set", key)` when a key gets a value, `("del", key)` when it is
+    /// removed and `("delprefix", prefix)` when the keys with a prefix
+    /// overlapping `prefix` are removed.
+    ///
+    /// Delivery is best-effort, events may be lost when the client is slow.
+    pub fn psubscribe(&self, prefix: String) -> impl Stream<Item = (String, String), Error = i32> {
+        self.subscribe(prefix).flatten_stream()
+    }
+
+    /// Like `psubscribe`, resolved with the events once the server confirms
+    /// the subscription, so no later event is missed.
+    pub(crate) fn subscribe(
+        &self,
+        prefix: String,
+    ) -> impl Future<Item = impl Stream<Item = (String, String), Error = i32>, Error = i32> {
+        let req = Proto::Seq(vec![
+            Proto::Str("PSUBSCRIBE".to_owned()),
+            Proto::Bulk(Vec::from(prefix)),
+        ]);
+        let addr = self.addr;
+        let log0 = self.log.clone();
+        let log1 = self.log.clone();
+        let log2 = self.log.clone();
+        let log3 = self.log.clone();
+        TcpStream::connect(&self.addr)
+            .map_err(move |e| {
+                crit!(log0, "failed to connect {}: {}", addr, e);
+                666
+            })
+            .and_then(|sock| {
+                Framed::new(sock, ProtoCodec::new())
+                    .send(req)
+                    .map_err(move |e| {
+                        crit!(log1, "failed to send command: {}", e);
+                        2
+                    })
+            })
+            .and_then(move |frame| {
+                let events = frame.map_err(move |e| {
+                    crit!(log2, "failed to decode reply: {}", e);
+                    999
+                });
+                events.into_future().map_err(|(code, _)| code)
+            })
+            .and_then(move |(rep, events)| match rep.map(Reply::parse) {
+                Some(Ok(Reply::Ok)) => Ok(events.and_then(parse_event)),
+                Some(Ok(Reply::Error(code, msg))) => {
+                    error!(log3, "server error: {} {}", code, msg);
+                    Err(26)
+                }
+                rep => {
+                    crit!(log3, "unexpected reply: {:?}", rep);
+                    Err(27)
+                }
+            })
+    }
+}
+
+/// Decode `[keys, bytes, max_keys, max_bytes]`, the limits `Nil` if none.
+fn parse_quota(rep: Reply) -> Result<(NamespaceUsage, Quota), Reply> {
+    let items = match rep {
+        Reply::Array(items) => items,
+        rep => return Err(rep),
+    };
+    let limit = |rep: &Reply| match rep {
+        Reply::Int(n) if *n >= 0 => Some(Some(*n as usize)),
+        Reply::Nil => Some(None),
+        _ => None,
+    };
+    if let [Reply::Int(keys), Reply::Int(bytes), max_keys, max_bytes] = &items[..] {
+        if let (Some(max_keys), Some(max_bytes)) = (limit(max_keys), limit(max_bytes)) {
+            let usage = NamespaceUsage {
+                keys: *keys as usize,
+                bytes: *bytes as usize,
+            };
+            return Ok((
+                usage,
+                Quota {
+                    max_keys,
+                    max_bytes,
+                },
+            ));
+        }
+    }
+    Err(Reply::Array(items))
+}
+
+/// Decode an `[event, key]` pushed to a subscriber.
+fn parse_event(rep: Proto) -> Result<(String, String), i32> {
+    match Reply::parse(rep) {
+        Ok(Reply::Array(mut items)) => {
+            if let (Some(Reply::Value(key)), Some(Reply::Value(event))) = (items.pop(), items.pop())
+            {
+                if items.is_empty() {
+                    return Ok((event, key));
+                }
+            }
+            Err(27)
+        }
+        _ => Err(27),
+    }
+}
@@ -0,0 +1,51 @@
+use crate::protocol::Proto;
+
+/// A reply of the server, decoded once from `Proto`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    /// Success without value.
+    Ok,
+    /// A string value.
+    Value(String),
+    /// No value.
+    Nil,
+    /// An integer.
+    Int(i64),
+    /// Several replies.
+    Array(Vec<Reply>),
+    /// An error code, e.g. `ERR`, and the message.
+    Error(String, String),
+}
+
+impl Reply {
+    pub fn parse(proto: Proto) -> Result<Reply, String> {
+        Ok(match proto {
+            Proto::Str(_) => Reply::Ok,
+            Proto::Bulk(v) => match String::from_utf8(v) {
+                Ok(s) => Reply::Value(s),
+                Err(e) => return Err(format!("bad bulk: {}", e)),
+            },
+            Proto::Null => Reply::Nil,
+            Proto::Int(n) => Reply::Int(n),
+            Proto::Err(e) => {
+                let (code, msg) = split_error(&e);
+                Reply::Error(code, msg)
+            }
+            Proto::Seq(v) => {
+                Reply::Array(v.into_iter().map(Reply::parse).collect::<Result<_, _>>()?)
+            }
+        })
+    }
+}
+
+/// Split an error into its leading upper-case code and the message.
+/// An error without code gets `ERR`.
+fn split_error(e: &str) -> (String, String) {
+    let mut parts = e.splitn(2, ' ');
+    let code = parts.next().unwrap_or("");
+    if !code.is_empty() && code.chars().all(|c| c.is_ascii_uppercase() || c == '_') {
+        (code.to_owned(), parts.next().unwrap_or("").to_owned())
+    } else {
+        ("ERR".to_owned(), e.to_owned())
+    }
+}
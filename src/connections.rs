@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Connections being served, with their peer address and connect time.
+pub struct Connections {
+    next_id: AtomicUsize,
+    conns: Mutex<HashMap<usize, (SocketAddr, Instant)>>,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Connections {
+            next_id: AtomicUsize::new(0),
+            conns: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Register a connection, it is removed when the guard is dropped.
+    pub fn register(this: &Arc<Self>, peer: SocketAddr) -> ConnGuard {
+        let id = this.next_id.fetch_add(1, Ordering::SeqCst);
+        this.conns
+            .lock()
+            .unwrap()
+            .insert(id, (peer, Instant::now()));
+        ConnGuard {
+            conns: this.clone(),
+            id,
+        }
+    }
+
+    /// One line per connection, `addr=<peer> age=<seconds>`, oldest first.
+    pub fn list(&self) -> String {
+        let mut conns: Vec<_> = self.conns.lock().unwrap().values().cloned().collect();
+        conns.sort_by_key(|(_, since)| *since);
+        let mut res = String::new();
+        for (peer, since) in conns {
+            res += &format!("addr={} age={}\n", peer, since.elapsed().as_secs());
+        }
+        res
+    }
+}
+
+pub struct ConnGuard {
+    conns: Arc<Connections>,
+    id: usize,
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        self.conns.conns.lock().unwrap().remove(&self.id);
+    }
+}
@@ -0,0 +1,108 @@
+//! Helpers to run the server without a service manager.
+
+#[cfg(unix)]
+extern crate libc;
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// A file holding the id of this process, removed when dropped.
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    /// Write the id of this process to `path`.
+    ///
+    /// An existing file naming a live process is an `AlreadyExists` error,
+    /// a stale one is replaced.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<PidFile> {
+        let path = path.as_ref();
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(path) {
+                Ok(mut file) => {
+                    writeln!(file, "{}", process::id())?;
+                    return Ok(PidFile(path.to_owned()));
+                }
+                Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {
+                    let content = fs::read_to_string(path)?;
+                    if let Ok(pid) = content.trim().parse::<u32>() {
+                        if is_alive(pid) {
+                            return Err(io::Error::new(
+                                ErrorKind::AlreadyExists,
+                                format!("{:?} belongs to running process {}", path, pid),
+                            ));
+                        }
+                    }
+                    fs::remove_file(path)?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(unix)]
+fn is_alive(pid: u32) -> bool {
+    if unsafe { libc::kill(pid as libc::pid_t, 0) } == 0 {
+        return true;
+    }
+    // The process exists but belongs to another user.
+    io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Without a way to check, assume the process is alive.
+#[cfg(not(unix))]
+fn is_alive(_pid: u32) -> bool {
+    true
+}
+
+/// Detach from the terminal: fork twice, start a new session and redirect
+/// stdin, stdout and stderr to `/dev/null`. The working directory is kept.
+///
+/// Call it before any thread is spawned, only the calling thread survives.
+#[cfg(unix)]
+pub fn daemonize() -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    fork_and_exit_parent()?;
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    fork_and_exit_parent()?;
+
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..3 {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn fork_and_exit_parent() -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => process::exit(0),
+    }
+}
+
+/// Daemon mode is only supported on Unix.
+#[cfg(not(unix))]
+pub fn daemonize() -> io::Result<()> {
+    Err(io::Error::new(
+        ErrorKind::Other,
+        "daemon mode is only supported on Unix",
+    ))
+}
@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+enum Entry<T> {
+    Pending,
+    Done(Instant, T),
+}
+
+/// Remember the results of requests carrying an idempotency token.
+///
+/// A token seen again within `window` after its request completed gets the
+/// remembered result, the request is not run again.
+/// A token seen again while its request is still running gets the `busy` result.
+/// Tokens are only kept in memory, so they are forgotten on restart.
+pub struct Dedup<T> {
+    window: Duration,
+    entries: Mutex<HashMap<String, Entry<T>>>,
+}
+
+impl<T: Clone> Dedup<T> {
+    pub fn new(window: Duration) -> Self {
+        Dedup {
+            window,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How long a reply is remembered.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Run `f` unless `token` was already seen within the window.
+    pub fn run<F>(&self, token: String, busy: T, f: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            let now = Instant::now();
+            let window = self.window;
+            entries.retain(|_, e| match e {
+                Entry::Pending => true,
+                Entry::Done(at, _) => now.duration_since(*at) < window,
+            });
+            match entries.get(&token) {
+                Some(Entry::Pending) => return busy,
+                Some(Entry::Done(_, res)) => return res.clone(),
+                None => {
+                    entries.insert(token.clone(), Entry::Pending);
+                }
+            }
+        }
+        let res = f();
+        let done = Entry::Done(Instant::now(), res.clone());
+        self.entries.lock().unwrap().insert(token, done);
+        res
+    }
+}
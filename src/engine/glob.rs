@@ -0,0 +1,79 @@
+//! Glob patterns of keys, as in `KEYS` of redis.
+
+/// Whether `s` matches `pattern`: `*` matches any run of characters, `?`
+/// any one character, `[abc]` one of the listed ones, `[^abc]` any other
+/// and `[a-z]` one of a range. `\` escapes the next character, in and out
+/// of brackets. A `[` without `]` runs to the end of the pattern, like in
+/// redis.
+pub(crate) fn glob_match(pattern: &str, s: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    let (mut pi, mut si) = (0, 0);
+    // The index of the last `*` and the position in `s` where it stops
+    // matching, moved forward when the rest of the pattern fails.
+    let mut star = None;
+    while si < s.len() {
+        if pi < p.len() {
+            if p[pi] == '*' {
+                star = Some((pi, si));
+                pi += 1;
+                continue;
+            }
+            if let Some(next) = match_one(&p, pi, s[si]) {
+                pi = next;
+                si += 1;
+                continue;
+            }
+        }
+        match star {
+            Some((star_pi, star_si)) => {
+                star = Some((star_pi, star_si + 1));
+                pi = star_pi + 1;
+                si = star_si + 1;
+            }
+            None => return false,
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+// Match `c` with the item of the pattern at `p[pi]`, return the index of
+// the next item.
+fn match_one(p: &[char], pi: usize, c: char) -> Option<usize> {
+    match p[pi] {
+        '?' => Some(pi + 1),
+        '[' => match_class(p, pi + 1, c),
+        // A trailing `\` is literal.
+        '\\' if pi + 1 < p.len() => Some(pi + 2).filter(|_| p[pi + 1] == c),
+        x => Some(pi + 1).filter(|_| x == c),
+    }
+}
+
+// Match `c` with the class whose items start at `p[i]`, after the `[`,
+// return the index after its `]`.
+fn match_class(p: &[char], mut i: usize, c: char) -> Option<usize> {
+    let negate = i < p.len() && p[i] == '^';
+    if negate {
+        i += 1;
+    }
+    let mut found = false;
+    while i < p.len() && p[i] != ']' {
+        if p[i] == '\\' && i + 1 < p.len() {
+            found |= p[i + 1] == c;
+            i += 2;
+        } else if i + 2 < p.len() && p[i + 1] == '-' && p[i + 2] != ']' {
+            let (lo, hi) = if p[i] <= p[i + 2] {
+                (p[i], p[i + 2])
+            } else {
+                (p[i + 2], p[i])
+            };
+            found |= lo <= c && c <= hi;
+            i += 3;
+        } else {
+            found |= p[i] == c;
+            i += 1;
+        }
+    }
+    let next = if i < p.len() { i + 1 } else { i };
+    Some(next).filter(|_| found != negate)
+}
@@ -0,0 +1,85 @@
+//! Bloom filters of the keys of the merged data files.
+
+use std::f64::consts::LN_2;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+use super::Error;
+use crate::Result;
+
+/// The keys of a data file: a key it holds is always found, a key it
+/// doesn't hold is found with a rate depending on the bits per key.
+pub(super) struct Bloom {
+    hashes: u32,
+    bits: Vec<u8>,
+}
+
+impl Bloom {
+    /// An empty filter for `keys` keys with `bits_per_key` bits each.
+    pub fn new(keys: usize, bits_per_key: usize) -> Self {
+        let len = (keys * bits_per_key).max(64);
+        // The number of hashes minimizing the false positives.
+        let hashes = (bits_per_key as f64 * LN_2).round().clamp(1.0, 30.0) as u32;
+        Bloom {
+            hashes,
+            bits: vec![0; len.div_ceil(8)],
+        }
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        for bit in positions(key, self.hashes, self.bits.len()) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// False if `key` is surely not in the file.
+    pub fn may_contain(&self, key: &str) -> bool {
+        positions(key, self.hashes, self.bits.len())
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Bytes taken in memory and on disk.
+    pub fn size(&self) -> usize {
+        self.bits.len() + 1
+    }
+
+    /// Write the filter to `temp`, then rename it to `path`, so that a
+    /// crash never leaves a partial filter.
+    pub fn save(&self, path: &Path, temp: &Path) -> Result<()> {
+        let mut wtr = File::create(temp)?;
+        wtr.write_all(&[self.hashes as u8])?;
+        wtr.write_all(&self.bits)?;
+        wtr.sync_all()?;
+        fs::rename(temp, path)?;
+        Ok(())
+    }
+
+    /// Read the filter saved at `path`, `None` if there is none.
+    pub fn load(path: &Path) -> Result<Option<Bloom>> {
+        let mut buf = match fs::read(path) {
+            Ok(buf) => buf,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e)?,
+        };
+        if buf.len() < 2 || buf[0] == 0 {
+            return Err(Error::Corruption(format!("bad bloom filter: {:?}", path)))?;
+        }
+        let hashes = u32::from(buf.remove(0));
+        Ok(Some(Bloom { hashes, bits: buf }))
+    }
+}
+
+/// The bits of `key` in a filter of `len` bytes, by double hashing.
+fn positions(key: &str, hashes: u32, len: usize) -> impl Iterator<Item = usize> {
+    let nbits = len as u64 * 8;
+    // FNV-1a, stable across builds unlike the hashers of std.
+    let fnv = |basis: u64| {
+        key.bytes().fold(basis, |hash, b| {
+            (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+        })
+    };
+    let h1 = fnv(0xcbf2_9ce4_8422_2325);
+    let h2 = fnv(0x8422_2325_cbf2_9ce4) | 1;
+    (0..u64::from(hashes)).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % nbits) as usize)
+}
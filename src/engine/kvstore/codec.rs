@@ -0,0 +1,268 @@
+#[cfg(feature = "bincode")]
+extern crate bincode;
+extern crate serde_json;
+
+use serde_json::Deserializer;
+
+use std::io::{self, Read};
+use std::sync::Arc;
+
+use super::command::Command;
+use super::Error;
+use crate::Result;
+
+/// Bytes read at once by `Records`, at least.
+const READ_CHUNK: usize = 8 * 1024;
+
+/// The encoding of the records in the data files, see
+/// `KvStoreBuilder::record_codec`.
+///
+/// The records are written back to back, so `decode` finds the end of a
+/// record by itself. An encoded record must not end with a zero byte: the
+/// zeros after the last record of a preallocated file are not records.
+pub trait RecordCodec: Send + Sync + 'static {
+    /// The name recorded in the meta file of a new database, which is then
+    /// never opened with another codec. `json` is the default codec.
+    fn name(&self) -> &'static str;
+
+    /// The bytes of the record of `cmd`.
+    fn encode(&self, cmd: &Command) -> Vec<u8>;
+
+    /// Decode the record at the start of `buf`, return it and its length in
+    /// bytes, never 0. `None` if `buf` ends inside the record, it is called
+    /// again with more bytes.
+    fn decode(&self, buf: &[u8]) -> Result<Option<(Command, usize)>>;
+
+    /// The first `n` bytes of the value of the record of `key` read by
+    /// `rdr`, all of them if the value is shorter, reading the record no
+    /// further, e.g. for `KvStore::get_capped`. `None` to read the whole
+    /// record instead, the default.
+    fn value_head(&self, _rdr: &mut dyn Read, _key: &str, _n: usize) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+}
+
+/// The records as JSON, the default, e.g. `{"R":["key"]}`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl RecordCodec for JsonCodec {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode(&self, cmd: &Command) -> Vec<u8> {
+        // Strings and integers only, which always serialize.
+        serde_json::to_vec(cmd).unwrap()
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<Option<(Command, usize)>> {
+        let mut stream = Deserializer::from_slice(buf).into_iter::<Command>();
+        match stream.next() {
+            Some(Ok(cmd)) => Ok(Some((cmd, stream.byte_offset()))),
+            Some(Err(ref e)) if e.is_eof() => Ok(None),
+            Some(Err(e)) => Err(e)?,
+            None => Ok(None),
+        }
+    }
+
+    fn value_head(&self, rdr: &mut dyn Read, key: &str, n: usize) -> Result<Option<Vec<u8>>> {
+        let head = format!(r#"{{"S":[{},""#, serde_json::to_string(key)?);
+        value_head(rdr, head.as_bytes(), n)
+    }
+}
+
+// The first `n` bytes of the JSON string read by `rdr` after `head`. `None`
+// if the record doesn't start with `head`, or holds an escape that
+// `JsonCodec` doesn't write. `rdr` reads a buffered data file, so reading
+// it byte by byte is cheap.
+#[allow(clippy::unbuffered_bytes)]
+fn value_head(rdr: &mut dyn Read, head: &[u8], n: usize) -> Result<Option<Vec<u8>>> {
+    let mut buf = vec![0; head.len()];
+    rdr.read_exact(&mut buf)?;
+    if buf != head {
+        return Ok(None);
+    }
+    let mut bytes = rdr.bytes();
+    let mut next = || match bytes.next() {
+        Some(b) => b.map(Some),
+        None => Ok(None),
+    };
+    let mut val = Vec::new();
+    while val.len() < n {
+        let b = match next()? {
+            Some(b'"') => break,
+            Some(b'\\') => match next()? {
+                Some(b'n') => b'\n',
+                Some(b'r') => b'\r',
+                Some(b't') => b'\t',
+                Some(b'b') => 8,
+                Some(b'f') => 12,
+                Some(b'u') => {
+                    let mut hex = String::new();
+                    for _ in 0..4 {
+                        match next()? {
+                            Some(h) => hex.push(h as char),
+                            None => return Ok(None),
+                        }
+                    }
+                    // Only the control characters are written so.
+                    match u8::from_str_radix(&hex, 16) {
+                        Ok(c) if c < 0x80 => c,
+                        _ => return Ok(None),
+                    }
+                }
+                Some(c @ b'"') | Some(c @ b'\\') | Some(c @ b'/') => c,
+                _ => return Ok(None),
+            },
+            Some(b) => b,
+            None => return Ok(None),
+        };
+        val.push(b);
+    }
+    Ok(Some(val))
+}
+
+/// The records as bincode, each ended by a newline since the bincode of a
+/// record may end with zeros. Smaller and faster to decode than JSON, but
+/// not readable by eye.
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+#[cfg(feature = "bincode")]
+impl RecordCodec for BincodeCodec {
+    fn name(&self) -> &'static str {
+        "bincode"
+    }
+
+    fn encode(&self, cmd: &Command) -> Vec<u8> {
+        let mut buf = bincode::serialize(cmd).unwrap();
+        buf.push(b'\n');
+        buf
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<Option<(Command, usize)>> {
+        let mut rdr = buf;
+        // The limit keeps a corrupted length from allocating, the record
+        // is then read to the end of the file and found truncated.
+        let res = bincode::config()
+            .limit(buf.len() as u64)
+            .deserialize_from(&mut rdr);
+        let cmd = match res {
+            Ok(cmd) => cmd,
+            Err(e) => match *e {
+                bincode::ErrorKind::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(None);
+                }
+                bincode::ErrorKind::SizeLimit => return Ok(None),
+                _ => return Err(e)?,
+            },
+        };
+        let len = buf.len() - rdr.len();
+        match rdr.first() {
+            Some(b'\n') => Ok(Some((cmd, len + 1))),
+            Some(_) => Err(Error::Corruption(
+                "record not ended by a newline".to_owned(),
+            ))?,
+            None => Ok(None),
+        }
+    }
+}
+
+/// Decode `buf`, which must hold exactly one record.
+pub(super) fn decode_exact(codec: &dyn RecordCodec, buf: &[u8]) -> Result<Command> {
+    match codec.decode(buf)? {
+        Some((cmd, len)) if len == buf.len() => Ok(cmd),
+        Some((_, len)) => {
+            let e = format!("{} bytes after the record", buf.len() - len);
+            Err(Error::Corruption(e))?
+        }
+        None => Err(Error::Corruption("truncated record".to_owned()))?,
+    }
+}
+
+/// The records read by `rdr` in order, see `offset` for where each starts.
+/// The iteration stops after an error.
+pub(super) struct Records<R> {
+    rdr: R,
+    codec: Arc<dyn RecordCodec>,
+    buf: Vec<u8>,
+    // Start of the next record in `buf`.
+    pos: usize,
+    offset: u64,
+    done: bool,
+}
+
+impl<R: Read> Records<R> {
+    pub fn new(rdr: R, codec: Arc<dyn RecordCodec>) -> Self {
+        Records {
+            rdr,
+            codec,
+            buf: Vec::new(),
+            pos: 0,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    /// Offset of the next record in the stream, i.e. the end of the last
+    /// one returned.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    // Read more bytes after the undecoded ones, return whether there were.
+    fn fill(&mut self) -> io::Result<bool> {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        let old = self.buf.len();
+        // The buffer at least doubles, so that a long record is decoded
+        // in linear time.
+        self.buf.resize(old + old.max(READ_CHUNK), 0);
+        let res = loop {
+            match self.rdr.read(&mut self.buf[old..]) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                res => break res,
+            }
+        };
+        let n = *res.as_ref().unwrap_or(&0);
+        self.buf.truncate(old + n);
+        res.map(|n| n > 0)
+    }
+}
+
+impl<R: Read> Iterator for Records<R> {
+    type Item = Result<Command>;
+
+    fn next(&mut self) -> Option<Result<Command>> {
+        while !self.done {
+            match self.codec.decode(&self.buf[self.pos..]) {
+                Ok(Some((cmd, len))) => {
+                    self.pos += len;
+                    self.offset += len as u64;
+                    return Some(Ok(cmd));
+                }
+                Ok(None) => match self.fill() {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        self.done = true;
+                        if self.pos < self.buf.len() {
+                            let e = format!("truncated record at offset {}", self.offset);
+                            return Some(Err(Error::Corruption(e).into()));
+                        }
+                    }
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(e.into()));
+                    }
+                },
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+}
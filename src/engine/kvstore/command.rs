@@ -10,16 +10,64 @@ use std::io::Read;
 
 use crate::Result;
 
+/// The kind of a value, kept beside it rather than in it, so that no
+/// string is ever taken for another kind.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ValueKind {
+    /// A string, the kind of the records written before kinds existed.
+    #[default]
+    #[serde(rename = "s")]
+    Str,
+    /// A list, stored as the JSON array of its items.
+    #[serde(rename = "l")]
+    List,
+}
+
+/// `Set` holds the key, the value, the expiry time, the version, the
+/// creation and the update time, all times in milliseconds since the Unix
+/// epoch, and the kind of the value. The expiry time is 0 for a key that
+/// never expires, the version is 0 in records written before versions
+/// existed, and the creation and update times are 0 in records written
+/// before timestamps existed.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Command {
     #[serde(rename = "S")]
-    Set(String, String),
+    Set(
+        String,
+        String,
+        #[serde(default)] u64,
+        #[serde(default)] u64,
+        #[serde(default)] u64,
+        #[serde(default)] u64,
+        #[serde(default)] ValueKind,
+    ),
     #[serde(rename = "R")]
     Rm(String),
 }
 
 // Only serde_json support stream, that's the reason to choose it.
 impl Command {
+    /// Expiry time of the value.
+    pub fn expires(&self) -> u64 {
+        match self {
+            Command::Set(_, _, expires, ..) => *expires,
+            Command::Rm(_) => 0,
+        }
+    }
+    /// Version, creation and update time of the value.
+    pub fn stamp(&self) -> (u64, u64, u64) {
+        match self {
+            Command::Set(_, _, _, version, created, updated, _) => (*version, *created, *updated),
+            Command::Rm(_) => (0, 0, 0),
+        }
+    }
+    /// Kind of the value.
+    pub fn kind(&self) -> ValueKind {
+        match self {
+            Command::Set(.., kind) => *kind,
+            Command::Rm(_) => ValueKind::Str,
+        }
+    }
     pub fn ser(&self) -> Result<String> {
         Ok(serde_json::to_string(self)?)
     }
@@ -30,4 +78,8 @@ impl Command {
         let mut de = Self::deserializer(rdr);
         Ok(Self::deserialize(&mut de)?)
     }
+    /// Fail unless the slice holds exactly one command.
+    pub fn from_slice(buf: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(buf)?)
+    }
 }
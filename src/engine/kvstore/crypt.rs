@@ -0,0 +1,109 @@
+extern crate chacha20poly1305;
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::Rng;
+
+use std::sync::Arc;
+
+use super::codec::{self, RecordCodec};
+use super::command::Command;
+use super::Error;
+use crate::Result;
+
+/// The AEAD cipher of the encrypted databases, recorded in their meta file.
+pub(super) const CIPHER: &str = "chacha20poly1305";
+
+const NONCE_LEN: usize = 12;
+// The length of the sealed record, then its nonce.
+const HEAD_LEN: usize = 4 + NONCE_LEN;
+// Sealed by `key_id`, never written.
+const KEY_ID_MSG: &[u8] = b"kvs key id";
+
+/// A key of `KvStoreBuilder::encryption`.
+pub(super) struct Cipher {
+    aead: ChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub fn new(key: &[u8; 32]) -> Self {
+        Cipher {
+            aead: ChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+
+    /// Tells the keys apart without revealing them: part of the tag of a
+    /// fixed message sealed with the zero nonce. The records have random
+    /// nonces.
+    pub fn key_id(&self) -> String {
+        let nonce = Nonce::from_slice(&[0u8; NONCE_LEN]);
+        let sealed = self.aead.encrypt(nonce, KEY_ID_MSG).unwrap();
+        sealed[sealed.len() - 8..]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// Seals the records of `inner`, each as its length in 4 bytes little
+/// endian, its random nonce, the ciphertext with the tag, and a newline
+/// since the tag may end with zeros.
+pub(super) struct Encrypted {
+    inner: Arc<dyn RecordCodec>,
+    cipher: Arc<Cipher>,
+}
+
+impl Encrypted {
+    pub fn new(inner: Arc<dyn RecordCodec>, cipher: Arc<Cipher>) -> Self {
+        Encrypted { inner, cipher }
+    }
+}
+
+impl RecordCodec for Encrypted {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn encode(&self, cmd: &Command) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill(&mut nonce);
+        let plain = self.inner.encode(cmd);
+        // Fails only for messages of hundreds of gigabytes.
+        let sealed = self
+            .cipher
+            .aead
+            .encrypt(Nonce::from_slice(&nonce), &plain[..])
+            .unwrap();
+        let mut buf = Vec::with_capacity(HEAD_LEN + sealed.len() + 1);
+        buf.extend_from_slice(&(sealed.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&nonce);
+        buf.extend_from_slice(&sealed);
+        buf.push(b'\n');
+        buf
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<Option<(Command, usize)>> {
+        if buf.len() < HEAD_LEN {
+            return Ok(None);
+        }
+        let mut len = [0; 4];
+        len.copy_from_slice(&buf[..4]);
+        let end = HEAD_LEN + u32::from_le_bytes(len) as usize;
+        match buf.get(end) {
+            Some(b'\n') => {}
+            Some(_) => Err(Error::Corruption(
+                "record not ended by a newline".to_owned(),
+            ))?,
+            None => return Ok(None),
+        }
+        let nonce = Nonce::from_slice(&buf[4..HEAD_LEN]);
+        let plain = match self.cipher.aead.decrypt(nonce, &buf[HEAD_LEN..end]) {
+            Ok(plain) => plain,
+            Err(_) => Err(Error::DecryptFailed(
+                "a record fails its authentication".to_owned(),
+            ))?,
+        };
+        let cmd = codec::decode_exact(&*self.inner, &plain)?;
+        Ok(Some((cmd, end + 1)))
+    }
+}
@@ -10,6 +10,35 @@ pub enum Error {
     BadPath(PathBuf),
     /// Invalid metadata
     InvalidMeta(PathBuf),
+    /// The meta file is truncated, fails its checksum or is malformed.
+    MetaCorrupt(PathBuf),
+    /// The database was written by a newer, incompatible version, see
+    /// `FORMAT_VERSION`.
+    UnsupportedVersion {
+        /// The version of the database.
+        found: u32,
+        /// The newest version this build reads.
+        supported: u32,
+    },
+    /// The records of the database are of a format this build no longer
+    /// decodes, e.g. the bincode records before `ValueKind`.
+    LegacyRecords {
+        /// The version of the database.
+        found: u32,
+        /// The codec of the database.
+        codec: String,
+    },
+    /// The database was written with another `RecordCodec`, see
+    /// `KvStoreBuilder::record_codec`.
+    CodecMismatch {
+        /// The codec of the database.
+        found: String,
+        /// The codec of the builder.
+        expected: String,
+    },
+    /// The key of `KvStoreBuilder::encryption` is missing or wrong, or a
+    /// record fails its authentication.
+    DecryptFailed(String),
     /// Found an unexpect command.
     UnexpectCmd {
         /// The found command.
@@ -19,6 +48,52 @@ pub enum Error {
     },
     /// Contains the key.
     KeyNotFound(String),
+    /// The value of the key is not a float.
+    NotFloat(String),
+    /// The increment of the key would overflow.
+    FloatOverflow(String),
+    /// The value of the key is not a 64-bit integer.
+    NotInteger(String),
+    /// The integer increment of the key would overflow.
+    IntegerOverflow(String),
+    /// The value of the key is not of the kind the operation needs, e.g. a
+    /// list given to `get` or a string to `push_back`.
+    WrongType(String),
+    /// The version of the key is not the expected one.
+    VersionMismatch {
+        /// The key.
+        key: String,
+        /// The expected version.
+        expected: u64,
+        /// The current version.
+        found: u64,
+    },
+    /// A record on disk does not match its index entry.
+    Corruption(String),
+    /// The directory is used by another store, see `KvStoreBuilder::dir_lock`.
+    Locked(PathBuf),
+    /// A write would grow a namespace beyond its quota, see
+    /// `KvStore::set_quota`.
+    QuotaExceeded {
+        /// The namespace.
+        namespace: String,
+    },
+    /// The store doesn't track namespaces, see
+    /// `KvStoreBuilder::namespaces`.
+    NamespacesOff,
+    /// A write of the empty key, see `KvStoreBuilder::reject_empty_keys`.
+    EmptyKey,
+    /// The store keeps no replication backlog, see
+    /// `KvStoreBuilder::replication_backlog`.
+    ReplicationOff,
+    /// Too few followers applied a write in time, see
+    /// `KvStoreBuilder::min_replicas_ack`. The write is done locally.
+    ReplicasNotAcked {
+        /// The followers that applied it.
+        acked: usize,
+        /// The followers to wait for.
+        needed: usize,
+    },
     /// Some unknown error.
     UnknowErr(String),
 }
@@ -28,12 +103,59 @@ impl Display for Error {
         match self {
             Error::BadPath(path) => write!(f, "bad path: {:?}", path),
             Error::InvalidMeta(path) => write!(f, "invalid metadata: {:?}", path),
+            Error::MetaCorrupt(path) => write!(f, "corrupted metadata: {:?}", path),
+            Error::UnsupportedVersion { found, supported } => write!(
+                f,
+                "unsupported format version {}, this build reads up to {}",
+                found, supported
+            ),
+            Error::LegacyRecords { found, codec } => write!(
+                f,
+                "records of format version {} can't be decoded with codec {:?}, \
+                 copy the data out with an older build",
+                found, codec
+            ),
+            Error::CodecMismatch { found, expected } => write!(
+                f,
+                "records encoded by codec {:?}, opened with {:?}",
+                found, expected
+            ),
+            Error::DecryptFailed(s) => write!(f, "decryption failed: {}", s),
             Error::UnexpectCmd { found, expect } => write!(
                 f,
                 "unexpect command: expect {:?}, but found {:?}",
                 expect, found
             ),
             Error::KeyNotFound(key) => write!(f, "Key not found: {}", key),
+            Error::NotFloat(key) => write!(f, "value is not a valid float: {}", key),
+            Error::FloatOverflow(key) => write!(f, "increment would overflow: {}", key),
+            Error::NotInteger(key) => write!(f, "value is not an integer: {}", key),
+            Error::IntegerOverflow(key) => write!(f, "increment would overflow: {}", key),
+            Error::WrongType(key) => {
+                write!(f, "WRONGTYPE value of the wrong kind: {}", key)
+            }
+            Error::VersionMismatch {
+                key,
+                expected,
+                found,
+            } => write!(
+                f,
+                "version mismatch of {}: expected {}, found {}",
+                key, expected, found
+            ),
+            Error::Corruption(s) => write!(f, "data corruption: {}", s),
+            Error::Locked(path) => write!(f, "directory in use by another store: {:?}", path),
+            Error::QuotaExceeded { namespace } => {
+                write!(f, "quota exceeded for namespace: {:?}", namespace)
+            }
+            Error::NamespacesOff => write!(f, "namespaces are not enabled"),
+            Error::EmptyKey => write!(f, "empty key"),
+            Error::ReplicationOff => write!(f, "replication is not enabled"),
+            Error::ReplicasNotAcked { acked, needed } => write!(
+                f,
+                "write applied by {} of {} replicas in time",
+                acked, needed
+            ),
             Error::UnknowErr(s) => write!(f, "unknown error: {}", s),
         }
     }
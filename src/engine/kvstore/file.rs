@@ -1,12 +1,19 @@
+#[cfg(unix)]
+extern crate libc;
+
+use serde_derive::{Deserialize, Serialize};
+
 use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom};
+use std::mem;
 use std::path::{Path, PathBuf};
 
+use super::Error;
 use crate::Result;
 
 pub type Fid = usize;
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Location {
     pub id: Fid,
     pub offset: u64,
@@ -21,6 +28,20 @@ pub struct Fdr {
 pub struct Fdw {
     pub id: Fid,
     pub wtr: BufWriter<File>,
+    /// End of the records, the file is longer when preallocated.
+    pub end: u64,
+}
+
+impl Fdw {
+    /// Cut the records back to `end`, dropping the buffered bytes after it
+    /// rather than flushing them.
+    pub fn truncate(&mut self, end: u64) -> Result<()> {
+        let file = self.wtr.get_ref().try_clone()?;
+        let (_, _) = mem::replace(&mut self.wtr, BufWriter::new(file)).into_parts();
+        self.wtr.get_ref().set_len(end)?;
+        self.end = end;
+        Ok(())
+    }
 }
 
 pub fn new(path: impl AsRef<Path>) -> Result<BufWriter<File>> {
@@ -33,29 +54,337 @@ pub fn new(path: impl AsRef<Path>) -> Result<BufWriter<File>> {
     ))
 }
 
-pub fn data(dir: &PathBuf, id: Fid) -> PathBuf {
-    dir.join(format!("{}.data", id))
+// The files of a data file id are named `<prefix><id>.<ext>`, the prefix
+// is empty by default.
+
+pub fn data(dir: &Path, prefix: &str, id: Fid) -> PathBuf {
+    dir.join(format!("{}{}.data", prefix, id))
+}
+
+pub fn temp(dir: &Path, prefix: &str, id: Fid) -> PathBuf {
+    dir.join(format!("{}{}.data.temp", prefix, id))
+}
+
+pub fn bloom(dir: &Path, prefix: &str, id: Fid) -> PathBuf {
+    dir.join(format!("{}{}.bloom", prefix, id))
+}
+
+pub fn bloom_temp(dir: &Path, prefix: &str, id: Fid) -> PathBuf {
+    dir.join(format!("{}{}.bloom.temp", prefix, id))
+}
+
+/// The id of the data file named `name`, none for any other file.
+pub fn data_id(name: &str, prefix: &str) -> Option<Fid> {
+    let ext = ".data";
+    if name.len() < prefix.len() + ext.len() || !name.starts_with(prefix) || !name.ends_with(ext) {
+        return None;
+    }
+    let id = &name[prefix.len()..name.len() - ext.len()];
+    // `parse` accepts a sign, which an id never has.
+    if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    id.parse().ok()
+}
+
+pub fn snapshot(dir: &Path) -> PathBuf {
+    dir.join("index.snapshot")
 }
 
-pub fn temp(dir: &PathBuf, id: Fid) -> PathBuf {
-    dir.join(format!("{}.data.temp", id))
+pub fn snapshot_temp(dir: &Path) -> PathBuf {
+    dir.join("index.snapshot.temp")
+}
+
+pub fn quotas(dir: &Path) -> PathBuf {
+    dir.join("quotas.json")
+}
+
+pub fn quotas_temp(dir: &Path) -> PathBuf {
+    dir.join("quotas.json.temp")
+}
+
+pub fn meta_temp(dir: &Path) -> PathBuf {
+    dir.join("meta.temp")
+}
+
+pub fn lock(dir: &Path) -> PathBuf {
+    dir.join("LOCK")
+}
+
+/// Version of the on-disk format written by this build, recorded in the
+/// meta file. Databases with the bare `kvs` meta file are of version 1.
+/// Version 2 added the `ValueKind` of the values to the records.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// The first version whose records have a `ValueKind`. The records of
+/// older versions decode without one only with the JSON codec.
+pub const KIND_VERSION: u32 = 2;
+
+/// The meta files of the first databases, the bare name of the engine.
+const LEGACY_META: [&str; 2] = ["kvs", "sled"];
+
+/// The codec of the records of the databases without a `codec` line.
+const DEFAULT_CODEC: &str = "json";
+
+/// The meta file: the engine on the first line, then `key=value` lines,
+/// e.g. `version=1`, and last the CRC-32 of the lines before, e.g.
+/// `checksum=0a1b2c3d`. Unknown keys are ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Meta {
+    pub engine: String,
+    pub version: u32,
+    /// The name of the `RecordCodec` of the data files.
+    pub codec: String,
+    /// The cipher of the records of an encrypted database.
+    pub cipher: Option<String>,
+    /// Tells the key of an encrypted database, see `Cipher::key_id`.
+    pub key_id: Option<String>,
+}
+
+impl Meta {
+    /// The meta file of a new database of `engine`.
+    pub fn new(engine: &str) -> Self {
+        Meta {
+            engine: engine.to_owned(),
+            version: FORMAT_VERSION,
+            codec: DEFAULT_CODEC.to_owned(),
+            cipher: None,
+            key_id: None,
+        }
+    }
+
+    /// The meta file of a new database of this engine.
+    pub fn current() -> Self {
+        Self::new("kvs")
+    }
+
+    /// `None` if `text` is truncated, fails its checksum or is malformed.
+    pub fn parse(text: &str) -> Option<Self> {
+        if LEGACY_META.contains(&text) {
+            return Some(Meta {
+                engine: text.to_owned(),
+                version: 1,
+                codec: DEFAULT_CODEC.to_owned(),
+                cipher: None,
+                key_id: None,
+            });
+        }
+        let split = text.rfind("checksum=")?;
+        let (body, sum) = text.split_at(split);
+        let sum = &sum["checksum=".len()..];
+        if !body.ends_with('\n') || sum.len() != 9 || !sum.ends_with('\n') {
+            return None;
+        }
+        // Lowercase only, so that a flipped case bit doesn't pass.
+        let hex = &sum[..8];
+        if !hex
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        {
+            return None;
+        }
+        if u32::from_str_radix(hex, 16).ok()? != crc32(body.as_bytes()) {
+            return None;
+        }
+
+        let mut lines = body.lines();
+        let engine = lines.next()?.trim().to_owned();
+        let mut version = 1;
+        let mut codec = DEFAULT_CODEC.to_owned();
+        let mut cipher = None;
+        let mut key_id = None;
+        for line in lines.map(str::trim).filter(|line| !line.is_empty()) {
+            let mut kv = line.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("version"), Some(v)) => version = v.trim().parse().ok()?,
+                (Some("codec"), Some(c)) => codec = c.trim().to_owned(),
+                (Some("cipher"), Some(c)) => cipher = Some(c.trim().to_owned()),
+                (Some("key_id"), Some(id)) => key_id = Some(id.trim().to_owned()),
+                (Some(_), Some(_)) => {}
+                _ => return None,
+            }
+        }
+        if engine.is_empty() || version == 0 {
+            return None;
+        }
+        Some(Meta {
+            engine,
+            version,
+            codec,
+            cipher,
+            key_id,
+        })
+    }
+
+    pub fn render(&self) -> String {
+        let mut body = format!("{}\nversion={}\n", self.engine, self.version);
+        // Left out by default, like in the databases before codecs.
+        if self.codec != DEFAULT_CODEC {
+            body += &format!("codec={}\n", self.codec);
+        }
+        if let Some(ref cipher) = self.cipher {
+            body += &format!("cipher={}\n", cipher);
+        }
+        if let Some(ref key_id) = self.key_id {
+            body += &format!("key_id={}\n", key_id);
+        }
+        let sum = crc32(body.as_bytes());
+        format!("{}checksum={:08x}\n", body, sum)
+    }
+}
+
+/// CRC-32 (IEEE) of `data`, bit by bit as the meta file is tiny.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= u32::from(*b);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Exclusive lock of a data directory, released when dropped.
+pub struct DirLock {
+    _file: File,
+}
+
+/// Lock `dir` with `flock` on its `LOCK` file, fail with `Error::Locked`
+/// if it is already locked, by this process or another one.
+#[cfg(unix)]
+pub fn lock_dir(dir: &Path) -> Result<DirLock> {
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(lock(dir))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            Err(Error::Locked(dir.to_path_buf()))?;
+        }
+        Err(e)?;
+    }
+    Ok(DirLock { _file: file })
+}
+
+/// Only the `LOCK` file is created on other platforms, nothing is locked.
+#[cfg(not(unix))]
+pub fn lock_dir(dir: &Path) -> Result<DirLock> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(lock(dir))?;
+    Ok(DirLock { _file: file })
 }
 
 pub fn open_r(path: impl AsRef<Path>) -> Result<BufReader<File>> {
     Ok(BufReader::new(File::open(path)?))
 }
 
+/// Open `path` to write, readable too so `logical_end` can scan its tail.
 pub fn open_w(path: impl AsRef<Path>) -> Result<BufWriter<File>> {
-    let wtr = OpenOptions::new().write(true).open(path)?;
+    let wtr = OpenOptions::new().read(true).write(true).open(path)?;
     Ok(BufWriter::new(wtr))
 }
 
-pub fn fdr(dir: &PathBuf, id: Fid) -> Result<Fdr> {
-    let rdr = open_r(&data(dir, id))?;
+/// Make the renames and removals in `dir` durable.
+#[cfg(unix)]
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Directories can't be opened on other platforms, renames are left to the OS.
+#[cfg(not(unix))]
+pub fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+pub fn fdr(dir: &Path, prefix: &str, id: Fid) -> Result<Fdr> {
+    let rdr = open_r(data(dir, prefix, id))?;
     Ok(Fdr { id, rdr })
 }
 
-pub fn fdw(dir: &PathBuf, id: Fid) -> Result<Fdw> {
-    let wtr = new(&data(dir, id))?;
-    Ok(Fdw { id, wtr })
+/// Create the data file `id`, preallocated to `prealloc` bytes.
+pub fn fdw(dir: &Path, prefix: &str, id: Fid, prealloc: u64) -> Result<Fdw> {
+    let wtr = new(data(dir, prefix, id))?;
+    preallocate(wtr.get_ref(), prealloc)?;
+    Ok(Fdw { id, wtr, end: 0 })
+}
+
+/// Open the data file `id` to append after its last record.
+pub fn reopen_w(dir: &Path, prefix: &str, id: Fid) -> Result<Fdw> {
+    let mut wtr = open_w(data(dir, prefix, id))?;
+    let end = logical_end(wtr.get_ref())?;
+    wtr.seek(SeekFrom::Start(end))?;
+    Ok(Fdw { id, wtr, end })
+}
+
+/// Reserve the space of `file` up to `len` bytes, a longer file is left
+/// as is. The reserved space reads as zeros.
+///
+/// On Linux the blocks are allocated with `posix_fallocate`, so the
+/// appends neither fragment the file nor fail for lack of space. Other
+/// platforms only extend the file with `set_len`, which leaves a sparse
+/// file on most file systems: the length is reserved, not the blocks.
+pub fn preallocate(file: &File, len: u64) -> Result<()> {
+    if len == 0 || file.metadata()?.len() >= len {
+        return Ok(());
+    }
+    allocate(file, len)
+}
+
+#[cfg(target_os = "linux")]
+fn allocate(file: &File, len: u64) -> Result<()> {
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    // Returns the error instead of setting `errno`.
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len as libc::off_t) } {
+        0 => Ok(()),
+        // Not supported by the file system.
+        libc::EOPNOTSUPP | libc::EINVAL => Ok(file.set_len(len)?),
+        e => Err(io::Error::from_raw_os_error(e))?,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allocate(file: &File, len: u64) -> Result<()> {
+    Ok(file.set_len(len)?)
+}
+
+/// The length of `file` without its trailing zeros, i.e. the end of the
+/// last record: a record never ends with a zero byte. The position of
+/// `file` is restored.
+pub fn logical_end(file: &File) -> Result<u64> {
+    const CHUNK: u64 = 64 * 1024;
+    let mut file = file;
+    let pos = file.stream_position()?;
+    let mut end = file.metadata()?.len();
+    let mut buf = vec![0; CHUNK as usize];
+    while end > 0 {
+        let start = end.saturating_sub(CHUNK);
+        let buf = &mut buf[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(buf)?;
+        match buf.iter().rposition(|b| *b != 0) {
+            Some(i) => {
+                end = start + i as u64 + 1;
+                break;
+            }
+            None => end = start,
+        }
+    }
+    file.seek(SeekFrom::Start(pos))?;
+    Ok(end)
 }
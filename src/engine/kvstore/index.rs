@@ -0,0 +1,159 @@
+//! The in-memory index of the keys, hashed with a choice of function.
+
+use std::collections::hash_map::{self, DefaultHasher, RandomState};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::RwLock;
+
+/// The hash function of the index, see `KvStoreBuilder::index_hasher`.
+///
+/// Both are keyed with random keys per store, so the keys colliding in
+/// one store don't collide in another: a client can't flood the index
+/// with keys of the same hash.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexHasher {
+    /// SipHash-1-3, the hasher of `std::collections::HashMap`. The default.
+    #[default]
+    Sip,
+    /// aHash, faster on the short keys of most workloads. Needs the `ahash`
+    /// feature.
+    #[cfg(feature = "ahash")]
+    AHash,
+}
+
+#[derive(Clone)]
+enum State {
+    Sip(RandomState),
+    #[cfg(feature = "ahash")]
+    AHash(ahash::RandomState),
+}
+
+impl State {
+    fn new(hasher: IndexHasher) -> Self {
+        match hasher {
+            IndexHasher::Sip => State::Sip(RandomState::new()),
+            #[cfg(feature = "ahash")]
+            IndexHasher::AHash => State::AHash(ahash::RandomState::new()),
+        }
+    }
+}
+
+impl BuildHasher for State {
+    type Hasher = KeyHasher;
+
+    fn build_hasher(&self) -> KeyHasher {
+        match self {
+            State::Sip(s) => KeyHasher::Sip(s.build_hasher()),
+            #[cfg(feature = "ahash")]
+            State::AHash(s) => KeyHasher::AHash(s.build_hasher()),
+        }
+    }
+}
+
+enum KeyHasher {
+    Sip(DefaultHasher),
+    #[cfg(feature = "ahash")]
+    AHash(ahash::AHasher),
+}
+
+impl Hasher for KeyHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        match self {
+            KeyHasher::Sip(h) => h.write(bytes),
+            #[cfg(feature = "ahash")]
+            KeyHasher::AHash(h) => h.write(bytes),
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        match self {
+            KeyHasher::Sip(h) => h.finish(),
+            #[cfg(feature = "ahash")]
+            KeyHasher::AHash(h) => h.finish(),
+        }
+    }
+}
+
+/// A map of the keys shared by the handles of a store.
+///
+/// The writes of a store are serialized by its active file, so one lock
+/// for the whole map only lets the reads run together, and they do.
+pub(super) struct Index<V> {
+    map: RwLock<HashMap<String, V, State>>,
+}
+
+impl<V: Clone> Index<V> {
+    pub fn new(hasher: IndexHasher) -> Self {
+        Self::with_capacity(0, hasher)
+    }
+
+    pub fn with_capacity(capacity: usize, hasher: IndexHasher) -> Self {
+        let map = HashMap::with_capacity_and_hasher(capacity, State::new(hasher));
+        Index {
+            map: RwLock::new(map),
+        }
+    }
+
+    /// A copy of the entry of `key`.
+    pub fn get(&self, key: &str) -> Option<V> {
+        self.map.read().unwrap().get(key).cloned()
+    }
+
+    /// Call `f` with the entry of `key` if any, return its result.
+    pub fn update<T, F>(&self, key: &str, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut V) -> T,
+    {
+        self.map.write().unwrap().get_mut(key).map(f)
+    }
+
+    pub fn insert(&self, key: String, val: V) -> Option<V> {
+        self.map.write().unwrap().insert(key, val)
+    }
+
+    /// Insert all the entries under one hold of the lock, so a reader sees
+    /// either all of them or none. Return the replaced entries, in order.
+    pub fn insert_many(&self, entries: Vec<(String, V)>) -> Vec<Option<V>> {
+        let mut map = self.map.write().unwrap();
+        entries
+            .into_iter()
+            .map(|(key, val)| map.insert(key, val))
+            .collect()
+    }
+
+    pub fn remove(&self, key: &str) -> Option<V> {
+        self.map.write().unwrap().remove(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.read().unwrap().len()
+    }
+
+    /// Call `f` with each entry, without copying the map. The writes wait
+    /// meanwhile, and `f` must not use the index.
+    pub fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&str, &V),
+    {
+        for (key, val) in self.map.read().unwrap().iter() {
+            f(key, val);
+        }
+    }
+}
+
+impl<V: Clone> Clone for Index<V> {
+    fn clone(&self) -> Self {
+        Index {
+            map: RwLock::new(self.map.read().unwrap().clone()),
+        }
+    }
+}
+
+impl<V> IntoIterator for Index<V> {
+    type Item = (String, V);
+    type IntoIter = hash_map::IntoIter<String, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.map.into_inner().unwrap().into_iter()
+    }
+}
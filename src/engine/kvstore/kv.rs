@@ -2,42 +2,155 @@ extern crate chashmap;
 extern crate crossbeam_channel;
 
 use chashmap::CHashMap;
-use crossbeam_channel::{unbounded, Sender};
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use rand::seq::{IteratorRandom, SliceRandom};
+use serde_derive::{Deserialize, Serialize};
 use slog::Logger;
 
-use std::cell::RefCell;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs::{self, File};
-use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Take, Write};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError, Weak};
 use std::thread::{self, JoinHandle};
-
-use super::command::Command;
-use super::file::{self, Fdr, Fdw, Fid, Location};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::vec;
+
+use super::bloom::Bloom;
+use super::codec::{self, JsonCodec, RecordCodec, Records};
+use super::command::{Command, ValueKind};
+use super::crypt::{self, Cipher, Encrypted};
+use super::file::{self, DirLock, Fdr, Fdw, Fid, Location, Meta, FORMAT_VERSION};
+use super::index::{self, IndexHasher};
+use super::quota::{NamespaceUsage, Namespaces, Quota};
+use super::repl::{ReplSink, ReplSync, ReplicationLog};
+use crate::engine::{
+    byte_range, cap_utf8, format_float, glob_match, index_range, parse_float, parse_integer,
+};
 use crate::get_logger;
+use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
 use crate::{KvsError as Error, Result};
 
 const ACTIVE_THRESHOLD: u64 = 1024 * 1024;
 const COMPACT_THRESHOLD: usize = 2 * 1024 * 1024;
-
-type Index = CHashMap<String, CmdInfo>;
+const PROGRESS_STEP: u64 = 1024 * 1024;
+const COMPACT_RETRIES: u32 = 3;
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const REPLICA_ACK_TIMEOUT: Duration = Duration::from_secs(1);
+// Eviction frees down to `maxmemory - maxmemory / EVICT_MARGIN`.
+const EVICT_MARGIN: usize = 16;
+const COMPACTER_NAME: &str = "kvs-compacter";
+
+type Index = index::Index<CmdInfo>;
 type FdrMap = BTreeMap<Fid, Fdr>;
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+// Bytes of overwritten or removed records, and tombstones, in each data file.
+type FileGarbage = BTreeMap<Fid, usize>;
+type EventHook = Arc<dyn Fn(CompactionEvent) + Send + Sync>;
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 struct CmdInfo {
     loc: Location,
     len: usize,
+    version: u64,
+    expires: u64,
+    created: u64,
+    updated: u64,
+    // Missing from the snapshots taken before kinds existed.
+    #[serde(default)]
+    kind: ValueKind,
 }
 
 impl CmdInfo {
-    fn new(id: Fid, offset: u64, len: usize) -> CmdInfo {
+    fn new(id: Fid, offset: u64, len: usize, cmd: &Command) -> CmdInfo {
+        let (version, created, updated) = cmd.stamp();
         CmdInfo {
             loc: Location { id, offset },
             len,
+            version,
+            expires: cmd.expires(),
+            created,
+            updated,
+            kind: cmd.kind(),
         }
     }
+
+    // Whether the key has expired at `now`, in milliseconds since the Unix
+    // epoch.
+    fn expired(&self, now: u64) -> bool {
+        self.expires != 0 && self.expires <= now
+    }
+}
+
+/// Milliseconds since the Unix epoch.
+fn now_ms() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_millis() as u64,
+        Err(_) => 0,
+    }
+}
+
+fn duration_ms(d: Duration) -> u64 {
+    d.as_millis().min(u128::from(u64::MAX)) as u64
+}
+
+/// Metadata of a key, see `KvStore::meta`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyMeta {
+    /// When the key was created, `None` if unknown because the key was
+    /// written before timestamps existed.
+    pub created_at: Option<SystemTime>,
+    /// When the value was last written, `None` if unknown.
+    pub updated_at: Option<SystemTime>,
+    /// Bytes taken by the record on disk.
+    pub size: usize,
+    /// Version of the value, see `KvStore::get_with_version`.
+    pub version: u64,
+}
+
+// The time left until the record of `info` expires, `None` if it never does.
+fn ttl_left(info: &CmdInfo, now: u64) -> Option<Duration> {
+    match info.expires {
+        0 => None,
+        at => Some(Duration::from_millis(at.saturating_sub(now))),
+    }
+}
+
+fn from_ms(ms: u64) -> Option<SystemTime> {
+    match ms {
+        0 => None,
+        ms => Some(UNIX_EPOCH + Duration::from_millis(ms)),
+    }
+}
+
+// Close the lowest file id of `open` if it holds `max` files, making room to
+// open another. The files are mostly read by increasing id, as both the
+// merges and the scans go.
+fn make_room<T>(open: &mut BTreeMap<Fid, T>, max: usize) {
+    if open.len() >= max {
+        let id = *open.keys().next().unwrap();
+        open.remove(&id);
+    }
+}
+
+// The condition of `KvStore::write_set`.
+enum SetIf {
+    Always,
+    // The current version, 0 for an absent key.
+    Version(u64),
+    // Whether the key exists.
+    Exists(bool),
+}
+
+/// The index saved by the last handle, see `KvStoreBuilder::index_snapshot`.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    /// Ids and sizes of the data files when it was taken.
+    files: Vec<(Fid, u64)>,
+    garbage: Vec<(Fid, usize)>,
+    index: Vec<(String, CmdInfo)>,
 }
 
 enum Action {
@@ -45,6 +158,254 @@ enum Action {
     Shutdown,
 }
 
+// Wait for `backoff` in the compacter thread, return false on shutdown.
+fn wait_backoff(rx: &Receiver<Action>, backoff: Duration) -> bool {
+    let deadline = Instant::now() + backoff;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        match rx.recv_timeout(deadline - now) {
+            Ok(Action::Compact) => {}
+            Err(RecvTimeoutError::Timeout) => return true,
+            Ok(Action::Shutdown) | Err(RecvTimeoutError::Disconnected) => return false,
+        }
+    }
+}
+
+/// Progress of a compaction, see `KvStoreBuilder::on_compaction`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompactionEvent {
+    /// A compaction begins, `bytes_to_merge` live bytes will be rewritten.
+    Started {
+        /// Bytes of live records to rewrite.
+        bytes_to_merge: u64,
+    },
+    /// Emitted about every megabyte rewritten.
+    Progress {
+        /// Bytes of live records rewritten so far.
+        bytes_done: u64,
+    },
+    /// The compaction is done and old files are deleted.
+    Finished {
+        /// Disk space freed by the compaction.
+        bytes_reclaimed: u64,
+        /// Time spent on the compaction.
+        duration: Duration,
+    },
+}
+
+/// State of the background compactions, see `KvStore::compaction_health`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionHealth {
+    /// A compaction failed after all its retries, cleared by a successful
+    /// one, or the compacter thread is gone.
+    pub degraded: bool,
+    /// The compacter thread is gone, e.g. it panicked. The writes crossing
+    /// the compaction threshold then compact on their own thread, once.
+    pub compacter_dead: bool,
+    /// Failed attempts since the last successful compaction.
+    pub failures: u32,
+    /// The last compaction error, kept after a success for diagnostics.
+    pub last_error: Option<String>,
+    /// The background compactions are paused, see
+    /// `KvStore::pause_compaction`.
+    pub paused: bool,
+}
+
+/// Bytes of the records written to and read from the data files since the
+/// store was opened, see `KvStore::io_stats`. The reads count the records
+/// only, the OS may read more around them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IoStats {
+    /// Bytes appended by the writes, tombstones included.
+    pub bytes_written: u64,
+    /// Bytes rewritten by the compactions.
+    pub compaction_written: u64,
+    /// Bytes read by the reads, e.g. `get` and the scans.
+    pub bytes_read: u64,
+    /// Bytes read by the compactions.
+    pub compaction_read: u64,
+}
+
+impl IoStats {
+    /// Bytes written to the data files per byte appended by the writes: 1
+    /// until a compaction rewrites records, 0 before any write.
+    pub fn write_amplification(&self) -> f64 {
+        match self.bytes_written {
+            0 => 0.0,
+            n => (n + self.compaction_written) as f64 / n as f64,
+        }
+    }
+
+    /// One line per counter, `io_<name>=<value>`, as in `INFO`.
+    pub fn lines(&self) -> String {
+        let counts = [
+            ("bytes_written", self.bytes_written),
+            ("compaction_written", self.compaction_written),
+            ("bytes_read", self.bytes_read),
+            ("compaction_read", self.compaction_read),
+        ];
+        let mut res = String::new();
+        for (name, n) in counts.iter() {
+            res += &format!("io_{}={}\n", name, n);
+        }
+        res += &format!("io_write_amplification={:.2}\n", self.write_amplification());
+        res
+    }
+}
+
+/// Counts of the live records by length, see `KvStore::size_histogram`.
+///
+/// The length is the one of the serialized record in the data file, which
+/// holds the key, the version and the times besides the value.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SizeHistogram {
+    /// `buckets[i]` counts the records of `2^i` to `2^(i+1) - 1` bytes,
+    /// up to the bucket of the longest record.
+    pub buckets: Vec<u64>,
+}
+
+impl SizeHistogram {
+    fn add(&mut self, len: usize) {
+        // Every record takes a byte at least.
+        let i = 63 - (len.max(1) as u64).leading_zeros() as usize;
+        if self.buckets.len() <= i {
+            self.buckets.resize(i + 1, 0);
+        }
+        self.buckets[i] += 1;
+    }
+
+    /// Number of records counted.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// One line, the lower bound of each non-empty bucket and its count,
+    /// as in `INFO`, e.g. `size_histogram=32:120,64:7,4096:1`.
+    pub fn lines(&self) -> String {
+        let buckets: Vec<String> = self
+            .buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| **n > 0)
+            .map(|(i, n)| format!("{}:{}", 1u64 << i, n))
+            .collect();
+        format!("size_histogram={}\n", buckets.join(","))
+    }
+}
+
+/// The counters of `IoStats`, shared by the handles of a store.
+#[derive(Default)]
+struct IoCounters {
+    written: AtomicU64,
+    compaction_written: AtomicU64,
+    read: AtomicU64,
+    compaction_read: AtomicU64,
+}
+
+impl IoCounters {
+    fn add(counter: &AtomicU64, bytes: usize) {
+        counter.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> IoStats {
+        IoStats {
+            bytes_written: self.written.load(Ordering::Relaxed),
+            compaction_written: self.compaction_written.load(Ordering::Relaxed),
+            bytes_read: self.read.load(Ordering::Relaxed),
+            compaction_read: self.compaction_read.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// On-disk layout of a database, see `KvStoreBuilder::inspect`.
+#[derive(Debug, Clone)]
+pub struct DbInfo {
+    /// Engine recorded in the meta file.
+    pub engine: String,
+    /// Version of the on-disk format, see `FORMAT_VERSION`.
+    pub version: u32,
+    /// Ids and sizes of the data files, sorted by id.
+    pub files: Vec<(usize, u64)>,
+    /// Id of the active data file.
+    pub active_id: Option<usize>,
+    /// Number of live keys.
+    pub keys: usize,
+    /// Bytes taken by overwritten or removed records.
+    pub garbage: u64,
+}
+
+/// Whether `KvStoreBuilder::build_with_status` found a database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenStatus {
+    /// The directory had no meta file, a new empty database was initialized.
+    Created,
+    /// An existing database was opened.
+    Opened,
+}
+
+impl DbInfo {
+    /// Total size of the data files.
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|(_, sz)| sz).sum()
+    }
+
+    /// Estimated fraction of the data files that compaction would reclaim.
+    pub fn garbage_ratio(&self) -> f64 {
+        match self.size() {
+            0 => 0.0,
+            sz => self.garbage as f64 / sz as f64,
+        }
+    }
+}
+
+/// Which keys to evict when the store is full, see `KvStoreBuilder::maxmemory`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// The least recently read or written keys first.
+    Lru,
+    /// Keys chosen at random.
+    Random,
+}
+
+/// Bookkeeping of `KvStoreBuilder::maxmemory`.
+struct Eviction {
+    maxmemory: usize,
+    policy: EvictionPolicy,
+    // Logical time of the last access of the keys, for `Lru` only.
+    clock: AtomicUsize,
+    access: CHashMap<String, usize>,
+}
+
+/// Threads running the compactions of many stores, instead of one
+/// compacter thread per store. See `KvStoreBuilder::compaction_scheduler`.
+pub struct CompactionScheduler {
+    pool: SharedQueueThreadPool,
+}
+
+impl CompactionScheduler {
+    /// Run compactions on `threads` threads, one per CPU if it is 0.
+    pub fn new(threads: u32) -> Result<CompactionScheduler> {
+        Ok(CompactionScheduler {
+            pool: SharedQueueThreadPool::new(threads)?,
+        })
+    }
+
+    fn schedule(&self, store: KvStore) {
+        self.pool.spawn(move || {
+            store.scheduled.store(false, Ordering::SeqCst);
+            if store.needs_compaction() {
+                store.compact_retrying(|backoff| {
+                    thread::sleep(backoff);
+                    true
+                });
+            }
+        });
+    }
+}
+
 /// Store key-value pairs.
 ///
 /// Example:
@@ -60,29 +421,86 @@ enum Action {
 /// ```
 pub struct KvStore {
     dir: PathBuf,
+    prefix: String,
+    codec: Arc<dyn RecordCodec>,
     log: Logger,
     cthreshold: usize,
 
     garbage_sz: Arc<AtomicUsize>,
+    // Bytes of the records in the index.
+    live_sz: Arc<AtomicUsize>,
     index: Arc<Index>,
     active: Arc<Mutex<Fdw>>,
     writer: Arc<Mutex<()>>,
     compact_lock: Arc<Mutex<()>>,
-    lowest_id: Arc<AtomicUsize>,
+    file_gbg: Arc<Mutex<FileGarbage>>,
+    // Bytes of the records of each key made garbage since the open.
+    key_gbg: Arc<Mutex<HashMap<String, usize>>>,
+    min_garbage: f64,
+    prealloc: u64,
+    key_order: bool,
+    reject_empty: bool,
+    namespaces: Option<Arc<Namespaces>>,
+    repl: Option<Arc<ReplicationLog>>,
+    // Followers to wait for after a write, see `min_replicas_ack`.
+    min_acks: usize,
+    ack_timeout: Duration,
+    // The keys of the merged files, by file id.
+    blooms: Arc<Mutex<HashMap<Fid, Bloom>>>,
+    bloom_bits: usize,
+    // The most data files a handle keeps open for reading.
+    max_open: usize,
 
     sx: Sender<Action>,
     compacter: Option<Arc<JoinHandle<()>>>,
     counter: Arc<AtomicUsize>,
-
-    fds: RefCell<FdrMap>,
+    events: Option<EventHook>,
+    // Weak, so that a compaction job never drops the pool running it.
+    scheduler: Option<Weak<CompactionScheduler>>,
+    scheduled: Arc<AtomicBool>,
+    snapshot: bool,
+    eviction: Option<Arc<Eviction>>,
+    retries: u32,
+    backoff: Duration,
+    health: Arc<Mutex<CompactionHealth>>,
+    paused: Arc<AtomicBool>,
+    io: Arc<IoCounters>,
+    // Held until the last handle, including the compacter's, is dropped.
+    _lock: Option<Arc<DirLock>>,
+
+    fds: Mutex<FdrMap>,
 }
 
 /// Use to costom KvStore.
 pub struct KvStoreBuilder {
     dir: PathBuf,
+    prefix: String,
+    codec: Arc<dyn RecordCodec>,
+    cipher: Option<Arc<Cipher>>,
     log: Option<Logger>,
     wthreshold: u64,
     cthreshold: usize,
+    events: Option<EventHook>,
+    scheduler: Option<Arc<CompactionScheduler>>,
+    snapshot: bool,
+    maxmemory: Option<usize>,
+    policy: EvictionPolicy,
+    retries: u32,
+    backoff: Duration,
+    lock: bool,
+    min_garbage: f64,
+    thread_name: String,
+    stack_size: Option<usize>,
+    prealloc: u64,
+    key_order: bool,
+    reject_empty: bool,
+    ns_sep: Option<char>,
+    repl_backlog: Option<usize>,
+    min_acks: usize,
+    ack_timeout: Duration,
+    bloom_bits: usize,
+    max_open: usize,
+    hasher: IndexHasher,
 }
 
 impl KvStore {
@@ -98,64 +516,261 @@ impl KvStore {
     /// If the key already in the store, return the `Some(value)`.  
     /// Otherwise, return `None`.
     pub fn get(&self, key: String) -> Result<Option<String>> {
-        let info = match self.index.get(&key) {
-            Some(info) => info.clone(),
+        Ok(self.get_with_version(key)?.map(|(val, _)| val))
+    }
+
+    /// Get the values of `keys`, in the same order, duplicates included.
+    /// A key given several times is read once.
+    ///
+    /// Each key is read on its own, so a concurrent write may be seen for
+    /// some keys and not for others.
+    pub fn multi_get(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let mut found: HashMap<&str, Option<String>> = HashMap::with_capacity(keys.len());
+        for key in keys {
+            if let Entry::Vacant(entry) = found.entry(key.as_str()) {
+                entry.insert(self.get(key.clone())?);
+            }
+        }
+        Ok(keys.iter().map(|key| found[key.as_str()].clone()).collect())
+    }
+
+    /// Get the bytes `start..=end` of the value of key, like `GETRANGE` of
+    /// redis: a negative index counts from the end, -1 being the last byte,
+    /// the indices are clamped to the value, and an empty range gives "".
+    /// A character of several bytes cut by the range is replaced with
+    /// U+FFFD.
+    ///
+    /// With non-negative indices, only the record up to `end` is read from
+    /// disk. A negative index needs the length, so the value is read whole.
+    pub fn get_range(&self, key: String, start: i64, end: i64) -> Result<Option<String>> {
+        if start >= 0 && end >= 0 {
+            if let Some(info) = self.entry(&key) {
+                // Anything unexpected, e.g. a record moved by a compaction,
+                // is left to `get`.
+                if let Ok(Some(head)) = self.read_value_head(&info, &key, end as usize + 1) {
+                    self.record_access(&key);
+                    let range = byte_range(&head, start, end);
+                    return Ok(Some(String::from_utf8_lossy(range).into_owned()));
+                }
+            }
+        }
+        Ok(self.get(key)?.map(|val| {
+            let range = byte_range(val.as_bytes(), start, end);
+            String::from_utf8_lossy(range).into_owned()
+        }))
+    }
+
+    /// Get the value of key cut to at most `max` bytes, and whether it was
+    /// cut, e.g. to preview values that may be huge.
+    ///
+    /// The value is cut at the last character boundary within `max` bytes,
+    /// so a cut value may be up to 3 bytes shorter than `max`, but it never
+    /// holds a partial character. Only the record up to `max` bytes of the
+    /// value is read from disk.
+    pub fn get_capped(&self, key: String, max: usize) -> Result<Option<(String, bool)>> {
+        if let Some(info) = self.entry(&key) {
+            // Like `get_range`, `get` handles anything unexpected.
+            if let Ok(Some(head)) = self.read_value_head(&info, &key, max.saturating_add(1)) {
+                self.record_access(&key);
+                return Ok(Some(cap_utf8(&head, max)));
+            }
+        }
+        Ok(self.get(key)?.map(|val| cap_utf8(val.as_bytes(), max)))
+    }
+
+    // The first `n` bytes of the value in the record of key at `info`, all
+    // of them if the value is shorter, reading the record no further.
+    // `None` if the value is not a string, the record doesn't start with
+    // key, or the codec can't tell.
+    fn read_value_head(&self, info: &CmdInfo, key: &str, n: usize) -> Result<Option<Vec<u8>>> {
+        if info.kind != ValueKind::Str {
+            return Ok(None);
+        }
+        self.read_at(&info.loc, |file| {
+            let mut rdr = file.take(info.len as u64);
+            let res = self.codec.value_head(&mut rdr, key, n);
+            IoCounters::add(&self.io.read, info.len - rdr.limit() as usize);
+            res
+        })
+    }
+
+    /// Like `get`, and also return the version of the value.
+    ///
+    /// A key gets version 1 when created and the version grows by 1 on
+    /// every write. A removed or expired key starts over from 1.
+    pub fn get_with_version(&self, key: String) -> Result<Option<(String, u64)>> {
+        Ok(self
+            .read_string(&key)?
+            .map(|(val, info)| (val, info.version)))
+    }
+
+    /// Like `get`, and also return the time left until the key expires,
+    /// `None` if it never does.
+    ///
+    /// The value and the TTL are of the same record, so a write between
+    /// them can't pair the value of one with the TTL of another.
+    pub fn get_with_ttl(&self, key: String) -> Result<Option<(String, Option<Duration>)>> {
+        let found = self.read_string(&key)?;
+        let now = now_ms();
+        Ok(found.map(|(val, info)| (val, ttl_left(&info, now))))
+    }
+
+    /// Get the value of key and make it expire `ttl` from now, whether it
+    /// had a TTL or not, for a cache whose entries expire when not read
+    /// for a while.
+    ///
+    /// The value, its version and its times are kept. The write locks are
+    /// held from the read to the write, so a concurrent write of the key
+    /// is either read or keeps its own TTL.
+    pub fn get_and_expire(&self, key: String, ttl: Duration) -> Result<Option<String>> {
+        let expires = now_ms().saturating_add(duration_ms(ttl));
+        self.rewrite_expiry(key, expires, Some(ValueKind::Str))
+    }
+
+    // Like `read_entry`, failing with `Error::WrongType` unless the value
+    // is a string.
+    fn read_string(&self, key: &str) -> Result<Option<(String, CmdInfo)>> {
+        match self.read_entry(key)? {
+            Some((_, ref info)) if info.kind != ValueKind::Str => {
+                Err(Error::WrongType(key.to_owned()))?
+            }
+            found => Ok(found),
+        }
+    }
+
+    // Like `read_entry` for a list, failing with `Error::WrongType` unless
+    // the value is one.
+    fn read_list(&self, key: &str) -> Result<Option<(Vec<String>, CmdInfo)>> {
+        let (val, info) = match self.read_entry(key)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        if info.kind != ValueKind::List {
+            return Err(Error::WrongType(key.to_owned()))?;
+        }
+        match serde_json::from_str(&val) {
+            Ok(items) => Ok(Some((items, info))),
+            Err(e) => Err(Error::Corruption(format!("list of {:?}: {}", key, e)))?,
+        }
+    }
+
+    // The value of key and its entry in the index, of any kind.
+    fn read_entry(&self, key: &str) -> Result<Option<(String, CmdInfo)>> {
+        let (info, cmd) = match self.lookup(key)? {
+            Some(found) => found,
             None => return Ok(None),
         };
-        let cmd = self.fetch(&info.loc)?;
-        if let Command::Set(k, v) = cmd {
+        if let Command::Set(k, v, ..) = cmd {
             if k == key {
-                Ok(Some(v))
+                self.record_access(key);
+                Ok(Some((v, info)))
             } else {
-                return Err(Error::UnexpectCmd {
+                Err(Error::UnexpectCmd {
                     found: format!("Set({:?}, {:?})", k, v),
                     expect: format!("Set({:?}, _)", key),
-                })?;
+                })?
             }
         } else {
-            return Err(Error::UnexpectCmd {
+            Err(Error::UnexpectCmd {
                 found: format!("{:?}", cmd),
                 expect: format!("Set({:?}, _)", key),
-            })?;
+            })?
         }
     }
 
     /// If the key already in the store, update the value.  
     /// Otherwise, insert the key-value pair into the store.
     pub fn set(&self, key: String, val: String) -> Result<()> {
-        let (info, writer) = self.append(&Command::Set(key.clone(), val.clone()))?;
-        let new_gbg = match self.index.insert(key.clone(), info.clone()) {
-            Some(old) => {
-                debug!(self.log, "Old location of key '{}': {:?}.", key, old);
-                debug!(self.log, "New location of key '{}': {:?}.", key, info);
-                old.len
-            }
-            None => {
-                debug!(self.log, "Insert new key '{}' at {:?}.", key, info);
-                0
+        self.write_set(key, val, SetIf::Always).map(|_| ())
+    }
+
+    /// Like `set`, and return whether the key was created, false if a value
+    /// was overwritten. An expired value counts as absent.
+    pub fn set_created(&self, key: String, val: String) -> Result<bool> {
+        // A key is created at version 1, see `write_set`.
+        self.write_set(key, val, SetIf::Always)
+            .map(|version| version == Some(1))
+    }
+
+    /// Set the value only if the current version of the key is `expected`,
+    /// 0 standing for an absent key, and return the new version.
+    /// Otherwise fail with `Error::VersionMismatch`.
+    pub fn set_if_version(&self, key: String, val: String, expected: u64) -> Result<u64> {
+        self.write_set(key, val, SetIf::Version(expected))
+            .map(Option::unwrap_or_default)
+    }
+
+    /// Set the value only if the key exists, like `SET XX` of redis, and
+    /// return whether it was set.
+    pub fn set_if_present(&self, key: String, val: String) -> Result<bool> {
+        self.write_set(key, val, SetIf::Exists(true))
+            .map(|version| version.is_some())
+    }
+
+    /// Set the value only if the key doesn't exist, like `SET NX` of redis,
+    /// and return whether it was set.
+    pub fn set_if_absent(&self, key: String, val: String) -> Result<bool> {
+        self.write_set(key, val, SetIf::Exists(false))
+            .map(|version| version.is_some())
+    }
+
+    // Return the new version, `None` if the condition left the key as is.
+    fn write_set(&self, key: String, val: String, cond: SetIf) -> Result<Option<u64>> {
+        self.check_key(&key)?;
+        // The version is read and written under both locks, same order as `compact`.
+        let mut active = self.active.lock().unwrap();
+        let writer = self.writer.lock().unwrap();
+        let exists = self.entry(&key).map(|info| info.version);
+        let found = exists.unwrap_or(0);
+        let now = now_ms();
+        let created = self.created_at(&key, now);
+        match cond {
+            SetIf::Always => {}
+            SetIf::Version(expected) if expected != found => {
+                return Err(Error::VersionMismatch {
+                    key,
+                    expected,
+                    found,
+                })?;
             }
-        };
-        if new_gbg == 0 {
-            return Ok(());
-        }
-        let gbg_sz = self.garbage_sz.fetch_add(new_gbg, Ordering::SeqCst);
-        drop(writer);
-        if gbg_sz > self.cthreshold {
-            self.call_compacter();
+            SetIf::Version(_) => {}
+            SetIf::Exists(present) if present != exists.is_some() => return Ok(None),
+            SetIf::Exists(_) => {}
         }
-        Ok(())
+        let cmd = Command::Set(key.clone(), val, 0, found + 1, created, now, ValueKind::Str);
+        self.check_quota(&[&cmd])?;
+        let evicted = self.evict(&mut active, &cmd)?;
+        let info = self.write_cmd(&mut active, &cmd)?;
+        active.wtr.flush()?;
+        drop(active);
+
+        let new_gbg = evicted
+            + match self.index_insert(key.clone(), info.clone()) {
+                Some(old) => {
+                    debug!(self.log, "Old location of key '{}': {:?}.", key, old);
+                    debug!(self.log, "New location of key '{}': {:?}.", key, info);
+                    old.len
+                }
+                None => {
+                    debug!(self.log, "Insert new key '{}' at {:?}.", key, info);
+                    0
+                }
+            };
+        self.add_garbage(new_gbg, writer);
+        self.wait_replicas()?;
+        Ok(Some(info.version))
     }
 
     /// If the key already in the store, remove it.  
     /// Otherwise, do nothing.
     pub fn remove(&self, key: String) -> Result<()> {
-        if None == self.index.get(&key) {
+        if self.entry(&key).is_none() {
             return Err(Error::KeyNotFound(key))?;
         }
 
         let (info, writer) = self.append(&Command::Rm(key.clone()))?;
 
-        let new_gbg = match self.index.remove(&key) {
+        let new_gbg = match self.index_remove(&key) {
             Some(old) => info.len + old.len,
             None => info.len,
         };
@@ -164,41 +779,1376 @@ impl KvStore {
         if gbg_sz > self.cthreshold {
             self.call_compacter();
         }
+        self.wait_replicas()?;
         if new_gbg == info.len {
             Err(Error::KeyNotFound(key))?;
         }
         Ok(())
     }
 
-    // Write command to the active data file.
-    // Allocate a new active data file if readched threshold.
-    fn append(&self, cmd: &Command) -> Result<(CmdInfo, MutexGuard<()>)> {
+    /// Append `cmd` as if it were a local write and update the index, for a
+    /// replication follower or a replayed command stream.
+    ///
+    /// A `Set` keeps the version and the times of `cmd`, and a `Rm` of an
+    /// absent key is written without failing. Applying the same commands
+    /// again in order, e.g. an overlap after a reconnect, gives the same
+    /// pairs.
+    pub fn apply_command(&self, cmd: Command) -> Result<()> {
+        match cmd {
+            Command::Set(ref key, ..) => {
+                let mut active = self.active.lock().unwrap();
+                let writer = self.writer.lock().unwrap();
+                let evicted = self.evict(&mut active, &cmd)?;
+                let info = self.write_cmd(&mut active, &cmd)?;
+                active.wtr.flush()?;
+                drop(active);
+                let old = self.index_insert(key.clone(), info);
+                self.add_garbage(evicted + old.map_or(0, |old| old.len), writer);
+                self.wait_replicas()?;
+            }
+            Command::Rm(ref key) => {
+                let (info, writer) = self.append(&cmd)?;
+                let old = self.index_remove(key);
+                self.add_garbage(info.len + old.map_or(0, |old| old.len), writer);
+                self.wait_replicas()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add `delta` to the float value of key, and return the new value.
+    ///
+    /// An absent key counts as `0`. The value is stored as formatted by
+    /// `format_float`, so repeated increments keep a stable representation.
+    /// The write locks are held from the read to the write, so concurrent
+    /// increments of the same key are not lost.
+    pub fn increment_float(&self, key: String, delta: f64) -> Result<f64> {
+        self.check_key(&key)?;
+        // Same lock order as `compact`.
         let mut active = self.active.lock().unwrap();
+        let writer = self.writer.lock().unwrap();
 
-        debug!(self.log, "Appending command: {:?}", cmd);
-        let offset = active.wtr.seek(SeekFrom::End(0))?;
-        let cmd = Command::ser(cmd)?;
-        let len = cmd.len();
-        active.wtr.write_all(cmd.as_ref())?;
+        let (cur, version) = match self.get_with_version(key.clone())? {
+            Some((val, version)) => (parse_float(&key, &val)?, version),
+            None => (0.0, 0),
+        };
+        let new = cur + delta;
+        if !new.is_finite() {
+            return Err(Error::FloatOverflow(key))?;
+        }
+        let expires = self.kept_expiry(&key);
+        let now = now_ms();
+        let created = self.created_at(&key, now);
+        let cmd = Command::Set(
+            key.clone(),
+            format_float(new),
+            expires,
+            version + 1,
+            created,
+            now,
+            ValueKind::Str,
+        );
+        self.check_quota(&[&cmd])?;
+        let evicted = self.evict(&mut active, &cmd)?;
+        let info = self.write_cmd(&mut active, &cmd)?;
+        active.wtr.flush()?;
+        drop(active);
+
+        let old = self.index_insert(key, info).map_or(0, |info| info.len);
+        self.add_garbage(evicted + old, writer);
+        self.wait_replicas()?;
+        Ok(new)
+    }
+
+    /// Add `delta` to the integer value of key and return the new value,
+    /// creating the key with the value `delta` and an expiry `ttl` from now
+    /// if it is absent, e.g. the counter of a fixed window of a rate
+    /// limiter.
+    ///
+    /// The TTL is only set when the key is created: an existing key keeps
+    /// its expiry time, or keeps never expiring, so the requests counted in
+    /// a window don't extend it. An expired key counts as absent, so the
+    /// first increment after the end of a window starts the next one, with
+    /// a new version. The write locks are held from the read to the write,
+    /// so concurrent increments are not lost and only one of them creates
+    /// the key.
+    ///
+    /// Fail with `Error::NotInteger` if the value isn't a 64-bit integer,
+    /// and with `Error::IntegerOverflow` if the sum doesn't fit.
+    pub fn increment_with_ttl(&self, key: String, delta: i64, ttl: Duration) -> Result<i64> {
+        self.check_key(&key)?;
+        // Same lock order as `compact`.
+        let mut active = self.active.lock().unwrap();
+        let writer = self.writer.lock().unwrap();
 
+        // Under the write locks the entry can only expire since the read,
+        // so it is checked against the time of the write.
+        let found = self
+            .get_with_version(key.clone())?
+            .and_then(|(val, _)| self.index.get(&key).map(|info| (val, info)));
+        let now = now_ms();
+        let (cur, version, created, expires) = match found {
+            Some((ref val, ref info)) if !info.expired(now) => {
+                let cur = parse_integer(&key, val)?;
+                (cur, info.version, info.created, info.expires)
+            }
+            _ => (0, 0, now, now.saturating_add(duration_ms(ttl))),
+        };
+        let new = match cur.checked_add(delta) {
+            Some(new) => new,
+            None => return Err(Error::IntegerOverflow(key))?,
+        };
+        let cmd = Command::Set(
+            key.clone(),
+            new.to_string(),
+            expires,
+            version + 1,
+            created,
+            now,
+            ValueKind::Str,
+        );
+        self.check_quota(&[&cmd])?;
+        let evicted = self.evict(&mut active, &cmd)?;
+        let info = self.write_cmd(&mut active, &cmd)?;
         active.wtr.flush()?;
+        drop(active);
 
+        let old = self.index_insert(key, info).map_or(0, |info| info.len);
+        self.add_garbage(evicted + old, writer);
+        self.wait_replicas()?;
+        Ok(new)
+    }
+
+    /// Append `val` to the list of key and return the new length of the
+    /// list, like `RPUSH` of redis. An absent key counts as an empty list;
+    /// fail with `Error::WrongType` if key holds a string.
+    ///
+    /// A list is stored as one value, so each push reads and rewrites the
+    /// whole list: it costs O(n) in time and in log space, the old value
+    /// becoming garbage. Keep lists short, e.g. split a long one over keys
+    /// of its own. The write locks are held from the read to the write, so
+    /// concurrent pushes are not lost. The key keeps its expiry time if any.
+    pub fn push_back(&self, key: String, val: String) -> Result<usize> {
+        self.push(key, val, false)
+    }
+
+    /// Prepend `val` to the list of key and return the new length of the
+    /// list, like `LPUSH` of redis, see `push_back`.
+    pub fn push_front(&self, key: String, val: String) -> Result<usize> {
+        self.push(key, val, true)
+    }
+
+    fn push(&self, key: String, val: String, front: bool) -> Result<usize> {
+        self.check_key(&key)?;
+        // Same lock order as `compact`.
+        let mut active = self.active.lock().unwrap();
         let writer = self.writer.lock().unwrap();
-        Ok((CmdInfo::new(active.id, offset, len), writer))
+
+        let (mut items, version) = match self.read_list(&key)? {
+            Some((items, info)) => (items, info.version),
+            None => (Vec::new(), 0),
+        };
+        if front {
+            items.insert(0, val);
+        } else {
+            items.push(val);
+        }
+        let now = now_ms();
+        let created = self.created_at(&key, now);
+        let expires = self.kept_expiry(&key);
+        // Strings only, which always serialize.
+        let cmd = Command::Set(
+            key.clone(),
+            serde_json::to_string(&items).unwrap(),
+            expires,
+            version + 1,
+            created,
+            now,
+            ValueKind::List,
+        );
+        self.check_quota(&[&cmd])?;
+        let evicted = self.evict(&mut active, &cmd)?;
+        let info = self.write_cmd(&mut active, &cmd)?;
+        active.wtr.flush()?;
+        drop(active);
+
+        let old = self.index_insert(key, info).map_or(0, |info| info.len);
+        self.add_garbage(evicted + old, writer);
+        self.wait_replicas()?;
+        Ok(items.len())
     }
 
-    fn fetch(&self, loc: &Location) -> Result<Command> {
-        debug!(self.log, "fetching location: {:?}", loc);
-        let mut fds = self.fds.borrow_mut();
-        let mut update = false;
-        let fd = match fds.get_mut(&loc.id) {
-            Some(fd) => fd,
-            None => {
-                update = true;
-                fds.insert(loc.id, file::fdr(&self.dir, loc.id)?);
-                fds.get_mut(&loc.id).unwrap()
+    /// Get the items `start..=stop` of the list of key, like `LRANGE` of
+    /// redis: a negative index counts from the end, -1 being the last item,
+    /// and the indices are clamped to the list. An absent key counts as an
+    /// empty list; fail with `Error::WrongType` if key holds a string.
+    pub fn list_range(&self, key: String, start: i64, stop: i64) -> Result<Vec<String>> {
+        match self.read_list(&key)? {
+            Some((items, _)) => Ok(index_range(&items, start, stop).to_vec()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Get the length of the list of key, 0 if it is absent. Fail with
+    /// `Error::WrongType` if key holds a string.
+    pub fn list_len(&self, key: String) -> Result<usize> {
+        Ok(self.read_list(&key)?.map_or(0, |(items, _)| items.len()))
+    }
+
+    /// Replace the value of key by what `f` returns for it, and return the
+    /// new value. `f` is given `None` for an absent key, and returns `None`
+    /// to remove the key.
+    ///
+    /// The write locks are held from the read to the write, so no other
+    /// write of the store runs in between, whatever the key: the other
+    /// atomic updates, e.g. `increment_float` or `set_if_version`, are
+    /// special cases of it. As every write waits for `f`, it should be
+    /// fast and do nothing but compute the value. A panic of `f` writes
+    /// nothing and is resumed once the locks are released.
+    ///
+    /// A new value keeps the creation time and the expiry time of the key.
+    pub fn update<F>(&self, key: String, f: F) -> Result<Option<String>>
+    where
+        F: FnOnce(Option<String>) -> Option<String>,
+    {
+        self.check_key(&key)?;
+        // Same lock order as `compact`.
+        let mut active = self.active.lock().unwrap();
+        let writer = self.writer.lock().unwrap();
+
+        let (cur, version) = match self.get_with_version(key.clone())? {
+            Some((val, version)) => (Some(val), version),
+            None => (None, 0),
+        };
+        let existed = cur.is_some();
+        let new = match panic::catch_unwind(AssertUnwindSafe(|| f(cur))) {
+            Ok(new) => new,
+            Err(e) => {
+                drop(active);
+                drop(writer);
+                panic::resume_unwind(e);
+            }
+        };
+        let val = match new {
+            Some(val) => val,
+            None if existed => {
+                let info = self.write_cmd(&mut active, &Command::Rm(key.clone()))?;
+                active.wtr.flush()?;
+                drop(active);
+                let old = self.index_remove(&key).map_or(0, |old| old.len);
+                self.add_garbage(info.len + old, writer);
+                self.wait_replicas()?;
+                return Ok(None);
             }
+            None => return Ok(None),
+        };
+        let now = now_ms();
+        let created = self.created_at(&key, now);
+        let expires = self.kept_expiry(&key);
+        let cmd = Command::Set(
+            key.clone(),
+            val.clone(),
+            expires,
+            version + 1,
+            created,
+            now,
+            ValueKind::Str,
+        );
+        self.check_quota(&[&cmd])?;
+        let evicted = self.evict(&mut active, &cmd)?;
+        let info = self.write_cmd(&mut active, &cmd)?;
+        active.wtr.flush()?;
+        drop(active);
+
+        let old = self.index_insert(key, info).map_or(0, |info| info.len);
+        self.add_garbage(evicted + old, writer);
+        self.wait_replicas()?;
+        Ok(Some(val))
+    }
+
+    /// Rename `src` to `dst` if `dst` doesn't exist, return whether it was renamed.
+    /// Fail with `Error::KeyNotFound` if `src` doesn't exist.
+    ///
+    /// The check and the write are atomic. The two records are written at
+    /// once, but a crash in the middle may leave both keys.
+    pub fn rename_nx(&self, src: String, dst: String) -> Result<bool> {
+        self.check_key(&src)?;
+        self.check_key(&dst)?;
+        // Same lock order as `compact`.
+        let mut active = self.active.lock().unwrap();
+        let writer = self.writer.lock().unwrap();
+
+        let (val, kind) = match self.read_entry(&src)? {
+            Some((val, info)) => (val, info.kind),
+            None => return Err(Error::KeyNotFound(src))?,
+        };
+        if self.entry(&dst).is_some() {
+            return Ok(false);
+        }
+        // The value keeps its expiry and creation time.
+        let expires = self.kept_expiry(&src);
+        let now = now_ms();
+        let created = self.created_at(&src, now);
+        let cmd = Command::Set(dst.clone(), val, expires, 1, created, now, kind);
+        let rm_cmd = Command::Rm(src.clone());
+        self.check_quota(&[&cmd, &rm_cmd])?;
+        let evicted = self.evict(&mut active, &cmd)?;
+        let set = self.write_cmd(&mut active, &cmd)?;
+        let rm = self.write_cmd(&mut active, &rm_cmd)?;
+        active.wtr.flush()?;
+        drop(active);
+
+        // An expired `dst` is still indexed, its record becomes garbage.
+        let expired = self.index_insert(dst, set).map_or(0, |info| info.len);
+        let old = self.index_remove(&src).map_or(0, |info| info.len);
+        self.add_garbage(evicted + expired + old + rm.len, writer);
+        self.wait_replicas()?;
+        Ok(true)
+    }
+
+    /// Copy the value of `src` to `dst`, return whether it was copied.
+    /// An existing `dst` is only overwritten with `replace`.
+    /// Fail with `Error::KeyNotFound` if `src` doesn't exist.
+    pub fn copy(&self, src: String, dst: String, replace: bool) -> Result<bool> {
+        self.check_key(&dst)?;
+        // Same lock order as `compact`.
+        let mut active = self.active.lock().unwrap();
+        let writer = self.writer.lock().unwrap();
+
+        let (val, kind) = match self.read_entry(&src)? {
+            Some((val, info)) => (val, info.kind),
+            None => return Err(Error::KeyNotFound(src))?,
         };
+        let version = self.entry(&dst).map_or(0, |info| info.version);
+        if version > 0 && !replace {
+            return Ok(false);
+        }
+        // The copy expires with `src`.
+        let expires = self.kept_expiry(&src);
+        let now = now_ms();
+        let created = self.created_at(&dst, now);
+        let cmd = Command::Set(dst.clone(), val, expires, version + 1, created, now, kind);
+        self.check_quota(&[&cmd])?;
+        let evicted = self.evict(&mut active, &cmd)?;
+        let info = self.write_cmd(&mut active, &cmd)?;
+        active.wtr.flush()?;
+        drop(active);
+
+        let old = self.index_insert(dst, info).map_or(0, |info| info.len);
+        self.add_garbage(evicted + old, writer);
+        self.wait_replicas()?;
+        Ok(true)
+    }
+
+    /// Exchange the values of `a` and `b`.
+    /// Fail with `Error::KeyNotFound` if either doesn't exist.
+    ///
+    /// All writes take the same two store-wide locks in a fixed order, so
+    /// swaps of the same keys in any order can't deadlock. Readers see
+    /// either both values exchanged or none of them: the two keys are
+    /// updated in the index at once.
+    pub fn swap(&self, a: String, b: String) -> Result<()> {
+        // Same lock order as `compact`.
+        let mut active = self.active.lock().unwrap();
+        let writer = self.writer.lock().unwrap();
+
+        let (val_a, info_a) = match self.read_entry(&a)? {
+            Some(found) => found,
+            None => return Err(Error::KeyNotFound(a))?,
+        };
+        let (val_b, info_b) = match self.read_entry(&b)? {
+            Some(found) => found,
+            None => return Err(Error::KeyNotFound(b))?,
+        };
+        if a == b {
+            return Ok(());
+        }
+        // Each key keeps its own expiry time.
+        let (exp_a, exp_b) = (self.kept_expiry(&a), self.kept_expiry(&b));
+        let now = now_ms();
+        let (created_a, created_b) = (self.created_at(&a, now), self.created_at(&b, now));
+        // The values move with their kinds.
+        let (ver_a, ver_b) = (info_a.version + 1, info_b.version + 1);
+        let cmd_a = Command::Set(a.clone(), val_b, exp_a, ver_a, created_a, now, info_b.kind);
+        let cmd_b = Command::Set(b.clone(), val_a, exp_b, ver_b, created_b, now, info_a.kind);
+        self.check_quota(&[&cmd_a, &cmd_b])?;
+        let info_a = self.write_cmd(&mut active, &cmd_a)?;
+        let info_b = self.write_cmd(&mut active, &cmd_b)?;
+        active.wtr.flush()?;
+        drop(active);
+
+        let olds = self.index_insert_many(vec![(a, info_a), (b, info_b)]);
+        let old_len = olds.iter().flatten().map(|info| info.len).sum();
+        self.add_garbage(old_len, writer);
+        self.wait_replicas()?;
+        Ok(())
+    }
+
+    /// Mark key as just used for `EvictionPolicy::Lru`, like a `get` that
+    /// doesn't read the value, and return whether it exists.
+    ///
+    /// Only the in-memory index is consulted and nothing is written, so the
+    /// update time given by `meta` is unchanged. Like every access, it is
+    /// forgotten on restart.
+    pub fn touch(&self, key: String) -> Result<bool> {
+        if self.entry(&key).is_none() {
+            return Ok(false);
+        }
+        self.record_access(&key);
+        Ok(true)
+    }
+
+    /// Get the creation and update time, the size and the version of key.
+    ///
+    /// Only the in-memory index is consulted, no value is read from disk.
+    pub fn meta(&self, key: String) -> Result<Option<KeyMeta>> {
+        Ok(self.entry(&key).map(|info| KeyMeta {
+            created_at: from_ms(info.created),
+            updated_at: from_ms(info.updated),
+            size: info.len,
+            version: info.version,
+        }))
+    }
+
+    // Creation time of a new value of key, kept from the current value.
+    fn created_at(&self, key: &str, now: u64) -> u64 {
+        self.entry(key).map_or(now, |info| info.created)
+    }
+
+    /// Return a live key chosen at random, `None` if the store is empty.
+    ///
+    /// Every key present during the whole call is equally likely to be
+    /// chosen. The index can't be sampled in place, so it is copied and
+    /// sampled, which takes time linear in the number of keys.
+    pub fn random_key(&self) -> Option<String> {
+        self.live_entries()
+            .map(|(key, _)| key)
+            .choose(&mut rand::thread_rng())
+    }
+
+    /// All the values of key still in the data files, oldest first.
+    /// The current value, if any, is the last one.
+    ///
+    /// For diagnostics only: every data file is scanned, so it is
+    /// O(database). Values overwritten or removed before the last
+    /// compaction are gone. Compaction waits for the scan.
+    #[cfg(feature = "history")]
+    pub fn history(&self, key: &str) -> Result<Vec<String>> {
+        let _compact = self.compact_lock.lock().unwrap();
+        let mut values = Vec::new();
+        for (id, limit) in self.readable_files()? {
+            if !self.may_contain(id, key) {
+                continue;
+            }
+            let rdr = file::open_r(self.datafile(id))?.take(limit);
+            for cmd in Records::new(rdr, self.codec.clone()) {
+                if let Command::Set(k, val, ..) = cmd? {
+                    if k == key {
+                        values.push(val);
+                    }
+                }
+            }
+        }
+        Ok(values)
+    }
+
+    /// The live pairs in the order of their records in the data files,
+    /// which is the order of their last writes, except that a compaction
+    /// rewrites the pairs of the files it merges in no particular order.
+    ///
+    /// Every data file is read sequentially, so the cost is O(database)
+    /// whatever the number of live keys, unlike the index lookups of `get`.
+    /// Compaction waits until the iterator is dropped. Under concurrent
+    /// writes it is best-effort: a key overwritten during the iteration
+    /// may be missed.
+    pub fn iter_log_order(&self) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        let compact = self.compact_lock.lock().unwrap();
+        let (files, error) = match self.readable_files() {
+            Ok(files) => (files, None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
+        LogOrder {
+            store: self,
+            _compact: compact,
+            error,
+            files: files.into_iter(),
+            stream: None,
+        }
+    }
+
+    // False if the bloom filter of the file `id` rules out `key`. The
+    // files without one, e.g. the active file, may contain any key.
+    #[cfg_attr(not(feature = "history"), allow(dead_code))]
+    fn may_contain(&self, id: Fid, key: &str) -> bool {
+        let blooms = self.blooms.lock().unwrap();
+        blooms.get(&id).is_none_or(|bloom| bloom.may_contain(key))
+    }
+
+    // Ids of the data files, each with the length that can be read: the
+    // active file up to its last record, the others whole.
+    fn readable_files(&self) -> Result<Vec<(Fid, u64)>> {
+        // Records are only flushed whole under the active lock, so the
+        // active file is complete up to its end.
+        let (active_id, active_len) = {
+            let active = self.active.lock().unwrap();
+            (active.id, active.end)
+        };
+        Ok(KvStoreBuilder::file_ids(&self.dir, &self.prefix)?
+            .into_iter()
+            .filter(|id| *id <= active_id)
+            .map(|id| match id {
+                id if id == active_id => (id, active_len),
+                id => (id, u64::MAX),
+            })
+            .collect())
+    }
+
+    /// All the live keys, in no particular order.
+    ///
+    /// Like `count_prefix`, a best-effort snapshot under concurrent writes.
+    pub fn keys(&self) -> Vec<String> {
+        self.live_entries().map(|(key, _)| key).collect()
+    }
+
+    /// The live keys matching the glob `pattern`, in no particular order:
+    /// `*` matches any run of characters, `?` one, `[abc]`, `[^abc]` and
+    /// `[a-z]` one of a set, and `\` escapes the next character. E.g.
+    /// `user:*:session` matches `user:42:session`.
+    ///
+    /// Every key of the store is matched, which takes time linear in the
+    /// number of keys and copies the index: on a large store, prefer the
+    /// commands working on a prefix, e.g. `count_prefix`.
+    pub fn keys_matching(&self, pattern: &str) -> Vec<String> {
+        self.live_entries()
+            .map(|(key, _)| key)
+            .filter(|key| glob_match(pattern, key))
+            .collect()
+    }
+
+    /// The members of both sets `a` and `b`, sorted, where the members of a
+    /// set are the keys starting with its prefix, less the prefix. E.g.
+    /// `set:x:` and `set:y:` have the member `m` if both `set:x:m` and
+    /// `set:y:m` exist.
+    ///
+    /// This emulates sets with keys, there is no set type: every key of the
+    /// store is read from the index, which takes time linear in the number
+    /// of keys. Like `count_prefix`, a best-effort snapshot under
+    /// concurrent writes.
+    pub fn intersect_prefixes(&self, a: &str, b: &str) -> Vec<String> {
+        let mut in_a = HashSet::new();
+        let mut in_b = HashSet::new();
+        for (key, _) in self.live_entries() {
+            if let Some(member) = key.strip_prefix(b) {
+                in_b.insert(member.to_owned());
+            }
+            if let Some(member) = key.strip_prefix(a) {
+                in_a.insert(member.to_owned());
+            }
+        }
+        let mut members: Vec<String> = in_a.intersection(&in_b).cloned().collect();
+        members.sort_unstable();
+        members
+    }
+
+    /// Remove every key starting with `prefix`, return how many were removed.
+    ///
+    /// The tombstones are appended and flushed as one batch while the write
+    /// locks are held, so no key matching when the call starts is missed.
+    pub fn remove_prefix(&self, prefix: &str) -> Result<usize> {
+        // Same lock order as `compact`.
+        let mut active = self.active.lock().unwrap();
+        let writer = self.writer.lock().unwrap();
+
+        let keys: Vec<String> = self
+            .live_entries()
+            .map(|(key, _)| key)
+            .filter(|key| key.starts_with(prefix))
+            .collect();
+        let mut new_gbg = 0;
+        for key in keys.iter() {
+            new_gbg += self.write_cmd(&mut active, &Command::Rm(key.clone()))?.len;
+        }
+        active.wtr.flush()?;
+        drop(active);
+
+        for key in keys.iter() {
+            new_gbg += self.index_remove(key).map_or(0, |old| old.len);
+        }
+        self.add_garbage(new_gbg, writer);
+        self.wait_replicas()?;
+        Ok(keys.len())
+    }
+
+    /// Remove every key whose TTL has passed, return how many were removed.
+    ///
+    /// An expired key reads as absent but keeps its record, and a record
+    /// of an older value, until it is written again. This writes their
+    /// tombstones, so the next compactions reclaim the space. Like
+    /// `remove_prefix`, it is one batch under the write locks.
+    pub fn purge_expired(&self) -> Result<usize> {
+        // Same lock order as `compact`.
+        let mut active = self.active.lock().unwrap();
+        let writer = self.writer.lock().unwrap();
+
+        let now = now_ms();
+        let keys: Vec<String> = (*self.index)
+            .clone()
+            .into_iter()
+            .filter(|(_, info)| info.expired(now))
+            .map(|(key, _)| key)
+            .collect();
+        let mut new_gbg = 0;
+        for key in keys.iter() {
+            new_gbg += self.write_cmd(&mut active, &Command::Rm(key.clone()))?.len;
+        }
+        active.wtr.flush()?;
+        drop(active);
+
+        for key in keys.iter() {
+            new_gbg += self.index_remove(key).map_or(0, |old| old.len);
+        }
+        self.add_garbage(new_gbg, writer);
+        self.wait_replicas()?;
+        info!(self.log, "purged expired keys"; "count" => keys.len());
+        Ok(keys.len())
+    }
+
+    /// The pairs of the keys starting with `prefix` whose value satisfies
+    /// `f`, sorted by key.
+    ///
+    /// The keys are found in the in-memory index, but the value of every
+    /// key with the prefix is read from disk to test it, so the cost is one
+    /// read per key matching the prefix, whatever `f` keeps. A key removed
+    /// during the scan is skipped.
+    pub fn retain_scan<F>(&self, prefix: &str, f: F) -> Result<Vec<(String, String)>>
+    where
+        F: Fn(&str, &str) -> bool,
+    {
+        let mut keys: Vec<_> = self
+            .keys()
+            .into_iter()
+            .filter(|key| key.starts_with(prefix))
+            .collect();
+        // Sequential reads after a compaction in key order.
+        keys.sort_unstable();
+        let mut pairs = Vec::new();
+        for key in keys {
+            let cmd = match self.lookup(&key)? {
+                Some((_, cmd)) => cmd,
+                None => continue,
+            };
+            match cmd {
+                Command::Set(ref k, val, ..) if *k == key => {
+                    if f(&key, &val) {
+                        pairs.push((key, val));
+                    }
+                }
+                cmd => {
+                    return Err(Error::UnexpectCmd {
+                        found: format!("{:?}", cmd),
+                        expect: format!("Set({:?}, _)", key),
+                    })?;
+                }
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Count the keys starting with `prefix`.
+    ///
+    /// Only the in-memory index is consulted, no value is read from disk.
+    /// The index is not locked as a whole, so under concurrent writes the
+    /// result is a best-effort snapshot.
+    pub fn count_prefix(&self, prefix: &str) -> usize {
+        self.live_entries()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .count()
+    }
+
+    /// Insert the pairs whose key is absent, return how many were inserted.
+    ///
+    /// The write locks are held for the whole batch, so each key is checked
+    /// and written atomically: a key inserted concurrently is skipped.
+    pub fn set_many_if_absent(&self, pairs: Vec<(String, String)>) -> Result<usize> {
+        for (key, _) in &pairs {
+            self.check_key(key)?;
+        }
+        // Same lock order as `compact`.
+        let mut active = self.active.lock().unwrap();
+        let writer = self.writer.lock().unwrap();
+
+        let now = now_ms();
+        let mut cmds: Vec<Command> = Vec::new();
+        for (key, val) in pairs {
+            let dup = cmds.iter().any(|cmd| match cmd {
+                Command::Set(k, ..) => *k == key,
+                Command::Rm(_) => false,
+            });
+            if self.entry(&key).is_some() || dup {
+                continue;
+            }
+            cmds.push(Command::Set(key, val, 0, 1, now, now, ValueKind::Str));
+        }
+        self.check_quota(&cmds.iter().collect::<Vec<_>>())?;
+        let mut inserted = Vec::with_capacity(cmds.len());
+        let mut evicted = 0;
+        for cmd in cmds {
+            evicted += self.evict(&mut active, &cmd)?;
+            let info = self.write_cmd(&mut active, &cmd)?;
+            if let Command::Set(key, ..) = cmd {
+                inserted.push((key, info));
+            }
+        }
+        active.wtr.flush()?;
+        drop(active);
+
+        let n = inserted.len();
+        for (key, info) in inserted {
+            debug!(self.log, "Insert new key '{}' at {:?}.", key, info);
+            self.index_insert(key, info);
+        }
+        self.add_garbage(evicted, writer);
+        self.wait_replicas()?;
+        Ok(n)
+    }
+
+    /// Make key expire `ttl` from now, return whether it exists.
+    ///
+    /// The value is rewritten with the new expiry time, which a later `set`
+    /// of the key clears.
+    pub fn expire(&self, key: String, ttl: Duration) -> Result<bool> {
+        let expires = now_ms().saturating_add(duration_ms(ttl));
+        let val = self.rewrite_expiry(key, expires, None)?;
+        Ok(val.is_some())
+    }
+
+    /// Make key never expire, return whether it had a TTL.
+    ///
+    /// Like `expire`, the value is rewritten. A missing key or one without
+    /// TTL is left as is.
+    pub fn persist(&self, key: String) -> Result<bool> {
+        Ok(self.rewrite_expiry(key, 0, None)?.is_some())
+    }
+
+    // Rewrite the value of key with the expiry time `expires`, return the
+    // value if rewritten. A value of another kind than `only`, if given,
+    // fails with `Error::WrongType`. The write locks are held from the read
+    // to the write, so a concurrent write of the key is either read or
+    // keeps its own expiry.
+    fn rewrite_expiry(
+        &self,
+        key: String,
+        expires: u64,
+        only: Option<ValueKind>,
+    ) -> Result<Option<String>> {
+        // Same lock order as `compact`.
+        let mut active = self.active.lock().unwrap();
+        let writer = self.writer.lock().unwrap();
+
+        let info = match self.entry(&key) {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+        match only {
+            Some(kind) if kind != info.kind => return Err(Error::WrongType(key))?,
+            _ => {}
+        }
+        if expires == 0 && info.expires == 0 {
+            return Ok(None);
+        }
+        let val = match self.fetch(&info)? {
+            Command::Set(_, val, ..) => val,
+            cmd => {
+                return Err(Error::UnexpectCmd {
+                    found: format!("{:?}", cmd),
+                    expect: format!("Set({:?}, _)", key),
+                })?;
+            }
+        };
+        let cmd = Command::Set(
+            key.clone(),
+            val.clone(),
+            expires,
+            info.version,
+            info.created,
+            info.updated,
+            info.kind,
+        );
+        self.check_quota(&[&cmd])?;
+        let info = self.write_cmd(&mut active, &cmd)?;
+        active.wtr.flush()?;
+        drop(active);
+
+        let old = self.index_insert(key, info).map_or(0, |info| info.len);
+        self.add_garbage(old, writer);
+        self.wait_replicas()?;
+        Ok(Some(val))
+    }
+
+    /// Set all the pairs, e.g. for a bulk load. A key given twice gets the
+    /// last of its values.
+    ///
+    /// The records are written under one hold of the write locks and
+    /// flushed once, rather than once per pair as with `set`. The batch is
+    /// all or nothing: a check failing, e.g. of an empty key or a quota,
+    /// writes none of the pairs, and a failed write cuts the active file
+    /// back to where the batch began. The keys evicted to make room stay
+    /// evicted.
+    pub fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, _) in &pairs {
+            self.check_key(key)?;
+        }
+        // Same lock order as `compact`.
+        let mut active = self.active.lock().unwrap();
+        let writer = self.writer.lock().unwrap();
+
+        let now = now_ms();
+        let mut cmds: Vec<Command> = Vec::with_capacity(pairs.len());
+        for (key, val) in pairs {
+            let earlier = cmds.iter().rev().find_map(|cmd| match cmd {
+                Command::Set(k, _, _, version, ..) if *k == key => Some(*version),
+                _ => None,
+            });
+            let version = earlier
+                .or_else(|| self.entry(&key).map(|info| info.version))
+                .unwrap_or(0);
+            let created = self.created_at(&key, now);
+            cmds.push(Command::Set(
+                key,
+                val,
+                0,
+                version + 1,
+                created,
+                now,
+                ValueKind::Str,
+            ));
+        }
+        self.check_quota(&cmds.iter().collect::<Vec<_>>())?;
+        let mut evicted = 0;
+        for cmd in &cmds {
+            evicted += self.evict(&mut active, cmd)?;
+        }
+        let start = active.end;
+        let written = match self.append_cmds(&mut active, &cmds) {
+            Ok(written) => written,
+            Err(e) => {
+                active.truncate(start)?;
+                if self.prealloc > 0 {
+                    file::preallocate(active.wtr.get_ref(), self.prealloc)?;
+                }
+                return Err(e);
+            }
+        };
+        for cmd in &cmds {
+            self.replicate_cmd(cmd)?;
+        }
+        drop(active);
+
+        let entries = cmds
+            .into_iter()
+            .zip(written)
+            .filter_map(|(cmd, info)| match cmd {
+                Command::Set(key, ..) => Some((key, info)),
+                Command::Rm(_) => None,
+            })
+            .collect();
+        let replaced = self.index_insert_many(entries);
+        let old_len: usize = replaced.iter().flatten().map(|info| info.len).sum();
+        self.add_garbage(evicted + old_len, writer);
+        self.wait_replicas()?;
+        Ok(())
+    }
+
+    /// Set all the pairs and return the previous value of each key, `None`
+    /// for an absent one. A key given twice gets the value set before it in
+    /// the batch.
+    ///
+    /// Like `swap`, the write locks are held for the whole batch, so no
+    /// other write interleaves with it, and the keys are updated in the
+    /// index at once, so readers see either all the new values or none of
+    /// them.
+    pub fn set_many_get(&self, pairs: Vec<(String, String)>) -> Result<Vec<Option<String>>> {
+        for (key, _) in &pairs {
+            self.check_key(key)?;
+        }
+        // Same lock order as `compact`.
+        let mut active = self.active.lock().unwrap();
+        let writer = self.writer.lock().unwrap();
+
+        let mut olds = Vec::with_capacity(pairs.len());
+        let mut cmds: Vec<Command> = Vec::with_capacity(pairs.len());
+        let now = now_ms();
+        for (key, val) in pairs {
+            let earlier = cmds.iter().rev().find_map(|cmd| match cmd {
+                Command::Set(k, v, _, version, ..) if *k == key => Some((v.clone(), *version)),
+                _ => None,
+            });
+            let old = match earlier {
+                Some(old) => Some(old),
+                None => self.get_with_version(key.clone())?,
+            };
+            let version = old.as_ref().map_or(0, |(_, ver)| *ver);
+            let created = self.created_at(&key, now);
+            cmds.push(Command::Set(
+                key,
+                val,
+                0,
+                version + 1,
+                created,
+                now,
+                ValueKind::Str,
+            ));
+            olds.push(old.map(|(val, _)| val));
+        }
+        self.check_quota(&cmds.iter().collect::<Vec<_>>())?;
+        let mut written = Vec::with_capacity(cmds.len());
+        let mut evicted = 0;
+        for cmd in cmds {
+            evicted += self.evict(&mut active, &cmd)?;
+            let info = self.write_cmd(&mut active, &cmd)?;
+            if let Command::Set(key, ..) = cmd {
+                written.push((key, info));
+            }
+        }
+        active.wtr.flush()?;
+        drop(active);
+
+        let replaced = self.index_insert_many(written);
+        let old_len: usize = replaced.iter().flatten().map(|info| info.len).sum();
+        self.add_garbage(evicted + old_len, writer);
+        self.wait_replicas()?;
+        Ok(olds)
+    }
+
+    /// Stream the records written from now on to `sink`, for a replication
+    /// follower that needs the records from `offset` of the history `id`.
+    /// Fail with `Error::ReplicationOff` without a replication backlog.
+    ///
+    /// With `ReplSync::Full`, the follower must copy the pairs read from
+    /// the store after this returns: together with the records sent to
+    /// `sink`, they give every write. See `ReplSync` for the offsets.
+    pub fn replicate(&self, id: &str, offset: u64, sink: ReplSink) -> Result<ReplSync> {
+        let repl = match self.repl {
+            Some(ref repl) => repl,
+            None => return Err(Error::ReplicationOff)?,
+        };
+        // Under the write locks, no write is between its record and its
+        // index update, so a pair read later is as new as the records.
+        let _active = self.active.lock().unwrap();
+        let _writer = self.writer.lock().unwrap();
+        Ok(repl.subscribe(id, offset, sink))
+    }
+
+    /// Record that the follower `replica` applied the records before
+    /// `offset`, see `KvStoreBuilder::min_replicas_ack`. `replica` names a
+    /// follower until `forget_replica`, e.g. by the address of its
+    /// connection. Fail with `Error::ReplicationOff` without a replication
+    /// backlog.
+    pub fn ack_replica(&self, replica: &str, offset: u64) -> Result<()> {
+        let repl = match self.repl {
+            Some(ref repl) => repl,
+            None => return Err(Error::ReplicationOff)?,
+        };
+        repl.ack(replica, offset);
+        Ok(())
+    }
+
+    /// Stop counting the acknowledgments of `replica`, e.g. once it is
+    /// disconnected.
+    pub fn forget_replica(&self, replica: &str) {
+        if let Some(ref repl) = self.repl {
+            repl.forget(replica);
+        }
+    }
+
+    // Wait for `min_acks` followers to apply the records written so far,
+    // after a write, with the write locks released.
+    fn wait_replicas(&self) -> Result<()> {
+        let repl = match self.repl {
+            Some(ref repl) if self.min_acks > 0 => repl,
+            _ => return Ok(()),
+        };
+        let acked = repl.wait_acks(self.min_acks, self.ack_timeout);
+        if acked < self.min_acks {
+            return Err(Error::ReplicasNotAcked {
+                acked,
+                needed: self.min_acks,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Limit the growth of `namespace`, with `Quota::default()` for no
+    /// limit. Fail with `Error::NamespacesOff` without
+    /// `KvStoreBuilder::namespaces`.
+    ///
+    /// The quotas are saved in the directory. A write that would grow a
+    /// namespace beyond its quota fails with `Error::QuotaExceeded`, and
+    /// writes shrinking it are still allowed. A namespace already beyond
+    /// a new quota keeps its keys.
+    pub fn set_quota(&self, namespace: &str, quota: Quota) -> Result<()> {
+        let namespaces = match self.namespaces {
+            Some(ref namespaces) => namespaces,
+            None => return Err(Error::NamespacesOff)?,
+        };
+        // Under the write locks, so the saves are in order.
+        let _active = self.active.lock().unwrap();
+        let _writer = self.writer.lock().unwrap();
+        let quotas = namespaces.set_quota(namespace, quota);
+        let temp = file::quotas_temp(&self.dir);
+        let mut wtr = File::create(&temp)?;
+        wtr.write_all(&serde_json::to_vec(&quotas)?)?;
+        wtr.sync_all()?;
+        fs::rename(&temp, file::quotas(&self.dir))?;
+        Ok(())
+    }
+
+    /// The usage and the quota of `namespace`. Fail with
+    /// `Error::NamespacesOff` without `KvStoreBuilder::namespaces`.
+    pub fn namespace_usage(&self, namespace: &str) -> Result<(NamespaceUsage, Quota)> {
+        match self.namespaces {
+            Some(ref namespaces) => Ok(namespaces.get(namespace)),
+            None => Err(Error::NamespacesOff)?,
+        }
+    }
+
+    /// Make the writes done so far durable, waiting for the fsync of the
+    /// active file.
+    ///
+    /// Every write reaches the OS before returning, but none is synced, so
+    /// a crash of the machine may lose the last ones. A write is only sure
+    /// to survive after a later `flush` returns. The files replaced by the
+    /// active one need no sync, compaction syncs the records it keeps.
+    pub fn flush(&self) -> Result<()> {
+        // Under the lock, so the file isn't replaced meanwhile.
+        let mut active = self.active.lock().unwrap();
+        active.wtr.flush()?;
+        active.wtr.get_ref().sync_data()?;
+        drop(active);
+        // The entry of an active file created since the last sync.
+        file::sync_dir(&self.dir)
+    }
+
+    /// Bytes of the live records, compared with `KvStoreBuilder::maxmemory`.
+    pub fn live_size(&self) -> usize {
+        self.live_sz.load(Ordering::SeqCst)
+    }
+
+    // Fail with `Error::QuotaExceeded` if writing `cmds` in order would grow
+    // a namespace beyond its quota. The write locks must be held.
+    fn check_quota(&self, cmds: &[&Command]) -> Result<()> {
+        let namespaces = match self.namespaces {
+            Some(ref namespaces) => namespaces,
+            None => return Ok(()),
+        };
+        // The record of each key, as left by the commands before.
+        let mut lens: HashMap<&str, Option<usize>> = HashMap::new();
+        let mut changes = Vec::with_capacity(cmds.len());
+        for cmd in cmds {
+            let (key, new) = match cmd {
+                Command::Set(key, ..) => (key.as_str(), Some(self.codec.encode(cmd).len())),
+                Command::Rm(key) => (key.as_str(), None),
+            };
+            let old = match lens.get(key) {
+                Some(len) => *len,
+                None => self.index.get(key).map(|info| info.len),
+            };
+            lens.insert(key, new);
+            changes.push((key, old, new));
+        }
+        match namespaces.check(&changes) {
+            Ok(()) => Ok(()),
+            Err(namespace) => Err(Error::QuotaExceeded { namespace })?,
+        }
+    }
+
+    // Fail for the empty key with `KvStoreBuilder::reject_empty_keys`.
+    fn check_key(&self, key: &str) -> Result<()> {
+        if self.reject_empty && key.is_empty() {
+            Err(Error::EmptyKey)?;
+        }
+        Ok(())
+    }
+
+    // Point key at `info`, return the replaced entry.
+    fn index_insert(&self, key: String, info: CmdInfo) -> Option<CmdInfo> {
+        self.record_access(&key);
+        let len = info.len;
+        let old = self.index.insert(key.clone(), info);
+        self.indexed(key, len, old.as_ref());
+        old
+    }
+
+    // Like `index_insert` for all the entries, which a reader sees all at
+    // once.
+    fn index_insert_many(&self, entries: Vec<(String, CmdInfo)>) -> Vec<Option<CmdInfo>> {
+        let mut lens = Vec::with_capacity(entries.len());
+        for (key, info) in &entries {
+            self.record_access(key);
+            lens.push((key.clone(), info.len));
+        }
+        let olds = self.index.insert_many(entries);
+        for ((key, len), old) in lens.into_iter().zip(&olds) {
+            self.indexed(key, len, old.as_ref());
+        }
+        olds
+    }
+
+    // Count key indexed with a record of `len` bytes in place of `old`.
+    fn indexed(&self, key: String, len: usize, old: Option<&CmdInfo>) {
+        self.live_sz.fetch_add(len, Ordering::SeqCst);
+        if let Some(ref namespaces) = self.namespaces {
+            namespaces.insert(&key, len, old.map(|old| old.len));
+        }
+        if let Some(old) = old {
+            self.live_sz.fetch_sub(old.len, Ordering::SeqCst);
+            self.file_garbage(old.loc.id, old.len);
+            self.key_garbage(key, old.len);
+        }
+    }
+
+    fn index_remove(&self, key: &str) -> Option<CmdInfo> {
+        if let Some(ref eviction) = self.eviction {
+            eviction.access.remove(key);
+        }
+        let old = self.index.remove(key);
+        if let Some(ref old) = old {
+            if let Some(ref namespaces) = self.namespaces {
+                namespaces.remove(key, old.len);
+            }
+            self.live_sz.fetch_sub(old.len, Ordering::SeqCst);
+            self.file_garbage(old.loc.id, old.len);
+            self.key_garbage(key.to_owned(), old.len);
+        }
+        old
+    }
+
+    // Count `len` bytes of the records of `key` as garbage, for `hot_keys`.
+    fn key_garbage(&self, key: String, len: usize) {
+        *self.key_gbg.lock().unwrap().entry(key).or_insert(0) += len;
+    }
+
+    // Count `len` bytes of file `id` as garbage. The total is counted by
+    // the callers.
+    fn file_garbage(&self, id: Fid, len: usize) {
+        *self.file_gbg.lock().unwrap().entry(id).or_insert(0) += len;
+    }
+
+    // Record an access of key for `EvictionPolicy::Lru`.
+    fn record_access(&self, key: &str) {
+        if let Some(ref eviction) = self.eviction {
+            if eviction.policy == EvictionPolicy::Lru {
+                let now = eviction.clock.fetch_add(1, Ordering::SeqCst);
+                eviction.access.insert(key.to_owned(), now);
+            }
+        }
+    }
+
+    // Remove keys other than the one of `cmd` if writing it would exceed
+    // `maxmemory`, return the garbage left. The write locks must be held,
+    // the removals are flushed with `cmd`.
+    fn evict(&self, active: &mut Fdw, cmd: &Command) -> Result<usize> {
+        let eviction = match self.eviction {
+            Some(ref eviction) => eviction,
+            None => return Ok(0),
+        };
+        let keep = match cmd {
+            Command::Set(key, ..) => key,
+            Command::Rm(_) => return Ok(0),
+        };
+        let live = self.live_sz.load(Ordering::SeqCst) + self.codec.encode(cmd).len();
+        let max = eviction.maxmemory;
+        if live <= max {
+            return Ok(0);
+        }
+        // Free some more, so that a full store doesn't scan the index on
+        // every write.
+        let mut need = live - (max - max / EVICT_MARGIN);
+
+        let mut victims: Vec<_> = (*self.index)
+            .clone()
+            .into_iter()
+            .filter(|(key, _)| key != keep)
+            .map(|(key, info)| {
+                let access = eviction.access.get(&key).map_or(0, |t| *t);
+                (access, key, info.len)
+            })
+            .collect();
+        match eviction.policy {
+            EvictionPolicy::Lru => victims.sort_unstable(),
+            EvictionPolicy::Random => victims.shuffle(&mut rand::thread_rng()),
+        }
+        let mut evicted = Vec::new();
+        let mut gbg = 0;
+        for (_, key, len) in victims {
+            if need == 0 {
+                break;
+            }
+            gbg += self.write_cmd(active, &Command::Rm(key.clone()))?.len;
+            need = need.saturating_sub(len);
+            evicted.push(key);
+        }
+        warn!(
+            self.log,
+            "evicting {} keys to stay under {} bytes",
+            evicted.len(),
+            max
+        );
+        for key in evicted {
+            gbg += self.index_remove(&key).map_or(0, |info| info.len);
+        }
+        Ok(gbg)
+    }
+
+    // Account garbage left by a write, and release the write lock.
+    fn add_garbage(&self, new_gbg: usize, writer: MutexGuard<()>) {
+        if new_gbg == 0 {
+            return;
+        }
+        let gbg_sz = self.garbage_sz.fetch_add(new_gbg, Ordering::SeqCst);
+        drop(writer);
+        if gbg_sz > self.cthreshold {
+            self.call_compacter();
+        }
+    }
+
+    // Expiry time of a new value of key, kept from the current value.
+    fn kept_expiry(&self, key: &str) -> u64 {
+        self.entry(key).map_or(0, |info| info.expires)
+    }
+
+    // The entry of key, `None` if it is absent or expired.
+    fn entry(&self, key: &str) -> Option<CmdInfo> {
+        let now = now_ms();
+        self.index.get(key).filter(|info| !info.expired(now))
+    }
+
+    // A copy of the entries of the index, but the expired ones.
+    fn live_entries(&self) -> impl Iterator<Item = (String, CmdInfo)> {
+        let now = now_ms();
+        (*self.index)
+            .clone()
+            .into_iter()
+            .filter(move |(_, info)| !info.expired(now))
+    }
+
+    // Write command to the active data file.
+    // Allocate a new active data file if readched threshold.
+    fn append(&self, cmd: &Command) -> Result<(CmdInfo, MutexGuard<'_, ()>)> {
+        let mut active = self.active.lock().unwrap();
+        let info = self.write_cmd(&mut active, cmd)?;
+        active.wtr.flush()?;
+
+        let writer = self.writer.lock().unwrap();
+        Ok((info, writer))
+    }
+
+    // Truncate the preallocated space of the active file, before it is
+    // replaced or closed.
+    fn seal(&self, active: &mut Fdw) -> Result<()> {
+        active.wtr.flush()?;
+        if self.prealloc > 0 {
+            active.wtr.get_ref().set_len(active.end)?;
+        }
+        Ok(())
+    }
+
+    // Write command to the locked active data file without flushing.
+    fn write_cmd(&self, active: &mut Fdw, cmd: &Command) -> Result<CmdInfo> {
+        let info = self.append_cmd(active, cmd)?;
+        self.replicate_cmd(cmd)?;
+        Ok(info)
+    }
+
+    // Write commands to the locked active data file and flush them, sending
+    // none to the followers, so that a failed batch can be cut off.
+    fn append_cmds(&self, active: &mut Fdw, cmds: &[Command]) -> Result<Vec<CmdInfo>> {
+        let mut infos = Vec::with_capacity(cmds.len());
+        for cmd in cmds {
+            infos.push(self.append_cmd(active, cmd)?);
+        }
+        active.wtr.flush()?;
+        Ok(infos)
+    }
+
+    fn append_cmd(&self, active: &mut Fdw, cmd: &Command) -> Result<CmdInfo> {
+        debug!(self.log, "Appending command: {:?}", cmd);
+        // Not the end of the file, which may be preallocated.
+        let offset = active.wtr.seek(SeekFrom::Start(active.end))?;
+        let s = self.codec.encode(cmd);
+        let len = s.len();
+        active.wtr.write_all(&s)?;
+        active.end += len as u64;
+        IoCounters::add(&self.io.written, len);
+        // A tombstone is garbage as soon as it is written.
+        if let Command::Rm(_) = cmd {
+            self.file_garbage(active.id, len);
+        }
+        Ok(CmdInfo::new(active.id, offset, len, cmd))
+    }
+
+    fn replicate_cmd(&self, cmd: &Command) -> Result<()> {
+        if let Some(ref repl) = self.repl {
+            // The followers read JSON, whatever the codec of the files.
+            repl.append(&cmd.ser()?);
+        }
+        Ok(())
+    }
+
+    /// Find the live entry of key in the index and read its record.
+    ///
+    /// A compaction may move the record and delete its file between the
+    /// two, so a failed read is retried while the entry keeps moving.
+    fn lookup(&self, key: &str) -> Result<Option<(CmdInfo, Command)>> {
+        loop {
+            let info = match self.entry(key) {
+                Some(info) => info,
+                None => return Ok(None),
+            };
+            match self.fetch(&info) {
+                Ok(cmd) => return Ok(Some((info, cmd))),
+                Err(e) => match self.index.get(key) {
+                    Some(ref now) if now.loc != info.loc => {
+                        debug!(self.log, "record of '{}' moved, reading again", key);
+                    }
+                    _ => return Err(e),
+                },
+            }
+        }
+    }
+
+    /// Read the command at `info.loc`, it must take exactly `info.len` bytes.
+    fn fetch(&self, info: &CmdInfo) -> Result<Command> {
+        let loc = &info.loc;
+        debug!(self.log, "fetching location: {:?}", loc);
+        self.read_at(loc, |file| {
+            let mut buf = vec![0; info.len];
+            IoCounters::add(&self.io.read, info.len);
+            let res = match file.read_exact(&mut buf) {
+                Ok(()) => codec::decode_exact(&*self.codec, &buf),
+                Err(e) => Err(e.into()),
+            };
+            res.map_err(|e| {
+                let e = format!("record of {} bytes at {:?}: {}", info.len, loc, e);
+                error!(self.log, "{}", e);
+                From::from(Error::Corruption(e))
+            })
+        })
+    }
+
+    /// Call `f` with the reader of the file of `loc`, at its offset.
+    fn read_at<T, F>(&self, loc: &Location, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut BufReader<File>) -> Result<T>,
+    {
+        let mut fds = self.fds.lock().unwrap();
+        let mut update = false;
+        if !fds.contains_key(&loc.id) {
+            update = true;
+            make_room(&mut fds, self.max_open);
+            fds.insert(loc.id, file::fdr(&self.dir, &self.prefix, loc.id)?);
+        }
+        let fd = fds.get_mut(&loc.id).unwrap();
         if fd.id != loc.id {
             let e = format!("get wrong fd: {:?}, expect: {:?}", fd.id, loc.id);
             error!(self.log, "{}", e);
@@ -207,7 +2157,7 @@ impl KvStore {
 
         let file = &mut fd.rdr;
         file.seek(SeekFrom::Start(loc.offset))?;
-        let res = Command::from_reader(file);
+        let res = f(file);
         drop(fds);
         if update {
             self.update_fds();
@@ -215,48 +2165,113 @@ impl KvStore {
         res
     }
 
+    fn emit(&self, event: CompactionEvent) {
+        if let Some(ref hook) = self.events {
+            hook(event);
+        }
+    }
+
     fn call_compacter(&self) {
+        if self.paused.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Some(ref scheduler) = self.scheduler {
+            match scheduler.upgrade() {
+                Some(scheduler) => {
+                    if !self.scheduled.swap(true, Ordering::SeqCst) {
+                        scheduler.schedule(self.clone());
+                    }
+                }
+                None => warn!(self.log, "compaction scheduler dropped, skip compaction"),
+            }
+            return;
+        }
         if let Err(e) = self.sx.send(Action::Compact) {
-            crit!(self.log, "failed to call compacter: {}", e);
+            let mut health = self.health.lock().unwrap();
+            if !health.compacter_dead {
+                crit!(self.log, "compacter is gone, compacting inline: {}", e);
+                health.compacter_dead = true;
+                health.degraded = true;
+            }
+            drop(health);
+            // Still reclaim the garbage, without retrying on the write path.
+            self.compact_retrying(|_| false);
         }
     }
 
-    /// remove old fds
+    /// Close the readers of the files deleted by compactions.
     fn update_fds(&self) {
-        let low = self.lowest_id.load(Ordering::SeqCst);
-        let new_fds = self.fds.borrow_mut().split_off(&low);
-        self.fds.replace(new_fds);
+        let mut fds = self.fds.lock().unwrap();
+        let gone: Vec<Fid> = fds
+            .keys()
+            .filter(|id| !self.datafile(**id).is_file())
+            .cloned()
+            .collect();
+        for id in gone {
+            fds.remove(&id);
+        }
     }
 
-    /// Read command from locations in vec, and write to tempfiles.
-    /// Tempfiles' id is a range: `lowest .. active_id`.
-    /// Return updated index and the `lowest`.
-    fn merge(&self, merge_id: Fid, vec: Vec<CmdInfo>) -> Result<HashMap<String, CmdInfo>> {
+    /// Write the records of `vec` and then the `tombstones` into the merged
+    /// file, and the bloom filter of its keys. Return the new index entries,
+    /// the size of the tombstones and the filter.
+    fn merge(
+        &self,
+        merge_id: Fid,
+        vec: Vec<CmdInfo>,
+        tombstones: &[String],
+    ) -> Result<(HashMap<String, CmdInfo>, usize, Option<Bloom>)> {
         let mut index = HashMap::new();
         let mut merge_wtr = self.new_temp(merge_id)?;
+        let mut bloom = match self.bloom_bits {
+            0 => None,
+            bits => Some(Bloom::new(vec.len() + tombstones.len(), bits)),
+        };
 
-        let mut data_id: Fid = vec[0].loc.id;
-        let mut rdr = file::open_r(self.datafile(data_id))?;
+        // The records alternate between the files, keep some of them open.
+        let mut rdrs = BTreeMap::new();
+        let mut bytes_done = 0;
 
         for CmdInfo {
             loc: Location { id: fid, offset },
+            len,
+            version,
+            created,
+            updated,
             ..
         } in vec.iter()
         {
-            if fid != &data_id {
-                data_id = *fid;
-                rdr = file::open_r(self.datafile(data_id))?;
+            let step = bytes_done / PROGRESS_STEP;
+            bytes_done += *len as u64;
+            if bytes_done / PROGRESS_STEP > step {
+                self.emit(CompactionEvent::Progress { bytes_done });
+            }
+            if !rdrs.contains_key(fid) {
+                make_room(&mut rdrs, self.max_open);
+                rdrs.insert(*fid, file::open_r(self.datafile(*fid))?);
             }
+            let rdr = rdrs.get_mut(fid).unwrap();
 
             rdr.seek(SeekFrom::Start(*offset))?;
-            let cmd = Command::from_reader(&mut rdr)?;
+            let mut buf = vec![0; *len];
+            rdr.read_exact(&mut buf)?;
+            let cmd = codec::decode_exact(&*self.codec, &buf)?;
+            IoCounters::add(&self.io.compaction_read, *len);
             match cmd {
-                Command::Set(ref key, _) => {
-                    let s = cmd.ser()?;
+                // Records written before versions existed get theirs here.
+                Command::Set(key, val, expires, .., kind) => {
+                    let (version, created, updated) = (*version, *created, *updated);
+                    let cmd =
+                        Command::Set(key.clone(), val, expires, version, created, updated, kind);
+                    let s = self.codec.encode(&cmd);
                     let len = s.len();
                     let offset = merge_wtr.seek(SeekFrom::End(0))?;
-                    merge_wtr.write_all(s.as_bytes())?;
-                    index.insert(key.to_owned(), CmdInfo::new(merge_id, offset, len));
+                    merge_wtr.write_all(&s)?;
+                    IoCounters::add(&self.io.compaction_written, len);
+                    if let Some(ref mut bloom) = bloom {
+                        bloom.insert(&key);
+                    }
+                    index.insert(key, CmdInfo::new(merge_id, offset, len, &cmd));
                 }
                 Command::Rm(ref key) => {
                     Err(Error::UnexpectCmd {
@@ -267,9 +2282,233 @@ impl KvStore {
             }
         }
 
+        let mut tomb_bytes = 0;
+        for key in tombstones {
+            let s = self.codec.encode(&Command::Rm(key.clone()));
+            tomb_bytes += s.len();
+            merge_wtr.write_all(&s)?;
+            IoCounters::add(&self.io.compaction_written, s.len());
+            if let Some(ref mut bloom) = bloom {
+                bloom.insert(key);
+            }
+        }
+
+        // The merged file must be durable before it replaces the old ones.
+        merge_wtr.flush()?;
+        merge_wtr.get_ref().sync_all()?;
+        drop(merge_wtr);
         fs::rename(self.tempfile(merge_id), self.datafile(merge_id))?;
+        // A missing filter only costs scans, don't fail the compaction.
+        let bloom = bloom.and_then(|bloom| {
+            let path = file::bloom(&self.dir, &self.prefix, merge_id);
+            match bloom.save(&path, &file::bloom_temp(&self.dir, &self.prefix, merge_id)) {
+                Ok(()) => {
+                    let bytes = bloom.size();
+                    debug!(self.log, "bloom filter written: {:?}", path; "bytes" => bytes);
+                    Some(bloom)
+                }
+                Err(e) => {
+                    error!(self.log, "failed to write bloom filter {:?}: {}", path, e);
+                    None
+                }
+            }
+        });
+        file::sync_dir(&self.dir)?;
+
+        Ok((index, tomb_bytes, bloom))
+    }
+
+    /// Split the data files up to the `active` one into the ones worth
+    /// merging, with at least `min_garbage` of garbage, and the ones to keep.
+    fn select_files(&self, active: &Fdw) -> Result<(Vec<Fid>, Vec<Fid>)> {
+        let file_gbg = self.file_gbg.lock().unwrap();
+        let mut merged = Vec::new();
+        let mut kept = Vec::new();
+        for id in KvStoreBuilder::file_ids(&self.dir, &self.prefix)? {
+            let size = match id {
+                id if id > active.id => continue,
+                id if id == active.id => active.end as f64,
+                id => fs::metadata(self.datafile(id))?.len() as f64,
+            };
+            let gbg = file_gbg.get(&id).cloned().unwrap_or(0) as f64;
+            if size == 0.0 || gbg >= self.min_garbage * size {
+                merged.push(id);
+            } else {
+                kept.push(id);
+            }
+        }
+        Ok((merged, kept))
+    }
+
+    /// The removed keys whose tombstones in `merged` must outlive the merge,
+    /// because a kept older file may still hold a value of the key.
+    fn live_tombstones(&self, merged: &[Fid], kept: &[Fid]) -> Result<Vec<String>> {
+        let oldest_kept = match kept.iter().min() {
+            Some(id) => *id,
+            None => return Ok(Vec::new()),
+        };
+        let mut keys = Vec::new();
+        for id in merged.iter().filter(|id| **id > oldest_kept) {
+            let rdr = file::open_r(self.datafile(*id))?;
+            for cmd in Records::new(rdr, self.codec.clone()) {
+                if let Command::Rm(key) = cmd? {
+                    if self.index.get(&key).is_none() {
+                        keys.push(key);
+                    }
+                }
+            }
+        }
+        keys.sort_unstable();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    /// Bytes of the records written and read since the store was opened,
+    /// by all its handles. They start from 0 on every open.
+    ///
+    /// Compare `bytes_written` with the bytes the application meant to
+    /// write for the overhead of the records, and `write_amplification`
+    /// for the cost of the compactions.
+    pub fn io_stats(&self) -> IoStats {
+        self.io.get()
+    }
+
+    /// Bytes of the overwritten and removed records left in the data files,
+    /// a compaction starts once they pass `KvStoreBuilder::compact_threshold`.
+    pub fn garbage(&self) -> usize {
+        self.garbage_sz.load(Ordering::SeqCst)
+    }
+
+    /// The live records counted by length, in powers of two, e.g. to pick
+    /// the thresholds of the compaction. The lengths are those of the
+    /// records, which hold the key and metadata besides the value, read
+    /// from the index without reading the data files.
+    pub fn size_histogram(&self) -> SizeHistogram {
+        let now = now_ms();
+        let mut hist = SizeHistogram::default();
+        self.index.for_each(|_, info| {
+            if !info.expired(now) {
+                hist.add(info.len);
+            }
+        });
+        hist
+    }
+
+    /// The settings of the store given to `KvStoreBuilder`, by the name of
+    /// their builder method, durations in milliseconds. Reported by the
+    /// `CONFIG GET` command of the server.
+    pub fn config(&self) -> Vec<(&'static str, String)> {
+        let backlog = self.repl.as_ref().map_or(0, |repl| repl.limit());
+        let backoff = duration_ms(self.backoff);
+        let ack_timeout = duration_ms(self.ack_timeout);
+        vec![
+            ("compact_threshold", self.cthreshold.to_string()),
+            ("compact_min_garbage", self.min_garbage.to_string()),
+            ("compaction_retries", self.retries.to_string()),
+            ("compaction_backoff", backoff.to_string()),
+            ("compact_key_order", self.key_order.to_string()),
+            ("preallocate", self.prealloc.to_string()),
+            ("max_open_files", self.max_open.to_string()),
+            ("bloom_bits_per_key", self.bloom_bits.to_string()),
+            ("index_snapshot", self.snapshot.to_string()),
+            ("reject_empty_keys", self.reject_empty.to_string()),
+            ("record_codec", self.codec.name().to_owned()),
+            ("replication_backlog", backlog.to_string()),
+            ("min_replicas_ack", self.min_acks.to_string()),
+            ("replica_ack_timeout", ack_timeout.to_string()),
+        ]
+    }
 
-        Ok(index)
+    /// The `top_n` keys whose overwrites and removals made the most
+    /// garbage, with the bytes of their records made garbage, most first.
+    ///
+    /// The bytes are counted from the open of the store, by all its
+    /// handles, and reset on every open: they show the current write
+    /// pattern, e.g. a client hammering a key, not the history of the
+    /// data. A key is counted until the store is closed even after it is
+    /// removed, so the counters take memory for every key written twice.
+    pub fn hot_keys(&self, top_n: usize) -> Vec<(String, usize)> {
+        let mut keys: Vec<(String, usize)> = self
+            .key_gbg
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, bytes)| (key.clone(), *bytes))
+            .collect();
+        keys.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        keys.truncate(top_n);
+        keys
+    }
+
+    /// State of the background compactions.
+    pub fn compaction_health(&self) -> CompactionHealth {
+        let mut health = self.health.lock().unwrap().clone();
+        health.paused = self.paused.load(Ordering::SeqCst);
+        health
+    }
+
+    /// Stop starting background compactions, e.g. during a traffic spike,
+    /// until `resume_compaction`. A running compaction finishes, but isn't
+    /// retried if it fails. The writes keep adding garbage meanwhile.
+    /// `compact` still compacts when called directly.
+    ///
+    /// The flag is shared by all the handles of the store.
+    pub fn pause_compaction(&self) {
+        if !self.paused.swap(true, Ordering::SeqCst) {
+            info!(self.log, "background compaction paused");
+        }
+    }
+
+    /// Start the background compactions again, at once if the garbage
+    /// went over the threshold while they were paused.
+    pub fn resume_compaction(&self) {
+        if self.paused.swap(false, Ordering::SeqCst) {
+            info!(self.log, "background compaction resumed");
+        }
+        if self.garbage_sz.load(Ordering::SeqCst) > self.cthreshold {
+            self.call_compacter();
+        }
+    }
+
+    // Whether a background compaction should run now.
+    fn needs_compaction(&self) -> bool {
+        !self.paused.load(Ordering::SeqCst)
+            && self.garbage_sz.load(Ordering::SeqCst) > self.cthreshold
+    }
+
+    // Compact, retry with doubling backoff on failure. `wait` sleeps for the
+    // backoff, and returns false if the store is shutting down.
+    fn compact_retrying<F>(&self, mut wait: F)
+    where
+        F: FnMut(Duration) -> bool,
+    {
+        let mut backoff = self.backoff;
+        for attempt in 0..=self.retries {
+            let res = self.compact();
+            let mut health = self.health.lock().unwrap();
+            match res {
+                Ok(()) => {
+                    health.degraded = health.compacter_dead;
+                    health.failures = 0;
+                    return;
+                }
+                Err(e) => {
+                    error!(self.log, "failed to compact: {}", e; "attempt" => attempt + 1);
+                    health.failures += 1;
+                    health.last_error = Some(e.to_string());
+                    if attempt == self.retries {
+                        crit!(self.log, "compaction failed after {} retries", self.retries);
+                        health.degraded = true;
+                        return;
+                    }
+                }
+            }
+            drop(health);
+            if !wait(backoff) || self.paused.load(Ordering::SeqCst) {
+                return;
+            }
+            backoff *= 2;
+        }
     }
 
     /// Compact
@@ -277,55 +2516,120 @@ impl KvStore {
         let lock = match self.compact_lock.try_lock() {
             Ok(mutex) => mutex,
             Err(TryLockError::WouldBlock) => return Ok(()),
-            Err(e) => panic!("compact lock poisoned: {}", e),
+            // A compaction that panicked deleted no file the index points to.
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
         };
+        let start = Instant::now();
         let mut active = self.active.lock().unwrap();
+        let (merged, kept) = self.select_files(&active)?;
+        if merged.is_empty() {
+            return Ok(());
+        }
         let merge_id = active.id + 1;
         let active_id = merge_id + 1;
-        *active = file::fdw(&self.dir, active_id)?;
+        self.seal(&mut active)?;
+        *active = file::fdw(&self.dir, &self.prefix, active_id, self.prealloc)?;
         let writer = self.writer.lock().unwrap();
         drop(active);
-        self.garbage_sz.store(0, Ordering::SeqCst);
         let index = (*self.index).clone();
-        let vec: Vec<_> = index
+        let mut vec: Vec<_> = index
             .into_iter()
-            .map(|(_, v)| v)
-            .filter(|v| v.loc.id < merge_id)
+            .filter(|(_, v)| merged.contains(&v.loc.id))
             .collect();
         drop(writer);
-        let index = if !(vec.is_empty()) {
-            self.merge(merge_id, vec)?
-        } else {
-            HashMap::new()
+        if self.key_order {
+            vec.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        }
+        let vec: Vec<_> = vec.into_iter().map(|(_, v)| v).collect();
+        let bytes_to_merge = vec.iter().map(|v| v.len as u64).sum();
+        self.emit(CompactionEvent::Started { bytes_to_merge });
+        // Nothing is reclaimed on failure, so a retry finds the garbage again.
+        let (index, tomb_bytes, bloom) = match self
+            .live_tombstones(&merged, &kept)
+            .and_then(|tombstones| self.merge(merge_id, vec, &tombstones))
+        {
+            Ok(res) => res,
+            Err(e) => {
+                let _ = fs::remove_file(self.tempfile(merge_id));
+                return Err(e);
+            }
         };
+        if let Some(bloom) = bloom {
+            self.blooms.lock().unwrap().insert(merge_id, bloom);
+        }
 
-        let mut new_gbg = 0;
+        let mut new_gbg = tomb_bytes;
         for (key, val) in index.iter() {
-            match self.index.get_mut(key) {
+            let moved = self.index.update(key, |rval| {
                 // If file id >= active id, not compacted.
-                Some(ref mut rval) if rval.loc.id < active_id => {
-                    **rval = val.clone();
+                if rval.loc.id >= active_id {
+                    return false;
                 }
-                _ => {
-                    new_gbg += val.len;
+                self.live_sz.fetch_add(val.len, Ordering::SeqCst);
+                self.live_sz.fetch_sub(rval.len, Ordering::SeqCst);
+                if let Some(ref namespaces) = self.namespaces {
+                    namespaces.insert(key, val.len, Some(rval.len));
                 }
+                *rval = val.clone();
+                true
+            });
+            if moved != Some(true) {
+                new_gbg += val.len;
             }
         }
+        let mut file_gbg = self.file_gbg.lock().unwrap();
+        let reclaimed: usize = merged.iter().filter_map(|id| file_gbg.remove(id)).sum();
+        *file_gbg.entry(merge_id).or_insert(0) += new_gbg;
+        drop(file_gbg);
         self.garbage_sz.fetch_add(new_gbg, Ordering::SeqCst);
-        let low = self.lowest_id.swap(merge_id, Ordering::SeqCst);
+        self.garbage_sz.fetch_sub(reclaimed, Ordering::SeqCst);
         drop(lock);
 
-        for id in low..merge_id {
+        let mut bytes_reclaimed = 0;
+        for id in merged {
+            // Before the data file, so a filter never outlives it.
+            self.blooms.lock().unwrap().remove(&id);
+            if let Err(e) = KvStoreBuilder::remove_bloom(&self.dir, &self.prefix, id) {
+                error!(
+                    self.log,
+                    "failed to delete the bloom filter of file {}: {}", id, e
+                );
+            }
             let path = self.datafile(id);
             info!(self.log, "delete file: {:?}", path);
-            if let Err(e) = fs::remove_file(&path) {
-                error!(self.log, "failed to delete file {:?}: {}", path, e);
+            let sz = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            match fs::remove_file(&path) {
+                Ok(()) => bytes_reclaimed += sz,
+                Err(e) => error!(self.log, "failed to delete file {:?}: {}", path, e),
             }
         }
+        if let Ok(m) = fs::metadata(self.datafile(merge_id)) {
+            bytes_reclaimed = bytes_reclaimed.saturating_sub(m.len());
+        }
+        self.emit(CompactionEvent::Finished {
+            bytes_reclaimed,
+            duration: start.elapsed(),
+        });
 
         Ok(())
     }
 
+    /// Save the index for the next `build`.
+    fn save_snapshot(&self) -> Result<()> {
+        let ids = KvStoreBuilder::file_ids(&self.dir, &self.prefix)?;
+        let snapshot = Snapshot {
+            files: KvStoreBuilder::generation(&self.dir, &self.prefix, &ids)?,
+            garbage: self.file_gbg.lock().unwrap().clone().into_iter().collect(),
+            index: (*self.index).clone().into_iter().collect(),
+        };
+        let temp = file::snapshot_temp(&self.dir);
+        let mut wtr = File::create(&temp)?;
+        wtr.write_all(&serde_json::to_vec(&snapshot)?)?;
+        wtr.sync_all()?;
+        fs::rename(&temp, file::snapshot(&self.dir))?;
+        Ok(())
+    }
+
     fn new_temp(&self, id: Fid) -> Result<BufWriter<File>> {
         let path = self.tempfile(id);
         info!(self.log, "Creating new file: {:?}", path);
@@ -333,11 +2637,72 @@ impl KvStore {
     }
 
     fn tempfile(&self, id: Fid) -> PathBuf {
-        file::temp(&self.dir, id)
+        file::temp(&self.dir, &self.prefix, id)
     }
 
     fn datafile(&self, id: Fid) -> PathBuf {
-        file::data(&self.dir, id)
+        file::data(&self.dir, &self.prefix, id)
+    }
+}
+
+type RecordStream = Records<Take<BufReader<File>>>;
+
+/// Iterator of `KvStore::iter_log_order`.
+struct LogOrder<'a> {
+    store: &'a KvStore,
+    _compact: MutexGuard<'a, ()>,
+    /// Failure to list the data files, yielded first.
+    error: Option<failure::Error>,
+    files: vec::IntoIter<(Fid, u64)>,
+    /// Records of the file being read.
+    stream: Option<(Fid, RecordStream)>,
+}
+
+impl<'a> Iterator for LogOrder<'a> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        loop {
+            let (id, stream) = match self.stream {
+                Some((id, ref mut stream)) => (id, stream),
+                None => {
+                    let (id, limit) = self.files.next()?;
+                    match File::open(self.store.datafile(id)) {
+                        Ok(rdr) => {
+                            let rdr = BufReader::new(rdr).take(limit);
+                            let stream = Records::new(rdr, self.store.codec.clone());
+                            self.stream = Some((id, stream));
+                        }
+                        // Deleted by a compaction finishing, its records
+                        // are in the merged file.
+                        Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+                        Err(e) => return Some(Err(e.into())),
+                    }
+                    continue;
+                }
+            };
+            let offset = stream.offset();
+            match stream.next() {
+                None => self.stream = None,
+                Some(Err(e)) => {
+                    self.stream = None;
+                    return Some(Err(e));
+                }
+                Some(Ok(Command::Set(key, val, ..))) => {
+                    let live = self
+                        .store
+                        .entry(&key)
+                        .is_some_and(|info| info.loc.id == id && info.loc.offset == offset);
+                    if live {
+                        return Some(Ok((key, val)));
+                    }
+                }
+                Some(Ok(Command::Rm(_))) => {}
+            }
+        }
     }
 }
 
@@ -346,28 +2711,68 @@ impl Clone for KvStore {
         self.counter.fetch_add(1, Ordering::SeqCst);
         Self {
             dir: self.dir.clone(),
+            prefix: self.prefix.clone(),
+            codec: self.codec.clone(),
             log: self.log.clone(),
             cthreshold: self.cthreshold,
 
             garbage_sz: self.garbage_sz.clone(),
+            live_sz: self.live_sz.clone(),
             index: self.index.clone(),
             active: self.active.clone(),
             writer: self.writer.clone(),
             compact_lock: self.compact_lock.clone(),
-            lowest_id: self.lowest_id.clone(),
+            file_gbg: self.file_gbg.clone(),
+            key_gbg: self.key_gbg.clone(),
+            min_garbage: self.min_garbage,
+            prealloc: self.prealloc,
+            key_order: self.key_order,
+            reject_empty: self.reject_empty,
+            namespaces: self.namespaces.clone(),
+            repl: self.repl.clone(),
+            min_acks: self.min_acks,
+            ack_timeout: self.ack_timeout,
+            blooms: self.blooms.clone(),
+            bloom_bits: self.bloom_bits,
+            max_open: self.max_open,
 
             sx: self.sx.clone(),
             compacter: self.compacter.clone(),
             counter: self.counter.clone(),
-
-            fds: RefCell::new(FdrMap::new()),
+            events: self.events.clone(),
+            scheduler: self.scheduler.clone(),
+            scheduled: self.scheduled.clone(),
+            snapshot: self.snapshot,
+            eviction: self.eviction.clone(),
+            retries: self.retries,
+            backoff: self.backoff,
+            health: self.health.clone(),
+            paused: self.paused.clone(),
+            io: self.io.clone(),
+            _lock: self._lock.clone(),
+
+            fds: Mutex::new(FdrMap::new()),
         }
     }
 }
 
 impl Drop for KvStore {
     fn drop(&mut self) {
-        if self.counter.fetch_sub(1, Ordering::SeqCst) <= 1 && self.compacter.is_none() {
+        // The compacter thread holds the other handle, which has no `compacter`.
+        let count = self.counter.fetch_sub(1, Ordering::SeqCst);
+        if count == 1 {
+            // Before the snapshot, which records the file sizes.
+            if let Err(e) = self.seal(&mut self.active.lock().unwrap()) {
+                error!(self.log, "failed to truncate the active file: {}", e);
+            }
+        }
+        if count == 1 && self.snapshot {
+            if let Err(e) = self.save_snapshot() {
+                error!(self.log, "failed to save index snapshot: {}", e);
+            }
+        }
+        let last = count <= 2;
+        if last && self.compacter.is_some() {
             if let Err(e) = self.sx.send(Action::Shutdown) {
                 crit!(self.log, "failed to shutdown compacter: {}", e);
             }
@@ -387,9 +2792,33 @@ impl KvStoreBuilder {
         let dir = dir.as_ref().to_owned();
         KvStoreBuilder {
             dir,
+            prefix: String::new(),
+            codec: Arc::new(JsonCodec),
+            cipher: None,
             wthreshold: ACTIVE_THRESHOLD,
             cthreshold: COMPACT_THRESHOLD,
             log: None,
+            events: None,
+            scheduler: None,
+            snapshot: false,
+            maxmemory: None,
+            policy: EvictionPolicy::Lru,
+            retries: COMPACT_RETRIES,
+            backoff: RETRY_BACKOFF,
+            lock: true,
+            min_garbage: 0.0,
+            thread_name: COMPACTER_NAME.to_owned(),
+            stack_size: None,
+            prealloc: 0,
+            key_order: false,
+            reject_empty: false,
+            ns_sep: None,
+            repl_backlog: None,
+            min_acks: 0,
+            ack_timeout: REPLICA_ACK_TIMEOUT,
+            bloom_bits: 10,
+            max_open: 64,
+            hasher: IndexHasher::default(),
         }
     }
 
@@ -410,16 +2839,289 @@ impl KvStoreBuilder {
         self
     }
 
+    /// Only rewrite the data files in which at least `ratio` of the bytes
+    /// are garbage, 0 by default so every file is rewritten. Files with
+    /// little garbage are kept as they are, saving the IO of copying them.
+    ///
+    /// A compaction is triggered by the total garbage as before, so with
+    /// garbage spread thinly over many files it may find no file to rewrite.
+    pub fn compact_min_garbage(mut self, ratio: f64) -> Self {
+        self.min_garbage = ratio;
+        self
+    }
+
+    /// Retry a failed background compaction `retries` times, waiting
+    /// `backoff` before the first retry and twice as long before each next
+    /// one. Once all retries fail, `KvStore::compaction_health` reports the
+    /// store as degraded until a compaction succeeds. 3 retries from 1 second
+    /// by default.
+    pub fn compaction_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    /// Lock the directory with an exclusive `flock` on its `LOCK` file, so
+    /// that `build` fails with `Error::Locked` while another store, in this
+    /// process or another one, has it open. Enabled by default.
+    ///
+    /// The lock is advisory: it only keeps out other stores, not other
+    /// programs writing the files. It is released when the last handle is
+    /// dropped, or by the OS when the process dies. On networked filesystems
+    /// `flock` may only lock against processes of the same host, or not at
+    /// all, so don't share a directory between hosts. There is no lock on
+    /// non-Unix platforms. `inspect` never takes the lock.
+    pub fn dir_lock(mut self, lock: bool) -> Self {
+        self.lock = lock;
+        self
+    }
+
+    /// Call `hook` with the progress of every compaction.
+    /// The hook runs on the compacting thread, so it should return quickly.
+    pub fn on_compaction<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(CompactionEvent) + Send + Sync + 'static,
+    {
+        self.events = Some(Arc::new(hook));
+        self
+    }
+
+    /// Name the compacter thread, `kvs-compacter` by default.
+    pub fn compacter_name(mut self, name: impl Into<String>) -> Self {
+        self.thread_name = name.into();
+        self
+    }
+
+    /// Set the stack size of the compacter thread in bytes, the default of
+    /// `std::thread` otherwise.
+    pub fn compacter_stack_size(mut self, bytes: usize) -> Self {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    /// Write the records merged by a compaction in key order, instead of
+    /// the order of the in-memory index. Off by default.
+    ///
+    /// The keys sharing a prefix then sit next to each other in the merged
+    /// file, so a prefix scan like `retain_scan` reads it mostly
+    /// sequentially; the records written since the compaction are still in
+    /// write order. Sorting costs O(n log n) in the keys being merged.
+    pub fn compact_key_order(mut self, key_order: bool) -> Self {
+        self.key_order = key_order;
+        self
+    }
+
+    /// Track the live keys and bytes of each namespace, the part of the keys
+    /// before `separator`, "" for the keys without it. Off by default.
+    ///
+    /// A namespace may then be given a quota with `KvStore::set_quota`,
+    /// saved in the directory. The usage is counted in records, like
+    /// `KvStore::live_size`: a key counts the bytes of its serialized
+    /// record, including the key and the metadata, and neither the garbage
+    /// nor the tombstones count. It is rebuilt from the index on `build`.
+    pub fn namespaces(mut self, separator: char) -> Self {
+        self.ns_sep = Some(separator);
+        self
+    }
+
+    /// Bits of the bloom filter of each merged data file per key, 10 by
+    /// default, 0 for no filter.
+    ///
+    /// Compaction writes the filter of the keys of the file it merges, and
+    /// `build` loads the filters of the files, keeping them in memory. The
+    /// lookups of the index don't need them: they let the scans of the data
+    /// files for a key, e.g. `KvStore::history`, skip the files without it.
+    /// A filter never misses a key of its file, but it wrongly matches
+    /// about 0.62^bits of the other keys: 1% with 10 bits, 0.1% with 15.
+    /// Each filter takes bits/8 bytes per key of its file, in memory and on
+    /// disk, e.g. 1.25MB for a million keys with 10 bits.
+    pub fn bloom_bits_per_key(mut self, bits: usize) -> Self {
+        self.bloom_bits = bits;
+        self
+    }
+
+    /// The most data files a handle keeps open for reading, 64 by default.
+    ///
+    /// `build` reads the data files one at a time, and the reads and the
+    /// compaction close the open file of the lowest id to open one more
+    /// beyond the limit. Each clone of the store has its own open files, and the
+    /// active file is always open for writing: set the file descriptor
+    /// limit of the process above `(max + 2) * handles` plus the sockets.
+    /// A low limit costs reopening files, not correctness.
+    pub fn max_open_files(mut self, max: usize) -> Self {
+        self.max_open = max.max(1);
+        self
+    }
+
+    /// The hash function of the in-memory index, `IndexHasher::Sip` by
+    /// default. `IndexHasher::AHash` speeds up the lookups of `get` and
+    /// `set`, both resist hash flooding.
+    pub fn index_hasher(mut self, hasher: IndexHasher) -> Self {
+        self.hasher = hasher;
+        self
+    }
+
+    /// Fail the writes of the empty key with `Error::EmptyKey`, off by
+    /// default.
+    ///
+    /// Otherwise "" is a key like any other. Empty values are always
+    /// allowed. Reads and removals of "" are not affected, nor are the
+    /// commands given to `KvStore::apply_command`.
+    pub fn reject_empty_keys(mut self, reject: bool) -> Self {
+        self.reject_empty = reject;
+        self
+    }
+
+    /// Keep about `bytes` of the recent records in memory for replication
+    /// followers, see `KvStore::replicate`. Replication is off by default.
+    ///
+    /// A follower disconnected for longer than the backlog covers must
+    /// resync in full, copying all the pairs.
+    pub fn replication_backlog(mut self, bytes: usize) -> Self {
+        self.repl_backlog = Some(bytes);
+        self
+    }
+
+    /// Return from a write only once `n` followers applied it, for writes
+    /// that survive the loss of the primary; 0, the default, doesn't wait.
+    /// Needs `replication_backlog`, the build fails with
+    /// `Error::ReplicationOff` otherwise.
+    ///
+    /// Each write then takes a round trip to the followers on top of its
+    /// own time, the write locks being released meanwhile so that other
+    /// writes proceed. After `replica_ack_timeout` it fails with
+    /// `Error::ReplicasNotAcked` rather than falling back to asynchronous
+    /// replication: the write is done locally and reaches the followers
+    /// later, but may be lost with the primary.
+    pub fn min_replicas_ack(mut self, n: usize) -> Self {
+        self.min_acks = n;
+        self
+    }
+
+    /// How long a write waits for the followers of `min_replicas_ack`,
+    /// 1 second by default.
+    pub fn replica_ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = timeout;
+        self
+    }
+
+    /// Name the data files `<prefix><id>.data`, and their bloom filters
+    /// `<prefix><id>.bloom`, to share the directory with the files of other
+    /// tools. Only the data files of this prefix belong to the store, so
+    /// it must be the same each time the database is opened. The prefix is
+    /// empty by default, as in the databases written before it existed.
+    pub fn file_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_owned();
+        self
+    }
+
+    /// Encode the records of the data files with `codec`, `JsonCodec` by
+    /// default. The codec of a new database is recorded in its meta file,
+    /// opening it with another one fails with `Error::CodecMismatch`. The
+    /// records sent to the followers are JSON whatever the codec.
+    pub fn record_codec(mut self, codec: impl RecordCodec) -> Self {
+        self.codec = Arc::new(codec);
+        self
+    }
+
+    /// Encrypt the records of the data files with ChaCha20-Poly1305 and
+    /// `key`, each with a random nonce. A new database records in its meta
+    /// file that it is encrypted, and an id of the key which doesn't reveal
+    /// it; opening it without the key or with another one fails with
+    /// `Error::DecryptFailed`, as does opening an unencrypted database with a
+    /// key. The key is never stored on disk, keep it safe: the data can't be
+    /// read without it. The keys stay in clear in the index snapshot, see
+    /// `snapshot`, and the followers receive the records unencrypted.
+    pub fn encryption(mut self, key: [u8; 32]) -> Self {
+        self.cipher = Some(Arc::new(Cipher::new(&key)));
+        self
+    }
+
+    // The codec of the records as stored, encrypted if there is a key.
+    fn data_codec(&self) -> Arc<dyn RecordCodec> {
+        match self.cipher {
+            Some(ref cipher) => Arc::new(Encrypted::new(self.codec.clone(), cipher.clone())),
+            None => self.codec.clone(),
+        }
+    }
+
+    /// Preallocate each new active data file to `bytes`, 0 (the default)
+    /// to let it grow with the writes. The records are appended after the
+    /// last one rather than at the end of the file, and the unused space is
+    /// truncated when the file stops being active: at the next compaction
+    /// or when the last handle is dropped. After a crash the zeros of the
+    /// unused space are skipped by `build`.
+    ///
+    /// On Linux the space is allocated with `posix_fallocate`, which avoids
+    /// fragmenting the file and running out of space mid-record. Elsewhere,
+    /// or if the file system doesn't support it, the file is only extended
+    /// with `set_len`, which leaves a sparse file on most file systems.
+    pub fn preallocate(mut self, bytes: u64) -> Self {
+        self.prealloc = bytes;
+        self
+    }
+
+    /// Run compactions on the shared `scheduler` instead of a dedicated
+    /// thread. The scheduler should outlive the store, compactions are
+    /// skipped once it is dropped.
+    pub fn compaction_scheduler(mut self, scheduler: Arc<CompactionScheduler>) -> Self {
+        self.scheduler = Some(scheduler);
+        self
+    }
+
+    /// Save the index to `index.snapshot` when the last handle is dropped,
+    /// and load it on the next `build` instead of reading all data files.
+    ///
+    /// The snapshot is only used if the data files are the same as when it
+    /// was taken, and it is removed once read, so after an unclean shutdown
+    /// the index is rebuilt from the data files. Off by default.
+    pub fn index_snapshot(mut self, snapshot: bool) -> Self {
+        self.snapshot = snapshot;
+        self
+    }
+
+    /// Cap the bytes of live records, see `KvStore::live_size`.
+    ///
+    /// A write that would exceed the cap first removes other keys, chosen by
+    /// the `eviction` policy, until the store is a bit below the cap. The
+    /// removals are persisted like `remove`. A single value larger than the
+    /// cap is still written. Unlimited by default.
+    pub fn maxmemory(mut self, bytes: usize) -> Self {
+        self.maxmemory = Some(bytes);
+        self
+    }
+
+    /// Set the policy of `maxmemory`, `EvictionPolicy::Lru` by default.
+    ///
+    /// Access times are kept in memory only, after a restart all keys
+    /// count as equally old.
+    pub fn eviction(mut self, policy: EvictionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
     fn metapath(&self) -> PathBuf {
         self.dir.join("meta")
     }
 
-    fn read_meta(&self) -> Result<Option<String>> {
+    /// The quotas saved by `KvStore::set_quota`, none if never set.
+    fn read_quotas(&self) -> Result<HashMap<String, Quota>> {
+        let path = file::quotas(&self.dir);
+        if path.is_file() {
+            Ok(serde_json::from_slice(&fs::read(&path)?)?)
+        } else {
+            Ok(HashMap::new())
+        }
+    }
+
+    fn read_meta(&self) -> Result<Option<Meta>> {
         let metapath = self.metapath();
         if metapath.is_file() {
-            Ok(Some(
-                String::from_utf8_lossy(&fs::read(&metapath)?).into_owned(),
-            ))
+            let text = String::from_utf8_lossy(&fs::read(&metapath)?).into_owned();
+            match Meta::parse(&text) {
+                Some(meta) => Ok(Some(meta)),
+                None => Err(Error::MetaCorrupt(metapath))?,
+            }
         } else if metapath.is_dir() {
             Err(Error::InvalidMeta(metapath))?
         } else {
@@ -427,134 +3129,443 @@ impl KvStoreBuilder {
         }
     }
 
+    /// Fail unless `meta` is of a database this build can read: a newer
+    /// format could be silently misread.
+    fn check_meta(&self, meta: &Meta) -> Result<()> {
+        if meta.engine != "kvs" {
+            return Err(Error::InvalidMeta(self.metapath()))?;
+        }
+        if meta.version > FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion {
+                found: meta.version,
+                supported: FORMAT_VERSION,
+            })?;
+        }
+        // Only JSON tells a record without a kind, bincode would misread it.
+        if meta.version < file::KIND_VERSION && meta.codec != JsonCodec.name() {
+            return Err(Error::LegacyRecords {
+                found: meta.version,
+                codec: meta.codec.clone(),
+            })?;
+        }
+        if meta.codec != self.codec.name() {
+            return Err(Error::CodecMismatch {
+                found: meta.codec.clone(),
+                expected: self.codec.name().to_owned(),
+            })?;
+        }
+        let known = meta.cipher.as_deref() == Some(crypt::CIPHER);
+        let reason = match (&meta.key_id, &self.cipher) {
+            (None, None) => None,
+            (Some(_), None) => Some("the database is encrypted, no key given"),
+            (None, Some(_)) => Some("the database is not encrypted"),
+            (Some(_), Some(_)) if !known => Some("unknown cipher"),
+            (Some(id), Some(cipher)) if *id != cipher.key_id() => Some("wrong key"),
+            (Some(_), Some(_)) => None,
+        };
+        if let Some(reason) = reason {
+            return Err(Error::DecryptFailed(reason.to_owned()))?;
+        }
+        Ok(())
+    }
+
+    /// Record `FORMAT_VERSION` in the meta file of an older database before
+    /// records of the current format are appended to it, so that older
+    /// builds refuse it instead of failing on these records.
+    fn upgrade_meta(&self, meta: &Meta) -> Result<()> {
+        if meta.version >= FORMAT_VERSION {
+            return Ok(());
+        }
+        let mut meta = meta.clone();
+        meta.version = FORMAT_VERSION;
+        let temp = file::meta_temp(&self.dir);
+        let mut wtr = File::create(&temp)?;
+        wtr.write_all(meta.render().as_bytes())?;
+        wtr.sync_all()?;
+        fs::rename(&temp, self.metapath())?;
+        file::sync_dir(&self.dir)?;
+        Ok(())
+    }
+
+    /// Read the layout of the database without modifying the directory.
+    /// For a directory of another engine only `engine` and `version` are
+    /// filled. Fails with `Error::UnsupportedVersion` for a newer format.
+    pub fn inspect(&self) -> Result<DbInfo> {
+        let meta = match self.read_meta()? {
+            Some(meta) => meta,
+            None => return Err(Error::BadPath(self.dir.clone()))?,
+        };
+        let mut info = DbInfo {
+            engine: meta.engine.clone(),
+            version: meta.version,
+            files: Vec::new(),
+            active_id: None,
+            keys: 0,
+            garbage: 0,
+        };
+        if info.engine != "kvs" {
+            return Ok(info);
+        }
+        self.check_meta(&meta)?;
+
+        let mut ids = Self::file_ids(&self.dir, &self.prefix)?;
+        info.files = Self::generation(&self.dir, &self.prefix, &ids)?;
+        Self::trim_empty_tail(&self.dir, &self.prefix, &mut ids)?;
+        info.active_id = ids.last().cloned();
+        let (index, garbage) = Self::load_index(
+            &self.dir,
+            &self.prefix,
+            &self.data_codec(),
+            &ids,
+            self.hasher,
+        )?;
+        info.keys = index.len();
+        info.garbage = garbage.values().sum::<usize>() as u64;
+        Ok(info)
+    }
+
     /// Build the KvStore.
-    pub fn build(mut self) -> Result<KvStore> {
+    pub fn build(self) -> Result<KvStore> {
+        self.build_with_status().map(|(store, _)| store)
+    }
+
+    /// Build the KvStore, and tell whether the database was created by it,
+    /// e.g. to catch a wrong path.
+    pub fn build_with_status(mut self) -> Result<(KvStore, OpenStatus)> {
         let log = get_logger(&mut self.log);
+        if self.min_acks > 0 && self.repl_backlog.is_none() {
+            return Err(Error::ReplicationOff)?;
+        }
 
-        let mut fds;
+        let mut ids;
         let active;
         let index;
-        let garbage_sz;
-        let low;
+        let file_gbg;
+        let blooms;
+        let status;
+
+        // Don't leave a `LOCK` file in the directory of another engine.
+        if let Some(ref meta) = self.read_meta()? {
+            self.check_meta(meta)?;
+        }
+        let lock = if self.lock {
+            Some(Arc::new(file::lock_dir(&self.dir)?))
+        } else {
+            None
+        };
 
+        // Read again, another store may have initialized the dir meanwhile.
         match self.read_meta()? {
-            Some(ref meta) if meta != "kvs" => {
-                return Err(Error::InvalidMeta(self.metapath()))?;
-            }
-            Some(_) => {
-                fds = Self::file_list(&self.dir)?;
-                low = *fds.keys().nth(0).unwrap();
+            Some(meta) => {
+                self.check_meta(&meta)?;
+                self.upgrade_meta(&meta)?;
+                ids = Self::file_ids(&self.dir, &self.prefix)?;
+                let files = Self::generation(&self.dir, &self.prefix, &ids)?;
+                let snapshot = Self::take_snapshot(&self.dir, &log)?;
+                if ids.is_empty() {
+                    warn!(log, "no data file in {:?}", self.dir);
+                    file::fdw(&self.dir, &self.prefix, 1, 0)?;
+                    ids.push(1);
+                }
+                for id in Self::trim_empty_tail(&self.dir, &self.prefix, &mut ids)? {
+                    let path = file::data(&self.dir, &self.prefix, id);
+                    warn!(log, "removing empty data file: {:?}", path);
+                    fs::remove_file(&path)?;
+                    Self::remove_bloom(&self.dir, &self.prefix, id)?;
+                }
 
-                let active_id = *fds.keys().last().unwrap();
-                active = Fdw {
-                    id: active_id,
-                    wtr: file::open_w(file::data(&self.dir, active_id))?,
+                let active_id = *ids.last().unwrap();
+                active = file::reopen_w(&self.dir, &self.prefix, active_id)?;
+                file::preallocate(active.wtr.get_ref(), self.prealloc)?;
+                // The filter of a merged file reopened as active would miss
+                // the keys appended to it.
+                Self::remove_bloom(&self.dir, &self.prefix, active_id)?;
+                blooms = self.load_blooms(ids.iter().filter(|id| **id != active_id), &log);
+
+                let (idx, gbg) = match snapshot {
+                    Some(snapshot) if self.snapshot && snapshot.files == files => {
+                        let idx = Index::with_capacity(snapshot.index.len(), self.hasher);
+                        for (key, info) in snapshot.index {
+                            idx.insert(key, info);
+                        }
+                        (idx, snapshot.garbage.into_iter().collect())
+                    }
+                    Some(_) if self.snapshot => {
+                        warn!(log, "index snapshot out of date, reading data files");
+                        Self::load_index(
+                            &self.dir,
+                            &self.prefix,
+                            &self.data_codec(),
+                            &ids,
+                            self.hasher,
+                        )?
+                    }
+                    _ => Self::load_index(
+                        &self.dir,
+                        &self.prefix,
+                        &self.data_codec(),
+                        &ids,
+                        self.hasher,
+                    )?,
                 };
-
-                let (idx, sz) = Self::load_index(&mut fds)?;
                 index = idx;
-                garbage_sz = sz;
+                file_gbg = gbg;
+                status = OpenStatus::Opened;
             }
             None => {
                 warn!(log, "initializing the dir: {:?}", self.dir);
-                fs::write(self.metapath(), "kvs")?;
-
-                active = file::fdw(&self.dir, 1)?;
-                low = 1;
+                let mut meta = Meta::current();
+                meta.codec = self.codec.name().to_owned();
+                if let Some(ref cipher) = self.cipher {
+                    meta.cipher = Some(crypt::CIPHER.to_owned());
+                    meta.key_id = Some(cipher.key_id());
+                }
+                fs::write(self.metapath(), meta.render())?;
 
-                fds = FdrMap::new();
-                fds.insert(1, file::fdr(&self.dir, 1)?);
+                active = file::fdw(&self.dir, &self.prefix, 1, self.prealloc)?;
 
-                index = Index::new();
-                garbage_sz = 0;
+                index = Index::new(self.hasher);
+                file_gbg = FileGarbage::new();
+                blooms = HashMap::new();
+                status = OpenStatus::Created;
             }
         }
 
         let (sx, rx) = unbounded();
+        let garbage_sz: usize = file_gbg.values().sum();
+        let live_sz: usize = index.clone().into_iter().map(|(_, info)| info.len).sum();
+        let namespaces = match self.ns_sep {
+            Some(sep) => {
+                let namespaces = Namespaces::new(sep, self.read_quotas()?);
+                for (key, info) in index.clone() {
+                    namespaces.insert(&key, info.len, None);
+                }
+                Some(Arc::new(namespaces))
+            }
+            None => None,
+        };
+        let eviction = self.maxmemory.map(|maxmemory| {
+            Arc::new(Eviction {
+                maxmemory,
+                policy: self.policy,
+                clock: AtomicUsize::new(1),
+                access: CHashMap::new(),
+            })
+        });
 
+        let codec = self.data_codec();
         let mut this = KvStore {
             log,
             dir: self.dir,
+            prefix: self.prefix,
+            codec,
             cthreshold: self.cthreshold,
             index: Arc::new(index),
             garbage_sz: Arc::new(AtomicUsize::new(garbage_sz)),
+            live_sz: Arc::new(AtomicUsize::new(live_sz)),
             active: Arc::new(Mutex::new(active)),
             writer: Arc::new(Mutex::new(())),
             compact_lock: Arc::new(Mutex::new(())),
-            lowest_id: Arc::new(AtomicUsize::new(low)),
+            file_gbg: Arc::new(Mutex::new(file_gbg)),
+            key_gbg: Arc::new(Mutex::new(HashMap::new())),
+            min_garbage: self.min_garbage,
+            prealloc: self.prealloc,
+            key_order: self.key_order,
+            reject_empty: self.reject_empty,
+            namespaces,
+            repl: self
+                .repl_backlog
+                .map(|bytes| Arc::new(ReplicationLog::new(bytes))),
+            min_acks: self.min_acks,
+            ack_timeout: self.ack_timeout,
+            blooms: Arc::new(Mutex::new(blooms)),
+            bloom_bits: self.bloom_bits,
+            max_open: self.max_open,
             sx,
             compacter: None,
             counter: Arc::new(AtomicUsize::new(1)),
-            fds: RefCell::new(fds),
+            events: self.events,
+            scheduler: self.scheduler.as_ref().map(Arc::downgrade),
+            scheduled: Arc::new(AtomicBool::new(false)),
+            snapshot: self.snapshot,
+            eviction,
+            retries: self.retries,
+            backoff: self.backoff,
+            health: Arc::new(Mutex::new(CompactionHealth::default())),
+            paused: Arc::new(AtomicBool::new(false)),
+            io: Arc::new(IoCounters::default()),
+            _lock: lock,
+            fds: Mutex::new(FdrMap::new()),
         };
+        if this.scheduler.is_some() {
+            return Ok((this, status));
+        }
 
         let compacter = this.clone();
 
-        let handle = thread::spawn(move || loop {
+        let mut thread = thread::Builder::new().name(self.thread_name);
+        if let Some(bytes) = self.stack_size {
+            thread = thread.stack_size(bytes);
+        }
+        let handle = thread.spawn(move || loop {
             match rx.recv().unwrap() {
                 Action::Shutdown => break,
                 Action::Compact => {
-                    let gbg_sz = compacter.garbage_sz.load(Ordering::SeqCst);
-                    if gbg_sz > compacter.cthreshold {
-                        if let Err(e) = compacter.compact() {
-                            error!(compacter.log, "failed to compact: {}", e);
+                    if compacter.needs_compaction() {
+                        let mut shutdown = false;
+                        // Calls coming during a backoff are covered by the retry.
+                        compacter.compact_retrying(|backoff| {
+                            shutdown = !wait_backoff(&rx, backoff);
+                            !shutdown
+                        });
+                        if shutdown {
+                            break;
                         }
                     }
                 }
             }
-        });
+        })?;
 
         this.compacter = Some(Arc::new(handle));
 
-        Ok(this)
+        Ok((this, status))
     }
 
     /// Return sorted file ids.
-    fn file_list(dir: &PathBuf) -> Result<FdrMap> {
+    fn file_ids(dir: &Path, prefix: &str) -> Result<Vec<Fid>> {
         let mut ids: Vec<Fid> = fs::read_dir(dir)?
             .flat_map(|entry| -> Result<_> { Ok(entry?.path()) })
             .filter(|path| path.is_file())
-            .filter(|path| path.extension() == Some("data".as_ref()))
             .flat_map(|path| {
-                path.file_stem()
+                path.file_name()
                     .and_then(OsStr::to_str)
-                    .map(str::parse::<Fid>)
+                    .and_then(|name| file::data_id(name, prefix))
             })
-            .flatten()
             .collect();
         ids.sort_unstable();
-        let mut fds = FdrMap::new();
+        Ok(ids)
+    }
+
+    /// Ids and sizes of the data files, identifying an index snapshot.
+    fn generation(dir: &Path, prefix: &str, ids: &[Fid]) -> Result<Vec<(Fid, u64)>> {
+        let mut files = Vec::new();
+        for id in ids {
+            files.push((*id, fs::metadata(file::data(dir, prefix, *id))?.len()));
+        }
+        Ok(files)
+    }
+
+    /// The bloom filters of the files `ids`, none if `bloom_bits_per_key`
+    /// is 0. A filter that can't be read is ignored, its file is scanned.
+    fn load_blooms<'a>(
+        &self,
+        ids: impl Iterator<Item = &'a Fid>,
+        log: &Logger,
+    ) -> HashMap<Fid, Bloom> {
+        let mut blooms = HashMap::new();
+        if self.bloom_bits == 0 {
+            return blooms;
+        }
+        for id in ids {
+            match Bloom::load(&file::bloom(&self.dir, &self.prefix, *id)) {
+                Ok(Some(bloom)) => {
+                    blooms.insert(*id, bloom);
+                }
+                Ok(None) => {}
+                Err(e) => warn!(log, "ignoring bloom filter of file {}: {}", id, e),
+            }
+        }
+        blooms
+    }
+
+    fn remove_bloom(dir: &Path, prefix: &str, id: Fid) -> Result<()> {
+        match fs::remove_file(file::bloom(dir, prefix, id)) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e)?,
+        }
+    }
+
+    /// Read and remove the index snapshot, so that it is never used after
+    /// the data files change. A snapshot that can't be parsed is ignored.
+    fn take_snapshot(dir: &Path, log: &Logger) -> Result<Option<Snapshot>> {
+        let path = file::snapshot(dir);
+        let buf = match fs::read(&path) {
+            Ok(buf) => buf,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e)?,
+        };
+        fs::remove_file(&path)?;
+        match serde_json::from_slice(&buf) {
+            Ok(snapshot) => Ok(Some(snapshot)),
+            Err(e) => {
+                warn!(log, "ignoring bad index snapshot: {}", e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Drop the empty files after the last file with content, e.g. an active
+    /// file created just before a crash, so the real active file is reused.
+    /// A preallocated file holding only zeros is empty. The first file is
+    /// kept even if empty. Return the dropped ids.
+    fn trim_empty_tail(dir: &Path, prefix: &str, ids: &mut Vec<Fid>) -> Result<Vec<Fid>> {
+        let mut dropped = Vec::new();
+        while ids.len() > 1 {
+            let id = *ids.last().unwrap();
+            if file::logical_end(&File::open(file::data(dir, prefix, id))?)? > 0 {
+                break;
+            }
+            ids.pop();
+            dropped.push(id);
+        }
+        Ok(dropped)
+    }
+
+    /// Read the data files `ids` in order, one at a time, to generate a
+    /// HashMap index, and the garbage in each file.
+    fn load_index(
+        dir: &Path,
+        prefix: &str,
+        codec: &Arc<dyn RecordCodec>,
+        ids: &[Fid],
+        hasher: IndexHasher,
+    ) -> Result<(Index, FileGarbage)> {
+        let index = Index::new(hasher);
+        let mut gbg = FileGarbage::new();
+        let mut add = |id: Fid, len: usize| *gbg.entry(id).or_insert(0) += len;
+
         for id in ids {
-            fds.insert(id, file::fdr(dir, id)?);
-        }
-        Ok(fds)
-    }
-
-    /// Read the data files to generate a HashMap index.
-    fn load_index(fds: &mut FdrMap) -> Result<(Index, usize)> {
-        let index = Index::new();
-        let mut sz = 0;
-
-        for (_, Fdr { id, rdr }) in fds.iter_mut() {
-            let mut stream = Command::deserializer(rdr).into_iter();
-            let mut offset = stream.byte_offset();
-            while let Some(cmd) = stream.next() {
-                let next_offset = stream.byte_offset();
-                match cmd? {
-                    Command::Set(key, _) => {
-                        let old = index
-                            .insert(key, CmdInfo::new(*id, offset as u64, next_offset - offset));
-                        sz += old.map_or(0, |i| i.len);
+            let rdr = file::open_r(file::data(dir, prefix, *id))?;
+            // Skip the zeros of a preallocated file.
+            let end = file::logical_end(rdr.get_ref())?;
+            let mut records = Records::new(rdr.take(end), codec.clone());
+            let mut offset = records.offset();
+            while let Some(cmd) = records.next() {
+                let next_offset = records.offset();
+                let len = (next_offset - offset) as usize;
+                let cmd = cmd?;
+                match cmd {
+                    Command::Set(ref key, ..) => {
+                        let mut info = CmdInfo::new(*id, offset, len, &cmd);
+                        // Records written before versions existed count the writes.
+                        if info.version == 0 {
+                            info.version = index.get(key).map_or(0, |i| i.version) + 1;
+                        }
+                        if let Some(old) = index.insert(key.clone(), info) {
+                            add(old.loc.id, old.len);
+                        }
                     }
                     Command::Rm(key) => {
-                        let old = index.remove(&key);
-                        sz += old.map_or(0, |i| i.len);
-                        sz += next_offset - offset;
+                        if let Some(old) = index.remove(&key) {
+                            add(old.loc.id, old.len);
+                        }
+                        add(*id, len);
                     }
                 }
                 offset = next_offset;
             }
         }
-        Ok((index, sz))
+        Ok((index, gbg))
     }
 }
@@ -1,7 +1,22 @@
+mod bloom;
+mod codec;
 mod command;
+mod crypt;
 mod error;
 mod file;
+mod index;
 mod kv;
+mod quota;
+mod repl;
 
+#[cfg(feature = "bincode")]
+pub use codec::BincodeCodec;
+pub use codec::{JsonCodec, RecordCodec};
+pub use command::{Command, ValueKind};
 pub use error::Error;
+pub(crate) use file::Meta;
+pub use file::FORMAT_VERSION;
+pub use index::IndexHasher;
 pub use kv::*;
+pub use quota::{NamespaceUsage, Quota};
+pub use repl::{ReplSink, ReplSync};
@@ -0,0 +1,119 @@
+//! The usage and the quotas of the namespaces of a store.
+
+use serde_derive::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Limits of a namespace, `None` for no limit. See
+/// `KvStoreBuilder::namespaces`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    pub max_keys: Option<usize>,
+    /// Compared with `NamespaceUsage::bytes`.
+    pub max_bytes: Option<usize>,
+}
+
+/// The live keys of a namespace and the bytes of their records, as counted
+/// by `KvStore::live_size`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NamespaceUsage {
+    pub keys: usize,
+    pub bytes: usize,
+}
+
+pub(super) struct Namespaces {
+    sep: char,
+    usage: Mutex<HashMap<String, NamespaceUsage>>,
+    quotas: Mutex<HashMap<String, Quota>>,
+}
+
+impl Namespaces {
+    pub fn new(sep: char, quotas: HashMap<String, Quota>) -> Self {
+        Namespaces {
+            sep,
+            usage: Mutex::new(HashMap::new()),
+            quotas: Mutex::new(quotas),
+        }
+    }
+
+    /// The part of `key` before the separator, "" if it has none.
+    pub fn of<'a>(&self, key: &'a str) -> &'a str {
+        key.find(self.sep).map_or("", |i| &key[..i])
+    }
+
+    /// Count a record of `len` bytes for `key`, replacing one of `old`
+    /// bytes if any.
+    pub fn insert(&self, key: &str, len: usize, old: Option<usize>) {
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(self.of(key).to_owned()).or_default();
+        match old {
+            Some(old) => usage.bytes -= old,
+            None => usage.keys += 1,
+        }
+        usage.bytes += len;
+    }
+
+    /// Stop counting the record of `old` bytes of `key`.
+    pub fn remove(&self, key: &str, old: usize) {
+        let mut usage = self.usage.lock().unwrap();
+        let ns = self.of(key);
+        if let Some(u) = usage.get_mut(ns) {
+            u.keys -= 1;
+            u.bytes -= old;
+            if u.keys == 0 {
+                usage.remove(ns);
+            }
+        }
+    }
+
+    pub fn get(&self, ns: &str) -> (NamespaceUsage, Quota) {
+        let usage = self.usage.lock().unwrap().get(ns).cloned();
+        let quota = self.quotas.lock().unwrap().get(ns).cloned();
+        (usage.unwrap_or_default(), quota.unwrap_or_default())
+    }
+
+    /// Set the quota of `ns`, return all of them to be saved.
+    pub fn set_quota(&self, ns: &str, quota: Quota) -> HashMap<String, Quota> {
+        let mut quotas = self.quotas.lock().unwrap();
+        if quota == Quota::default() {
+            quotas.remove(ns);
+        } else {
+            quotas.insert(ns.to_owned(), quota);
+        }
+        quotas.clone()
+    }
+
+    /// Check the writes of `changes`, each a key with the length of its
+    /// record before and after, `None` for no record. Return the first
+    /// namespace that would grow beyond its quota. A write shrinking a
+    /// namespace is allowed even if it stays beyond its quota.
+    pub fn check(&self, changes: &[(&str, Option<usize>, Option<usize>)]) -> Result<(), String> {
+        let quotas = self.quotas.lock().unwrap();
+        if quotas.is_empty() {
+            return Ok(());
+        }
+        let mut deltas: HashMap<&str, (isize, isize)> = HashMap::new();
+        for (key, old, new) in changes {
+            let delta = deltas.entry(self.of(key)).or_insert((0, 0));
+            delta.0 += new.is_some() as isize - old.is_some() as isize;
+            delta.1 += new.unwrap_or(0) as isize - old.unwrap_or(0) as isize;
+        }
+        let usage = self.usage.lock().unwrap();
+        for (ns, (keys, bytes)) in deltas {
+            let quota = match quotas.get(ns) {
+                Some(quota) => quota,
+                None => continue,
+            };
+            let cur = usage.get(ns).cloned().unwrap_or_default();
+            let over = |max: Option<usize>, cur: usize, delta: isize| match max {
+                Some(max) => delta > 0 && cur as isize + delta > max as isize,
+                None => false,
+            };
+            if over(quota.max_keys, cur.keys, keys) || over(quota.max_bytes, cur.bytes, bytes) {
+                return Err(ns.to_owned());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+//! The replication backlog of a store.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Receiver of the records of a follower, with their offset. Return false
+/// to unsubscribe, e.g. when the follower is gone.
+pub type ReplSink = Box<dyn FnMut(u64, &Arc<str>) -> bool + Send>;
+
+/// How a follower starts, see `KvStore::replicate`.
+///
+/// Every record appended to the active data file by a write, including
+/// the removals of evicted keys, gets the next offset: the first record
+/// after the store is opened has offset 0. Records rewritten by compaction
+/// are not new writes and get none. The offsets are in memory only, so
+/// each open of the store starts a new history, told apart by a random
+/// replication id.
+///
+/// A follower that knows the id and the offset of the next record it needs
+/// continues from there, as long as that record is still in the backlog of
+/// the recent records, see `KvStoreBuilder::replication_backlog`. Any other
+/// follower, e.g. a new one, one too far behind or one of a previous open
+/// of the store, resyncs in full: it copies the live pairs, then continues
+/// from the offset given by the resync.
+#[derive(Debug)]
+pub enum ReplSync {
+    /// The follower is up to date after applying the records of the
+    /// backlog from its offset, given with their offset.
+    Continue(Vec<(u64, Arc<str>)>),
+    /// The follower must drop its pairs, copy the live ones, and then
+    /// continue from `offset` of the replication `id`.
+    Full { id: String, offset: u64 },
+}
+
+pub(super) struct ReplicationLog {
+    id: String,
+    state: Mutex<State>,
+    /// Notified on each acknowledgment.
+    acked: Condvar,
+}
+
+struct State {
+    /// Offset of the first record of `backlog`.
+    start: u64,
+    backlog: VecDeque<Arc<str>>,
+    bytes: usize,
+    limit: usize,
+    sinks: Vec<ReplSink>,
+    /// Offset of the first record not yet applied, by follower.
+    acks: HashMap<String, u64>,
+}
+
+impl ReplicationLog {
+    /// Keep about `limit` bytes of the recent records.
+    pub fn new(limit: usize) -> Self {
+        ReplicationLog {
+            id: format!("{:016x}", rand::random::<u64>()),
+            state: Mutex::new(State {
+                start: 0,
+                backlog: VecDeque::new(),
+                bytes: 0,
+                limit,
+                sinks: Vec::new(),
+                acks: HashMap::new(),
+            }),
+            acked: Condvar::new(),
+        }
+    }
+
+    /// Bytes of the recent records kept, see `new`.
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    /// Publish a record written to the data file, in the order of the file.
+    pub fn append(&self, record: &str) {
+        let record: Arc<str> = Arc::from(record);
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let offset = state.start + state.backlog.len() as u64;
+        let mut i = 0;
+        while i < state.sinks.len() {
+            if (state.sinks[i])(offset, &record) {
+                i += 1;
+            } else {
+                drop(state.sinks.swap_remove(i));
+            }
+        }
+        state.bytes += record.len();
+        state.backlog.push_back(record);
+        // The newest record is kept even if larger than the limit.
+        while state.bytes > state.limit && state.backlog.len() > 1 {
+            let old = state.backlog.pop_front().unwrap();
+            state.bytes -= old.len();
+            state.start += 1;
+        }
+    }
+
+    /// Record that `replica` applied the records before `offset`.
+    pub fn ack(&self, replica: &str, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.acks.insert(replica.to_owned(), offset);
+        self.acked.notify_all();
+    }
+
+    pub fn forget(&self, replica: &str) {
+        self.state.lock().unwrap().acks.remove(replica);
+    }
+
+    /// Wait up to `timeout` for `n` followers to apply every record
+    /// appended so far, return how many did.
+    pub fn wait_acks(&self, n: usize, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        let next = state.start + state.backlog.len() as u64;
+        loop {
+            let acked = state
+                .acks
+                .values()
+                .filter(|&&offset| offset >= next)
+                .count();
+            let now = Instant::now();
+            if acked >= n || now >= deadline {
+                return acked;
+            }
+            state = self.acked.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Send the records from `offset` of the history `id` to `sink`.
+    pub fn subscribe(&self, id: &str, offset: u64, sink: ReplSink) -> ReplSync {
+        let mut state = self.state.lock().unwrap();
+        let next = state.start + state.backlog.len() as u64;
+        let sync = if id == self.id && offset >= state.start && offset <= next {
+            let skip = (offset - state.start) as usize;
+            ReplSync::Continue(
+                state
+                    .backlog
+                    .iter()
+                    .enumerate()
+                    .skip(skip)
+                    .map(|(i, record)| (state.start + i as u64, record.clone()))
+                    .collect(),
+            )
+        } else {
+            ReplSync::Full {
+                id: self.id.clone(),
+                offset: next,
+            }
+        };
+        state.sinks.push(sink);
+        sync
+    }
+}
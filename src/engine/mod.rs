@@ -1,19 +1,278 @@
 extern crate failure;
 
+use failure::format_err;
+
+mod glob;
 pub mod kvstore;
 pub mod sledkv;
 
-use crate::Result;
-pub use kvstore::KvStore;
+use std::collections::HashSet;
+use std::time::Duration;
+
+use crate::{KvsError, Result};
+pub(crate) use glob::glob_match;
+pub use kvstore::{
+    IoStats, KeyMeta, KvStore, NamespaceUsage, Quota, ReplSink, ReplSync, SizeHistogram,
+};
 
 /// KV server storage backend.
-pub trait KvsEngine: Clone + Send + 'static {
+pub trait KvsEngine: Clone + Send + Sync + 'static {
     /// Set key-value.
     fn set(&self, key: String, value: String) -> Result<()>;
     /// Get key.
     fn get(&self, key: String) -> Result<Option<String>>;
     /// Remove key.
     fn remove(&self, key: String) -> Result<()>;
+    /// Count keys with the prefix.
+    fn count_prefix(&self, prefix: String) -> Result<usize>;
+    /// Make key expire `ttl` from now, return whether it exists.
+    fn expire(&self, key: String, ttl: Duration) -> Result<bool>;
+    /// Make key never expire, return whether it had a TTL.
+    fn persist(&self, key: String) -> Result<bool>;
+    /// Add `delta` to the float value of key, and return the new value.
+    fn increment_float(&self, key: String, delta: f64) -> Result<f64>;
+    /// Name of the engine, as recorded in the meta file.
+    fn engine_name(&self) -> &'static str;
+    /// Add `delta` to the integer value of key and return the new value,
+    /// setting the expiry `ttl` only if the key is created, see
+    /// `KvStore::increment_with_ttl`.
+    fn increment_with_ttl(&self, _key: String, _delta: i64, _ttl: Duration) -> Result<i64> {
+        let name = self.engine_name();
+        Err(format_err!("INCRTTL is not supported by {}", name))
+    }
+    /// Get key with the time left until it expires, `None` if it never
+    /// does, as for every key of the engines without TTLs.
+    fn get_with_ttl(&self, key: String) -> Result<Option<(String, Option<Duration>)>> {
+        Ok(self.get(key)?.map(|val| (val, None)))
+    }
+    /// Get key and make it expire `ttl` from now, see
+    /// `KvStore::get_and_expire`.
+    fn get_and_expire(&self, _key: String, _ttl: Duration) -> Result<Option<String>> {
+        let name = self.engine_name();
+        Err(format_err!("GETEX is not supported by {}", name))
+    }
+    /// Get key with the version of its value.
+    fn get_with_version(&self, _key: String) -> Result<Option<(String, u64)>> {
+        let name = self.engine_name();
+        Err(format_err!("versions are not supported by {}", name))
+    }
+    /// Set key-value if the version of key is `expected`, return the new version.
+    fn set_if_version(&self, _key: String, _value: String, _expected: u64) -> Result<u64> {
+        let name = self.engine_name();
+        Err(format_err!("versions are not supported by {}", name))
+    }
+    /// Set key-value if key exists, return whether it was set.
+    /// Set the value and return whether the key was created, false if a
+    /// value was overwritten. Not atomic by default: a concurrent write
+    /// may be missed.
+    fn set_created(&self, key: String, value: String) -> Result<bool> {
+        let created = self.get(key.clone())?.is_none();
+        self.set(key, value)?;
+        Ok(created)
+    }
+    fn set_if_present(&self, _key: String, _value: String) -> Result<bool> {
+        let name = self.engine_name();
+        Err(format_err!("SET XX is not supported by {}", name))
+    }
+    /// Set key-value if key doesn't exist, return whether it was set.
+    fn set_if_absent(&self, _key: String, _value: String) -> Result<bool> {
+        let name = self.engine_name();
+        Err(format_err!("SET NX is not supported by {}", name))
+    }
+    /// Rename `src` to `dst` if `dst` doesn't exist, return whether it was renamed.
+    fn rename_nx(&self, _src: String, _dst: String) -> Result<bool> {
+        let name = self.engine_name();
+        Err(format_err!("RENAMENX is not supported by {}", name))
+    }
+    /// Copy `src` to `dst`, overwrite `dst` only with `replace`.
+    /// Return whether it was copied.
+    fn copy(&self, _src: String, _dst: String, _replace: bool) -> Result<bool> {
+        let name = self.engine_name();
+        Err(format_err!("COPY is not supported by {}", name))
+    }
+    /// Get the timestamps, size and version of key.
+    fn meta(&self, _key: String) -> Result<Option<KeyMeta>> {
+        let name = self.engine_name();
+        Err(format_err!("metadata is not supported by {}", name))
+    }
+    /// Return a key chosen at random, `None` if empty.
+    fn random_key(&self) -> Result<Option<String>> {
+        let name = self.engine_name();
+        Err(format_err!("RANDOMKEY is not supported by {}", name))
+    }
+    /// Exchange the values of two existing keys.
+    fn swap(&self, _a: String, _b: String) -> Result<()> {
+        let name = self.engine_name();
+        Err(format_err!("SWAP is not supported by {}", name))
+    }
+    /// All the keys, in no particular order.
+    fn keys(&self) -> Result<Vec<String>> {
+        let name = self.engine_name();
+        Err(format_err!("listing keys is not supported by {}", name))
+    }
+    /// The keys matching the glob `pattern`, see `KvStore::keys_matching`.
+    fn keys_matching(&self, pattern: &str) -> Result<Vec<String>> {
+        let keys = self.keys()?;
+        Ok(keys
+            .into_iter()
+            .filter(|key| glob_match(pattern, key))
+            .collect())
+    }
+    /// The members of both sets, the keys with the prefixes less the
+    /// prefixes, sorted, see `KvStore::intersect_prefixes`.
+    fn intersect_prefixes(&self, a: String, b: String) -> Result<Vec<String>> {
+        let keys = self.keys()?;
+        let members = |prefix: &str| -> HashSet<String> {
+            keys.iter()
+                .filter(|key| key.starts_with(prefix))
+                .map(|key| key[prefix.len()..].to_owned())
+                .collect()
+        };
+        let mut members: Vec<String> = members(&a).intersection(&members(&b)).cloned().collect();
+        members.sort_unstable();
+        Ok(members)
+    }
+    /// Remove the keys with the prefix, return how many were removed.
+    fn remove_prefix(&self, _prefix: String) -> Result<usize> {
+        let name = self.engine_name();
+        Err(format_err!("DELPREFIX is not supported by {}", name))
+    }
+    /// Remove the keys whose TTL has passed, return how many were removed,
+    /// none for the engines without TTLs.
+    fn purge_expired(&self) -> Result<usize> {
+        Ok(0)
+    }
+    /// The pairs of the keys with the prefix whose value contains
+    /// `substring`, sorted by key.
+    fn scan_value(&self, _prefix: String, _substring: String) -> Result<Vec<(String, String)>> {
+        let name = self.engine_name();
+        Err(format_err!("SCANVALUE is not supported by {}", name))
+    }
+    /// Mark key as just used for eviction, return whether it exists.
+    fn touch(&self, _key: String) -> Result<bool> {
+        let name = self.engine_name();
+        Err(format_err!("TOUCH is not supported by {}", name))
+    }
+    /// Get the values of `keys`, in the same order, reading each distinct
+    /// key once.
+    fn multi_get(&self, _keys: &[String]) -> Result<Vec<Option<String>>> {
+        let name = self.engine_name();
+        Err(format_err!("MGET is not supported by {}", name))
+    }
+    /// Get the bytes `start..=end` of the value of key, negative indices
+    /// counting from the end.
+    fn get_range(&self, _key: String, _start: i64, _end: i64) -> Result<Option<String>> {
+        let name = self.engine_name();
+        Err(format_err!("GETRANGE is not supported by {}", name))
+    }
+    /// Get the value of key cut to at most `max` bytes, and whether it was
+    /// cut, see `KvStore::get_capped`.
+    fn get_capped(&self, key: String, max: usize) -> Result<Option<(String, bool)>> {
+        Ok(self.get(key)?.map(|val| cap_utf8(val.as_bytes(), max)))
+    }
+    /// Make the writes done so far durable.
+    fn flush(&self) -> Result<()> {
+        let name = self.engine_name();
+        Err(format_err!("WAIT is not supported by {}", name))
+    }
+    /// Stop the background compactions until `resume_compaction`.
+    fn pause_compaction(&self) -> Result<()> {
+        let name = self.engine_name();
+        Err(format_err!("COMPACT is not supported by {}", name))
+    }
+    /// Start the background compactions again.
+    fn resume_compaction(&self) -> Result<()> {
+        let name = self.engine_name();
+        Err(format_err!("COMPACT is not supported by {}", name))
+    }
+    /// Limit the growth of `namespace`, `Quota::default()` for no limit.
+    fn set_quota(&self, _namespace: &str, _quota: Quota) -> Result<()> {
+        let name = self.engine_name();
+        Err(format_err!("QUOTA is not supported by {}", name))
+    }
+    /// The usage and the quota of `namespace`.
+    fn namespace_usage(&self, _namespace: &str) -> Result<(NamespaceUsage, Quota)> {
+        let name = self.engine_name();
+        Err(format_err!("QUOTA is not supported by {}", name))
+    }
+    /// Stream the records written from now on to `sink`, for a replication
+    /// follower that needs the records from `offset` of the history `id`.
+    fn replicate(&self, _id: &str, _offset: u64, _sink: ReplSink) -> Result<ReplSync> {
+        let name = self.engine_name();
+        Err(format_err!("replication is not supported by {}", name))
+    }
+    /// Record that the follower `replica` applied the records before
+    /// `offset`, see `KvStore::ack_replica`.
+    fn ack_replica(&self, _replica: &str, _offset: u64) -> Result<()> {
+        let name = self.engine_name();
+        Err(format_err!("replication is not supported by {}", name))
+    }
+    /// Stop counting the acknowledgments of `replica`.
+    fn forget_replica(&self, _replica: &str) {}
+    /// Set all the pairs, flushed once, see `KvStore::set_many`. By
+    /// default each pair is set on its own, so a failure leaves the pairs
+    /// before it set.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+    /// Set all the pairs in one atomic batch, return the previous value of
+    /// each key.
+    fn set_many_get(&self, _pairs: Vec<(String, String)>) -> Result<Vec<Option<String>>> {
+        let name = self.engine_name();
+        Err(format_err!("MSETGET is not supported by {}", name))
+    }
+    /// Bytes written to and read from the storage since it was opened.
+    fn io_stats(&self) -> Result<IoStats> {
+        let name = self.engine_name();
+        Err(format_err!("IO stats are not supported by {}", name))
+    }
+    /// The live records counted by length, see `KvStore::size_histogram`.
+    fn size_histogram(&self) -> Result<SizeHistogram> {
+        let name = self.engine_name();
+        Err(format_err!("size histograms are not supported by {}", name))
+    }
+    /// The settings of the engine by name, see `KvStore::config`. None by
+    /// default.
+    fn config(&self) -> Vec<(&'static str, String)> {
+        Vec::new()
+    }
+    /// The keys whose writes made the most garbage since the open, see
+    /// `KvStore::hot_keys`.
+    fn hot_keys(&self, _top_n: usize) -> Result<Vec<(String, usize)>> {
+        let name = self.engine_name();
+        Err(format_err!("HOTKEYS is not supported by {}", name))
+    }
+    /// Prepend `value` to the list of key and return its new length, see
+    /// `KvStore::push_front`.
+    fn push_front(&self, _key: String, _value: String) -> Result<usize> {
+        let name = self.engine_name();
+        Err(format_err!("LPUSH is not supported by {}", name))
+    }
+    /// Append `value` to the list of key and return its new length, see
+    /// `KvStore::push_back`.
+    fn push_back(&self, _key: String, _value: String) -> Result<usize> {
+        let name = self.engine_name();
+        Err(format_err!("RPUSH is not supported by {}", name))
+    }
+    /// The items `start..=stop` of the list of key, negative indices
+    /// counting from the end, see `KvStore::list_range`. The values of the
+    /// engines without lists are all strings.
+    fn list_range(&self, key: String, _start: i64, _stop: i64) -> Result<Vec<String>> {
+        match self.get(key.clone())? {
+            Some(_) => Err(KvsError::WrongType(key))?,
+            None => Ok(Vec::new()),
+        }
+    }
+    /// The length of the list of key, 0 if it is absent.
+    fn list_len(&self, key: String) -> Result<usize> {
+        match self.get(key.clone())? {
+            Some(_) => Err(KvsError::WrongType(key))?,
+            None => Ok(0),
+        }
+    }
 }
 
 impl KvsEngine for KvStore {
@@ -26,4 +285,214 @@ impl KvsEngine for KvStore {
     fn remove(&self, key: String) -> Result<()> {
         self.remove(key)
     }
+    fn count_prefix(&self, prefix: String) -> Result<usize> {
+        Ok(self.count_prefix(&prefix))
+    }
+    fn expire(&self, key: String, ttl: Duration) -> Result<bool> {
+        self.expire(key, ttl)
+    }
+    fn persist(&self, key: String) -> Result<bool> {
+        self.persist(key)
+    }
+    fn increment_float(&self, key: String, delta: f64) -> Result<f64> {
+        self.increment_float(key, delta)
+    }
+    fn engine_name(&self) -> &'static str {
+        "kvs"
+    }
+    fn increment_with_ttl(&self, key: String, delta: i64, ttl: Duration) -> Result<i64> {
+        self.increment_with_ttl(key, delta, ttl)
+    }
+    fn get_with_ttl(&self, key: String) -> Result<Option<(String, Option<Duration>)>> {
+        self.get_with_ttl(key)
+    }
+    fn get_and_expire(&self, key: String, ttl: Duration) -> Result<Option<String>> {
+        self.get_and_expire(key, ttl)
+    }
+    fn get_with_version(&self, key: String) -> Result<Option<(String, u64)>> {
+        self.get_with_version(key)
+    }
+    fn set_if_version(&self, key: String, value: String, expected: u64) -> Result<u64> {
+        self.set_if_version(key, value, expected)
+    }
+    fn set_created(&self, key: String, value: String) -> Result<bool> {
+        self.set_created(key, value)
+    }
+    fn set_if_present(&self, key: String, value: String) -> Result<bool> {
+        self.set_if_present(key, value)
+    }
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.set_if_absent(key, value)
+    }
+    fn rename_nx(&self, src: String, dst: String) -> Result<bool> {
+        self.rename_nx(src, dst)
+    }
+    fn copy(&self, src: String, dst: String, replace: bool) -> Result<bool> {
+        self.copy(src, dst, replace)
+    }
+    fn meta(&self, key: String) -> Result<Option<KeyMeta>> {
+        self.meta(key)
+    }
+    fn random_key(&self) -> Result<Option<String>> {
+        Ok(self.random_key())
+    }
+    fn swap(&self, a: String, b: String) -> Result<()> {
+        self.swap(a, b)
+    }
+    fn keys(&self) -> Result<Vec<String>> {
+        Ok(self.keys())
+    }
+    fn keys_matching(&self, pattern: &str) -> Result<Vec<String>> {
+        Ok(self.keys_matching(pattern))
+    }
+    fn intersect_prefixes(&self, a: String, b: String) -> Result<Vec<String>> {
+        Ok(self.intersect_prefixes(&a, &b))
+    }
+    fn remove_prefix(&self, prefix: String) -> Result<usize> {
+        self.remove_prefix(&prefix)
+    }
+    fn purge_expired(&self) -> Result<usize> {
+        self.purge_expired()
+    }
+    fn scan_value(&self, prefix: String, substring: String) -> Result<Vec<(String, String)>> {
+        self.retain_scan(&prefix, |_, val| val.contains(substring.as_str()))
+    }
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.set_many(pairs)
+    }
+    fn set_many_get(&self, pairs: Vec<(String, String)>) -> Result<Vec<Option<String>>> {
+        self.set_many_get(pairs)
+    }
+    fn touch(&self, key: String) -> Result<bool> {
+        self.touch(key)
+    }
+    fn multi_get(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        self.multi_get(keys)
+    }
+    fn get_range(&self, key: String, start: i64, end: i64) -> Result<Option<String>> {
+        self.get_range(key, start, end)
+    }
+    fn get_capped(&self, key: String, max: usize) -> Result<Option<(String, bool)>> {
+        self.get_capped(key, max)
+    }
+    fn flush(&self) -> Result<()> {
+        self.flush()
+    }
+    fn pause_compaction(&self) -> Result<()> {
+        self.pause_compaction();
+        Ok(())
+    }
+    fn resume_compaction(&self) -> Result<()> {
+        self.resume_compaction();
+        Ok(())
+    }
+    fn set_quota(&self, namespace: &str, quota: Quota) -> Result<()> {
+        self.set_quota(namespace, quota)
+    }
+    fn namespace_usage(&self, namespace: &str) -> Result<(NamespaceUsage, Quota)> {
+        self.namespace_usage(namespace)
+    }
+    fn replicate(&self, id: &str, offset: u64, sink: ReplSink) -> Result<ReplSync> {
+        self.replicate(id, offset, sink)
+    }
+    fn ack_replica(&self, replica: &str, offset: u64) -> Result<()> {
+        self.ack_replica(replica, offset)
+    }
+    fn forget_replica(&self, replica: &str) {
+        self.forget_replica(replica)
+    }
+    fn io_stats(&self) -> Result<IoStats> {
+        Ok(self.io_stats())
+    }
+    fn size_histogram(&self) -> Result<SizeHistogram> {
+        Ok(self.size_histogram())
+    }
+    fn config(&self) -> Vec<(&'static str, String)> {
+        self.config()
+    }
+    fn hot_keys(&self, top_n: usize) -> Result<Vec<(String, usize)>> {
+        Ok(self.hot_keys(top_n))
+    }
+    fn push_front(&self, key: String, value: String) -> Result<usize> {
+        self.push_front(key, value)
+    }
+    fn push_back(&self, key: String, value: String) -> Result<usize> {
+        self.push_back(key, value)
+    }
+    fn list_range(&self, key: String, start: i64, stop: i64) -> Result<Vec<String>> {
+        self.list_range(key, start, stop)
+    }
+    fn list_len(&self, key: String) -> Result<usize> {
+        self.list_len(key)
+    }
+}
+
+/// Parse the value of key as a finite float.
+pub(crate) fn parse_float(key: &str, val: &str) -> Result<f64> {
+    match val.parse::<f64>() {
+        Ok(x) if x.is_finite() => Ok(x),
+        _ => Err(KvsError::NotFloat(key.to_owned()))?,
+    }
+}
+
+/// Parse the value of key as a 64-bit integer.
+pub(crate) fn parse_integer(key: &str, val: &str) -> Result<i64> {
+    match val.parse::<i64>() {
+        Ok(n) => Ok(n),
+        Err(_) => Err(KvsError::NotInteger(key.to_owned()))?,
+    }
+}
+
+/// The bytes `start..=end` of `val`, a negative index counting from the
+/// end, clamped like `GETRANGE` of redis.
+pub(crate) fn byte_range(val: &[u8], start: i64, end: i64) -> &[u8] {
+    index_range(val, start, end)
+}
+
+/// The items `start..=end` of `val`, clamped like `byte_range`.
+pub(crate) fn index_range<T>(val: &[T], start: i64, end: i64) -> &[T] {
+    let len = val.len() as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let end = if end < 0 {
+        (len + end).max(0)
+    } else {
+        end.min(len - 1)
+    };
+    if len == 0 || start > end {
+        &[]
+    } else {
+        &val[start as usize..=end as usize]
+    }
+}
+
+/// The longest prefix of the UTF-8 `val` of at most `max` bytes that
+/// doesn't cut a character, and whether it is shorter than `val`.
+pub(crate) fn cap_utf8(val: &[u8], max: usize) -> (String, bool) {
+    let head = &val[..val.len().min(max)];
+    let valid = match std::str::from_utf8(head) {
+        Ok(_) => head.len(),
+        Err(e) => e.valid_up_to(),
+    };
+    (
+        String::from_utf8_lossy(&head[..valid]).into_owned(),
+        val.len() > max,
+    )
+}
+
+/// Format a float in plain decimal notation.
+///
+/// The shortest representation that parses back to the same value is used,
+/// without exponent and trailing zeros, e.g. `3`, `0.1`, `1000000000000000000000`.
+/// So a value stored and incremented again never changes its format.
+pub(crate) fn format_float(x: f64) -> String {
+    if x == 0.0 {
+        // Avoid "-0".
+        "0".to_owned()
+    } else {
+        x.to_string()
+    }
 }
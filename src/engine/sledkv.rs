@@ -7,7 +7,10 @@ pub use sled::{Db, Tree};
 use std::fs;
 use std::path::Path;
 use std::string::String;
+use std::time::Duration;
 
+use crate::engine::kvstore::Meta;
+use crate::engine::{format_float, parse_float};
 use crate::{KvsEngine, KvsError, Result};
 
 #[derive(Clone)]
@@ -19,12 +22,20 @@ impl SledDb {
         if metapath.is_dir() {
             return Err(format_err!("{:?} is dir", metapath));
         } else if metapath.is_file() {
-            let meta = String::from_utf8_lossy(&fs::read(&metapath)?).into_owned();
-            if meta != "sled" {
-                return Err(format_err!("invalid metadata {:?}: {}", metapath, meta));
+            let text = String::from_utf8_lossy(&fs::read(&metapath)?).into_owned();
+            match Meta::parse(&text) {
+                Some(ref meta) if meta.engine == "sled" => {}
+                Some(meta) => {
+                    return Err(format_err!(
+                        "invalid metadata {:?}: {}",
+                        metapath,
+                        meta.engine
+                    ))
+                }
+                None => return Err(KvsError::MetaCorrupt(metapath))?,
             }
         } else {
-            fs::write(metapath, "sled")?;
+            fs::write(metapath, Meta::new("sled").render())?;
         }
         Ok(Self(Db::start_default(path)?))
     }
@@ -37,6 +48,16 @@ impl KvsEngine for SledDb {
         self.0.flush()?;
         Ok(())
     }
+    /// Set all the pairs and flush once. Unlike `KvStore::set_many`, the
+    /// write is not atomic: sled 0.24 has no batches, so a failure leaves
+    /// the pairs before it set.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            Tree::set(&self.0, key.as_bytes(), value.as_bytes())?;
+        }
+        self.0.flush()?;
+        Ok(())
+    }
     /// Get key.
     fn get(&self, key: String) -> Result<Option<String>> {
         Ok(Tree::get(&self.0, key.as_bytes())?.map(|v| String::from_utf8_lossy(&v).to_string()))
@@ -44,10 +65,75 @@ impl KvsEngine for SledDb {
 
     /// Remove key.
     fn remove(&self, key: String) -> Result<()> {
-        if None == self.0.del(key.clone())? {
+        if self.0.del(key.clone())?.is_none() {
             Err(KvsError::KeyNotFound(key))?;
         }
         self.0.flush()?;
         Ok(())
     }
+
+    /// Every write is already flushed.
+    fn flush(&self) -> Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+
+    /// Count keys with the prefix.
+    fn count_prefix(&self, prefix: String) -> Result<usize> {
+        let mut n = 0;
+        for item in self.0.scan(prefix.as_bytes()) {
+            let (key, _) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    /// Keys never expire in sled.
+    fn expire(&self, _key: String, _ttl: Duration) -> Result<bool> {
+        Err(format_err!("EXPIRE is not supported by sled"))
+    }
+
+    /// Keys never expire in sled, so none has a TTL.
+    fn persist(&self, _key: String) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Add to a float value, retry if the value changed concurrently.
+    fn increment_float(&self, key: String, delta: f64) -> Result<f64> {
+        loop {
+            let old = self.0.get(key.as_bytes())?;
+            let cur = match old {
+                Some(ref v) => parse_float(&key, &String::from_utf8_lossy(v))?,
+                None => 0.0,
+            };
+            let new = cur + delta;
+            if !new.is_finite() {
+                return Err(KvsError::FloatOverflow(key))?;
+            }
+            let val = format_float(new);
+            let swapped = self.0.cas(key.as_bytes(), old, Some(val.as_bytes()))?;
+            if swapped.is_ok() {
+                self.0.flush()?;
+                return Ok(new);
+            }
+        }
+    }
+
+    /// All keys, in key order.
+    fn keys(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for item in self.0.iter() {
+            let (key, _) = item?;
+            keys.push(String::from_utf8_lossy(&key).into_owned());
+        }
+        Ok(keys)
+    }
+
+    /// Name of the engine.
+    fn engine_name(&self) -> &'static str {
+        "sled"
+    }
 }
@@ -0,0 +1,89 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Category of a failed request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The request can't be decoded, e.g. a malformed frame.
+    Decode,
+    /// A well-framed command the server doesn't know.
+    UnknownCommand,
+    /// A known command with missing, extra or invalid arguments.
+    BadArgs,
+    /// The engine failed the command.
+    Engine,
+    /// The thread pool refused the job.
+    Busy,
+    /// The reply can't be sent.
+    Send,
+}
+
+/// Number of failed requests by category, see `KvsServer::errors`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ErrorCounts {
+    pub decode: usize,
+    pub unknown_command: usize,
+    pub bad_args: usize,
+    /// Including the expected failures, e.g. removing a missing key.
+    pub engine: usize,
+    pub busy: usize,
+    pub send: usize,
+}
+
+impl ErrorCounts {
+    /// One line per category, `errors_<category>=<count>`, as in `INFO`.
+    pub fn lines(&self) -> String {
+        let counts = [
+            ("decode", self.decode),
+            ("unknown_command", self.unknown_command),
+            ("bad_args", self.bad_args),
+            ("engine", self.engine),
+            ("busy", self.busy),
+            ("send", self.send),
+        ];
+        let mut res = String::new();
+        for (name, n) in counts.iter() {
+            res += &format!("errors_{}={}\n", name, n);
+        }
+        res
+    }
+}
+
+/// Counters of the failed requests since the server was created.
+#[derive(Default)]
+pub struct ErrorCounters {
+    decode: AtomicUsize,
+    unknown_command: AtomicUsize,
+    bad_args: AtomicUsize,
+    engine: AtomicUsize,
+    busy: AtomicUsize,
+    send: AtomicUsize,
+}
+
+impl ErrorCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn count(&self, kind: ErrorKind) {
+        let counter = match kind {
+            ErrorKind::Decode => &self.decode,
+            ErrorKind::UnknownCommand => &self.unknown_command,
+            ErrorKind::BadArgs => &self.bad_args,
+            ErrorKind::Engine => &self.engine,
+            ErrorKind::Busy => &self.busy,
+            ErrorKind::Send => &self.send,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> ErrorCounts {
+        ErrorCounts {
+            decode: self.decode.load(Ordering::Relaxed),
+            unknown_command: self.unknown_command.load(Ordering::Relaxed),
+            bad_args: self.bad_args.load(Ordering::Relaxed),
+            engine: self.engine.load(Ordering::Relaxed),
+            busy: self.busy.load(Ordering::Relaxed),
+            send: self.send.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -7,23 +7,60 @@ extern crate failure;
 extern crate slog_stdlog;
 
 pub use failure::Error;
-use slog::{Drain, Logger};
+#[cfg(not(feature = "no-logging"))]
+use slog::Drain;
+use slog::Logger;
 
 mod client;
+mod connections;
+pub mod daemon;
+mod dedup;
 mod engine;
+mod errors;
+mod log_level;
+mod msgpack;
+mod notify;
 mod protocol;
+mod replica;
 mod server;
+mod sharded;
+#[cfg(feature = "testing")]
+pub mod testing;
 pub mod thread_pool;
+mod trace;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-pub use client::KvsClient;
-pub use engine::kvstore::{Error as KvsError, KvStore as RealKvStore};
+#[cfg(feature = "async-client")]
+pub use client::{AsyncKvsClient, ClientError};
+pub use client::{CachingClient, KvsClient, KEY_NOT_FOUND};
+#[cfg(feature = "bincode")]
+pub use engine::kvstore::BincodeCodec;
+pub use engine::kvstore::{
+    Command, CompactionEvent, CompactionHealth, CompactionScheduler, DbInfo, Error as KvsError,
+    EvictionPolicy, IndexHasher, IoStats, JsonCodec, KeyMeta, KvStore as RealKvStore,
+    KvStoreBuilder, NamespaceUsage, OpenStatus, Quota, RecordCodec, ReplSink, ReplSync,
+    SizeHistogram, ValueKind, FORMAT_VERSION,
+};
 pub use engine::sledkv::SledDb;
 pub use engine::{KvStore, KvsEngine};
+pub use errors::ErrorCounts;
+pub use log_level::{level_name, parse_level, LevelSwitch, LogLevel};
+pub use msgpack::MsgPackCodec;
+pub use protocol::{Proto, ProtoCodec, ProtoError};
+pub use replica::Follower;
 pub use server::KvsServer;
+pub use sharded::ShardedClient;
 
+#[cfg(not(feature = "no-logging"))]
 fn get_logger(opt: &mut Option<Logger>) -> Logger {
     opt.take()
         .unwrap_or_else(|| Logger::root(slog_stdlog::StdLog.fuse(), o!()))
 }
+
+#[cfg(feature = "no-logging")]
+fn get_logger(opt: &mut Option<Logger>) -> Logger {
+    // The log calls are compiled out, so no record reaches the drain.
+    opt.take()
+        .unwrap_or_else(|| Logger::root(slog::Discard, o!()))
+}
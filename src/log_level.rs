@@ -0,0 +1,91 @@
+use slog::{Drain, Level, OwnedKVList, Record};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// A drain passing the records up to a level that can be changed at
+/// runtime through its `LogLevel`, e.g. by the `LOGLEVEL` command.
+///
+/// The check is a relaxed atomic load, so the logging path takes no lock.
+/// The levels above the static maximum of slog are compiled out and can't
+/// be turned on.
+pub struct LevelSwitch<D> {
+    drain: D,
+    level: LogLevel,
+}
+
+impl<D: Drain> LevelSwitch<D> {
+    /// Pass the records up to `level` to `drain`, return the drain and the
+    /// handle changing the level.
+    pub fn new(drain: D, level: Level) -> (Self, LogLevel) {
+        let handle = LogLevel(Arc::new(AtomicUsize::new(level.as_usize())));
+        let switch = LevelSwitch {
+            drain,
+            level: handle.clone(),
+        };
+        (switch, handle)
+    }
+}
+
+impl<D: Drain> Drain for LevelSwitch<D> {
+    type Ok = Option<D::Ok>;
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        if self.level.passes(record.level()) {
+            self.drain.log(record, values).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn is_enabled(&self, level: Level) -> bool {
+        self.level.passes(level) && self.drain.is_enabled(level)
+    }
+}
+
+/// The level of a `LevelSwitch`, shared by its clones.
+#[derive(Clone, Debug)]
+pub struct LogLevel(Arc<AtomicUsize>);
+
+impl LogLevel {
+    pub fn get(&self) -> Level {
+        Level::from_usize(self.0.load(Ordering::Relaxed)).unwrap_or(Level::Critical)
+    }
+
+    /// Takes effect for the records logged afterwards, on every thread.
+    pub fn set(&self, level: Level) {
+        self.0.store(level.as_usize(), Ordering::Relaxed);
+    }
+
+    fn passes(&self, level: Level) -> bool {
+        level.as_usize() <= self.0.load(Ordering::Relaxed)
+    }
+}
+
+const LEVELS: [(&str, Level); 6] = [
+    ("critical", Level::Critical),
+    ("error", Level::Error),
+    ("warning", Level::Warning),
+    ("info", Level::Info),
+    ("debug", Level::Debug),
+    ("trace", Level::Trace),
+];
+
+/// Parse a level name, e.g. "debug", in any case.
+pub fn parse_level(name: &str) -> Result<Level, String> {
+    let lower = name.to_lowercase();
+    LEVELS
+        .iter()
+        .find(|(n, _)| *n == lower)
+        .map(|(_, level)| *level)
+        .ok_or_else(|| format!("invalid log level: {}", name))
+}
+
+/// The name of `level` accepted by `parse_level`.
+pub fn level_name(level: Level) -> &'static str {
+    LEVELS
+        .iter()
+        .find(|(_, l)| *l == level)
+        .map_or("critical", |(name, _)| *name)
+}
@@ -0,0 +1,238 @@
+//! Replies as length-delimited msgpack frames, see `HELLO` in the server.
+
+extern crate bytes;
+extern crate tokio;
+
+use bytes::{BufMut, BytesMut};
+use tokio::codec::{Decoder, Encoder};
+
+use std::str;
+
+use crate::protocol::{Proto, ProtoError, MAX_BULK_LEN, MAX_DEPTH};
+use crate::{Error, Result};
+
+/// Length of the frame header, the big-endian length of the value.
+const HEADER_LEN: usize = 4;
+
+/// Codec of `Proto` as msgpack values, each framed by its length as a
+/// 4-byte big-endian integer.
+///
+/// `Str` is a msgpack str, `Bulk` a bin, `Int` an int, `Null` nil and
+/// `Seq` an array. `Err` is a map of one pair, `{"error": <message>}`, as
+/// msgpack has no error type.
+#[derive(Default)]
+pub struct MsgPackCodec;
+
+impl MsgPackCodec {
+    pub fn new() -> Self {
+        MsgPackCodec
+    }
+}
+
+impl Decoder for MsgPackCodec {
+    type Item = Proto;
+    type Error = Error;
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Proto>> {
+        if buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let len = read_uint(&buf[..HEADER_LEN]) as usize;
+        if len > MAX_BULK_LEN {
+            return Err(ProtoError::TooLong(len))?;
+        }
+        if buf.len() < HEADER_LEN + len {
+            buf.reserve(HEADER_LEN + len - buf.len());
+            return Ok(None);
+        }
+        buf.advance(HEADER_LEN);
+        let frame = buf.split_to(len);
+        let mut rdr = Reader {
+            buf: &frame,
+            pos: 0,
+        };
+        let item = rdr.value(0)?;
+        if rdr.pos != frame.len() {
+            return Err(ProtoError::InvalidFrame("bytes after the value"))?;
+        }
+        Ok(Some(item))
+    }
+}
+
+impl Encoder for MsgPackCodec {
+    type Item = Proto;
+    type Error = Error;
+    fn encode(&mut self, item: Proto, dst: &mut BytesMut) -> Result<()> {
+        let mut value = Vec::new();
+        write_value(&item, &mut value);
+        dst.reserve(HEADER_LEN + value.len());
+        dst.put_u32_be(value.len() as u32);
+        dst.extend_from_slice(&value);
+        Ok(())
+    }
+}
+
+fn write_value(item: &Proto, out: &mut Vec<u8>) {
+    match item {
+        Proto::Null => out.push(0xc0),
+        Proto::Int(n) if *n >= 0 && *n < 0x80 => out.push(*n as u8),
+        Proto::Int(n) if *n < 0 && *n >= -32 => out.push(*n as u8),
+        Proto::Int(n) => {
+            out.push(0xd3);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+        Proto::Str(s) => write_str(s, out),
+        Proto::Bulk(v) => {
+            match v.len() {
+                n if n < 1 << 8 => out.extend_from_slice(&[0xc4, n as u8]),
+                n if n < 1 << 16 => {
+                    out.push(0xc5);
+                    out.extend_from_slice(&(n as u16).to_be_bytes());
+                }
+                n => {
+                    out.push(0xc6);
+                    out.extend_from_slice(&(n as u32).to_be_bytes());
+                }
+            }
+            out.extend_from_slice(v);
+        }
+        Proto::Err(e) => {
+            out.push(0x81);
+            write_str("error", out);
+            write_str(e, out);
+        }
+        Proto::Seq(items) => {
+            match items.len() {
+                n if n < 16 => out.push(0x90 | n as u8),
+                n if n < 1 << 16 => {
+                    out.push(0xdc);
+                    out.extend_from_slice(&(n as u16).to_be_bytes());
+                }
+                n => {
+                    out.push(0xdd);
+                    out.extend_from_slice(&(n as u32).to_be_bytes());
+                }
+            }
+            for item in items {
+                write_value(item, out);
+            }
+        }
+    }
+}
+
+fn write_str(s: &str, out: &mut Vec<u8>) {
+    match s.len() {
+        n if n < 32 => out.push(0xa0 | n as u8),
+        n if n < 1 << 8 => out.extend_from_slice(&[0xd9, n as u8]),
+        n if n < 1 << 16 => {
+            out.push(0xda);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        n => {
+            out.push(0xdb);
+            out.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// The big-endian unsigned integer of up to 8 bytes.
+fn read_uint(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |n, b| n << 8 | u64::from(*b))
+}
+
+/// Reads the value of a whole frame.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() - self.pos < n {
+            return Err(ProtoError::InvalidFrame("frame ends inside a value"))?;
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn uint(&mut self, n: usize) -> Result<u64> {
+        Ok(read_uint(self.take(n)?))
+    }
+
+    fn int(&mut self, n: usize) -> Result<i64> {
+        // Sign-extend the big-endian integer of `n` bytes.
+        let shift = 64 - 8 * n as u32;
+        Ok(((self.uint(n)? << shift) as i64) >> shift)
+    }
+
+    fn string(&mut self, len: usize) -> Result<String> {
+        Ok(str::from_utf8(self.take(len)?)?.to_owned())
+    }
+
+    /// The value at `pos`, inside `depth` arrays or maps.
+    fn value(&mut self, depth: usize) -> Result<Proto> {
+        if depth > MAX_DEPTH {
+            return Err(ProtoError::TooDeep)?;
+        }
+        let x = self.take(1)?[0];
+        Ok(match x {
+            0x00..=0x7f => Proto::Int(i64::from(x)),
+            0xe0..=0xff => Proto::Int(i64::from(x as i8)),
+            0xc0 => Proto::Null,
+            0xcc => Proto::Int(self.uint(1)? as i64),
+            0xcd => Proto::Int(self.uint(2)? as i64),
+            0xce => Proto::Int(self.uint(4)? as i64),
+            0xcf => match self.uint(8)? {
+                n if n > i64::MAX as u64 => {
+                    return Err(ProtoError::InvalidFrame("integer out of range"))?;
+                }
+                n => Proto::Int(n as i64),
+            },
+            0xd0 => Proto::Int(self.int(1)?),
+            0xd1 => Proto::Int(self.int(2)?),
+            0xd2 => Proto::Int(self.int(4)?),
+            0xd3 => Proto::Int(self.int(8)?),
+            0xa0..=0xbf => Proto::Str(self.string(usize::from(x & 0x1f))?),
+            0xd9 => Proto::Str(self.sized(1, Self::string)?),
+            0xda => Proto::Str(self.sized(2, Self::string)?),
+            0xdb => Proto::Str(self.sized(4, Self::string)?),
+            0xc4 => Proto::Bulk(self.sized(1, Self::bytes)?),
+            0xc5 => Proto::Bulk(self.sized(2, Self::bytes)?),
+            0xc6 => Proto::Bulk(self.sized(4, Self::bytes)?),
+            0x90..=0x9f => self.array(usize::from(x & 0x0f), depth)?,
+            0xdc => {
+                let len = self.uint(2)? as usize;
+                self.array(len, depth)?
+            }
+            0xdd => {
+                let len = self.uint(4)? as usize;
+                self.array(len, depth)?
+            }
+            0x81 => match (self.value(depth + 1)?, self.value(depth + 1)?) {
+                (Proto::Str(ref key), Proto::Str(ref e)) if key == "error" => Proto::Err(e.clone()),
+                _ => return Err(ProtoError::InvalidFrame("map other than an error"))?,
+            },
+            x => return Err(ProtoError::InvalidPrefix(x))?,
+        })
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        Ok(Vec::from(self.take(len)?))
+    }
+
+    // Read a length of `n` bytes, then what `f` reads of that length.
+    fn sized<T>(&mut self, n: usize, f: fn(&mut Self, usize) -> Result<T>) -> Result<T> {
+        let len = self.uint(n)? as usize;
+        f(self, len)
+    }
+
+    fn array(&mut self, len: usize, depth: usize) -> Result<Proto> {
+        // Every value takes a byte at least, a bad length can't allocate more.
+        let mut items = Vec::with_capacity(len.min(self.buf.len() - self.pos));
+        for _ in 0..len {
+            items.push(self.value(depth + 1)?);
+        }
+        Ok(Proto::Seq(items))
+    }
+}
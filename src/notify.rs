@@ -0,0 +1,98 @@
+use futures::sync::mpsc::{self, Receiver, Sender};
+use futures::sync::oneshot;
+
+use std::sync::Mutex;
+
+use crate::protocol::Proto;
+
+// The prefix of each subscriber and its queue.
+type Subscribers = Vec<(String, Sender<Proto>)>;
+
+/// Key events pushed to the connections in `PSUBSCRIBE` mode.
+///
+/// Delivery is best-effort: an event is dropped for a subscriber whose
+/// queue is full, and nothing is kept for a subscriber that reconnects.
+pub struct Notifier {
+    // `None` once closed.
+    subs: Mutex<Option<Subscribers>>,
+    // Dropped on close, `None` once closed.
+    closers: Mutex<Option<Vec<oneshot::Sender<()>>>>,
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        Notifier {
+            subs: Mutex::new(Some(Vec::new())),
+            closers: Mutex::new(Some(Vec::new())),
+        }
+    }
+
+    /// Subscribe to the events of the keys starting with `prefix`.
+    /// The stream ends when the notifier is closed.
+    pub fn subscribe(&self, prefix: String) -> Receiver<Proto> {
+        let (tx, rx) = mpsc::channel(QUEUE_LEN);
+        if let Some(subs) = self.subs.lock().unwrap().as_mut() {
+            subs.push((prefix, tx));
+        }
+        rx
+    }
+
+    /// Resolve once the notifier is closed, e.g. to end other streams of
+    /// the connections along with the subscriptions.
+    pub fn closed(&self) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        if let Some(closers) = self.closers.lock().unwrap().as_mut() {
+            closers.retain(|tx| !tx.is_canceled());
+            closers.push(tx);
+        }
+        rx
+    }
+
+    /// Publish `event` of `key` as an array `[event, key]`.
+    /// Subscribers gone away are removed.
+    pub fn publish(&self, event: &str, key: &str) {
+        self.send(event, key, |sub| key.starts_with(sub));
+    }
+
+    /// Publish `event` of the keys starting with `prefix` as an array
+    /// `[event, prefix]`, to the subscribers of any of these keys.
+    pub fn publish_prefix(&self, event: &str, prefix: &str) {
+        self.send(event, prefix, |sub| {
+            prefix.starts_with(sub) || sub.starts_with(prefix)
+        });
+    }
+
+    // Send `[event, key]` to the subscribers whose prefix `matches`.
+    fn send(&self, event: &str, key: &str, matches: impl Fn(&str) -> bool) {
+        let mut subs = self.subs.lock().unwrap();
+        let subs = match subs.as_mut() {
+            Some(subs) => subs,
+            None => return,
+        };
+        let msg = Proto::Seq(vec![
+            Proto::Bulk(Vec::from(event)),
+            Proto::Bulk(Vec::from(key)),
+        ]);
+        let mut i = 0;
+        while i < subs.len() {
+            let (prefix, tx) = &mut subs[i];
+            if matches(prefix.as_str()) {
+                if let Err(e) = tx.try_send(msg.clone()) {
+                    if e.is_disconnected() {
+                        subs.swap_remove(i);
+                        continue;
+                    }
+                }
+            }
+            i += 1;
+        }
+    }
+
+    /// End all subscriptions, and refuse new ones.
+    pub fn close(&self) {
+        self.subs.lock().unwrap().take();
+        self.closers.lock().unwrap().take();
+    }
+}
+
+const QUEUE_LEN: usize = 1024;
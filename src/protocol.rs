@@ -11,9 +11,16 @@ use std::str;
 use crate::{Error, Result};
 
 const CRLF: &[u8; 2] = b"\r\n";
+/// Arrays nested deeper are rejected, a command is a flat array and a
+/// reply nests two deep. Dropping a deeper item could overflow the stack.
+pub(crate) const MAX_DEPTH: usize = 64;
+/// Longer bulks are rejected instead of buffered, as in redis.
+pub(crate) const MAX_BULK_LEN: usize = 512 << 20;
+/// Longer arrays are rejected by default, see `ProtoCodec::max_array_len`.
+pub(crate) const MAX_ARRAY_LEN: usize = 1 << 24;
 
 /// Proto
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Proto {
     /// Sequence
     Seq(Vec<Proto>),
@@ -23,76 +30,139 @@ pub enum Proto {
     Err(String),
     /// Binary
     Bulk(Vec<u8>),
+    /// Integer
+    Int(i64),
     /// Null
     Null,
 }
 
-pub enum ProtoCodec {
+/// Decoding state of a single item.
+enum State {
     Unknown,
     Str(usize),
     Err(usize),
+    Int(usize),
+    SeqLen(usize),
     BulkOrNull(usize),
     Bulk(usize),
 }
 
-impl ProtoCodec {
-    pub fn new() -> Self {
-        ProtoCodec::Unknown
-    }
-
-    fn dispatch(&self, x: u8) -> Result<Self> {
+impl State {
+    fn dispatch(x: u8) -> Result<Self> {
         Ok(match x {
-            b'+' => ProtoCodec::Str(0),
-            b'-' => ProtoCodec::Err(0),
-            b'$' => ProtoCodec::BulkOrNull(0),
+            b'+' => State::Str(0),
+            b'-' => State::Err(0),
+            b':' => State::Int(0),
+            b'*' => State::SeqLen(0),
+            b'$' => State::BulkOrNull(0),
             x => return Err(ProtoError::InvalidPrefix(x))?,
         })
     }
 }
 
-impl Decoder for ProtoCodec {
-    type Item = Proto;
-    type Error = Error;
-    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Proto>> {
+pub struct ProtoCodec {
+    state: State,
+    /// Arrays being decoded, with their declared length and decoded items.
+    seqs: Vec<(usize, Vec<Proto>)>,
+    max_array_len: usize,
+}
+
+impl Default for ProtoCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProtoCodec {
+    pub fn new() -> Self {
+        ProtoCodec {
+            state: State::Unknown,
+            seqs: Vec::new(),
+            max_array_len: MAX_ARRAY_LEN,
+        }
+    }
+
+    /// Reject the arrays declaring more than `max` items with
+    /// `ProtoError::ArrayTooLong`, 2^24 by default. The items
+    /// of an array are kept until the last one is decoded, so a peer
+    /// could otherwise hold the connection buffering a made-up length.
+    pub fn max_array_len(mut self, max: usize) -> Self {
+        self.max_array_len = max;
+        self
+    }
+
+    /// Decode the next item, an array header is not an item.
+    fn decode_item(&mut self, buf: &mut BytesMut) -> Result<Option<Proto>> {
         loop {
             if buf.is_empty() {
                 return Ok(None);
             }
-            match self {
-                ProtoCodec::Unknown => {
-                    *self = self.dispatch(buf.split_to(1)[0])?;
+            match self.state {
+                State::Unknown => {
+                    self.state = State::dispatch(buf.split_to(1)[0])?;
                 }
-                ProtoCodec::Str(ref mut offset) => {
+                State::Str(ref mut offset) => {
                     if let Some(s) = until_crlf(offset, buf)? {
-                        *self = ProtoCodec::Unknown;
+                        self.state = State::Unknown;
                         return Ok(Some(Proto::Str(s)));
                     } else {
                         return Ok(None);
                     }
                 }
-                ProtoCodec::Err(ref mut offset) => {
+                State::Err(ref mut offset) => {
                     if let Some(s) = until_crlf(offset, buf)? {
-                        *self = ProtoCodec::Unknown;
+                        self.state = State::Unknown;
                         return Ok(Some(Proto::Err(s)));
                     } else {
                         return Ok(None);
                     }
                 }
-                ProtoCodec::BulkOrNull(ref mut offset) => {
+                State::Int(ref mut offset) => {
+                    if let Some(s) = until_crlf(offset, buf)? {
+                        self.state = State::Unknown;
+                        return Ok(Some(Proto::Int(s.parse()?)));
+                    } else {
+                        return Ok(None);
+                    }
+                }
+                State::SeqLen(ref mut offset) => {
                     if let Some(s) = until_crlf(offset, buf)? {
+                        self.state = State::Unknown;
                         let len: isize = s.parse()?;
                         if len <= -1 {
-                            *self = ProtoCodec::Unknown;
                             return Ok(Some(Proto::Null));
+                        } else if len == 0 {
+                            return Ok(Some(Proto::Seq(Vec::new())));
+                        }
+                        if len as usize > self.max_array_len {
+                            return Err(ProtoError::ArrayTooLong(len as usize))?;
                         }
-                        *self = ProtoCodec::Bulk(len as usize);
+                        if self.seqs.len() >= MAX_DEPTH {
+                            return Err(ProtoError::TooDeep)?;
+                        }
+                        self.seqs.push((len as usize, Vec::new()));
                     } else {
                         return Ok(None);
                     }
                 }
-                &mut ProtoCodec::Bulk(len) => {
+                State::BulkOrNull(ref mut offset) => {
+                    if let Some(s) = until_crlf(offset, buf)? {
+                        let len: isize = s.parse()?;
+                        if len <= -1 {
+                            self.state = State::Unknown;
+                            return Ok(Some(Proto::Null));
+                        }
+                        if len as usize > MAX_BULK_LEN {
+                            return Err(ProtoError::TooLong(len as usize))?;
+                        }
+                        self.state = State::Bulk(len as usize);
+                    } else {
+                        return Ok(None);
+                    }
+                }
+                State::Bulk(len) => {
                     if let Some(v) = until_len_crlf(len, buf)? {
-                        *self = ProtoCodec::Unknown;
+                        self.state = State::Unknown;
                         return Ok(Some(Proto::Bulk(v)));
                     } else {
                         return Ok(None);
@@ -101,19 +171,53 @@ impl Decoder for ProtoCodec {
             }
         }
     }
+
+    /// Put the item into the innermost array.
+    /// Return the item when it is not inside any unfinished array.
+    fn collect(&mut self, mut item: Proto) -> Option<Proto> {
+        loop {
+            match self.seqs.last_mut() {
+                None => return Some(item),
+                Some((len, items)) => {
+                    items.push(item);
+                    if items.len() < *len {
+                        return None;
+                    }
+                }
+            }
+            let (_, items) = self.seqs.pop().unwrap();
+            item = Proto::Seq(items);
+        }
+    }
+}
+
+impl Decoder for ProtoCodec {
+    type Item = Proto;
+    type Error = Error;
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Proto>> {
+        while let Some(item) = self.decode_item(buf)? {
+            if let Some(item) = self.collect(item) {
+                return Ok(Some(item));
+            }
+        }
+        Ok(None)
+    }
 }
 
 impl Encoder for ProtoCodec {
     type Item = Proto;
     type Error = Error;
     fn encode(&mut self, item: Proto, dst: &mut BytesMut) -> Result<()> {
-        dst.extend_from_slice(&item.ser());
+        dst.extend_from_slice(&item.ser()?);
         Ok(())
     }
 }
 
+/// Split off a line ended by CRLF.
+/// `offset` is how far the buffer was already searched, the line starts at 0.
 fn until_crlf(offset: &mut usize, buf: &mut BytesMut) -> Result<Option<String>> {
-    if let Some(idx) = buf[*offset..].iter().position(|b| *b == b'\n') {
+    if let Some(pos) = buf[*offset..].iter().position(|b| *b == b'\n') {
+        let idx = *offset + pos;
         let s = buf.split_to(idx + 1);
         *offset = 0;
         if s.len() < 2 || s[idx - 1] != b'\r' {
@@ -143,17 +247,27 @@ fn until_len_crlf(len: usize, buf: &mut BytesMut) -> Result<Option<Vec<u8>>> {
 }
 
 impl Proto {
-    /// Str and Err should not contain CR or LF.
-    pub fn ser(&self) -> Vec<u8> {
+    /// Build an error reply from any message.
+    /// CR and LF are replaced by spaces so the reply is always a valid line.
+    pub fn error(e: impl Display) -> Proto {
+        Proto::Err(e.to_string().replace(&['\r', '\n'][..], " "))
+    }
+
+    /// Str and Err must not contain CR or LF, otherwise `InvalidLine` is returned.
+    pub fn ser(&self) -> Result<Vec<u8>> {
         let mut res = Vec::new();
         match self {
             Proto::Str(s) => {
                 res.push(b'+');
-                res.extend_from_slice(s.as_bytes());
+                res.extend_from_slice(check_line(s)?);
             }
             Proto::Err(e) => {
                 res.push(b'-');
-                res.extend_from_slice(e.to_string().as_bytes());
+                res.extend_from_slice(check_line(e)?);
+            }
+            Proto::Int(n) => {
+                res.push(b':');
+                res.extend_from_slice(n.to_string().as_bytes());
             }
             Proto::Bulk(s) => {
                 res.push(b'$');
@@ -163,17 +277,28 @@ impl Proto {
                 res.extend_from_slice(s);
             }
             Proto::Null => {
-                return Vec::from("$-1\r\n");
+                return Ok(Vec::from("$-1\r\n"));
             }
             Proto::Seq(v) => {
-                return v.iter().fold(Vec::new(), |mut acc, x| {
-                    acc.append(&mut x.ser());
-                    acc
-                });
+                res.push(b'*');
+                res.extend_from_slice(v.len().to_string().as_bytes());
+                res.extend_from_slice(CRLF);
+                for x in v.iter() {
+                    res.append(&mut x.ser()?);
+                }
+                return Ok(res);
             }
         }
         res.extend_from_slice(CRLF);
-        res
+        Ok(res)
+    }
+}
+
+fn check_line(s: &str) -> Result<&[u8]> {
+    if s.contains(&['\r', '\n'][..]) {
+        Err(ProtoError::InvalidLine(s.to_owned()))?
+    } else {
+        Ok(s.as_bytes())
     }
 }
 
@@ -184,6 +309,16 @@ pub enum ProtoError {
     InvalidPrefix(u8),
     UnexpectedLF,
     InvalidBulk(Vec<u8>),
+    /// CR or LF in a Str or Err
+    InvalidLine(String),
+    /// Arrays nested deeper than `MAX_DEPTH`
+    TooDeep,
+    /// Bulk longer than `MAX_BULK_LEN`
+    TooLong(usize),
+    /// Array longer than the `max_array_len` of the codec
+    ArrayTooLong(usize),
+    /// A msgpack frame that isn't one `Proto`
+    InvalidFrame(&'static str),
 }
 
 impl Display for ProtoError {
@@ -192,6 +327,11 @@ impl Display for ProtoError {
             ProtoError::InvalidPrefix(x) => write!(f, "invalid prefix: {:x?}", x),
             ProtoError::UnexpectedLF => write!(f, "unexpected '\\n'"),
             ProtoError::InvalidBulk(u) => write!(f, "invalid bulk: {:?}", u),
+            ProtoError::InvalidLine(s) => write!(f, "CR or LF in line: {:?}", s),
+            ProtoError::TooDeep => write!(f, "arrays nested deeper than {}", MAX_DEPTH),
+            ProtoError::TooLong(len) => write!(f, "bulk too long: {} bytes", len),
+            ProtoError::ArrayTooLong(len) => write!(f, "array too long: {} items", len),
+            ProtoError::InvalidFrame(e) => write!(f, "invalid msgpack frame: {}", e),
         }
     }
 }
@@ -0,0 +1,240 @@
+extern crate tokio;
+
+use slog::Logger;
+use tokio::codec::Framed;
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+use std::net::SocketAddr;
+
+use crate::engine::kvstore::{Command, KvStore};
+use crate::get_logger;
+use crate::protocol::{Proto, ProtoCodec};
+
+/// Replication id of a follower that never synced.
+const NO_ID: &str = "?";
+
+/// A follower copying the writes of a primary server into a local store,
+/// with `PSYNC`. The primary needs a replication backlog, see
+/// `KvStoreBuilder::replication_backlog`.
+///
+/// The follower remembers its position in the history of the primary:
+/// after a lost connection, `run` continues from there if the primary still
+/// has the records, otherwise it resyncs in full, dropping the local pairs.
+/// The records are applied with `KvStore::apply_command`, keeping their
+/// version and timestamps, while the pairs of a full resync get new ones.
+/// The local store should take no other writes.
+///
+/// Each applied record, and the end of a full resync, is acknowledged with
+/// `REPLACK <offset>`, the offset of the next record, for the primaries
+/// waiting for their followers, see `KvStoreBuilder::min_replicas_ack`.
+pub struct Follower {
+    primary: SocketAddr,
+    store: KvStore,
+    log: Logger,
+    id: String,
+    /// Offset of the next record.
+    offset: u64,
+    /// The id of a full resync whose pairs are being copied.
+    resync: Option<String>,
+}
+
+impl Follower {
+    pub fn new<LG>(primary: SocketAddr, store: KvStore, log: LG) -> Self
+    where
+        LG: Into<Option<Logger>>,
+    {
+        let log = get_logger(&mut log.into());
+        Follower {
+            primary,
+            store,
+            log,
+            id: NO_ID.to_owned(),
+            offset: 0,
+            resync: None,
+        }
+    }
+
+    /// The replication id and the offset of the next record, the id is "?"
+    /// until a full resync completes.
+    pub fn position(&self) -> (&str, u64) {
+        (&self.id, self.offset)
+    }
+
+    /// Follow the primary until it closes the connection, e.g. at shutdown
+    /// or when the follower is too slow, then call `run` again to continue.
+    ///
+    /// Fail with 1 if the primary can't be reached, 2 if it refuses to sync,
+    /// 3 for a broken stream and 4 if the local store fails.
+    pub fn run(&mut self) -> Result<(), i32> {
+        let primary = self.primary;
+        let log = self.log.clone();
+        let sock = TcpStream::connect(&primary).wait().map_err(|e| {
+            crit!(log, "failed to connect {}: {}", primary, e);
+            1
+        })?;
+        let req = Proto::Seq(vec![
+            Proto::Str("PSYNC".to_owned()),
+            Proto::Bulk(Vec::from(self.id.as_str())),
+            Proto::Bulk(Vec::from(self.offset.to_string())),
+        ]);
+        let frame = Framed::new(sock, ProtoCodec::new())
+            .send(req)
+            .wait()
+            .map_err(|e| {
+                crit!(log, "failed to send command: {}", e);
+                1
+            })?;
+
+        let (mut acks, msgs) = frame.split();
+        let mut msgs = Stream::wait(msgs);
+        match msgs.next() {
+            Some(Ok(Proto::Seq(header))) => self.start(header)?,
+            Some(Ok(Proto::Err(e))) => {
+                error!(log, "primary refused to sync: {}", e);
+                return Err(2);
+            }
+            Some(Ok(rep)) => {
+                crit!(log, "unexpected reply: {:?}", rep);
+                return Err(3);
+            }
+            Some(Err(e)) => {
+                crit!(log, "failed to decode reply: {}", e);
+                return Err(3);
+            }
+            None => {
+                crit!(log, "empty reply");
+                return Err(3);
+            }
+        }
+        for msg in msgs {
+            let offset = self.offset;
+            match msg {
+                Ok(msg) => self.apply(msg)?,
+                Err(e) => {
+                    crit!(log, "failed to decode record: {}", e);
+                    return Err(3);
+                }
+            }
+            if self.resync.is_none() && self.offset != offset {
+                let ack = Proto::Seq(vec![
+                    Proto::Str("REPLACK".to_owned()),
+                    Proto::Bulk(Vec::from(self.offset.to_string())),
+                ]);
+                acks = acks.send(ack).wait().map_err(|e| {
+                    crit!(log, "failed to acknowledge: {}", e);
+                    3
+                })?;
+            }
+        }
+        info!(log, "primary closed the stream"; "id" => &self.id, "offset" => self.offset);
+        Ok(())
+    }
+
+    /// Handle the header of the stream, `[kind, id, offset]`.
+    fn start(&mut self, header: Vec<Proto>) -> Result<(), i32> {
+        let mut header = header.into_iter();
+        let (kind, id, offset) = match (header.next(), header.next(), header.next()) {
+            (Some(Proto::Bulk(kind)), Some(Proto::Bulk(id)), Some(Proto::Int(offset)))
+                if offset >= 0 =>
+            {
+                (
+                    kind,
+                    String::from_utf8_lossy(&id).into_owned(),
+                    offset as u64,
+                )
+            }
+            header => {
+                crit!(self.log, "bad replication header: {:?}", header);
+                return Err(3);
+            }
+        };
+        match kind.as_slice() {
+            b"CONTINUE" if id == self.id && offset == self.offset => {
+                info!(self.log, "continuing replication"; "id" => id, "offset" => offset);
+                Ok(())
+            }
+            b"FULLRESYNC" => {
+                warn!(self.log, "full resync"; "id" => &id, "offset" => offset);
+                // Invalid until the pairs are copied.
+                self.id = NO_ID.to_owned();
+                self.resync = Some(id);
+                self.store.remove_prefix("").map_err(|e| {
+                    crit!(self.log, "failed to clear the store: {}", e);
+                    4
+                })?;
+                Ok(())
+            }
+            _ => {
+                crit!(self.log, "bad replication header";
+                    "kind" => String::from_utf8_lossy(&kind).into_owned(),
+                    "id" => id, "offset" => offset);
+                Err(3)
+            }
+        }
+    }
+
+    /// Apply a message of the stream: a pair `[Null, key, value]` of a full
+    /// resync, its end `[offset]`, or a record `[offset, record]`.
+    fn apply(&mut self, msg: Proto) -> Result<(), i32> {
+        let log = self.log.clone();
+        let store_err = move |e: crate::Error| {
+            crit!(log, "failed to apply: {}", e);
+            4
+        };
+        let parts = match msg {
+            Proto::Seq(parts) => parts,
+            Proto::Err(e) => {
+                error!(self.log, "primary failed the stream: {}", e);
+                return Err(3);
+            }
+            msg => {
+                crit!(self.log, "unexpected message: {:?}", msg);
+                return Err(3);
+            }
+        };
+        let mut parts = parts.into_iter();
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(Proto::Null), Some(Proto::Bulk(key)), Some(Proto::Bulk(val)), None)
+                if self.resync.is_some() =>
+            {
+                match (String::from_utf8(key), String::from_utf8(val)) {
+                    (Ok(key), Ok(val)) => self.store.set(key, val).map_err(store_err),
+                    _ => {
+                        crit!(self.log, "pair is not UTF-8");
+                        Err(3)
+                    }
+                }
+            }
+            (Some(Proto::Int(offset)), None, None, None) if offset >= 0 => {
+                match self.resync.take() {
+                    Some(id) => {
+                        info!(self.log, "resync done"; "id" => &id, "offset" => offset);
+                        self.id = id;
+                        self.offset = offset as u64;
+                        Ok(())
+                    }
+                    None => {
+                        crit!(self.log, "end of a resync not started");
+                        Err(3)
+                    }
+                }
+            }
+            (Some(Proto::Int(offset)), Some(Proto::Bulk(record)), None, None)
+                if self.resync.is_none() && offset as u64 == self.offset =>
+            {
+                let cmd = Command::from_slice(&record).map_err(|e| {
+                    crit!(self.log, "bad record: {}", e);
+                    3
+                })?;
+                self.store.apply_command(cmd).map_err(store_err)?;
+                self.offset += 1;
+                Ok(())
+            }
+            parts => {
+                crit!(self.log, "unexpected message: {:?}", parts);
+                Err(3)
+            }
+        }
+    }
+}
@@ -1,32 +1,73 @@
+extern crate net2;
 extern crate tokio;
+extern crate tokio_signal;
 
-use future::FutureResult;
-use tokio::codec::{FramedRead, FramedWrite};
+use arc_swap::ArcSwap;
+use bytes::BytesMut;
+use future::{Either, FutureResult};
+use futures::sync::{mpsc, oneshot};
+use net2::TcpBuilder;
+use tokio::codec::{Encoder, FramedRead, FramedWrite};
 use tokio::io::ReadHalf;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::prelude::*;
+use tokio::reactor::Handle;
 use tokio::runtime::Runtime;
-use tokio::sync::oneshot;
+use tokio::timer::Delay;
 
 use std::fmt::Display;
+use std::io;
+use std::mem;
 use std::net::{self, SocketAddr};
-use std::str;
 use std::string::String;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::vec;
 
+use crate::connections::Connections;
+use crate::dedup::Dedup;
+use crate::engine::{format_float, glob_match};
+use crate::errors::{ErrorCounters, ErrorCounts, ErrorKind};
 use crate::get_logger;
+use crate::log_level::{level_name, parse_level, LogLevel};
+use crate::msgpack::MsgPackCodec;
+use crate::notify::Notifier;
 use crate::protocol::{Proto, ProtoCodec};
 use crate::slog::Logger;
 use crate::thread_pool::ThreadPool;
-use crate::KvsEngine;
+use crate::trace::RequestTrace;
+use crate::{KeyMeta, KvsEngine, NamespaceUsage, Quota, ReplSync};
 
 pub struct KvsServer<EG: KvsEngine, TP: ThreadPool> {
-    store: EG,
+    // Loaded by each connection, replaced by `swap_store`.
+    store: Arc<ArcSwap<EG>>,
     pool: TP,
     stop: Arc<AtomicBool>,
     addr: SocketAddr,
     log: Logger,
+    dedup: Arc<Dedup<Reply>>,
+    conns: Arc<Connections>,
+    notify: Arc<Notifier>,
+    errors: Arc<ErrorCounters>,
+    log_level: Option<LogLevel>,
+    backlog: i32,
+    nodelay: bool,
+    signals: bool,
+    accept_backoff: Duration,
+    max_array_len: usize,
+    started: Instant,
+    bound: Arc<(Mutex<Bound>, Condvar)>,
+}
+
+// Where the server listens, set by `start` and waited for by
+// `spawn_background`.
+#[derive(Clone, Copy)]
+enum Bound {
+    Pending,
+    Addr(SocketAddr),
+    Failed,
 }
 
 impl<EG: KvsEngine, TP: ThreadPool> Clone for KvsServer<EG, TP> {
@@ -37,6 +78,18 @@ impl<EG: KvsEngine, TP: ThreadPool> Clone for KvsServer<EG, TP> {
             stop: self.stop.clone(),
             addr: self.addr,
             log: self.log.clone(),
+            dedup: self.dedup.clone(),
+            conns: self.conns.clone(),
+            notify: self.notify.clone(),
+            errors: self.errors.clone(),
+            log_level: self.log_level.clone(),
+            backlog: self.backlog,
+            nodelay: self.nodelay,
+            signals: self.signals,
+            accept_backoff: self.accept_backoff,
+            max_array_len: self.max_array_len,
+            started: self.started,
+            bound: self.bound.clone(),
         }
     }
 }
@@ -48,42 +101,237 @@ impl<EG: KvsEngine, TP: ThreadPool> KvsServer<EG, TP> {
     {
         let log = get_logger(&mut log.into());
         Self {
-            store,
+            store: Arc::new(ArcSwap::from_pointee(store)),
             pool,
             stop: Arc::new(AtomicBool::new(false)),
             addr,
             log,
+            dedup: Arc::new(Dedup::new(DEDUP_WINDOW)),
+            conns: Arc::new(Connections::new()),
+            notify: Arc::new(Notifier::new()),
+            errors: Arc::new(ErrorCounters::new()),
+            log_level: None,
+            backlog: BACKLOG,
+            nodelay: false,
+            signals: false,
+            accept_backoff: ACCEPT_BACKOFF,
+            max_array_len: MAX_ARGS,
+            started: Instant::now(),
+            bound: Arc::new((Mutex::new(Bound::Pending), Condvar::new())),
         }
     }
 
+    /// Set the length of the queue of pending connections, 1024 by default.
+    /// The OS may cap it, e.g. by `net.core.somaxconn` on Linux.
+    pub fn backlog(mut self, backlog: i32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Set `TCP_NODELAY` on accepted connections, off by default.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Set the longest pause after failed accepts, 1 second by default.
+    ///
+    /// A failed accept, e.g. for lack of file descriptors, is logged and
+    /// the next one waits 5 milliseconds, twice as long after each failure
+    /// in a row up to `max`, and not at all after a success. So a lasting
+    /// failure neither spins nor floods the log. `Duration::from_secs(0)`
+    /// retries at once.
+    pub fn accept_backoff(mut self, max: Duration) -> Self {
+        self.accept_backoff = max;
+        self
+    }
+
+    /// Set the most arguments of a command, 2^20 by default. A command
+    /// declaring more gets an error and its connection is closed, before
+    /// its arguments are read.
+    pub fn max_array_len(mut self, max: usize) -> Self {
+        self.max_array_len = max;
+        self
+    }
+
+    /// Shut down gracefully on SIGTERM or SIGINT (Ctrl-C on Windows),
+    /// off by default. See `shutdown_graceful`.
+    pub fn signals(mut self, signals: bool) -> Self {
+        self.signals = signals;
+        self
+    }
+
+    /// Set how long the reply of a `SET ... ID <token>` is remembered.
+    ///
+    /// A request repeating a token within the window after the first one
+    /// completed gets the first reply and is not executed again.
+    /// A repeat while the first one is still running gets an error,
+    /// so the client should retry it later.
+    /// Tokens are kept in memory only, a restart forgets them.
+    pub fn dedup_window(mut self, window: Duration) -> Self {
+        self.dedup = Arc::new(Dedup::new(window));
+        self
+    }
+
+    /// Let the `LOGLEVEL` command read and change `level`, the handle of a
+    /// `LevelSwitch` in the drain of the server's logger. The command fails
+    /// without it.
+    pub fn log_level(mut self, level: LogLevel) -> Self {
+        self.log_level = Some(level);
+        self
+    }
+
+    /// Point the server at `store`, e.g. a freshly imported data directory.
+    ///
+    /// Requests arriving afterwards use `store`, requests in flight finish
+    /// with the old engine, which is closed once they are done.
+    /// This is a coarse swap, not a live migration: nothing is copied
+    /// between the engines, and writes to the old one in flight are not
+    /// seen by the new one.
+    pub fn swap_store(&self, store: EG) {
+        let new = store.engine_name();
+        let old = self.store.swap(Arc::new(store));
+        warn!(self.log, "swapped store"; "old" => old.engine_name(), "new" => new);
+    }
+
+    /// The failed requests by category since the server was created, also
+    /// reported by the `INFO` command.
+    pub fn errors(&self) -> ErrorCounts {
+        self.errors.get()
+    }
+
+    /// The address the server listens on once `start` bound it, e.g. with
+    /// the port the OS picked for port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match *self.bound.0.lock().unwrap() {
+            Bound::Addr(addr) => Some(addr),
+            Bound::Pending | Bound::Failed => None,
+        }
+    }
+
+    fn set_bound(&self, bound: Bound) {
+        let (lock, cvar) = &*self.bound;
+        *lock.lock().unwrap() = bound;
+        cvar.notify_all();
+    }
+
+    fn store(&self) -> EG {
+        EG::clone(&self.store.load_full())
+    }
+
+    /// The settings of the server given to its builder methods, for
+    /// `CONFIG GET`, durations in milliseconds.
+    fn config(&self, store: &EG) -> Vec<(&'static str, String)> {
+        let accept_backoff = self.accept_backoff.as_millis() as u64;
+        let dedup_window = self.dedup.window().as_millis() as u64;
+        let mut config = vec![("engine", store.engine_name().to_owned())];
+        if let Some(threads) = self.pool.threads() {
+            config.push(("threads", threads.to_string()));
+        }
+        config.extend(vec![
+            ("backlog", self.backlog.to_string()),
+            ("nodelay", self.nodelay.to_string()),
+            ("signals", self.signals.to_string()),
+            ("accept_backoff", accept_backoff.to_string()),
+            ("max_array_len", self.max_array_len.to_string()),
+            ("dedup_window", dedup_window.to_string()),
+        ]);
+        config
+    }
+
     pub fn run(&self) -> Result<(), i32> {
         let server = self.start();
+        let server: Box<dyn Future<Item = (), Error = i32> + Send> = if self.signals {
+            Box::new(self.until_signal(server))
+        } else {
+            server
+        };
         let mut rt = Runtime::new().unwrap();
         let res = rt.block_on(server);
         rt.shutdown_on_idle().wait().unwrap();
         res
     }
 
+    /// Run the server on a thread of its own, return once it accepts
+    /// connections, at `local_addr`. The thread returns the result of `run`.
+    ///
+    /// Fails with the code of `run` if the server can't listen.
+    pub fn spawn_background(&self) -> Result<JoinHandle<Result<(), i32>>, i32> {
+        let runner = self.clone();
+        let handle = thread::spawn(move || runner.run());
+        let (lock, cvar) = &*self.bound;
+        let mut bound = lock.lock().unwrap();
+        while let Bound::Pending = *bound {
+            bound = cvar.wait(bound).unwrap();
+        }
+        match *bound {
+            Bound::Addr(_) => Ok(handle),
+            _ => {
+                drop(bound);
+                Err(handle.join().ok().and_then(Result::err).unwrap_or(1))
+            }
+        }
+    }
+
+    /// Run `server`, shut it down gracefully when a signal arrives.
+    fn until_signal<F>(&self, server: F) -> impl Future<Item = (), Error = i32>
+    where
+        F: Future<Item = (), Error = i32>,
+    {
+        let this = self.clone();
+        let log = self.log.clone();
+        let signal = future::lazy(shutdown_signal).then(move |res| {
+            match res {
+                Ok(sig) => {
+                    warn!(this.log, "received {}", sig);
+                    this.shutdown_graceful();
+                }
+                Err(e) => error!(this.log, "failed to wait for signals: {}", e),
+            }
+            Ok::<(), ()>(())
+        });
+        server.select2(signal).then(move |res| match res {
+            Ok(Either::A(((), _))) => Either::A(future::ok(())),
+            Err(Either::A((e, _))) => Either::A(future::err(e)),
+            Ok(Either::B(((), server))) | Err(Either::B(((), server))) => {
+                Either::B(server.map(move |()| warn!(log, "server stopped")))
+            }
+        })
+    }
+
     pub fn start(&self) -> Box<dyn Future<Item = (), Error = i32> + Send + 'static> {
         let log1 = self.log.clone();
         let stop = self.stop.clone();
         let this = self.clone();
-        let listener = match TcpListener::bind(&self.addr) {
+        let max_backoff = self.accept_backoff;
+        // Failed accepts in a row.
+        let mut failures: u32 = 0;
+        let (listener, addr) = match self.listen() {
             Ok(x) => x,
             Err(e) => {
                 crit!(self.log, "failed to listen the the {}: {}", self.addr, e);
+                self.set_bound(Bound::Failed);
                 return Box::new(future::err(1));
             }
         };
+        self.set_bound(Bound::Addr(addr));
+        info!(self.log, "listening on {}", addr; "engine" => self.store().engine_name());
         Box::new(
             listener
                 .incoming()
                 .take_while(move |_| future::ok(!stop.load(Ordering::SeqCst)))
                 .then(move |res| match res {
-                    Ok(sock) => Ok(Some(sock)),
+                    Ok(sock) => {
+                        failures = 0;
+                        Either::A(future::ok(Some(sock)))
+                    }
                     Err(e) => {
-                        error!(log1, "bad stream: {}", e);
-                        Ok(None)
+                        failures = failures.saturating_add(1);
+                        let backoff = accept_backoff(failures, max_backoff);
+                        error!(log1, "bad stream: {}", e;
+                            "failures" => failures, "backoff_ms" => backoff.as_millis() as u64);
+                        let resume = Delay::new(Instant::now() + backoff);
+                        Either::B(resume.then(|_| Ok(None)))
                     }
                 })
                 .filter_map(|opt| opt)
@@ -94,6 +342,19 @@ impl<EG: KvsEngine, TP: ThreadPool> KvsServer<EG, TP> {
         )
     }
 
+    fn listen(&self) -> io::Result<(TcpListener, SocketAddr)> {
+        let builder = match self.addr {
+            SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+            SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+        };
+        let listener = builder
+            .reuse_address(true)?
+            .bind(self.addr)?
+            .listen(self.backlog)?;
+        let addr = listener.local_addr()?;
+        Ok((TcpListener::from_std(listener, &Handle::default())?, addr))
+    }
+
     pub fn process(&self, sock: TcpStream) -> FutureResult<(), ()> {
         let peer = match sock.peer_addr() {
             Ok(addr) => addr,
@@ -102,60 +363,1104 @@ impl<EG: KvsEngine, TP: ThreadPool> KvsServer<EG, TP> {
                 return future::ok(());
             }
         };
+        if self.nodelay {
+            if let Err(e) = sock.set_nodelay(true) {
+                error!(self.log, "failed to set nodelay for {}: {}", peer, e);
+            }
+        }
 
-        let log = self.log.new(o!("client" => peer.to_string()));
-        let store = self.store.clone();
+        let store = self.store();
+        let log = self.log.new(o!(
+            "client" => peer.to_string(),
+            "engine" => store.engine_name(),
+        ));
         let pool = self.pool.clone();
+        let dedup = self.dedup.clone();
+        let conns = self.conns.clone();
+        let version = format!(
+            "version={} engine={} uptime={}",
+            env!("CARGO_PKG_VERSION"),
+            store.engine_name(),
+            self.started.elapsed().as_secs()
+        );
+        let config = self.config(&store);
+        let log_level = self.log_level.clone();
+        let log1 = log.clone();
+        let notify1 = self.notify.clone();
+        let notify2 = self.notify.clone();
+        let closed = self.notify.closed();
+        let errors1 = self.errors.clone();
+        let errors2 = self.errors.clone();
+        let errors3 = self.errors.clone();
+        // The records of `PSYNC`, from the answer to the send stage.
+        let repl1 = Arc::new(Mutex::new(None));
+        let repl2 = repl1.clone();
+        // The requests after `PSYNC`, the acknowledgments of the follower.
+        let acks1 = Arc::new(Mutex::new(None));
+        let acks2 = acks1.clone();
+        let store1 = store.clone();
+        let log2 = log.clone();
+        let mut trace = RequestTrace::new(peer);
+        let guard = Connections::register(&self.conns, peer);
+        // The encoding asked by `HELLO`, for the replies after its own.
+        let hello1 = Arc::new(Mutex::new(None));
+        let hello2 = hello1.clone();
         let (rdr, wtr) = sock.split();
-        let wtr = FramedWrite::new(wtr, ProtoCodec::new());
+        let wtr = FramedWrite::new(wtr, ReplyCodec::new());
 
         tokio::spawn(
-            ReqFuture::new(rdr)
+            ReqFuture::new(rdr, self.max_array_len)
                 .into_future()
-                .map_err(|(e, _)| e)
-                .and_then(|(req, _)| req.ok_or_else(|| "empty request".to_owned()))
-                .and_then(move |req| {
-                    EngineFuture::new(req.clone(), store, pool).map(|rep| (req, rep))
+                .and_then(move |(req, rest)| match req {
+                    // The handshake, the command of the connection follows.
+                    Some(Request::Hello(encoding)) => {
+                        *hello1.lock().unwrap() = Some(encoding);
+                        Either::A(rest.into_future())
+                    }
+                    req => Either::B(future::ok((req, rest))),
+                })
+                .then(move |res| match res {
+                    Ok((req, rest)) => {
+                        if let Some(Request::PSync(..)) = req {
+                            *acks1.lock().unwrap() = Some(rest);
+                        }
+                        req.ok_or_else(|| "empty request".to_owned())
+                    }
+                    Err((e, _)) => {
+                        if unknown_command(&e) {
+                            errors1.count(ErrorKind::UnknownCommand);
+                        } else {
+                            errors1.count(ErrorKind::Decode);
+                        }
+                        Ok(Request::Malformed(e))
+                    }
+                })
+                .map(move |req| {
+                    trace.decoded(req.name(), req.key());
+                    (req, trace)
+                })
+                .and_then(move |(req, trace)| match req {
+                    Request::Clients => {
+                        let rep = Reply::G(Ok(Some(conns.list())));
+                        Either::A(future::ok((req, rep)))
+                    }
+                    Request::PSubscribe(_) => Either::A(future::ok((req, Reply::SR(Ok(()))))),
+                    Request::PSync(ref id, offset) => {
+                        let rep = match replicate(store, pool, id, offset) {
+                            Ok((header, records)) => {
+                                *repl1.lock().unwrap() = Some((records, closed));
+                                Reply::Repl(Ok(header))
+                            }
+                            Err((kind, e)) => {
+                                errors2.count(kind);
+                                Reply::Repl(Err(e))
+                            }
+                        };
+                        Either::A(future::ok((req, rep)))
+                    }
+                    Request::ReplAck(_) => {
+                        errors2.count(ErrorKind::BadArgs);
+                        let e = "REPLACK is only sent by followers after PSYNC".to_owned();
+                        Either::A(future::ok((req, Reply::SR(Err(e)))))
+                    }
+                    Request::Quit => Either::A(future::ok((req, Reply::Bye))),
+                    Request::Hello(_) => {
+                        errors2.count(ErrorKind::BadArgs);
+                        let e = "HELLO must be the first command of a connection".to_owned();
+                        Either::A(future::ok((req, Reply::SR(Err(e)))))
+                    }
+                    Request::Version => Either::A(future::ok((req, Reply::G(Ok(Some(version)))))),
+                    Request::Info => {
+                        let mut info = errors2.get().lines();
+                        // Only some engines count their IO.
+                        if let Ok(io) = store.io_stats() {
+                            info.push_str(&io.lines());
+                        }
+                        if let Ok(hist) = store.size_histogram() {
+                            info.push_str(&hist.lines());
+                        }
+                        let rep = Reply::G(Ok(Some(info)));
+                        Either::A(future::ok((req, rep)))
+                    }
+                    Request::ConfigGet(ref pattern) => {
+                        let mut params = config;
+                        if let Some(ref level) = log_level {
+                            params.push(("log_level", level_name(level.get()).to_owned()));
+                        }
+                        params.extend(store.config());
+                        let pattern = pattern.to_lowercase();
+                        let pairs = params
+                            .into_iter()
+                            .filter(|(name, _)| glob_match(&pattern, name))
+                            .map(|(name, val)| (name.to_owned(), val))
+                            .collect();
+                        Either::A(future::ok((req, Reply::KV(Ok(pairs)))))
+                    }
+                    Request::LogLevel(ref level) => {
+                        let rep = change_level(log_level.as_ref(), level.as_ref(), &log1);
+                        if rep.is_err() {
+                            errors2.count(ErrorKind::BadArgs);
+                        }
+                        Either::A(future::ok((req, rep)))
+                    }
+                    // Counted when decoded.
+                    Request::Malformed(ref e) => {
+                        let rep = Reply::SR(Err(e.clone()));
+                        Either::A(future::ok((req, rep)))
+                    }
+                    Request::Invalid(ref e) => {
+                        if unknown_command(e) {
+                            errors2.count(ErrorKind::UnknownCommand);
+                        } else {
+                            errors2.count(ErrorKind::BadArgs);
+                        }
+                        let rep = Reply::SR(Err(e.clone()));
+                        Either::A(future::ok((req, rep)))
+                    }
+                    _ => {
+                        let engine = EngineFuture::new(req.clone(), store, pool, dedup, trace);
+                        let busy = engine.busy;
+                        if busy {
+                            errors2.count(ErrorKind::Busy);
+                        }
+                        let errors = errors2.clone();
+                        Either::B(
+                            engine
+                                .map_err(move |e| {
+                                    errors.count(ErrorKind::Engine);
+                                    e
+                                })
+                                .map(move |rep| {
+                                    if !busy && rep.is_err() {
+                                        errors2.count(ErrorKind::Engine);
+                                    }
+                                    publish_writes(&notify1, &req, &rep);
+                                    (req, rep)
+                                }),
+                        )
+                    }
+                })
+                .and_then(|(req, resp)| {
+                    let resp = match resp {
+                        Reply::SR(Ok(())) => Ok(Proto::Str("".to_owned())),
+                        Reply::SR(Err(e)) => Ok(Proto::error(e)),
+                        Reply::G(Ok(Some(val))) => Ok(Proto::Bulk(Vec::from(val))),
+                        Reply::G(Ok(None)) => Ok(Proto::Null),
+                        Reply::G(Err(e)) => Ok(Proto::error(e)),
+                        Reply::N(Ok(n)) => Ok(Proto::Int(n as i64)),
+                        Reply::N(Err(e)) => Ok(Proto::error(e)),
+                        Reply::I(Ok(n)) => Ok(Proto::Int(n)),
+                        Reply::I(Err(e)) => Ok(Proto::error(e)),
+                        Reply::F(Ok(x)) => Ok(Proto::Bulk(Vec::from(format_float(x)))),
+                        Reply::F(Err(e)) => Ok(Proto::error(e)),
+                        Reply::GV(Ok(Some((val, ver)))) => Ok(Proto::Seq(vec![
+                            Proto::Bulk(Vec::from(val)),
+                            Proto::Int(ver as i64),
+                        ])),
+                        Reply::GV(Ok(None)) => Ok(Proto::Null),
+                        Reply::GV(Err(e)) => Ok(Proto::error(e)),
+                        // The TTL in milliseconds, -1 for a key that never expires.
+                        Reply::GT(Ok(Some((val, ttl)))) => Ok(Proto::Seq(vec![
+                            Proto::Bulk(Vec::from(val)),
+                            Proto::Int(ttl.map_or(-1, |ttl| ttl.as_millis() as i64)),
+                        ])),
+                        Reply::GT(Ok(None)) => Ok(Proto::Null),
+                        Reply::GT(Err(e)) => Ok(Proto::error(e)),
+                        Reply::Ver(Ok(ver)) => Ok(Proto::Int(ver as i64)),
+                        Reply::Ver(Err(e)) => Ok(Proto::error(e)),
+                        Reply::B(Ok(b)) => Ok(Proto::Int(b as i64)),
+                        Reply::B(Err(e)) => Ok(Proto::error(e)),
+                        // Creation and update time in milliseconds, size and version.
+                        Reply::M(Ok(Some(meta))) => Ok(Proto::Seq(vec![
+                            millis(meta.created_at),
+                            millis(meta.updated_at),
+                            Proto::Int(meta.size as i64),
+                            Proto::Int(meta.version as i64),
+                        ])),
+                        Reply::M(Ok(None)) => Ok(Proto::Null),
+                        Reply::M(Err(e)) => Ok(Proto::error(e)),
+                        // Keys and values alternate.
+                        Reply::KV(Ok(pairs)) => Ok(Proto::Seq(
+                            pairs
+                                .into_iter()
+                                .flat_map(|(key, val)| {
+                                    vec![Proto::Bulk(Vec::from(key)), Proto::Bulk(Vec::from(val))]
+                                })
+                                .collect(),
+                        )),
+                        Reply::KV(Err(e)) => Ok(Proto::error(e)),
+                        // Keys and bytes alternate.
+                        Reply::HK(Ok(keys)) => Ok(Proto::Seq(
+                            keys.into_iter()
+                                .flat_map(|(key, bytes)| {
+                                    vec![Proto::Bulk(Vec::from(key)), Proto::Int(bytes as i64)]
+                                })
+                                .collect(),
+                        )),
+                        Reply::HK(Err(e)) => Ok(Proto::error(e)),
+                        Reply::GS(Ok(vals)) => Ok(Proto::Seq(
+                            vals.into_iter()
+                                .map(|val| match val {
+                                    Some(val) => Proto::Bulk(Vec::from(val)),
+                                    None => Proto::Null,
+                                })
+                                .collect(),
+                        )),
+                        Reply::GS(Err(e)) => Ok(Proto::error(e)),
+                        // An error in place of each failed operation.
+                        Reply::Batch(outcomes) => Ok(Proto::Seq(
+                            outcomes
+                                .into_iter()
+                                .map(|outcome| match outcome {
+                                    Ok(b) => Proto::Int(b as i64),
+                                    Err(e) => Proto::error(e),
+                                })
+                                .collect(),
+                        )),
+                        // Usage, then the limits, `Null` for none.
+                        Reply::Q(Ok((usage, quota))) => {
+                            let limit = |max: Option<usize>| {
+                                max.map_or(Proto::Null, |n| Proto::Int(n as i64))
+                            };
+                            Ok(Proto::Seq(vec![
+                                Proto::Int(usage.keys as i64),
+                                Proto::Int(usage.bytes as i64),
+                                limit(quota.max_keys),
+                                limit(quota.max_bytes),
+                            ]))
+                        }
+                        Reply::Q(Err(e)) => Ok(Proto::error(e)),
+                        Reply::Repl(Ok((kind, id, offset))) => Ok(Proto::Seq(vec![
+                            Proto::Bulk(Vec::from(kind)),
+                            Proto::Bulk(Vec::from(id)),
+                            Proto::Int(offset as i64),
+                        ])),
+                        Reply::Repl(Err(e)) => Ok(Proto::error(e)),
+                        Reply::Bye => Ok(Proto::Str("OK".to_owned())),
+                    };
+                    resp.map(|resp| (req, resp))
                 })
-                .and_then(|(_req, resp)| match resp {
-                    Reply::SR(Ok(())) => Ok(Proto::Str("".to_owned())),
-                    Reply::SR(Err(e)) => Ok(Proto::Err(e)),
-                    Reply::G(Ok(Some(val))) => Ok(Proto::Bulk(Vec::from(val))),
-                    Reply::G(Ok(None)) => Ok(Proto::Null),
-                    Reply::G(Err(e)) => Ok(Proto::Err(e)),
+                .and_then(move |(req, resp)| {
+                    // Subscribe before the reply, so no event after it is missed.
+                    let sub: Option<MsgStream> = match req {
+                        Request::PSubscribe(prefix) => Some(Box::new(
+                            notify2
+                                .subscribe(prefix)
+                                .map_err(|()| "failed to receive event".to_owned()),
+                        )),
+                        // Ends with the subscriptions.
+                        Request::PSync(..) => {
+                            repl2.lock().unwrap().take().map(|(records, closed)| {
+                                let (done, stopped) = oneshot::channel::<()>();
+                                if let Some(reqs) = acks2.lock().unwrap().take() {
+                                    let replica = peer.to_string();
+                                    tokio::spawn(follow_acks(reqs, store1, replica, stopped, log2));
+                                }
+                                let closed = closed.into_stream().then(|_| Ok::<_, String>(None));
+                                let records = records
+                                    // Dropped with the stream, which stops
+                                    // reading the acknowledgments.
+                                    .inspect(move |_| {
+                                        let _ = &done;
+                                    })
+                                    .map(Some)
+                                    .select(closed)
+                                    .take_while(|msg| Ok(msg.is_some()))
+                                    .filter_map(|msg| msg);
+                                Box::new(records) as MsgStream
+                            })
+                        }
+                        _ => None,
+                    };
+                    // `HELLO` is answered in RESP, the replies after it as asked.
+                    let wtr = match hello2.lock().unwrap().take() {
+                        Some(encoding) => {
+                            Either::A(wtr.send(Proto::Str("OK".to_owned())).map(move |mut wtr| {
+                                wtr.encoder_mut().encoding = encoding;
+                                wtr
+                            }))
+                        }
+                        None => Either::B(future::ok(wtr)),
+                    };
+                    wtr.and_then(|wtr| wtr.send(resp))
+                        .map_err(move |e| {
+                            errors3.count(ErrorKind::Send);
+                            format!("failed to send reply: {}", e)
+                        })
+                        .map(|wtr| (wtr, sub))
                 })
-                .and_then(move |resp| {
-                    wtr.send(resp)
-                        .map_err(|e| format!("failed to send reply: {}", e))
+                .and_then(|(wtr, sub)| match sub {
+                    Some(msgs) => Either::A(
+                        msgs.forward(wtr.sink_map_err(|e| format!("failed to send event: {}", e)))
+                            .map(|_| ()),
+                    ),
+                    None => Either::B(future::ok(())),
                 })
                 .map_err(move |e| error!(log, "{}", e))
-                .map(|_| ()),
+                .then(move |_| {
+                    drop(guard);
+                    Ok(())
+                }),
         );
 
         future::ok(())
     }
 
+    /// Stop accepting connections, `run` returns once the requests in flight
+    /// are answered. A write reaches the data file before it is acknowledged,
+    /// so nothing is left to flush.
+    pub fn shutdown_graceful(&self) {
+        warn!(self.log, "shutting down, draining requests in flight");
+        self.shutdown();
+    }
+
+    /// Stop accepting connections and end the subscriptions.
     pub fn shutdown(&self) {
         self.stop.store(true, Ordering::SeqCst);
-        let _ = net::TcpStream::connect(self.addr);
+        self.notify.close();
+        // Wake up the accept loop, at the port the OS picked for port 0.
+        let _ = net::TcpStream::connect(self.local_addr().unwrap_or(self.addr));
     }
 }
 
+#[cfg(unix)]
+fn shutdown_signal() -> impl Future<Item = &'static str, Error = io::Error> {
+    use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+    let term = Signal::new(SIGTERM).flatten_stream().map(|_| "SIGTERM");
+    let int = Signal::new(SIGINT).flatten_stream().map(|_| "SIGINT");
+    term.select(int)
+        .into_future()
+        .map(|(sig, _)| sig.unwrap_or("end of signals"))
+        .map_err(|(e, _)| e)
+}
+
+#[cfg(not(unix))]
+fn shutdown_signal() -> impl Future<Item = &'static str, Error = io::Error> {
+    tokio_signal::ctrl_c()
+        .flatten_stream()
+        .into_future()
+        .map(|_| "Ctrl-C")
+        .map_err(|(e, _)| e)
+}
+
+/// Milliseconds since the Unix epoch, `Null` if unknown.
+fn millis(time: Option<SystemTime>) -> Proto {
+    match time.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+        Some(d) => Proto::Int(d.as_millis() as i64),
+        None => Proto::Null,
+    }
+}
+
+/// The pause after the `failures`-th failed accept in a row, see
+/// `KvsServer::accept_backoff`.
+fn accept_backoff(failures: u32, max: Duration) -> Duration {
+    // 5ms doubled 20 times is more than any sensible cap.
+    let backoff = ACCEPT_BACKOFF_MIN * (1 << (failures - 1).min(20));
+    backoff.min(max)
+}
+
+const DEDUP_WINDOW: Duration = Duration::from_secs(60);
+const BACKLOG: i32 = 1024;
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+const MAX_ARGS: usize = 1 << 20;
+/// Records queued for a follower before it is dropped.
+const REPL_QUEUE_LEN: usize = 1 << 14;
+
 type ClientR = FramedRead<ReadHalf<TcpStream>, ProtoCodec>;
 
+/// The encoding of the replies of a connection.
+///
+/// The commands are always in RESP. A connection may start with
+/// `HELLO MSGPACK` before its command: the server answers `+OK` in RESP,
+/// then every reply after it, the pushed messages included, is a
+/// length-delimited msgpack frame, see `MsgPackCodec`. `HELLO RESP` keeps
+/// the default. A server that doesn't know `HELLO` replies an error in
+/// RESP and closes the connection.
+#[derive(Clone, Copy, Debug)]
+enum Encoding {
+    Resp,
+    MsgPack,
+}
+
+/// Encodes the replies in the encoding of the connection.
+struct ReplyCodec {
+    encoding: Encoding,
+    resp: ProtoCodec,
+    msgpack: MsgPackCodec,
+}
+
+impl ReplyCodec {
+    fn new() -> Self {
+        ReplyCodec {
+            encoding: Encoding::Resp,
+            resp: ProtoCodec::new(),
+            msgpack: MsgPackCodec::new(),
+        }
+    }
+}
+
+impl Encoder for ReplyCodec {
+    type Item = Proto;
+    type Error = crate::Error;
+    fn encode(&mut self, item: Proto, dst: &mut BytesMut) -> crate::Result<()> {
+        match self.encoding {
+            Encoding::Resp => self.resp.encode(item, dst),
+            Encoding::MsgPack => self.msgpack.encode(item, dst),
+        }
+    }
+}
+/// Messages pushed to a connection after the reply.
+type MsgStream = Box<dyn Stream<Item = Proto, Error = String> + Send>;
+/// The header of the reply to `PSYNC`: `CONTINUE` or `FULLRESYNC`, the
+/// history id and the offset.
+type ReplHeader = (&'static str, String, u64);
+
+/// Subscribe a follower to the records of `store` from `offset` of the
+/// history `id`. Give the header of the reply, `CONTINUE` or `FULLRESYNC`
+/// with the id and the offset the records continue from, and the messages
+/// following it.
+///
+/// A record is sent as `[offset, record]`, a pair of a full resync as
+/// `[Null, key, value]`, and the end of the pairs as `[offset]`. A follower
+/// too slow to take the records is dropped and the stream ends, so it
+/// reconnects to continue from its offset.
+fn replicate<E, T>(
+    store: E,
+    pool: T,
+    id: &str,
+    offset: u64,
+) -> Result<(ReplHeader, MsgStream), (ErrorKind, String)>
+where
+    E: KvsEngine,
+    T: ThreadPool,
+{
+    let (mut tx, rx) = mpsc::channel(REPL_QUEUE_LEN);
+    let sink = Box::new(move |offset: u64, record: &Arc<str>| {
+        let msg = Proto::Seq(vec![
+            Proto::Int(offset as i64),
+            Proto::Bulk(Vec::from(record.as_bytes())),
+        ]);
+        tx.try_send(msg).is_ok()
+    });
+    let records = rx.map_err(|()| "failed to receive record".to_owned());
+    match store.replicate(id, offset, sink) {
+        Err(e) => Err((ErrorKind::Engine, e.to_string())),
+        Ok(ReplSync::Continue(backlog)) => {
+            let backlog = backlog.into_iter().map(|(offset, record)| {
+                Proto::Seq(vec![
+                    Proto::Int(offset as i64),
+                    Proto::Bulk(Vec::from(record.as_bytes())),
+                ])
+            });
+            let msgs = stream::iter_ok(backlog.collect::<Vec<_>>()).chain(records);
+            Ok((("CONTINUE", id.to_owned(), offset), Box::new(msgs)))
+        }
+        Ok(ReplSync::Full { id, offset }) => {
+            let (pairs_tx, pairs_rx) = mpsc::channel(REPL_QUEUE_LEN);
+            let job = move || match copy_pairs(&store, pairs_tx.clone()) {
+                Ok(Some(tx)) => {
+                    let _ = tx.send(Proto::Seq(vec![Proto::Int(offset as i64)])).wait();
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    let _ = pairs_tx.send(Proto::error(e.to_string())).wait();
+                }
+            };
+            if pool.try_spawn(job).is_err() {
+                let e = "server busy, try again later".to_owned();
+                return Err((ErrorKind::Busy, e));
+            }
+            let pairs = pairs_rx.map_err(|()| "failed to read pairs".to_owned());
+            Ok((("FULLRESYNC", id, offset), Box::new(pairs.chain(records))))
+        }
+    }
+}
+
+/// Record the acknowledgments `REPLACK <offset>` of the follower `replica`
+/// until it disconnects or its stream of records ends, signaled by
+/// `stopped`.
+fn follow_acks<E>(
+    reqs: ReqFuture,
+    store: E,
+    replica: String,
+    stopped: oneshot::Receiver<()>,
+    log: Logger,
+) -> impl Future<Item = (), Error = ()>
+where
+    E: KvsEngine,
+{
+    let store1 = store.clone();
+    let replica1 = replica.clone();
+    let acks = reqs.for_each(move |req| match req {
+        Request::ReplAck(offset) => store
+            .ack_replica(&replica, offset)
+            .map_err(|e| format!("failed to record acknowledgment: {}", e)),
+        req => Err(format!("unexpected command of a follower: {}", req.name())),
+    });
+    let stopped = stopped.then(|_| Ok::<_, String>(()));
+    acks.select(stopped).then(move |res| {
+        if let Err((e, _)) = res {
+            error!(log, "{}", e);
+        }
+        store1.forget_replica(&replica1);
+        Ok(())
+    })
+}
+
+/// Send the live pairs of `store` as `[Null, key, value]`, `None` if the
+/// connection is gone.
+fn copy_pairs<E>(
+    store: &E,
+    mut tx: mpsc::Sender<Proto>,
+) -> crate::Result<Option<mpsc::Sender<Proto>>>
+where
+    E: KvsEngine,
+{
+    for key in store.keys()? {
+        // Removed since listed.
+        let val = match store.get(key.clone())? {
+            Some(val) => val,
+            None => continue,
+        };
+        let msg = Proto::Seq(vec![
+            Proto::Null,
+            Proto::Bulk(Vec::from(key)),
+            Proto::Bulk(Vec::from(val)),
+        ]);
+        tx = match tx.send(msg).wait() {
+            Ok(tx) => tx,
+            Err(_) => return Ok(None),
+        };
+    }
+    Ok(Some(tx))
+}
+
 #[derive(Clone)]
 enum Request {
-    Set(String, String),
+    /// Key, value, idempotency token, expected version and whether the key
+    /// must exist (`XX`) or not (`NX`).
+    Set(String, String, Option<String>, Option<u64>, Option<bool>),
+    /// Key and value, replied with whether the key was created.
+    SetInfo(String, String),
     Get(String),
+    /// Key and the most bytes of the value to return.
+    GetCapped(String, usize),
+    /// Key and the first and last byte, negative from the end.
+    GetRange(String, i64, i64),
+    GetVer(String),
+    /// Key and the new TTL in milliseconds, if any.
+    GetEx(String, Option<u64>),
     Rm(String),
+    Count(String),
+    /// Key and its TTL in milliseconds.
+    Expire(String, u64),
+    /// Key whose TTL is removed.
+    Persist(String),
+    IncrByFloat(String, f64),
+    /// Key, delta and the TTL in milliseconds if the key is created.
+    IncrTtl(String, i64, u64),
+    /// Key, item and whether to push it at the front of the list.
+    Push(String, String, bool),
+    /// Key and the first and last item, negative from the end.
+    LRange(String, i64, i64),
+    LLen(String),
+    RenameNx(String, String),
+    /// Source, destination and whether to replace the destination.
+    Copy(String, String, bool),
+    Object(String),
+    RandomKey,
+    Swap(String, String),
+    DelPrefix(String),
+    PurgeExpired,
+    /// Prefix and substring of the values.
+    ScanValue(String, String),
+    /// Glob pattern of the keys.
+    Keys(String),
+    /// Prefixes of the two sets.
+    SInter(String, String),
+    /// The most keys to list.
+    HotKeys(usize),
+    /// Key-value pairs, set in one batch.
+    MSetGet(Vec<(String, String)>),
+    /// Key-value pairs, each set on its own if the key is absent.
+    MSetNx(Vec<(String, String)>),
+    /// Keys marked as just used.
+    Touch(Vec<String>),
+    /// Sync the writes done so far.
+    Wait,
+    /// Pause the background compactions if true, resume them otherwise.
+    Compact(bool),
+    /// Usage and quota of a namespace.
+    QuotaGet(String),
+    /// Namespace and its new quota.
+    QuotaSet(String, Quota),
+    Clients,
+    /// Answered with `OK`, then the connection is closed. A connection
+    /// serves a single command, commands pipelined after it are not read.
+    Quit,
+    /// The encoding of the replies, before the command of the connection.
+    Hello(Encoding),
+    Version,
+    /// Counters of the server, answered by the connection.
+    Info,
+    /// Read the log level of the server, or change it to the given one.
+    LogLevel(Option<String>),
+    /// Glob pattern of the names of the settings, answered by the
+    /// connection with the matching settings and their values.
+    ConfigGet(String),
+    /// Keep the connection open to receive the events of keys with the prefix.
+    PSubscribe(String),
+    /// Keep the connection open to stream the records to a follower, from
+    /// the offset of the replication id.
+    PSync(String, u64),
+    /// Sent by a follower after `PSYNC`: it applied the records before the
+    /// offset.
+    ReplAck(u64),
+    /// A well-framed command that can't be parsed, answered with the error.
+    Invalid(String),
+    /// A command that can't be decoded, answered with the error before the
+    /// connection is closed, as the stream is out of sync.
+    Malformed(String),
+}
+
+impl Request {
+    /// Name of the command.
+    fn name(&self) -> &'static str {
+        match self {
+            Request::Set(..) => "SET",
+            Request::SetInfo(..) => "SETINFO",
+            Request::Get(_) | Request::GetCapped(..) => "GET",
+            Request::GetRange(..) => "GETRANGE",
+            Request::GetVer(_) => "GETVER",
+            Request::GetEx(..) => "GETEX",
+            Request::Rm(_) => "RM",
+            Request::Count(_) => "COUNT",
+            Request::Expire(..) => "EXPIRE",
+            Request::Persist(_) => "PERSIST",
+            Request::IncrByFloat(..) => "INCRBYFLOAT",
+            Request::IncrTtl(..) => "INCRTTL",
+            Request::Push(_, _, true) => "LPUSH",
+            Request::Push(_, _, false) => "RPUSH",
+            Request::LRange(..) => "LRANGE",
+            Request::LLen(_) => "LLEN",
+            Request::RenameNx(..) => "RENAMENX",
+            Request::Copy(..) => "COPY",
+            Request::Object(_) => "OBJECT",
+            Request::RandomKey => "RANDOMKEY",
+            Request::Swap(..) => "SWAP",
+            Request::DelPrefix(_) => "DELPREFIX",
+            Request::PurgeExpired => "PURGEEXPIRED",
+            Request::ScanValue(..) => "SCANVALUE",
+            Request::Keys(_) => "KEYS",
+            Request::SInter(..) => "SINTER",
+            Request::HotKeys(_) => "HOTKEYS",
+            Request::MSetGet(_) => "MSETGET",
+            Request::MSetNx(_) => "MSETNX",
+            Request::Touch(_) => "TOUCH",
+            Request::Wait => "WAIT",
+            Request::Compact(_) => "COMPACT",
+            Request::QuotaGet(_) | Request::QuotaSet(..) => "QUOTA",
+            Request::Clients => "CLIENTS",
+            Request::Quit => "QUIT",
+            Request::Hello(_) => "HELLO",
+            Request::Version => "VERSION",
+            Request::Info => "INFO",
+            Request::LogLevel(_) => "LOGLEVEL",
+            Request::ConfigGet(_) => "CONFIG",
+            Request::PSubscribe(_) => "PSUBSCRIBE",
+            Request::PSync(..) => "PSYNC",
+            Request::ReplAck(_) => "REPLACK",
+            Request::Invalid(_) => "INVALID",
+            Request::Malformed(_) => "MALFORMED",
+        }
+    }
+
+    /// The first key or prefix of the command, if any.
+    fn key(&self) -> Option<&str> {
+        match self {
+            Request::Set(key, ..)
+            | Request::SetInfo(key, _)
+            | Request::Get(key)
+            | Request::GetCapped(key, _)
+            | Request::GetRange(key, ..)
+            | Request::GetVer(key)
+            | Request::GetEx(key, _)
+            | Request::Rm(key)
+            | Request::Count(key)
+            | Request::Expire(key, _)
+            | Request::Persist(key)
+            | Request::IncrByFloat(key, _)
+            | Request::IncrTtl(key, ..)
+            | Request::Push(key, ..)
+            | Request::LRange(key, ..)
+            | Request::LLen(key)
+            | Request::RenameNx(key, _)
+            | Request::Copy(key, ..)
+            | Request::Object(key)
+            | Request::Swap(key, _)
+            | Request::DelPrefix(key)
+            | Request::ScanValue(key, _)
+            | Request::Keys(key)
+            | Request::SInter(key, _)
+            | Request::PSubscribe(key) => Some(key.as_str()),
+            Request::MSetGet(pairs) | Request::MSetNx(pairs) => {
+                pairs.first().map(|(key, _)| key.as_str())
+            }
+            Request::Touch(keys) => keys.first().map(String::as_str),
+            Request::RandomKey
+            | Request::PurgeExpired
+            | Request::HotKeys(_)
+            | Request::Wait
+            | Request::Compact(_)
+            | Request::QuotaGet(_)
+            | Request::QuotaSet(..)
+            | Request::Clients
+            | Request::Quit
+            | Request::Hello(_)
+            | Request::Version
+            | Request::Info
+            | Request::LogLevel(_)
+            | Request::ConfigGet(_)
+            | Request::PSync(..)
+            | Request::ReplAck(_)
+            | Request::Invalid(_)
+            | Request::Malformed(_) => None,
+        }
+    }
+
+    fn parse(head: &str, args: Vec<Vec<u8>>) -> Result<Request, String> {
+        let mut args = Args::new(head, args);
+        let req = match head {
+            "SET" => {
+                let key = args.string()?;
+                let val = args.string()?;
+                let mut token = None;
+                let mut version = None;
+                let (mut nx, mut xx) = (false, false);
+                // The options come in any order, like in redis.
+                while let Some(opt) = args.opt_string()? {
+                    match opt.to_uppercase().as_str() {
+                        "ID" => token = Some(args.string()?),
+                        "VERSION" => version = Some(args.uint()?),
+                        "NX" => nx = true,
+                        "XX" => xx = true,
+                        x => return Err(format!("unknown option of SET: {}", x)),
+                    }
+                }
+                if nx as u8 + xx as u8 + version.is_some() as u8 > 1 {
+                    return Err("VERSION, NX and XX of SET are exclusive".to_owned());
+                }
+                let exists = if nx || xx { Some(xx) } else { None };
+                Request::Set(key, val, token, version, exists)
+            }
+            "SETINFO" => Request::SetInfo(args.string()?, args.string()?),
+            "GET" => {
+                let key = args.string()?;
+                match args.opt_string()? {
+                    Some(ref opt) if opt.eq_ignore_ascii_case("CAP") => {
+                        Request::GetCapped(key, args.uint()? as usize)
+                    }
+                    Some(opt) => return Err(format!("unknown option of GET: {}", opt)),
+                    None => Request::Get(key),
+                }
+            }
+            "GETRANGE" => Request::GetRange(args.string()?, args.int()?, args.int()?),
+            "GETVER" => Request::GetVer(args.string()?),
+            "GETEX" => {
+                let key = args.string()?;
+                match args.opt_string()? {
+                    Some(ref opt) if opt.eq_ignore_ascii_case("PX") => match args.uint()? {
+                        0 => return Err("invalid TTL of GETEX: 0".to_owned()),
+                        ttl => Request::GetEx(key, Some(ttl)),
+                    },
+                    Some(opt) => return Err(format!("unknown option of GETEX: {}", opt)),
+                    None => Request::GetEx(key, None),
+                }
+            }
+            "RM" => Request::Rm(args.string()?),
+            "COUNT" => Request::Count(args.string()?),
+            "EXPIRE" => {
+                let key = args.string()?;
+                match args.uint()? {
+                    0 => return Err("invalid TTL of EXPIRE: 0".to_owned()),
+                    ttl => Request::Expire(key, ttl),
+                }
+            }
+            "PERSIST" => Request::Persist(args.string()?),
+            // The members of the set with the prefix are its keys.
+            "SCARD" => Request::Count(args.string()?),
+            "INCRBYFLOAT" => Request::IncrByFloat(args.string()?, args.float()?),
+            "INCRTTL" => {
+                let (key, delta) = (args.string()?, args.int()?);
+                match args.uint()? {
+                    0 => return Err("invalid TTL of INCRTTL: 0".to_owned()),
+                    ttl => Request::IncrTtl(key, delta, ttl),
+                }
+            }
+            "LPUSH" => Request::Push(args.string()?, args.string()?, true),
+            "RPUSH" => Request::Push(args.string()?, args.string()?, false),
+            "LRANGE" => Request::LRange(args.string()?, args.int()?, args.int()?),
+            "LLEN" => Request::LLen(args.string()?),
+            "RENAMENX" => Request::RenameNx(args.string()?, args.string()?),
+            "COPY" => {
+                let src = args.string()?;
+                let dst = args.string()?;
+                let mut replace = false;
+                while let Some(opt) = args.opt_string()? {
+                    match opt.to_uppercase().as_str() {
+                        "REPLACE" => replace = true,
+                        x => return Err(format!("unknown option of COPY: {}", x)),
+                    }
+                }
+                Request::Copy(src, dst, replace)
+            }
+            "OBJECT" => Request::Object(args.string()?),
+            "RANDOMKEY" => Request::RandomKey,
+            "SWAP" => Request::Swap(args.string()?, args.string()?),
+            "DELPREFIX" => Request::DelPrefix(args.string()?),
+            "PURGEEXPIRED" => Request::PurgeExpired,
+            "SCANVALUE" => Request::ScanValue(args.string()?, args.string()?),
+            "KEYS" => Request::Keys(args.string()?),
+            "SINTER" => Request::SInter(args.string()?, args.string()?),
+            "HOTKEYS" => Request::HotKeys(args.uint()? as usize),
+            "MSETGET" => Request::MSetGet(args.pairs()?),
+            "MSETNX" => Request::MSetNx(args.pairs()?),
+            "TOUCH" => {
+                let mut keys = vec![args.string()?];
+                while let Some(key) = args.opt_string()? {
+                    keys.push(key);
+                }
+                Request::Touch(keys)
+            }
+            "QUOTA" => match args.string()?.to_uppercase().as_str() {
+                "GET" => Request::QuotaGet(args.string()?),
+                "SET" => {
+                    let namespace = args.string()?;
+                    let mut quota = Quota::default();
+                    while let Some(opt) = args.opt_string()? {
+                        match opt.to_uppercase().as_str() {
+                            "KEYS" => quota.max_keys = Some(args.uint()? as usize),
+                            "BYTES" => quota.max_bytes = Some(args.uint()? as usize),
+                            x => return Err(format!("unknown option of QUOTA: {}", x)),
+                        }
+                    }
+                    Request::QuotaSet(namespace, quota)
+                }
+                x => return Err(format!("unknown subcommand of QUOTA: {}", x)),
+            },
+            "COMPACT" => match args.string()?.to_uppercase().as_str() {
+                "PAUSE" => Request::Compact(true),
+                "RESUME" => Request::Compact(false),
+                x => return Err(format!("unknown subcommand of COMPACT: {}", x)),
+            },
+            "QUIT" => Request::Quit,
+            "HELLO" => match args.string()?.to_uppercase().as_str() {
+                "RESP" => Request::Hello(Encoding::Resp),
+                "MSGPACK" => Request::Hello(Encoding::MsgPack),
+                x => return Err(format!("unknown encoding of HELLO: {}", x)),
+            },
+            "VERSION" => Request::Version,
+            "WAIT" => Request::Wait,
+            "INFO" => Request::Info,
+            "LOGLEVEL" => Request::LogLevel(args.opt_string()?),
+            // Only reads: `LOGLEVEL` changes the one setting that can change
+            // at runtime.
+            "CONFIG" => match args.string()?.to_uppercase().as_str() {
+                "GET" => Request::ConfigGet(args.string()?),
+                x => return Err(format!("unknown subcommand of CONFIG: {}", x)),
+            },
+            "CLIENTS" => Request::Clients,
+            "PSUBSCRIBE" => Request::PSubscribe(args.string()?),
+            "PSYNC" => Request::PSync(args.string()?, args.uint()?),
+            "REPLACK" => Request::ReplAck(args.uint()?),
+            x => return Err(format!("unknown command: {}", x)),
+        };
+        args.finish()?;
+        Ok(req)
+    }
+}
+
+/// Publish the key events of a successful write: `set` for a key given a
+/// value, `expire` for a key given a TTL, `del` for a removed key and
+/// `delprefix` for removed keys with a prefix.
+fn publish_writes(notify: &Notifier, req: &Request, rep: &Reply) {
+    match (req, rep) {
+        (Request::Set(key, ..), Reply::SR(Ok(())))
+        | (Request::Set(key, ..), Reply::Ver(Ok(_)))
+        | (Request::Set(key, ..), Reply::B(Ok(true)))
+        | (Request::SetInfo(key, _), Reply::B(Ok(_)))
+        | (Request::IncrByFloat(key, _), Reply::F(Ok(_)))
+        | (Request::IncrTtl(key, ..), Reply::I(Ok(_)))
+        | (Request::Push(key, ..), Reply::N(Ok(_)))
+        | (Request::Copy(_, key, _), Reply::B(Ok(true))) => notify.publish("set", key),
+        (Request::GetEx(key, Some(_)), Reply::GT(Ok(Some(_))))
+        | (Request::Expire(key, _), Reply::B(Ok(true))) => notify.publish("expire", key),
+        (Request::Rm(key), Reply::SR(Ok(()))) => notify.publish("del", key),
+        (Request::RenameNx(src, dst), Reply::B(Ok(true))) => {
+            notify.publish("del", src);
+            notify.publish("set", dst);
+        }
+        (Request::Swap(a, b), Reply::SR(Ok(()))) => {
+            notify.publish("set", a);
+            notify.publish("set", b);
+        }
+        (Request::MSetGet(pairs), Reply::GS(Ok(_))) => {
+            for (key, _) in pairs {
+                notify.publish("set", key);
+            }
+        }
+        (Request::MSetNx(pairs), Reply::Batch(outcomes)) => {
+            for ((key, _), outcome) in pairs.iter().zip(outcomes) {
+                if let Ok(true) = outcome {
+                    notify.publish("set", key);
+                }
+            }
+        }
+        (Request::DelPrefix(prefix), Reply::N(Ok(n))) if *n > 0 => {
+            notify.publish_prefix("delprefix", prefix)
+        }
+        _ => {}
+    }
+}
+
+/// Set the level of `handle` to `level` if given, reply with the level.
+fn change_level(handle: Option<&LogLevel>, level: Option<&String>, log: &Logger) -> Reply {
+    let handle = match handle {
+        Some(handle) => handle,
+        None => return Reply::G(Err("LOGLEVEL is not enabled".to_owned())),
+    };
+    if let Some(level) = level {
+        match parse_level(level) {
+            Ok(level) => {
+                warn!(log, "log level set to {}", level_name(level));
+                handle.set(level);
+            }
+            Err(e) => return Reply::G(Err(e)),
+        }
+    }
+    Reply::G(Ok(Some(level_name(handle.get()).to_owned())))
+}
+
+/// Whether a request failed for naming no known command.
+fn unknown_command(e: &str) -> bool {
+    e.starts_with("unknown command") || e.starts_with("unknown inline command")
+}
+
+/// Arity of the commands that may be sent without array framing,
+/// as a `Str` head followed by `Bulk` arguments.
+fn inline_arity(head: &str) -> Result<usize, String> {
+    match head {
+        "SET" => Ok(2),
+        "EXPIRE" => Ok(2),
+        "GET" | "RM" | "COUNT" | "PERSIST" => Ok(1),
+        x => Err(format!("unknown inline command: {}", x)),
+    }
+}
+
+/// Arguments of a command.
+struct Args<'a> {
+    head: &'a str,
+    args: vec::IntoIter<Vec<u8>>,
+}
+
+impl<'a> Args<'a> {
+    fn new(head: &'a str, args: Vec<Vec<u8>>) -> Self {
+        Args {
+            head,
+            args: args.into_iter(),
+        }
+    }
+
+    fn opt_string(&mut self) -> Result<Option<String>, String> {
+        match self.args.next() {
+            // Keys and values are stored as strings.
+            Some(v) => match String::from_utf8(v) {
+                Ok(s) => Ok(Some(s)),
+                Err(_) => Err(format!("argument of {} is not valid UTF-8", self.head)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        match self.opt_string()? {
+            Some(s) => Ok(s),
+            None => Err(format!("incomplete command: {}", self.head)),
+        }
+    }
+
+    fn uint(&mut self) -> Result<u64, String> {
+        let s = self.string()?;
+        s.parse()
+            .map_err(|_| format!("invalid integer argument of {}: {}", self.head, s))
+    }
+
+    /// At least one key-value pair.
+    fn pairs(&mut self) -> Result<Vec<(String, String)>, String> {
+        let mut pairs = Vec::new();
+        while let Some(key) = self.opt_string()? {
+            pairs.push((key, self.string()?));
+        }
+        if pairs.is_empty() {
+            return Err(format!("incomplete command: {}", self.head));
+        }
+        Ok(pairs)
+    }
+
+    fn int(&mut self) -> Result<i64, String> {
+        let s = self.string()?;
+        s.parse()
+            .map_err(|_| format!("invalid integer argument of {}: {}", self.head, s))
+    }
+
+    fn float(&mut self) -> Result<f64, String> {
+        let s = self.string()?;
+        match s.parse::<f64>() {
+            Ok(x) if x.is_finite() => Ok(x),
+            _ => Err(format!("invalid float argument of {}: {}", self.head, s)),
+        }
+    }
+
+    fn finish(mut self) -> Result<(), String> {
+        match self.args.next() {
+            Some(_) => Err(format!("too many arguments: {}", self.head)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn wrong_item(item: Proto) -> String {
+    format!("unexpected item: {:?}", item)
+}
+
+fn decode_err(e: impl Display) -> String {
+    format!("decode error: {}", e)
+}
+
+/// Split an array into the command name and its arguments.
+fn split_command(items: Vec<Proto>) -> Result<(String, Vec<Vec<u8>>), String> {
+    let mut items = items.into_iter();
+    let head = match items.next() {
+        Some(Proto::Str(h)) => h,
+        Some(Proto::Bulk(h)) => String::from_utf8(h).map_err(decode_err)?,
+        Some(x) => return Err(wrong_item(x)),
+        None => return Err("empty command".to_owned()),
+    };
+    let mut args = Vec::new();
+    for item in items {
+        match item {
+            Proto::Bulk(v) => args.push(v),
+            x => return Err(wrong_item(x)),
+        }
+    }
+    Ok((head.to_uppercase(), args))
 }
 
 enum ReqState {
     Unknown,
-    Get,
-    Rm,
-    Set0,
-    Set1(String),
+    /// An inline command and the arguments received so far.
+    Inline(String, Vec<Vec<u8>>),
 }
 
 struct ReqFuture {
@@ -164,8 +1469,9 @@ struct ReqFuture {
 }
 
 impl ReqFuture {
-    fn new(rdr: ReadHalf<TcpStream>) -> Self {
-        let rdr = FramedRead::new(rdr, ProtoCodec::new());
+    fn new(rdr: ReadHalf<TcpStream>, max_array_len: usize) -> Self {
+        let codec = ProtoCodec::new().max_array_len(max_array_len);
+        let rdr = FramedRead::new(rdr, codec);
         ReqFuture {
             rdr,
             state: ReqState::Unknown,
@@ -184,67 +1490,35 @@ impl Stream for ReqFuture {
                 Ok(_) => return Ok(Async::NotReady),
                 Err(e) => return Err(decode_err(e)),
             };
-            match self.state {
-                ReqState::Unknown => {
-                    let head = match proto {
-                        Some(Proto::Str(h)) => h,
+            let (head, args) = match mem::replace(&mut self.state, ReqState::Unknown) {
+                ReqState::Unknown => match proto {
+                    Some(Proto::Seq(items)) => split_command(items)?,
+                    Some(Proto::Str(head)) => {
+                        if inline_arity(&head)? > 0 {
+                            self.state = ReqState::Inline(head, Vec::new());
+                            continue;
+                        }
+                        (head, Vec::new())
+                    }
+                    Some(x) => return Err(wrong_item(x)),
+                    None => return Ok(Async::Ready(None)),
+                },
+                ReqState::Inline(head, mut args) => {
+                    match proto {
+                        Some(Proto::Bulk(v)) => args.push(v),
                         Some(x) => return Err(wrong_item(x)),
-                        None => return Ok(Async::Ready(None)),
-                    };
-                    self.state = match head.as_str() {
-                        "SET" => ReqState::Set0,
-                        "GET" => ReqState::Get,
-                        "RM" => ReqState::Rm,
-                        x => return Err(format!("unknown command: {}", x)),
+                        None => return Err(format!("incomplete command: {}", head)),
                     }
+                    if args.len() < inline_arity(&head)? {
+                        self.state = ReqState::Inline(head, args);
+                        continue;
+                    }
+                    (head, args)
                 }
-                ReqState::Get => {
-                    let key = get_bulk_string(proto, &["GET"])?;
-                    let cmd = Request::Get(key);
-                    self.state = ReqState::Unknown;
-                    return Ok(Async::Ready(Some(cmd)));
-                }
-                ReqState::Rm => {
-                    let key = get_bulk_string(proto, &["RM"])?;
-                    let cmd = Request::Rm(key);
-                    self.state = ReqState::Unknown;
-                    return Ok(Async::Ready(Some(cmd)));
-                }
-                ReqState::Set0 => {
-                    let key = get_bulk_string(proto, &["SET"])?;
-                    self.state = ReqState::Set1(key);
-                }
-                ReqState::Set1(ref key) => {
-                    let val = get_bulk_string(proto, &["SET", key])?;
-                    let cmd = Request::Set(key.to_owned(), val);
-                    self.state = ReqState::Unknown;
-                    return Ok(Async::Ready(Some(cmd)));
-                }
-            }
-        }
-
-        fn wrong_item(item: Proto) -> String {
-            format!("unexpected item: {:?}", item)
-        }
-
-        fn incomplete(cmd: &[&str]) -> String {
-            format!("incomplete command: {:?}", cmd)
-        }
-
-        fn decode_err(e: impl Display) -> String {
-            format!("decode error: {}", e)
-        }
-
-        fn get_bulk_string(proto: Option<Proto>, cmd: &[&str]) -> Result<String, String> {
-            let s = match proto {
-                Some(Proto::Bulk(v)) => v,
-                Some(x) => return Err(wrong_item(x)),
-                None => return Err(incomplete(cmd)),
             };
-            match str::from_utf8(&s) {
-                Ok(s) => Ok(s.to_string()),
-                Err(e) => Err(decode_err(e)),
-            }
+            // The stream is still in sync, so the client gets the error.
+            let req = Request::parse(&head, args).unwrap_or_else(Request::Invalid);
+            return Ok(Async::Ready(Some(req)));
         }
     }
 }
@@ -253,30 +1527,244 @@ impl Stream for ReqFuture {
 enum Reply {
     SR(Result<(), String>),
     G(Result<Option<String>, String>),
+    N(Result<usize, String>),
+    I(Result<i64, String>),
+    F(Result<f64, String>),
+    GV(Result<Option<(String, u64)>, String>),
+    /// The value and the time left until it expires.
+    GT(Result<Option<(String, Option<Duration>)>, String>),
+    Ver(Result<u64, String>),
+    B(Result<bool, String>),
+    M(Result<Option<KeyMeta>, String>),
+    KV(Result<Vec<(String, String)>, String>),
+    /// Keys and the bytes of garbage made by their writes.
+    HK(Result<Vec<(String, usize)>, String>),
+    GS(Result<Vec<Option<String>>, String>),
+    Q(Result<(NamespaceUsage, Quota), String>),
+    /// Outcome of each operation of a batch, in order.
+    Batch(Vec<Result<bool, String>>),
+    /// Kind of the replication, id and offset, see `replicate`.
+    Repl(Result<ReplHeader, String>),
+    /// The connection is closed after it.
+    Bye,
+}
+
+impl Reply {
+    fn is_err(&self) -> bool {
+        matches!(
+            self,
+            Reply::SR(Err(_))
+                | Reply::G(Err(_))
+                | Reply::N(Err(_))
+                | Reply::I(Err(_))
+                | Reply::F(Err(_))
+                | Reply::GV(Err(_))
+                | Reply::GT(Err(_))
+                | Reply::Ver(Err(_))
+                | Reply::B(Err(_))
+                | Reply::M(Err(_))
+                | Reply::KV(Err(_))
+                | Reply::HK(Err(_))
+                | Reply::GS(Err(_))
+                | Reply::Q(Err(_))
+                | Reply::Repl(Err(_))
+        )
+    }
 }
 
 struct EngineFuture {
     rep: oneshot::Receiver<Reply>,
+    /// The pool refused the job.
+    busy: bool,
 }
 
 impl EngineFuture {
-    fn new<E, T>(cmd: Request, store: E, pool: T) -> Self
+    fn new<E, T>(
+        cmd: Request,
+        store: E,
+        pool: T,
+        dedup: Arc<Dedup<Reply>>,
+        mut trace: RequestTrace,
+    ) -> Self
     where
         E: KvsEngine,
         T: ThreadPool,
     {
         let (res, rep) = oneshot::channel();
 
-        pool.spawn(move || {
-            let rep = match cmd {
-                Request::Set(key, val) => Reply::SR(store.set(key, val).map_err(|e| e.to_string())),
+        trace.queued();
+        let job = move || {
+            let rep = trace.execute(move || match cmd {
+                Request::Set(key, val, token, version, exists) => {
+                    let set = move || match (version, exists) {
+                        (Some(v), _) => {
+                            Reply::Ver(store.set_if_version(key, val, v).map_err(|e| e.to_string()))
+                        }
+                        (None, Some(true)) => {
+                            Reply::B(store.set_if_present(key, val).map_err(|e| e.to_string()))
+                        }
+                        (None, Some(false)) => {
+                            Reply::B(store.set_if_absent(key, val).map_err(|e| e.to_string()))
+                        }
+                        (None, None) => Reply::SR(store.set(key, val).map_err(|e| e.to_string())),
+                    };
+                    match token {
+                        None => set(),
+                        Some(token) => {
+                            let busy = Reply::SR(Err("duplicate request in progress".to_owned()));
+                            dedup.run(token, busy, set)
+                        }
+                    }
+                }
+                // 1 for a new key, 0 for an overwrite.
+                Request::SetInfo(key, val) => {
+                    Reply::B(store.set_created(key, val).map_err(|e| e.to_string()))
+                }
                 Request::Get(key) => Reply::G(store.get(key).map_err(|e| e.to_string())),
+                // The value and 1 if it was cut.
+                Request::GetCapped(key, max) => Reply::GV(
+                    store
+                        .get_capped(key, max)
+                        .map(|found| found.map(|(val, cut)| (val, cut as u64)))
+                        .map_err(|e| e.to_string()),
+                ),
+                Request::GetRange(key, start, end) => {
+                    Reply::G(store.get_range(key, start, end).map_err(|e| e.to_string()))
+                }
+                Request::GetVer(key) => {
+                    Reply::GV(store.get_with_version(key).map_err(|e| e.to_string()))
+                }
+                Request::GetEx(key, None) => {
+                    Reply::GT(store.get_with_ttl(key).map_err(|e| e.to_string()))
+                }
+                Request::GetEx(key, Some(ttl)) => {
+                    let ttl = Duration::from_millis(ttl);
+                    Reply::GT(
+                        store
+                            .get_and_expire(key, ttl)
+                            .map(|found| found.map(|val| (val, Some(ttl))))
+                            .map_err(|e| e.to_string()),
+                    )
+                }
                 Request::Rm(key) => Reply::SR(store.remove(key).map_err(|e| e.to_string())),
-            };
+                Request::Count(prefix) => {
+                    Reply::N(store.count_prefix(prefix).map_err(|e| e.to_string()))
+                }
+                Request::Expire(key, ttl) => {
+                    let ttl = Duration::from_millis(ttl);
+                    Reply::B(store.expire(key, ttl).map_err(|e| e.to_string()))
+                }
+                Request::Persist(key) => Reply::B(store.persist(key).map_err(|e| e.to_string())),
+                Request::IncrByFloat(key, delta) => {
+                    Reply::F(store.increment_float(key, delta).map_err(|e| e.to_string()))
+                }
+                Request::IncrTtl(key, delta, ttl) => Reply::I(
+                    store
+                        .increment_with_ttl(key, delta, Duration::from_millis(ttl))
+                        .map_err(|e| e.to_string()),
+                ),
+                // The new length of the list.
+                Request::Push(key, val, front) => Reply::N(
+                    if front {
+                        store.push_front(key, val)
+                    } else {
+                        store.push_back(key, val)
+                    }
+                    .map_err(|e| e.to_string()),
+                ),
+                Request::LRange(key, start, stop) => Reply::GS(
+                    store
+                        .list_range(key, start, stop)
+                        .map(|items| items.into_iter().map(Some).collect())
+                        .map_err(|e| e.to_string()),
+                ),
+                Request::LLen(key) => Reply::N(store.list_len(key).map_err(|e| e.to_string())),
+                Request::RenameNx(src, dst) => {
+                    Reply::B(store.rename_nx(src, dst).map_err(|e| e.to_string()))
+                }
+                Request::Copy(src, dst, replace) => {
+                    Reply::B(store.copy(src, dst, replace).map_err(|e| e.to_string()))
+                }
+                Request::Object(key) => Reply::M(store.meta(key).map_err(|e| e.to_string())),
+                Request::RandomKey => Reply::G(store.random_key().map_err(|e| e.to_string())),
+                Request::Wait => Reply::SR(store.flush().map_err(|e| e.to_string())),
+                Request::Swap(a, b) => Reply::SR(store.swap(a, b).map_err(|e| e.to_string())),
+                Request::DelPrefix(prefix) => {
+                    Reply::N(store.remove_prefix(prefix).map_err(|e| e.to_string()))
+                }
+                Request::PurgeExpired => Reply::N(store.purge_expired().map_err(|e| e.to_string())),
+                Request::Keys(pattern) => Reply::GS(
+                    store
+                        .keys_matching(&pattern)
+                        .map(|keys| keys.into_iter().map(Some).collect())
+                        .map_err(|e| e.to_string()),
+                ),
+                Request::SInter(a, b) => Reply::GS(
+                    store
+                        .intersect_prefixes(a, b)
+                        .map(|members| members.into_iter().map(Some).collect())
+                        .map_err(|e| e.to_string()),
+                ),
+                Request::HotKeys(top_n) => {
+                    Reply::HK(store.hot_keys(top_n).map_err(|e| e.to_string()))
+                }
+                Request::ScanValue(prefix, substring) => Reply::KV(
+                    store
+                        .scan_value(prefix, substring)
+                        .map_err(|e| e.to_string()),
+                ),
+                Request::MSetGet(pairs) => {
+                    Reply::GS(store.set_many_get(pairs).map_err(|e| e.to_string()))
+                }
+                // Not atomic: a failed pair doesn't stop the next ones.
+                Request::MSetNx(pairs) => Reply::Batch(
+                    pairs
+                        .into_iter()
+                        .map(|(key, val)| store.set_if_absent(key, val).map_err(|e| e.to_string()))
+                        .collect(),
+                ),
+                // Count the existing keys.
+                Request::Touch(keys) => Reply::N(
+                    keys.into_iter()
+                        .map(|key| store.touch(key).map(|found| found as usize))
+                        .sum::<crate::Result<usize>>()
+                        .map_err(|e| e.to_string()),
+                ),
+                Request::Compact(true) => {
+                    Reply::SR(store.pause_compaction().map_err(|e| e.to_string()))
+                }
+                Request::Compact(false) => {
+                    Reply::SR(store.resume_compaction().map_err(|e| e.to_string()))
+                }
+                Request::QuotaGet(namespace) => {
+                    Reply::Q(store.namespace_usage(&namespace).map_err(|e| e.to_string()))
+                }
+                Request::QuotaSet(namespace, quota) => Reply::Q(
+                    store
+                        .set_quota(&namespace, quota)
+                        .and_then(|()| store.namespace_usage(&namespace))
+                        .map_err(|e| e.to_string()),
+                ),
+                Request::Clients => unreachable!("CLIENTS is answered by the connection"),
+                Request::PSubscribe(_) => unreachable!("PSUBSCRIBE is answered by the connection"),
+                Request::PSync(..) => unreachable!("PSYNC is answered by the connection"),
+                Request::ReplAck(_) => unreachable!("REPLACK is answered by the connection"),
+                Request::Quit => unreachable!("QUIT is answered by the connection"),
+                Request::Hello(_) => unreachable!("HELLO is answered by the connection"),
+                Request::Version => unreachable!("VERSION is answered by the connection"),
+                Request::Info => unreachable!("INFO is answered by the connection"),
+                Request::LogLevel(_) => unreachable!("LOGLEVEL is answered by the connection"),
+                Request::ConfigGet(_) => unreachable!("CONFIG is answered by the connection"),
+                Request::Invalid(_) | Request::Malformed(_) => {
+                    unreachable!("invalid commands are answered by the connection")
+                }
+            });
+            // Only once applied, which gives clients read-your-writes.
             res.send(rep).unwrap();
-        });
+        };
+        let busy = pool.try_spawn(job).is_err();
 
-        Self { rep }
+        Self { rep, busy }
     }
 }
 
@@ -285,6 +1773,10 @@ impl Future for EngineFuture {
     type Error = String;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.busy {
+            let e = "server busy, try again later".to_owned();
+            return Ok(Async::Ready(Reply::SR(Err(e))));
+        }
         self.rep.poll().map_err(|e| format!("engine error: {}", e))
     }
 }
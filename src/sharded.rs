@@ -0,0 +1,124 @@
+extern crate tokio;
+
+use slog::Logger;
+use tokio::prelude::*;
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use crate::client::KvsClient;
+use crate::get_logger;
+
+/// Points of each server on the hash ring.
+const VNODES: usize = 160;
+
+/// A client spreading keys over several independent servers.
+///
+/// Each key is sent to the server chosen by consistent hashing, so adding or
+/// removing a server only moves the keys of its neighbours on the ring.
+/// Nothing is rebalanced: keys written before a membership change stay on
+/// their old server and are not found through the new ring.
+pub struct ShardedClient {
+    clients: Vec<KvsClient>,
+    ring: BTreeMap<u64, usize>,
+    log: Logger,
+}
+
+impl ShardedClient {
+    pub fn new<LG>(addrs: Vec<SocketAddr>, log: LG) -> Result<Self, i32>
+    where
+        LG: Into<Option<Logger>>,
+    {
+        let log = get_logger(&mut log.into());
+        if addrs.is_empty() {
+            crit!(log, "no server address");
+            return Err(1);
+        }
+        let mut clients = Vec::with_capacity(addrs.len());
+        let mut ring = BTreeMap::new();
+        for (i, addr) in addrs.into_iter().enumerate() {
+            for v in 0..VNODES {
+                ring.insert(hash(format!("{}#{}", addr, v).as_bytes()), i);
+            }
+            clients.push(KvsClient::new(addr, log.clone())?);
+        }
+        Ok(Self { clients, ring, log })
+    }
+
+    /// Index of the server owning the key.
+    fn shard(&self, key: &str) -> usize {
+        let h = hash(key.as_bytes());
+        match self.ring.range(h..).next() {
+            Some((_, i)) => *i,
+            None => *self.ring.values().next().unwrap(),
+        }
+    }
+
+    pub fn set(&self, key: String, val: String) -> impl Future<Item = (), Error = i32> {
+        self.clients[self.shard(&key)].set(key, val)
+    }
+
+    pub fn get(&self, key: String) -> impl Future<Item = Option<String>, Error = i32> {
+        self.clients[self.shard(&key)].get(key)
+    }
+
+    pub fn rm(&mut self, key: String) -> impl Future<Item = (), Error = i32> {
+        let i = self.shard(&key);
+        self.clients[i].rm(key)
+    }
+
+    pub fn incrbyfloat(&self, key: String, delta: f64) -> impl Future<Item = f64, Error = i32> {
+        self.clients[self.shard(&key)].incrbyfloat(key, delta)
+    }
+
+    /// Get several keys, in the order of `keys`.
+    ///
+    /// Keys are grouped by server and the servers are queried concurrently.
+    pub fn mget(&self, keys: Vec<String>) -> impl Future<Item = Vec<Option<String>>, Error = i32> {
+        let mut groups: BTreeMap<usize, Vec<(usize, String)>> = BTreeMap::new();
+        for (pos, key) in keys.into_iter().enumerate() {
+            groups.entry(self.shard(&key)).or_default().push((pos, key));
+        }
+        let n = groups.values().map(Vec::len).sum();
+        debug!(self.log, "mget {} keys from {} servers", n, groups.len());
+
+        let gets = groups.into_iter().map(|(i, group)| {
+            let (pos, keys): (Vec<_>, Vec<_>) = group.into_iter().unzip();
+            let client = &self.clients[i];
+            future::join_all(
+                keys.into_iter()
+                    .map(|key| client.get(key))
+                    .collect::<Vec<_>>(),
+            )
+            .map(move |vals| pos.into_iter().zip(vals).collect::<Vec<_>>())
+        });
+        future::join_all(gets.collect::<Vec<_>>()).map(move |groups| {
+            let mut res = vec![None; n];
+            for (pos, val) in groups.into_iter().flatten() {
+                res[pos] = val;
+            }
+            res
+        })
+    }
+
+    /// Count the keys with the prefix on all servers.
+    pub fn count(&self, prefix: String) -> impl Future<Item = usize, Error = i32> {
+        let counts: Vec<_> = self
+            .clients
+            .iter()
+            .map(|client| client.count(prefix.clone()))
+            .collect();
+        future::join_all(counts).map(|counts| counts.into_iter().sum())
+    }
+}
+
+/// 64-bit FNV-1a, stable across processes and Rust versions,
+/// so every client maps a key to the same server.
+fn hash(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in data {
+        h ^= u64::from(*b);
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h
+}
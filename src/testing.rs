@@ -0,0 +1,61 @@
+//! Helpers to test against a real server, with the `testing` feature.
+
+use std::net::SocketAddr;
+use std::thread::JoinHandle;
+
+use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use crate::{KvsEngine, KvsServer};
+
+/// A server started by `spawn_test_server`, shut down when dropped.
+pub struct ServerHandle<E: KvsEngine> {
+    server: KvsServer<E, SharedQueueThreadPool>,
+    thread: Option<JoinHandle<Result<(), i32>>>,
+}
+
+impl<E: KvsEngine> ServerHandle<E> {
+    /// The server, e.g. for its `errors`.
+    pub fn server(&self) -> &KvsServer<E, SharedQueueThreadPool> {
+        &self.server
+    }
+
+    /// Stop the server and wait for its thread, return the result of `run`.
+    pub fn shutdown(mut self) -> Result<(), i32> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Result<(), i32> {
+        match self.thread.take() {
+            Some(thread) => {
+                self.server.shutdown();
+                thread.join().unwrap_or(Err(1))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl<E: KvsEngine> Drop for ServerHandle<E> {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// Start a server of `engine` on a port picked by the OS, return once it
+/// accepts connections, with the address it listens on.
+///
+/// Panics if the server can't start, as a test can't go on without it.
+pub fn spawn_test_server<E: KvsEngine>(engine: E) -> (ServerHandle<E>, SocketAddr) {
+    let pool = SharedQueueThreadPool::new(2).expect("unable to create the thread pool");
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let server = KvsServer::new(engine, pool, addr, None);
+    let thread = match server.spawn_background() {
+        Ok(thread) => thread,
+        Err(code) => panic!("unable to start the test server: {}", code),
+    };
+    let addr = server.local_addr().unwrap();
+    let handle = ServerHandle {
+        server,
+        thread: Some(thread),
+    };
+    (handle, addr)
+}
@@ -0,0 +1,103 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use super::ThreadPool;
+use crate::Result;
+
+const CAPACITY: usize = 1024;
+
+/// Limit the jobs queued or running in a pool.
+///
+/// The pools don't expose their queue, rayon's included, so the jobs are
+/// counted here: `try_spawn` gives the job back once `capacity` jobs are
+/// outstanding. `spawn` is never refused, but its jobs are counted too.
+pub struct BoundedThreadPool<TP: ThreadPool> {
+    pool: TP,
+    capacity: usize,
+    outstanding: Arc<AtomicUsize>,
+}
+
+impl<TP: ThreadPool> Clone for BoundedThreadPool<TP> {
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            capacity: self.capacity,
+            outstanding: self.outstanding.clone(),
+        }
+    }
+}
+
+impl<TP: ThreadPool> BoundedThreadPool<TP> {
+    pub fn with_capacity(pool: TP, capacity: usize) -> Self {
+        Self {
+            pool,
+            capacity,
+            outstanding: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Number of jobs queued or running.
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::SeqCst)
+    }
+
+    fn run<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let done = Done(self.outstanding.clone());
+        self.pool.spawn(move || {
+            let _done = done;
+            job();
+        });
+    }
+}
+
+/// Count a job as finished when dropped, even if it panicked.
+struct Done(Arc<AtomicUsize>);
+
+impl Drop for Done {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<TP: ThreadPool> ThreadPool for BoundedThreadPool<TP> {
+    /// Wrap a new `TP` with a capacity of 1024 jobs.
+    fn new(threads: u32) -> Result<Self> {
+        Ok(Self::with_capacity(TP::new(threads)?, CAPACITY))
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.outstanding.fetch_add(1, Ordering::SeqCst);
+        self.run(job);
+    }
+
+    fn try_spawn<F>(&self, job: F) -> std::result::Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut n = self.outstanding.load(Ordering::SeqCst);
+        loop {
+            if n >= self.capacity {
+                return Err(job);
+            }
+            match self
+                .outstanding
+                .compare_exchange(n, n + 1, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => break,
+                Err(cur) => n = cur,
+            }
+        }
+        self.run(job);
+        Ok(())
+    }
+
+    fn threads(&self) -> Option<u32> {
+        self.pool.threads()
+    }
+}
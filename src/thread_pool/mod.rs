@@ -4,10 +4,12 @@ use rayon::{ThreadPool as RayonTP, ThreadPoolBuilder};
 
 use std::sync::Arc;
 
+mod bounded;
 mod naive;
 mod shared_queue;
 
 use crate::Result;
+pub use bounded::BoundedThreadPool;
 pub use naive::NaiveThreadPool;
 pub use shared_queue::SharedQueueThreadPool;
 
@@ -18,6 +20,20 @@ pub trait ThreadPool: Clone + Send + 'static {
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+    /// Spawn the job unless the pool is saturated, otherwise give it back.
+    /// Pools accept every job by default, wrap them in `BoundedThreadPool`
+    /// to bound the outstanding jobs.
+    fn try_spawn<F>(&self, job: F) -> std::result::Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn(job);
+        Ok(())
+    }
+    /// Number of threads running the jobs, `None` if it isn't fixed.
+    fn threads(&self) -> Option<u32> {
+        None
+    }
 }
 
 #[derive(Clone)]
@@ -36,4 +52,8 @@ impl ThreadPool for RayonThreadPool {
     {
         self.0.spawn(job);
     }
+
+    fn threads(&self) -> Option<u32> {
+        Some(self.0.current_num_threads() as u32)
+    }
 }
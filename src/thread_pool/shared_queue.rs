@@ -71,6 +71,10 @@ impl ThreadPool for SharedQueueThreadPool {
         self.0.monitor.send(Control::Test).expect("monitor dead");
         self.0.worker.send(Message::Run(Box::new(job))).unwrap();
     }
+
+    fn threads(&self) -> Option<u32> {
+        Some(self.0.size)
+    }
 }
 
 impl QueuedThreadPool {
@@ -0,0 +1,78 @@
+//! Spans of the phases of a request, with the `tracing-spans` feature.
+//!
+//! A `request` span lives as long as the request, with the `client`, and
+//! once decoded the `cmd` and `key`. Its children are `decode` until the
+//! request is parsed, `queue` while the job waits for a pool thread, and
+//! `execute` while the engine runs it. Without the feature, `RequestTrace`
+//! is empty and does nothing.
+
+#[cfg(feature = "tracing-spans")]
+extern crate tracing;
+
+use std::net::SocketAddr;
+
+#[cfg(feature = "tracing-spans")]
+use tracing::{field, span, Level, Span};
+
+#[cfg(feature = "tracing-spans")]
+pub struct RequestTrace {
+    request: Span,
+    // The phase in progress, if any.
+    phase: Option<Span>,
+}
+
+#[cfg(feature = "tracing-spans")]
+impl RequestTrace {
+    pub fn new(peer: SocketAddr) -> Self {
+        let request = span!(
+            Level::INFO,
+            "request",
+            client = %peer,
+            cmd = field::Empty,
+            key = field::Empty
+        );
+        let decode = span!(parent: &request, Level::INFO, "decode");
+        RequestTrace {
+            request,
+            phase: Some(decode),
+        }
+    }
+
+    /// End the `decode` span.
+    pub fn decoded(&mut self, cmd: &str, key: Option<&str>) {
+        self.phase.take();
+        self.request.record("cmd", cmd);
+        if let Some(key) = key {
+            self.request.record("key", key);
+        }
+    }
+
+    /// Start the `queue` span.
+    pub fn queued(&mut self) {
+        self.phase = Some(span!(parent: &self.request, Level::INFO, "queue"));
+    }
+
+    /// End the `queue` span, and run `f` in the `execute` span.
+    pub fn execute<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        self.phase.take();
+        span!(parent: &self.request, Level::INFO, "execute").in_scope(f)
+    }
+}
+
+#[cfg(not(feature = "tracing-spans"))]
+pub struct RequestTrace;
+
+#[cfg(not(feature = "tracing-spans"))]
+impl RequestTrace {
+    pub fn new(_peer: SocketAddr) -> Self {
+        RequestTrace
+    }
+
+    pub fn decoded(&mut self, _cmd: &str, _key: Option<&str>) {}
+
+    pub fn queued(&mut self) {}
+
+    pub fn execute<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        f()
+    }
+}
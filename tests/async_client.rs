@@ -0,0 +1,36 @@
+#![cfg(feature = "async-client")]
+
+use futures03::executor::block_on;
+use tempfile::TempDir;
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{AsyncKvsClient, KvStore, KvsServer};
+
+// The async client should set, get and remove keys with `.await`
+#[test]
+fn async_client() {
+    let addr = "127.0.0.1:4110";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let server = KvsServer::new(store, pool, addr.parse().unwrap(), None);
+    let handle = server.spawn_background().unwrap();
+
+    let mut client = AsyncKvsClient::new(addr.parse().unwrap(), None).unwrap();
+    block_on(async {
+        client.set("key1".to_owned(), "value1".to_owned()).await?;
+        assert_eq!(
+            client.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(client.count("key".to_owned()).await?, 1);
+        client.rm("key1".to_owned()).await?;
+        assert_eq!(client.get("key1".to_owned()).await?, None);
+        assert!(client.rm("key1".to_owned()).await.is_err());
+        Ok::<(), kvs::ClientError>(())
+    })
+    .unwrap();
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
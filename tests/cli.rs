@@ -1,6 +1,7 @@
 use assert_cmd::prelude::*;
+use kvs::KvsEngine;
 use predicates::str::{contains, is_empty};
-use std::fs::{self, File};
+use std::fs;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -20,67 +21,84 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 }
 
+// `kvs-client` should exit with 1 on a failure other than a missing key,
+// reporting it on stderr only
+#[test]
+fn client_cli_no_server() {
+    let temp_dir = TempDir::new().unwrap();
+    for args in &[&["get", "key"][..], &["rm", "key"][..]] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(*args)
+            .args(["--addr", "127.0.0.1:4009"])
+            .current_dir(&temp_dir)
+            .assert()
+            .code(1)
+            .stdout(is_empty());
+    }
+}
+
 #[test]
 fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +109,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +141,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +152,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,25 +163,28 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// The binaries log nothing with `no-logging`.
+#[cfg(not(feature = "no-logging"))]
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
-        .stderr(File::create(&stderr_path).unwrap())
+        .stderr(fs::File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -178,15 +199,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,39 +219,94 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
     }
 }
 
+// `kvs-server --pidfile` should write its pid and refuse a pid file in use
+#[test]
+fn cli_pidfile() {
+    let temp_dir = TempDir::new().unwrap();
+    let pidfile = temp_dir.path().join("kvs.pid");
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4006", "--pidfile"])
+        .arg(&pidfile)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let content = fs::read_to_string(&pidfile).expect("unable to read pid file");
+    assert_eq!(content.trim(), child.id().to_string());
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4007", "--pidfile"])
+        .arg(&pidfile)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `kvs-server` should exit cleanly on SIGTERM and remove its pid file
+#[cfg(unix)]
+#[test]
+fn cli_sigterm() {
+    let temp_dir = TempDir::new().unwrap();
+    let pidfile = temp_dir.path().join("kvs.pid");
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4008", "--pidfile"])
+        .arg(&pidfile)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    let status = child.wait().unwrap();
+    assert!(status.success());
+    assert!(!pidfile.exists());
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +314,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +322,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +330,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +338,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,15 +346,16 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
-        .failure()
+        .code(2)
+        .stdout(is_empty())
         .stderr(contains("Key not found"));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +363,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -298,26 +376,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -335,3 +414,104 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// `kvs info` should print the layout of an existing database
+#[test]
+fn cli_info() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = kvs::KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned()).unwrap();
+    }
+    store.set("key0".to_owned(), "other".to_owned()).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["info", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(contains("engine:      kvs"))
+        .stdout(contains("keys:        10"))
+        .stdout(contains("active file: 1"));
+}
+
+// `kvs info` on a directory without database should fail
+#[test]
+fn cli_info_empty_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["info", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .failure();
+    assert!(fs::read_dir(temp_dir.path()).unwrap().next().is_none());
+}
+
+// `kvs migrate` should copy every pair into the other engine
+#[test]
+fn cli_migrate() {
+    let from = TempDir::new().unwrap();
+    let to = TempDir::new().unwrap();
+    let store = kvs::KvStore::open(from.path()).unwrap();
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    store.remove("key0".to_owned()).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from"])
+        .arg(from.path())
+        .args(["--to"])
+        .arg(to.path())
+        .args(["--to-engine", "sled"])
+        .assert()
+        .success()
+        .stdout(contains("migrated 99 keys"));
+
+    let db = kvs::SledDb::open(to.path()).unwrap();
+    assert_eq!(db.get("key0".to_owned()).unwrap(), None);
+    assert_eq!(
+        db.get("key99".to_owned()).unwrap(),
+        Some("value99".to_owned())
+    );
+    assert_eq!(db.count_prefix("key".to_owned()).unwrap(), 99);
+}
+
+// `kvs migrate` should not touch a target of another engine without --force
+#[test]
+fn cli_migrate_force() {
+    let from = TempDir::new().unwrap();
+    let to = TempDir::new().unwrap();
+    let store = kvs::KvStore::open(from.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+    let other = kvs::KvStore::open(to.path()).unwrap();
+    other.set("old".to_owned(), "value".to_owned()).unwrap();
+    drop(other);
+
+    let migrate = |force: bool| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(["migrate", "--from"])
+            .arg(from.path())
+            .args(["--to"])
+            .arg(to.path())
+            .args(["--to-engine", "sled"]);
+        if force {
+            cmd.arg("--force");
+        }
+        cmd.assert()
+    };
+    migrate(false).failure();
+    let meta = fs::read_to_string(to.path().join("meta")).unwrap();
+    assert_eq!(meta.lines().next(), Some("kvs"));
+
+    migrate(true).success().stdout(contains("migrated 1 keys"));
+    let db = kvs::SledDb::open(to.path()).unwrap();
+    assert_eq!(db.get("old".to_owned()).unwrap(), None);
+    assert_eq!(db.get("key".to_owned()).unwrap(), Some("value".to_owned()));
+}
@@ -0,0 +1,54 @@
+// Its own test binary, as the file descriptor limit is of the process.
+#![cfg(unix)]
+
+use kvs::{KvStoreBuilder, Result};
+use std::fs;
+use tempfile::TempDir;
+
+// Lower the soft limit of open files of the process to `max`.
+fn limit_open_files(max: u64) {
+    let mut lim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    unsafe {
+        assert_eq!(libc::getrlimit(libc::RLIMIT_NOFILE, &mut lim), 0);
+        lim.rlim_cur = max as libc::rlim_t;
+        assert_eq!(libc::setrlimit(libc::RLIMIT_NOFILE, &lim), 0);
+    }
+}
+
+// Should open and compact more data files than it may hold open
+#[test]
+fn more_files_than_descriptors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let files = 300;
+    fs::write(temp_dir.path().join("meta"), "kvs")?;
+    for id in 1..=files {
+        fs::write(
+            temp_dir.path().join(format!("{}.data", id)),
+            format!(r#"{{"S":["key{}","value{}",0,1,0,0]}}"#, id, id),
+        )?;
+    }
+    limit_open_files(64);
+
+    let store = KvStoreBuilder::new(temp_dir.path())
+        .max_open_files(16)
+        .build()?;
+    for id in 1..=files {
+        let key = format!("key{}", id);
+        assert_eq!(store.get(key)?, Some(format!("value{}", id)));
+    }
+    store.compact()?;
+    for id in (1..=files).rev() {
+        let key = format!("key{}", id);
+        assert_eq!(store.get(key)?, Some(format!("value{}", id)));
+    }
+    drop(store);
+
+    let store = KvStoreBuilder::new(temp_dir.path())
+        .max_open_files(16)
+        .build()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -1,6 +1,14 @@
-use kvs::{KvStore, Result};
-use std::sync::{Arc, Barrier};
+use kvs::{
+    Command, CompactionEvent, CompactionScheduler, EvictionPolicy, IndexHasher, IoStats, JsonCodec,
+    KvStore, KvStoreBuilder, KvsError, NamespaceUsage, OpenStatus, Quota, RecordCodec, ReplSync,
+    Result, SizeHistogram, FORMAT_VERSION,
+};
+use std::fs;
+use std::panic;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -209,3 +217,2812 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Should count only the keys with the given prefix
+#[test]
+fn count_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.count_prefix("user:"), 0);
+    for i in 0..10 {
+        store.set(format!("user:{}", i), format!("value{}", i))?;
+    }
+    for i in 0..5 {
+        store.set(format!("group:{}", i), format!("value{}", i))?;
+    }
+    store.set("user:0".to_owned(), "overwritten".to_owned())?;
+    store.remove("user:1".to_owned())?;
+
+    assert_eq!(store.count_prefix("user:"), 9);
+    assert_eq!(store.count_prefix("group:"), 5);
+    assert_eq!(store.count_prefix(""), 14);
+    assert_eq!(store.count_prefix("none:"), 0);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.count_prefix("user:"), 9);
+
+    Ok(())
+}
+
+// Should insert only the absent keys, and only the first of the pairs
+// with the same key in a batch
+#[test]
+fn set_many_if_absent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "override".to_owned())?;
+    let pairs = vec![
+        ("key1".to_owned(), "default1".to_owned()),
+        ("key2".to_owned(), "default2".to_owned()),
+        ("key3".to_owned(), "default3".to_owned()),
+        ("key2".to_owned(), "duplicate".to_owned()),
+    ];
+    assert_eq!(store.set_many_if_absent(pairs.clone())?, 2);
+    assert_eq!(store.get("key1".to_owned())?, Some("override".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("default2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("default3".to_owned()));
+    assert_eq!(store.set_many_if_absent(pairs)?, 0);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("override".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("default2".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn increment_float() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.increment_float("key1".to_owned(), 0.1)?, 0.1);
+    assert_eq!(store.get("key1".to_owned())?, Some("0.1".to_owned()));
+    store.increment_float("key1".to_owned(), 0.9)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("1".to_owned()));
+    store.increment_float("key1".to_owned(), -1.0)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("0".to_owned()));
+    store.increment_float("key1".to_owned(), 1e21)?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("1000000000000000000000".to_owned())
+    );
+
+    store.set("key2".to_owned(), "10.50".to_owned())?;
+    assert_eq!(store.increment_float("key2".to_owned(), 0.25)?, 10.75);
+    assert_eq!(store.get("key2".to_owned())?, Some("10.75".to_owned()));
+
+    store.set("key3".to_owned(), "abc".to_owned())?;
+    assert!(store.increment_float("key3".to_owned(), 1.0).is_err());
+    store.set("key3".to_owned(), "inf".to_owned())?;
+    assert!(store.increment_float("key3".to_owned(), 1.0).is_err());
+    store.set("key3".to_owned(), "1e308".to_owned())?;
+    assert!(store.increment_float("key3".to_owned(), 1e308).is_err());
+    assert_eq!(store.get("key3".to_owned())?, Some("1e308".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_increment_float() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for _ in 0..100 {
+                    store.increment_float("key".to_owned(), 0.5).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("key".to_owned())?, Some("400".to_owned()));
+
+    Ok(())
+}
+
+// increment_with_ttl should set the TTL only when it creates the key
+#[test]
+fn increment_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let ttl = Duration::from_millis(300);
+    let incr = |key: &str, delta| store.increment_with_ttl(key.to_owned(), delta, ttl);
+
+    assert_eq!(incr("counter", 1)?, 1);
+    assert_eq!(incr("counter", 2)?, 3);
+    assert_eq!(store.get("counter".to_owned())?, Some("3".to_owned()));
+    // A key without TTL keeps never expiring.
+    store.set("plain".to_owned(), "5".to_owned())?;
+    assert_eq!(incr("plain", -6)?, -1);
+    thread::sleep(Duration::from_millis(100));
+    // The increments don't extend the window.
+    assert_eq!(incr("counter", 1)?, 4);
+    thread::sleep(Duration::from_millis(250));
+
+    assert_eq!(store.get("counter".to_owned())?, None);
+    assert_eq!(store.meta("counter".to_owned())?, None);
+    assert_eq!(store.keys(), vec!["plain".to_owned()]);
+    assert_eq!(store.get("plain".to_owned())?, Some("-1".to_owned()));
+    // An expired key starts a new window, with a new version.
+    assert_eq!(incr("counter", 1)?, 1);
+    assert_eq!(
+        store.get_with_version("counter".to_owned())?,
+        Some(("1".to_owned(), 1))
+    );
+
+    store.set("text".to_owned(), "abc".to_owned())?;
+    match incr("text", 1) {
+        Err(e) => match e.downcast_ref::<KvsError>() {
+            Some(KvsError::NotInteger(_)) => {}
+            _ => panic!("unexpected error: {}", e),
+        },
+        Ok(n) => panic!("incremented a text to {}", n),
+    }
+    store.set("big".to_owned(), i64::MAX.to_string())?;
+    assert!(incr("big", 1).is_err());
+    assert_eq!(incr("big", -1)?, i64::MAX - 1);
+
+    // The expiry survives a compaction and a restart.
+    store.increment_with_ttl("window".to_owned(), 1, Duration::from_secs(1))?;
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("window".to_owned())?, Some("1".to_owned()));
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(store.get("window".to_owned())?, None);
+    assert_eq!(store.get("counter".to_owned())?, None);
+    assert_eq!(store.get("plain".to_owned())?, Some("-1".to_owned()));
+    // A plain set clears the TTL.
+    assert_eq!(
+        store.increment_with_ttl("counter".to_owned(), 1, Duration::from_millis(50))?,
+        1
+    );
+    store.set("counter".to_owned(), "7".to_owned())?;
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.get("counter".to_owned())?, Some("7".to_owned()));
+
+    Ok(())
+}
+
+// Clients counting their requests in a shared window should get each count
+// once, and only the creator of the window should set its TTL
+#[test]
+fn rate_limit_window() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let window = Duration::from_secs(2);
+    let limit = 100;
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                (0..50)
+                    .map(|_| {
+                        store
+                            .increment_with_ttl("rate".to_owned(), 1, window)
+                            .unwrap()
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    let mut counts = Vec::new();
+    for handle in handles {
+        counts.extend(handle.join().unwrap());
+    }
+    counts.sort_unstable();
+    assert_eq!(counts, (1..=400).collect::<Vec<i64>>());
+    assert_eq!(counts.iter().filter(|n| **n <= limit).count(), 100);
+
+    // The window ends after its TTL, whatever the requests in it.
+    thread::sleep(window);
+    assert_eq!(store.increment_with_ttl("rate".to_owned(), 1, window)?, 1);
+
+    Ok(())
+}
+
+// Concurrent decrements with a floor, built on `update`, should neither be
+// lost nor go below the floor
+#[test]
+fn update_decrement_with_floor() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    // Take one from the stock unless it is out, return whether one was taken.
+    let take_one = |store: &KvStore| -> Result<bool> {
+        let mut taken = false;
+        store.update("stock".to_owned(), |val| {
+            let n: u64 = val?.parse().unwrap();
+            taken = n > 0;
+            Some(n.saturating_sub(1).to_string())
+        })?;
+        Ok(taken)
+    };
+    assert!(!take_one(&store)?);
+    assert_eq!(store.get("stock".to_owned())?, None);
+
+    store.set("stock".to_owned(), "100".to_owned())?;
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                (0..20).filter(|_| take_one(&store).unwrap()).count()
+            })
+        })
+        .collect();
+    let taken: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    assert_eq!(taken, 100);
+    assert_eq!(store.get("stock".to_owned())?, Some("0".to_owned()));
+
+    // `None` removes the key, and a panic writes nothing.
+    assert_eq!(store.update("stock".to_owned(), |_| None)?, None);
+    assert_eq!(store.get("stock".to_owned())?, None);
+    store.set("stock".to_owned(), "1".to_owned())?;
+    let res = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        store.update("stock".to_owned(), |_| panic!("bad update"))
+    }));
+    assert!(res.is_err());
+    assert_eq!(store.get("stock".to_owned())?, Some("1".to_owned()));
+    assert_eq!(
+        store.update("stock".to_owned(), |val| val.map(|v| v + "0"))?,
+        Some("10".to_owned())
+    );
+
+    Ok(())
+}
+
+// The members of two sets of prefixed keys should intersect, even when a
+// prefix starts the other
+#[test]
+fn intersect_prefixes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &[
+        "set:a:1", "set:a:2", "set:a:3", "set:b:2", "set:b:3", "set:b:4",
+    ] {
+        store.set(key.to_string(), String::new())?;
+    }
+    store.set("set:ab:3".to_owned(), String::new())?;
+    store.remove("set:a:3".to_owned())?;
+
+    assert_eq!(store.intersect_prefixes("set:a:", "set:b:"), vec!["2"]);
+    assert_eq!(store.intersect_prefixes("set:b:", "set:a:"), vec!["2"]);
+    assert_eq!(store.intersect_prefixes("set:a", "set:"), vec!["b:3"]);
+    assert!(store.intersect_prefixes("set:a:", "set:c:").is_empty());
+    assert_eq!(store.intersect_prefixes("", "set:a:").len(), 0);
+    assert_eq!(store.count_prefix("set:b:"), 3);
+
+    Ok(())
+}
+
+// A record not taking the length recorded in the index should be an error
+#[test]
+fn corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    // Overwrite the first record with a shorter one followed by garbage.
+    let path = temp_dir.path().join("1.data");
+    let data = fs::read_to_string(&path)?;
+    let old = &data[..data.find("]}").unwrap() + 2];
+    assert!(old.starts_with(r#"{"S":["key1","value1",0,1,"#));
+    let mut new = r#"{"S":["key1","x",0,1]}"#.to_owned();
+    new += &"1".repeat(old.len() - new.len());
+    fs::write(&path, data.replacen(old, &new, 1))?;
+
+    match store.get("key1".to_owned()) {
+        Err(e) => match e.downcast_ref::<KvsError>() {
+            Some(KvsError::Corruption(_)) => {}
+            _ => panic!("unexpected error: {}", e),
+        },
+        Ok(v) => panic!("corruption not detected: {:?}", v),
+    }
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Empty data files left by a crash should not be taken as the active file
+#[test]
+fn skip_empty_data_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    fs::write(temp_dir.path().join("4.data"), "")?;
+    fs::write(temp_dir.path().join("5.data"), "")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    assert!(!temp_dir.path().join("4.data").exists());
+    assert!(!temp_dir.path().join("5.data").exists());
+    let info = KvStoreBuilder::new(temp_dir.path()).inspect()?;
+    assert_eq!(info.active_id, Some(1));
+    assert_eq!(info.keys, 2);
+
+    Ok(())
+}
+
+#[test]
+fn file_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Files of another tool sharing the directory.
+    fs::write(temp_dir.path().join("1.data"), "not a record")?;
+    fs::write(temp_dir.path().join("kvs-x.data"), "not a record")?;
+
+    let open = || {
+        KvStoreBuilder::new(temp_dir.path())
+            .file_prefix("kvs-")
+            .build()
+    };
+    let store = open()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("kvs-1.data").exists());
+    assert_eq!(fs::read(temp_dir.path().join("1.data"))?, b"not a record");
+
+    let store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    assert_eq!(fs::read(temp_dir.path().join("1.data"))?, b"not a record");
+
+    let info = KvStoreBuilder::new(temp_dir.path())
+        .file_prefix("kvs-")
+        .inspect()?;
+    assert_eq!(info.keys, 2);
+
+    Ok(())
+}
+
+// JSON with every byte flipped, so without `value_head`.
+struct XorCodec;
+
+impl RecordCodec for XorCodec {
+    fn name(&self) -> &'static str {
+        "xor"
+    }
+
+    fn encode(&self, cmd: &Command) -> Vec<u8> {
+        JsonCodec.encode(cmd).iter().map(|b| b ^ 0x5a).collect()
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<Option<(Command, usize)>> {
+        let plain: Vec<u8> = buf.iter().map(|b| b ^ 0x5a).collect();
+        JsonCodec.decode(&plain)
+    }
+}
+
+#[test]
+fn record_codec() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStoreBuilder::new(temp_dir.path())
+            .record_codec(XorCodec)
+            .build()
+    };
+    let store = open()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(
+        store.get_capped("key1".to_owned(), 3)?,
+        Some(("val".to_owned(), true))
+    );
+    drop(store);
+    let data = fs::read(temp_dir.path().join("1.data"))?;
+    assert!(!data.windows(4).any(|w| w == b"key1"));
+
+    let store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(e) => match e.downcast_ref::<KvsError>() {
+            Some(KvsError::CodecMismatch { found, expected }) => {
+                assert_eq!(found, "xor");
+                assert_eq!(expected, "json");
+            }
+            _ => panic!("unexpected error: {}", e),
+        },
+        Ok(_) => panic!("opened with another codec"),
+    }
+    let store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+#[cfg(feature = "bincode")]
+#[test]
+fn bincode_codec() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStoreBuilder::new(temp_dir.path())
+            .record_codec(kvs::BincodeCodec)
+            .preallocate(1 << 16)
+            .build()
+    };
+    let store = open()?;
+    for iter in 0..100 {
+        store.set(format!("key{}", iter % 10), format!("{}", iter))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let store = open()?;
+    store.compact()?;
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}", 90 + key_id))
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn encryption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |key: Option<[u8; 32]>| {
+        let builder = KvStoreBuilder::new(temp_dir.path());
+        let builder = match key {
+            Some(key) => builder.encryption(key),
+            None => builder,
+        };
+        builder.build()
+    };
+    let decrypt_failed = |res: Result<KvStore>| match res {
+        Err(e) => match e.downcast_ref::<KvsError>() {
+            Some(KvsError::DecryptFailed(_)) => {}
+            _ => panic!("unexpected error: {}", e),
+        },
+        Ok(_) => panic!("opened without the key"),
+    };
+
+    let store = open(Some([7; 32]))?;
+    for iter in 0..100 {
+        store.set(format!("key{}", iter % 10), format!("secret{}", iter))?;
+    }
+    store.remove("key0".to_owned())?;
+    assert_eq!(
+        store.get_capped("key1".to_owned(), 6)?,
+        Some(("secret".to_owned(), true))
+    );
+    drop(store);
+    for entry in fs::read_dir(temp_dir.path())? {
+        let data = fs::read(entry?.path())?;
+        assert!(!data.windows(6).any(|w| w == b"secret"));
+    }
+
+    decrypt_failed(open(None));
+    let mut key = [7; 32];
+    key[31] = 8;
+    decrypt_failed(open(Some(key)));
+
+    let store = open(Some([7; 32]))?;
+    store.compact()?;
+    drop(store);
+    let store = open(Some([7; 32]))?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("secret{}", 90 + key_id))
+        );
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(temp_dir.path())?;
+    decrypt_failed(
+        KvStoreBuilder::new(temp_dir.path())
+            .encryption([7; 32])
+            .build(),
+    );
+
+    Ok(())
+}
+
+#[test]
+fn versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.get_with_version("key1".to_owned())?, None);
+    assert_eq!(
+        store.set_if_version("key1".to_owned(), "a".to_owned(), 0)?,
+        1
+    );
+    store.set("key1".to_owned(), "b".to_owned())?;
+    assert_eq!(
+        store.get_with_version("key1".to_owned())?,
+        Some(("b".to_owned(), 2))
+    );
+
+    match store.set_if_version("key1".to_owned(), "c".to_owned(), 1) {
+        Err(e) => match e.downcast_ref::<KvsError>() {
+            Some(KvsError::VersionMismatch {
+                expected: 1,
+                found: 2,
+                ..
+            }) => {}
+            _ => panic!("unexpected error: {}", e),
+        },
+        Ok(v) => panic!("set with a stale version: {}", v),
+    }
+    assert_eq!(
+        store.set_if_version("key1".to_owned(), "c".to_owned(), 2)?,
+        3
+    );
+    store.increment_float("key2".to_owned(), 1.0)?;
+    store.increment_float("key2".to_owned(), 1.0)?;
+
+    // Versions survive reopening and compaction
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_with_version("key1".to_owned())?,
+        Some(("c".to_owned(), 3))
+    );
+    store.compact()?;
+    assert_eq!(
+        store.get_with_version("key2".to_owned())?,
+        Some(("2".to_owned(), 2))
+    );
+
+    // Removing a key starts over
+    store.remove("key1".to_owned())?;
+    store.set("key1".to_owned(), "d".to_owned())?;
+    assert_eq!(
+        store.get_with_version("key1".to_owned())?,
+        Some(("d".to_owned(), 1))
+    );
+
+    Ok(())
+}
+
+// Records written before versions existed should get versions on load
+#[test]
+fn versions_of_old_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("meta"), "kvs")?;
+    fs::write(
+        temp_dir.path().join("1.data"),
+        r#"{"S":["key1","a"]}{"S":["key1","b"]}{"S":["key2","c"]}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_with_version("key1".to_owned())?,
+        Some(("b".to_owned(), 2))
+    );
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_with_version("key1".to_owned())?,
+        Some(("b".to_owned(), 2))
+    );
+    assert_eq!(
+        store.get_with_version("key2".to_owned())?,
+        Some(("c".to_owned(), 1))
+    );
+
+    Ok(())
+}
+
+// A database of the first format, with the bare meta file and records
+// without versions or times over several files, should read the same
+// before and after a compaction
+#[test]
+fn open_legacy_database() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("meta"), "kvs")?;
+    fs::write(
+        temp_dir.path().join("1.data"),
+        r#"{"S":["key1","a"]}{"S":["key2","b"]}{"S":["key3","c"]}"#,
+    )?;
+    fs::write(
+        temp_dir.path().join("2.data"),
+        r#"{"R":"key2"}{"S":["key1","d"]}{"S":["key4","e\n\"f\""]}"#,
+    )?;
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key1".to_owned())?, Some("d".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, Some("c".to_owned()));
+        assert_eq!(store.get("key4".to_owned())?, Some("e\n\"f\"".to_owned()));
+        assert_eq!(store.keys().len(), 3);
+        Ok(())
+    };
+
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    // Upgraded, the compaction rewrote the records in the current format.
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("meta"))?,
+        meta_file("kvs", FORMAT_VERSION)
+    );
+
+    Ok(())
+}
+
+// Dropping the last handle should stop the compacter thread, whose own
+// handle keeps the data files open
+#[cfg(target_os = "linux")]
+#[test]
+fn drop_closes_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().canonicalize()?;
+    let open_files = || {
+        fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|entry| fs::read_link(entry.ok()?.path()).ok())
+            .filter(|path| path.starts_with(&dir))
+            .count()
+    };
+
+    let store = KvStore::open(temp_dir.path())?;
+    let other = store.clone();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert!(open_files() > 0);
+    assert_eq!(other.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(other);
+    assert_eq!(open_files(), 0);
+
+    Ok(())
+}
+
+// Compaction should report its start, progress and end to the hook
+#[test]
+fn compaction_events() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let sink = events.clone();
+    let store = KvStoreBuilder::new(temp_dir.path())
+        .compact_threshold(usize::MAX)
+        .on_compaction(move |e| sink.lock().unwrap().push(e))
+        .build()?;
+
+    let value = "v".repeat(2048);
+    for iter in 0..3 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}{}", value, iter))?;
+        }
+    }
+    assert!(events.lock().unwrap().is_empty());
+    store.compact()?;
+
+    let events = events.lock().unwrap();
+    match events.first() {
+        Some(CompactionEvent::Started { bytes_to_merge }) => assert!(*bytes_to_merge > 1000 * 1024),
+        e => panic!("unexpected first event: {:?}", e),
+    }
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, CompactionEvent::Progress { .. })));
+    match events.last() {
+        Some(CompactionEvent::Finished {
+            bytes_reclaimed, ..
+        }) => assert!(*bytes_reclaimed > 0),
+        e => panic!("unexpected last event: {:?}", e),
+    }
+    Ok(())
+}
+
+// Should expire a key, and remove the TTL of a key without rewriting one
+// that has none
+#[test]
+fn persist() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let ttl = Duration::from_millis(200);
+
+    assert!(!store.persist("key".to_owned())?);
+    assert!(!store.expire("key".to_owned(), ttl)?);
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert!(!store.persist("key".to_owned())?);
+
+    assert!(store.expire("key".to_owned(), ttl)?);
+    assert!(store.persist("key".to_owned())?);
+    assert!(!store.persist("key".to_owned())?);
+    // The version is kept.
+    assert_eq!(
+        store.get_with_version("key".to_owned())?,
+        Some(("value".to_owned(), 1))
+    );
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    // An expired key is gone, there is nothing to persist.
+    store.expire("key".to_owned(), ttl)?;
+    thread::sleep(Duration::from_millis(300));
+    assert!(!store.persist("key".to_owned())?);
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert_eq!(store.count_prefix(""), 0);
+    assert!(store.remove("key".to_owned()).is_err());
+
+    // A set clears the TTL.
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.expire("other".to_owned(), ttl)?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    assert!(!store.persist("other".to_owned())?);
+
+    // The TTL, or its absence, survives compaction and restarts.
+    store.set("short".to_owned(), "value".to_owned())?;
+    store.expire("short".to_owned(), ttl)?;
+    store.expire("other".to_owned(), ttl)?;
+    store.persist("other".to_owned())?;
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("other".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("short".to_owned())?, None);
+
+    Ok(())
+}
+
+// Stores sharing a scheduler should all be compacted by its threads
+#[test]
+fn shared_compaction_scheduler() -> Result<()> {
+    let scheduler = Arc::new(CompactionScheduler::new(1)?);
+    let dirs: Vec<_> = (0..3)
+        .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+        .collect();
+    let finished = Arc::new(Mutex::new(vec![0; dirs.len()]));
+    let stores = dirs
+        .iter()
+        .enumerate()
+        .map(|(i, dir)| {
+            let sink = finished.clone();
+            KvStoreBuilder::new(dir.path())
+                .compact_threshold(64 * 1024)
+                .compaction_scheduler(scheduler.clone())
+                .on_compaction(move |e| {
+                    if let CompactionEvent::Finished { .. } = e {
+                        sink.lock().unwrap()[i] += 1;
+                    }
+                })
+                .build()
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let value = "v".repeat(1024);
+    for iter in 0..3 {
+        for store in stores.iter() {
+            for key_id in 0..100 {
+                store.set(format!("key{}", key_id), format!("{}{}", value, iter))?;
+            }
+        }
+    }
+    thread::sleep(Duration::from_secs(1));
+
+    assert!(finished.lock().unwrap().iter().all(|n| *n > 0));
+    for store in stores.iter() {
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{}{}", value, 2))
+            );
+        }
+    }
+    Ok(())
+}
+
+// RENAMENX should only rename to an absent key, COPY should respect `replace`
+#[test]
+fn rename_nx_and_copy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(!store.rename_nx("key1".to_owned(), "key2".to_owned())?);
+    assert!(store.rename_nx("key1".to_owned(), "key3".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value1".to_owned()));
+    assert!(store
+        .rename_nx("key1".to_owned(), "key4".to_owned())
+        .is_err());
+
+    assert!(!store.copy("key3".to_owned(), "key2".to_owned(), false)?);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(store.copy("key3".to_owned(), "key2".to_owned(), true)?);
+    assert!(store.copy("key3".to_owned(), "key5".to_owned(), false)?);
+    assert!(store
+        .copy("key1".to_owned(), "key6".to_owned(), true)
+        .is_err());
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, Some("value1".to_owned()));
+
+    // The renamed key and the copy keep the TTL of `src`.
+    store.expire("key5".to_owned(), Duration::from_millis(200))?;
+    assert!(store.rename_nx("key5".to_owned(), "key7".to_owned())?);
+    assert!(store.copy("key7".to_owned(), "key8".to_owned(), false)?);
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key7".to_owned())?, None);
+    assert_eq!(store.get("key8".to_owned())?, None);
+
+    Ok(())
+}
+
+// RENAMENX onto an expired key should succeed and count the record of the
+// expired key as garbage
+#[test]
+fn rename_nx_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key in &["src1", "src2", "dst2"] {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    store.get_and_expire("dst2".to_owned(), Duration::from_millis(50))?;
+    thread::sleep(Duration::from_millis(100));
+
+    let before = store.garbage();
+    assert!(store.rename_nx("src1".to_owned(), "dst1".to_owned())?);
+    let onto_absent = store.garbage() - before;
+    let before = store.garbage();
+    assert!(store.rename_nx("src2".to_owned(), "dst2".to_owned())?);
+    let onto_expired = store.garbage() - before;
+    assert!(
+        onto_expired > onto_absent,
+        "{} {}",
+        onto_expired,
+        onto_absent
+    );
+    assert_eq!(store.get("dst2".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("src2".to_owned())?, None);
+
+    Ok(())
+}
+
+// The index snapshot should be used after a clean shutdown only
+#[test]
+fn index_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot = temp_dir.path().join("index.snapshot");
+    let open = || {
+        KvStoreBuilder::new(temp_dir.path())
+            .index_snapshot(true)
+            .build()
+    };
+
+    let store = open()?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+    assert!(snapshot.is_file());
+
+    let store = open()?;
+    assert!(!snapshot.is_file());
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.count_prefix("key"), 99);
+    drop(store);
+
+    // A snapshot older than the data files is not trusted
+    let stale = fs::read(&snapshot)?;
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    drop(store);
+    assert!(!snapshot.is_file());
+    fs::write(&snapshot, stale)?;
+
+    let store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("changed".to_owned()));
+
+    Ok(())
+}
+
+// Creation time should survive overwrites and reopening, update time should not
+#[test]
+fn key_meta() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.meta("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let first = store.meta("key1".to_owned())?.unwrap();
+    assert!(first.created_at.is_some());
+    assert_eq!(first.created_at, first.updated_at);
+    assert_eq!(first.version, 1);
+
+    thread::sleep(Duration::from_millis(10));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let second = store.meta("key1".to_owned())?.unwrap();
+    assert_eq!(second.created_at, first.created_at);
+    assert!(second.updated_at > first.updated_at);
+    assert_eq!(second.version, 2);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.meta("key1".to_owned())?, Some(second));
+
+    // Records written before timestamps existed have unknown times
+    let legacy = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(legacy.path().join("meta"), "kvs")?;
+    fs::write(
+        legacy.path().join("1.data"),
+        r#"{"S":["key1","value1",0,1]}"#,
+    )?;
+    let store = KvStore::open(legacy.path())?;
+    let meta = store.meta("key1".to_owned())?.unwrap();
+    assert_eq!(meta.created_at, None);
+    assert_eq!(meta.updated_at, None);
+
+    Ok(())
+}
+
+// The bare meta file of old databases should open as version 1 and be
+// upgraded, and a newer format should be refused
+#[test]
+fn meta_format_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    drop(store);
+    let meta = fs::read_to_string(temp_dir.path().join("meta"))?;
+    assert_eq!(meta, meta_file("kvs", FORMAT_VERSION));
+    assert_eq!(
+        KvStoreBuilder::new(temp_dir.path()).inspect()?.version,
+        FORMAT_VERSION
+    );
+
+    let v1 = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(v1.path().join("meta"), "kvs")?;
+    fs::write(
+        v1.path().join("1.data"),
+        r#"{"S":["key1","value1",0,1,0,0]}"#,
+    )?;
+    assert_eq!(KvStoreBuilder::new(v1.path()).inspect()?.version, 1);
+    let store = KvStore::open(v1.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert_eq!(
+        fs::read_to_string(v1.path().join("meta"))?,
+        meta_file("kvs", FORMAT_VERSION)
+    );
+
+    let future = TempDir::new().expect("unable to create temporary working directory");
+    let meta = meta_file("kvs", FORMAT_VERSION + 1);
+    fs::write(future.path().join("meta"), &meta)?;
+    fs::write(future.path().join("1.data"), "")?;
+    let unsupported = |res: Result<()>| match res {
+        Err(e) => match e.downcast_ref::<KvsError>() {
+            Some(KvsError::UnsupportedVersion { found, supported }) => {
+                assert_eq!(*found, FORMAT_VERSION + 1);
+                assert_eq!(*supported, FORMAT_VERSION);
+            }
+            _ => panic!("unexpected error: {}", e),
+        },
+        Ok(()) => panic!("opened a database of a newer format"),
+    };
+    unsupported(KvStore::open(future.path()).map(|_| ()));
+    unsupported(KvStoreBuilder::new(future.path()).inspect().map(|_| ()));
+    // Refused before touching anything.
+    assert_eq!(fs::read_to_string(future.path().join("meta"))?, meta);
+    assert!(!future.path().join("LOCK").exists());
+
+    fs::write(future.path().join("meta"), "kvs\nversion=x\n")?;
+    assert!(KvStore::open(future.path()).is_err());
+
+    Ok(())
+}
+
+// A bincode database of version 1, whose records have no kind, should be
+// refused rather than misread, with any codec
+#[cfg(feature = "bincode")]
+#[test]
+fn legacy_bincode_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let meta = meta_file_with("kvs\nversion=1\ncodec=bincode\n");
+    fs::write(temp_dir.path().join("meta"), &meta)?;
+    // `Set` of version 1: the variant, then the fields before the kind.
+    let mut record = bincode::serialize(&(0u32, "key1", "value1", 1u64, 0u64, 0u64, 0u64))
+        .expect("unable to encode the record");
+    record.push(b'\n');
+    fs::write(temp_dir.path().join("1.data"), &record)?;
+
+    let legacy = |res: Result<()>| match res {
+        Err(e) => match e.downcast_ref::<KvsError>() {
+            Some(KvsError::LegacyRecords { found, codec }) => {
+                assert_eq!(*found, 1);
+                assert_eq!(codec, "bincode");
+            }
+            _ => panic!("unexpected error: {}", e),
+        },
+        Ok(()) => panic!("opened the records of version 1 with bincode"),
+    };
+    let builder = || KvStoreBuilder::new(temp_dir.path());
+    legacy(
+        builder()
+            .record_codec(kvs::BincodeCodec)
+            .build()
+            .map(|_| ()),
+    );
+    legacy(builder().build().map(|_| ()));
+    legacy(builder().inspect().map(|_| ()));
+    // Refused before touching anything.
+    assert_eq!(fs::read_to_string(temp_dir.path().join("meta"))?, meta);
+    assert_eq!(fs::read(temp_dir.path().join("1.data"))?, record);
+
+    Ok(())
+}
+
+// The meta file with its checksum, computed on its own.
+fn meta_file(engine: &str, version: u32) -> String {
+    meta_file_with(&format!("{}\nversion={}\n", engine, version))
+}
+
+fn meta_file_with(body: &str) -> String {
+    let mut crc = !0u32;
+    for b in body.bytes() {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    format!("{}checksum={:08x}\n", body, !crc)
+}
+
+// A damaged meta file should fail with MetaCorrupt, unlike the meta file of
+// another engine
+#[test]
+fn meta_checksum() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let path = temp_dir.path().join("meta");
+    let good = fs::read(&path)?;
+
+    let corrupt = |meta: &[u8]| -> Result<()> {
+        fs::write(&path, meta)?;
+        for res in [
+            KvStore::open(temp_dir.path()).map(|_| ()),
+            KvStoreBuilder::new(temp_dir.path()).inspect().map(|_| ()),
+        ]
+        .iter()
+        {
+            match res {
+                Err(e) => match e.downcast_ref::<KvsError>() {
+                    Some(KvsError::MetaCorrupt(_)) => {}
+                    _ => panic!("unexpected error: {}", e),
+                },
+                Ok(()) => panic!("opened with a damaged meta file: {:?}", meta),
+            }
+        }
+        Ok(())
+    };
+    // Truncated at every length, except to the bare legacy "kvs".
+    for len in (0..good.len()).filter(|len| *len != 3) {
+        corrupt(&good[..len])?;
+    }
+    // Any flipped bit.
+    for i in 0..good.len() {
+        for bit in 0..8 {
+            let mut flipped = good.clone();
+            flipped[i] ^= 1 << bit;
+            corrupt(&flipped)?;
+        }
+    }
+
+    // The meta file of another engine is not corrupt.
+    fs::write(&path, meta_file("sled", 1))?;
+    match KvStore::open(temp_dir.path()) {
+        Err(e) => match e.downcast_ref::<KvsError>() {
+            Some(KvsError::InvalidMeta(_)) => {}
+            _ => panic!("unexpected error: {}", e),
+        },
+        Ok(_) => panic!("opened the directory of another engine"),
+    }
+
+    fs::write(&path, &good)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// keys_matching should support each metacharacter of a glob
+#[test]
+fn keys_matching() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let keys = [
+        "user:1:session",
+        "user:22:session",
+        "user:1:profile",
+        "hallo",
+        "hello",
+        "hxllo",
+        "hllo",
+        "h*llo",
+        "h[llo",
+    ];
+    for key in keys.iter() {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    let matching = |pattern: &str| {
+        let mut keys = store.keys_matching(pattern);
+        keys.sort();
+        keys
+    };
+
+    assert_eq!(matching("*").len(), keys.len());
+    assert_eq!(
+        matching("user:*:session"),
+        vec!["user:1:session", "user:22:session"]
+    );
+    assert_eq!(matching("h*llo").len(), 6);
+    assert_eq!(
+        matching("h?llo"),
+        vec!["h*llo", "h[llo", "hallo", "hello", "hxllo"]
+    );
+    assert_eq!(matching("h[ae]llo"), vec!["hallo", "hello"]);
+    assert_eq!(
+        matching("h[^e]llo"),
+        vec!["h*llo", "h[llo", "hallo", "hxllo"]
+    );
+    assert_eq!(matching("h[a-f]llo"), vec!["hallo", "hello"]);
+    assert_eq!(matching("h\\*llo"), vec!["h*llo"]);
+    assert_eq!(matching("h\\[llo"), vec!["h[llo"]);
+    assert_eq!(matching("h[\\[]llo"), vec!["h[llo"]);
+    assert_eq!(
+        matching("user:?:*"),
+        vec!["user:1:profile", "user:1:session"]
+    );
+    assert!(matching("nothing*").is_empty());
+    assert_eq!(matching("hello"), vec!["hello"]);
+
+    Ok(())
+}
+
+// Writes over maxmemory should evict the least recently used keys
+#[test]
+fn maxmemory_lru() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new(temp_dir.path())
+        .maxmemory(4096)
+        .eviction(EvictionPolicy::Lru)
+        .build()?;
+
+    let value = "v".repeat(100);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), value.clone())?;
+        assert_eq!(store.get("key0".to_owned())?, Some(value.clone()));
+        assert!(store.live_size() <= 4096);
+    }
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key99".to_owned())?, Some(value.clone()));
+
+    // Evictions are persisted
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.live_size() <= 4096);
+    assert_eq!(store.get("key0".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Random eviction should also keep the store under maxmemory
+#[test]
+fn maxmemory_random() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new(temp_dir.path())
+        .maxmemory(4096)
+        .eviction(EvictionPolicy::Random)
+        .build()?;
+
+    let value = "v".repeat(100);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), value.clone())?;
+        assert!(store.live_size() <= 4096);
+    }
+    assert_eq!(store.get("key99".to_owned())?, Some(value));
+    assert!(store.count_prefix("key") < 100);
+
+    Ok(())
+}
+
+// Touched keys should be evicted last, without being rewritten
+#[test]
+fn touch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new(temp_dir.path())
+        .maxmemory(4096)
+        .eviction(EvictionPolicy::Lru)
+        .build()?;
+    assert!(!store.touch("key0".to_owned())?);
+
+    let value = "v".repeat(100);
+    store.set("key0".to_owned(), value.clone())?;
+    let meta = store.meta("key0".to_owned())?.unwrap();
+    for key_id in 1..100 {
+        store.set(format!("key{}", key_id), value.clone())?;
+        assert!(store.touch("key0".to_owned())?);
+    }
+    assert_eq!(store.get("key0".to_owned())?, Some(value));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    let touched = store.meta("key0".to_owned())?.unwrap();
+    assert_eq!(touched.version, meta.version);
+    assert_eq!(touched.updated_at, meta.updated_at);
+
+    Ok(())
+}
+
+// replicate should continue from an offset in the backlog, and resync in
+// full otherwise
+#[test]
+fn replicate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    {
+        let store = KvStore::open(temp_dir.path())?;
+        assert!(store.replicate("?", 0, Box::new(|_, _| true)).is_err());
+    }
+    let store = KvStoreBuilder::new(temp_dir.path())
+        .replication_backlog(1 << 20)
+        .build()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let id = match store.replicate("?", 0, Box::new(|_, _| true))? {
+        ReplSync::Full { id, offset } => {
+            assert_eq!(offset, 1);
+            id
+        }
+        sync => panic!("unexpected sync: {:?}", sync),
+    };
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen2 = seen.clone();
+    let sink = Box::new(move |offset, record: &Arc<str>| {
+        seen2.lock().unwrap().push((offset, record.to_string()));
+        true
+    });
+    match store.replicate(&id, 0, sink)? {
+        ReplSync::Continue(backlog) => {
+            assert_eq!(backlog.len(), 1);
+            assert_eq!(backlog[0].0, 0);
+            assert!(backlog[0].1.contains("value1"));
+        }
+        sync => panic!("unexpected sync: {:?}", sync),
+    }
+    store.remove("key1".to_owned())?;
+    {
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0].0, 1);
+        assert!(seen[0].1.contains("key1"));
+    }
+
+    // Ahead of the primary, or of another history.
+    for (id, offset) in [(id.as_str(), 3), ("0", 0)].iter() {
+        match store.replicate(id, *offset, Box::new(|_, _| true))? {
+            ReplSync::Full { offset, .. } => assert_eq!(offset, 2),
+            sync => panic!("unexpected sync: {:?}", sync),
+        }
+    }
+
+    Ok(())
+}
+
+// Commands captured from one store and replayed into another should give
+// the same pairs, even when replayed twice
+#[test]
+fn apply_command() -> Result<()> {
+    let src_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dst_dir = TempDir::new().expect("unable to create temporary working directory");
+    let src = KvStoreBuilder::new(src_dir.path())
+        .replication_backlog(1 << 20)
+        .build()?;
+    let captured = Arc::new(Mutex::new(Vec::new()));
+    let captured2 = captured.clone();
+    let sink = Box::new(move |_, record: &Arc<str>| {
+        captured2.lock().unwrap().push(record.to_string());
+        true
+    });
+    src.replicate("?", 0, sink)?;
+    for key_id in 0..20 {
+        src.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in (0..20).step_by(3) {
+        src.remove(format!("key{}", key_id))?;
+    }
+    src.set("key1".to_owned(), "changed".to_owned())?;
+
+    let dst = KvStore::open(dst_dir.path())?;
+    let records = captured.lock().unwrap().clone();
+    for _ in 0..2 {
+        for record in &records {
+            dst.apply_command(Command::from_slice(record.as_bytes())?)?;
+        }
+    }
+    dst.apply_command(Command::Rm("missing".to_owned()))?;
+
+    let mut keys = src.keys();
+    keys.sort();
+    let mut dst_keys = dst.keys();
+    dst_keys.sort();
+    assert_eq!(keys, dst_keys);
+    for key in keys {
+        assert_eq!(
+            src.get_with_version(key.clone())?,
+            dst.get_with_version(key)?
+        );
+    }
+
+    // Written like local writes.
+    drop(dst);
+    let dst = KvStore::open(dst_dir.path())?;
+    assert_eq!(dst.get("key1".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(dst.get("key0".to_owned())?, None);
+
+    Ok(())
+}
+
+// Empty values should round-trip, and the empty key is rejected only when
+// configured
+#[test]
+fn empty_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    {
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("".to_owned(), "".to_owned())?;
+        store.set("key1".to_owned(), "".to_owned())?;
+        assert_eq!(store.get("".to_owned())?, Some("".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+    }
+
+    let store = KvStoreBuilder::new(temp_dir.path())
+        .reject_empty_keys(true)
+        .build()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+    // Written before the option, still readable and removable.
+    assert_eq!(store.get("".to_owned())?, Some("".to_owned()));
+    store.remove("".to_owned())?;
+
+    let empty_key = |res: Result<()>| match res {
+        Err(e) => match e.downcast_ref::<KvsError>() {
+            Some(KvsError::EmptyKey) => {}
+            _ => panic!("unexpected error: {}", e),
+        },
+        Ok(()) => panic!("empty key accepted"),
+    };
+    empty_key(store.set("".to_owned(), "value".to_owned()));
+    empty_key(store.increment_float("".to_owned(), 1.0).map(|_| ()));
+    empty_key(
+        store
+            .copy("key1".to_owned(), "".to_owned(), true)
+            .map(|_| ()),
+    );
+    let pairs = vec![
+        ("key2".to_owned(), "value2".to_owned()),
+        ("".to_owned(), "value".to_owned()),
+    ];
+    empty_key(store.set_many_get(pairs).map(|_| ()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn namespace_quotas() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || KvStoreBuilder::new(temp_dir.path()).namespaces(':').build();
+    let exceeded = |res: Result<()>| match res {
+        Err(e) => match e.downcast_ref::<KvsError>() {
+            Some(KvsError::QuotaExceeded { namespace }) => assert_eq!(namespace, "a"),
+            _ => panic!("unexpected error: {}", e),
+        },
+        Ok(()) => panic!("quota not enforced"),
+    };
+    {
+        let store = open()?;
+        let quota = Quota {
+            max_keys: Some(2),
+            max_bytes: None,
+        };
+        store.set_quota("a", quota)?;
+        store.set("a:1".to_owned(), "value".to_owned())?;
+        store.set("a:2".to_owned(), "value".to_owned())?;
+        exceeded(store.set("a:3".to_owned(), "value".to_owned()));
+        // Other namespaces and overwrites are not limited by the keys.
+        store.set("b:1".to_owned(), "value".to_owned())?;
+        store.set("nosep".to_owned(), "value".to_owned())?;
+        store.set("a:1".to_owned(), "value1".to_owned())?;
+        exceeded(
+            store
+                .copy("b:1".to_owned(), "a:3".to_owned(), false)
+                .map(|_| ()),
+        );
+        exceeded(
+            store
+                .rename_nx("b:1".to_owned(), "a:3".to_owned())
+                .map(|_| ()),
+        );
+        let pairs = vec![
+            ("b:2".to_owned(), "value".to_owned()),
+            ("a:3".to_owned(), "value".to_owned()),
+        ];
+        exceeded(store.set_many_if_absent(pairs).map(|_| ()));
+        assert_eq!(store.get("b:2".to_owned())?, None);
+
+        let (usage, _) = store.namespace_usage("a")?;
+        assert_eq!(usage.keys, 2);
+        assert_eq!(store.namespace_usage("")?.0.keys, 1);
+        assert_eq!(store.namespace_usage("c")?.0, NamespaceUsage::default());
+
+        // A namespace beyond a new quota may still shrink.
+        let quota = Quota {
+            max_keys: None,
+            max_bytes: Some(usage.bytes - 1),
+        };
+        store.set_quota("a", quota)?;
+        exceeded(store.set("a:1".to_owned(), "value11".to_owned()));
+        store.set("a:1".to_owned(), "v".to_owned())?;
+        store.remove("a:2".to_owned())?;
+        assert_eq!(store.namespace_usage("a")?.0.keys, 1);
+    }
+
+    let store = open()?;
+    let (usage, quota) = store.namespace_usage("a")?;
+    assert_eq!(usage.keys, 1);
+    assert!(quota.max_bytes.is_some());
+    store.compact()?;
+    assert_eq!(store.namespace_usage("a")?.0, usage);
+    store.set_quota("a", Quota::default())?;
+    store.set("a:2".to_owned(), "value".repeat(100))?;
+    assert_eq!(store.namespace_usage("a")?.1, Quota::default());
+
+    // Without namespaces.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    match store.namespace_usage("a") {
+        Err(e) => match e.downcast_ref::<KvsError>() {
+            Some(KvsError::NamespacesOff) => {}
+            _ => panic!("unexpected error: {}", e),
+        },
+        Ok(usage) => panic!("usage without namespaces: {:?}", usage),
+    }
+
+    Ok(())
+}
+
+// set_if_present and set_if_absent should only write when the key exists,
+// or doesn't
+#[test]
+fn set_if_present_or_absent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = || "key1".to_owned();
+
+    assert!(!store.set_if_present(key(), "value1".to_owned())?);
+    assert_eq!(store.get(key())?, None);
+    assert!(store.set_if_absent(key(), "value1".to_owned())?);
+    assert!(!store.set_if_absent(key(), "value2".to_owned())?);
+    assert_eq!(
+        store.get_with_version(key())?,
+        Some(("value1".to_owned(), 1))
+    );
+    assert!(store.set_if_present(key(), "value3".to_owned())?);
+    assert_eq!(
+        store.get_with_version(key())?,
+        Some(("value3".to_owned(), 2))
+    );
+
+    store.remove(key())?;
+    assert!(!store.set_if_present(key(), "value4".to_owned())?);
+    assert!(store.set_if_absent(key(), "value5".to_owned())?);
+    assert!(!store.set_created(key(), "value5".to_owned())?);
+    assert!(store.set_created("key2".to_owned(), "value1".to_owned())?);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(key())?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+// flush should sync the active file, also a new one after a compaction
+#[test]
+fn flush() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new(temp_dir.path())
+        .preallocate(1 << 16)
+        .build()?;
+    store.flush()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.flush()?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.flush()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// multi_get should align the values with the keys, duplicates included
+#[test]
+fn multi_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let keys: Vec<String> = ["key2", "missing", "key1", "key2", "missing", "key2"]
+        .iter()
+        .map(|key| key.to_string())
+        .collect();
+    let value = |val: &str| Some(val.to_owned());
+    assert_eq!(
+        store.multi_get(&keys)?,
+        vec![
+            value("value2"),
+            None,
+            value("value1"),
+            value("value2"),
+            None,
+            value("value2"),
+        ]
+    );
+    assert!(store.multi_get(&[])?.is_empty());
+
+    Ok(())
+}
+
+// get_range should slice the bytes of the value like GETRANGE of redis
+#[test]
+fn get_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "This is a string".to_owned())?;
+    store.set("\"quoted\"".to_owned(), "a\"b\\c\nd\u{1}é!".to_owned())?;
+    store.set("empty".to_owned(), "".to_owned())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        let range = |key: &str, start, end| store.get_range(key.to_owned(), start, end);
+        let value = |val: &str| Some(val.to_owned());
+        assert_eq!(range("key", 0, 3)?, value("This"));
+        assert_eq!(range("key", -3, -1)?, value("ing"));
+        assert_eq!(range("key", 0, -1)?, value("This is a string"));
+        assert_eq!(range("key", 10, 100)?, value("string"));
+        assert_eq!(range("key", -100, 3)?, value("This"));
+        assert_eq!(range("key", 5, 2)?, value(""));
+        assert_eq!(range("key", 100, 200)?, value(""));
+        assert_eq!(range("empty", 0, -1)?, value(""));
+        assert_eq!(range("missing", 0, -1)?, None);
+        // The escapes of the record are decoded.
+        assert_eq!(range("\"quoted\"", 0, 7)?, value("a\"b\\c\nd\u{1}"));
+        assert_eq!(range("\"quoted\"", 1, 3)?, value("\"b\\"));
+        assert_eq!(range("\"quoted\"", -3, -1)?, value("é!"));
+        // Half of "é".
+        assert_eq!(range("\"quoted\"", 8, 8)?, value("\u{fffd}"));
+        Ok(())
+    };
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+
+    // Only the start of the record is read.
+    store.set("big".to_owned(), format!("head{}", "x".repeat(1000)))?;
+    let path = temp_dir.path().join("3.data");
+    let data = fs::read_to_string(&path)?;
+    let start = data.find(r#"{"S":["big","#).unwrap();
+    let end = start + data[start..].find("]}").unwrap();
+    fs::write(&path, format!("{}}}}}{}", &data[..end], &data[end + 2..]))?;
+    assert_eq!(
+        store.get_range("big".to_owned(), 0, 3)?,
+        Some("head".to_owned())
+    );
+    assert!(store.get("big".to_owned()).is_err());
+
+    Ok(())
+}
+
+// get_capped should cut long values at a character boundary
+#[test]
+fn get_capped() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "This is a string".to_owned())?;
+    store.set("utf8".to_owned(), "aé€".to_owned())?;
+    store.set("escaped".to_owned(), "a\"b\nc".to_owned())?;
+
+    let capped = |key: &str, max| store.get_capped(key.to_owned(), max);
+    let value = |val: &str, cut| Some((val.to_owned(), cut));
+    assert_eq!(capped("key", 4)?, value("This", true));
+    assert_eq!(capped("key", 16)?, value("This is a string", false));
+    assert_eq!(capped("key", 100)?, value("This is a string", false));
+    assert_eq!(capped("key", 0)?, value("", true));
+    assert_eq!(capped("missing", 4)?, None);
+    assert_eq!(capped("escaped", 3)?, value("a\"b", true));
+    // "a" is 1 byte, "é" 2 and "€" 3: a character is never split.
+    assert_eq!(capped("utf8", 1)?, value("a", true));
+    assert_eq!(capped("utf8", 2)?, value("a", true));
+    assert_eq!(capped("utf8", 3)?, value("aé", true));
+    assert_eq!(capped("utf8", 5)?, value("aé", true));
+    assert_eq!(capped("utf8", 6)?, value("aé€", false));
+
+    // Only the start of the record is read.
+    store.set("big".to_owned(), format!("head{}", "x".repeat(1000)))?;
+    let path = temp_dir.path().join("1.data");
+    let data = fs::read_to_string(&path)?;
+    let start = data.find(r#"{"S":["big","#).unwrap();
+    let end = start + data[start..].find("]}").unwrap();
+    fs::write(&path, format!("{}}}}}{}", &data[..end], &data[end + 2..]))?;
+    assert_eq!(capped("big", 4)?, value("head", true));
+    assert!(store.get("big".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Every live key should come up as a random key
+#[test]
+fn random_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.random_key(), None);
+
+    for key_id in 0..4 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.remove("key3".to_owned())?;
+    let mut seen = std::collections::HashSet::new();
+    for _ in 0..1000 {
+        seen.insert(store.random_key().unwrap());
+    }
+    let mut seen: Vec<_> = seen.into_iter().collect();
+    seen.sort();
+    assert_eq!(seen, vec!["key0", "key1", "key2"]);
+
+    Ok(())
+}
+
+// Failing compactions should be retried, then mark the store degraded
+#[test]
+fn compaction_retries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new(temp_dir.path())
+        .compact_threshold(1024)
+        .compaction_retries(2, Duration::from_millis(10))
+        .build()?;
+
+    // Merged files can't be created where a directory is in the way.
+    let blockers: Vec<_> = (2..200)
+        .map(|id| temp_dir.path().join(format!("{}.data.temp", id)))
+        .collect();
+    for path in blockers.iter() {
+        fs::create_dir(path)?;
+    }
+    let value = "v".repeat(100);
+    for _ in 0..20 {
+        store.set("key1".to_owned(), value.clone())?;
+    }
+    thread::sleep(Duration::from_millis(500));
+    let health = store.compaction_health();
+    assert!(health.degraded);
+    assert!(health.failures >= 3);
+    assert!(health.last_error.is_some());
+
+    for path in blockers.iter() {
+        fs::remove_dir(path)?;
+    }
+    store.set("key1".to_owned(), value.clone())?;
+    thread::sleep(Duration::from_millis(500));
+    let health = store.compaction_health();
+    assert!(!health.degraded);
+    assert_eq!(health.failures, 0);
+    assert_eq!(store.get("key1".to_owned())?, Some(value));
+
+    Ok(())
+}
+
+// Writes should compact inline once the compacter thread is dead, and the
+// store should report it
+#[test]
+fn dead_compacter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let killed = AtomicBool::new(false);
+    let finished = Arc::new(AtomicUsize::new(0));
+    let finished2 = finished.clone();
+    let store = KvStoreBuilder::new(temp_dir.path())
+        .compact_threshold(1024)
+        .on_compaction(move |event| match event {
+            CompactionEvent::Started { .. } if !killed.swap(true, Ordering::SeqCst) => {
+                panic!("killing the compacter")
+            }
+            CompactionEvent::Finished { .. } => {
+                finished2.fetch_add(1, Ordering::SeqCst);
+            }
+            _ => {}
+        })
+        .build()?;
+
+    let value = "v".repeat(100);
+    for _ in 0..500 {
+        store.set("key1".to_owned(), value.clone())?;
+        if finished.load(Ordering::SeqCst) > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    let health = store.compaction_health();
+    assert!(health.compacter_dead);
+    assert!(health.degraded);
+    assert!(finished.load(Ordering::SeqCst) > 0);
+    assert_eq!(store.get("key1".to_owned())?, Some(value));
+
+    Ok(())
+}
+
+// Compactions should run on a thread with the configured name
+#[test]
+fn compacter_thread_name() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let names = Arc::new(Mutex::new(Vec::new()));
+    let names2 = names.clone();
+    let store = KvStoreBuilder::new(temp_dir.path())
+        .compact_threshold(1024)
+        .compacter_name("store-a-compacter")
+        .compacter_stack_size(4 * 1024 * 1024)
+        .on_compaction(move |_| {
+            let name = thread::current().name().map(str::to_owned);
+            names2.lock().unwrap().push(name);
+        })
+        .build()?;
+
+    let value = "v".repeat(100);
+    for _ in 0..20 {
+        store.set("key1".to_owned(), value.clone())?;
+    }
+    thread::sleep(Duration::from_millis(500));
+    let names = names.lock().unwrap();
+    assert!(!names.is_empty());
+    assert!(names
+        .iter()
+        .all(|name| name.as_ref().map(String::as_str) == Some("store-a-compacter")));
+
+    Ok(())
+}
+
+// A compaction failing to put the merged file in place should be aborted
+// and reported, without losing data
+#[test]
+fn compaction_rename_failure() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new(temp_dir.path())
+        .compact_threshold(1024)
+        .compaction_retries(0, Duration::from_millis(10))
+        .build()?;
+
+    // The first compaction merges into 2.data, a directory is in the way.
+    let blocker = temp_dir.path().join("2.data");
+    fs::create_dir(&blocker)?;
+    fs::write(blocker.join("file"), "x")?;
+    let value = "v".repeat(100);
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id % 4), format!("{}{}", value, key_id))?;
+    }
+    thread::sleep(Duration::from_millis(500));
+
+    assert!(store.compaction_health().last_error.is_some());
+    assert!(!temp_dir.path().join("2.data.temp").exists());
+    for key_id in 16..20 {
+        assert_eq!(
+            store.get(format!("key{}", key_id % 4))?,
+            Some(format!("{}{}", value, key_id))
+        );
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 16..20 {
+        assert_eq!(
+            store.get(format!("key{}", key_id % 4))?,
+            Some(format!("{}{}", value, key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Concurrent swaps of the same pair in both orders should neither deadlock
+// nor lose a value
+#[test]
+fn concurrent_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    assert!(store.swap("a".to_owned(), "c".to_owned()).is_err());
+
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    if i % 2 == 0 {
+                        store.swap("a".to_owned(), "b".to_owned()).unwrap();
+                    } else {
+                        store.swap("b".to_owned(), "a".to_owned()).unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // 800 swaps put the values back.
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, Some("2".to_owned()));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, Some("2".to_owned()));
+
+    Ok(())
+}
+
+// History should list the values still on disk, oldest first, and lose the
+// overwritten ones on compaction
+#[cfg(feature = "history")]
+#[test]
+fn history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.history("key")?.is_empty());
+
+    store.set("key".to_owned(), "1".to_owned())?;
+    store.set("other".to_owned(), "x".to_owned())?;
+    store.set("key".to_owned(), "2".to_owned())?;
+    store.remove("key".to_owned())?;
+    store.set("key".to_owned(), "3".to_owned())?;
+    assert_eq!(store.history("key")?, vec!["1", "2", "3"]);
+
+    store.compact()?;
+    assert_eq!(store.history("key")?, vec!["3"]);
+    store.set("key".to_owned(), "4".to_owned())?;
+    assert_eq!(store.history("key")?, vec!["3", "4"]);
+    assert_eq!(store.history("other")?, vec!["x"]);
+
+    Ok(())
+}
+
+// Compaction should write a bloom filter of the merged file, which the
+// scans for a key trust once loaded, and drop it with the file
+#[cfg(feature = "history")]
+#[test]
+fn bloom_filters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let bloom = |id: u64| temp_dir.path().join(format!("{}.bloom", id));
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "1".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+    assert!(!bloom(1).exists());
+    store.compact()?;
+    assert!(bloom(2).exists());
+    for key_id in 1..100 {
+        assert_eq!(store.history(&format!("key{}", key_id))?, vec!["1"]);
+    }
+    store.set("key1".to_owned(), "2".to_owned())?;
+    store.compact()?;
+    assert!(!bloom(2).exists());
+    assert!(bloom(4).exists());
+    assert_eq!(store.history("key1")?, vec!["2"]);
+    store.set("other".to_owned(), "x".to_owned())?;
+    drop(store);
+
+    // A filter matching nothing hides the keys of its file.
+    fs::write(bloom(4), [1, 0, 0, 0, 0, 0, 0, 0, 0])?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.history("key1")?.is_empty());
+    assert_eq!(store.get("key1".to_owned())?, Some("2".to_owned()));
+    drop(store);
+
+    // A bad filter is ignored, like no filter.
+    fs::write(bloom(4), [0])?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.history("key1")?, vec!["2"]);
+    drop(store);
+
+    let store = KvStoreBuilder::new(temp_dir.path())
+        .bloom_bits_per_key(0)
+        .build()?;
+    store.set("key1".to_owned(), "3".to_owned())?;
+    store.compact()?;
+    assert!(!bloom(4).exists());
+    assert!(!bloom(6).exists());
+    assert_eq!(store.history("key1")?, vec!["3"]);
+
+    Ok(())
+}
+
+// A directory should be opened by one store at a time, unless the lock is
+// disabled
+#[test]
+fn dir_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(e) => match e.downcast_ref::<KvsError>() {
+            Some(KvsError::Locked(_)) => {}
+            _ => panic!("unexpected error: {}", e),
+        },
+        Ok(_) => panic!("opened a locked directory"),
+    }
+    let unlocked = KvStoreBuilder::new(temp_dir.path())
+        .dir_lock(false)
+        .build()?;
+    assert_eq!(unlocked.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(unlocked);
+
+    // A clone keeps the directory locked
+    let clone = store.clone();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(clone);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// remove_prefix should remove the matching keys durably and count them
+#[test]
+fn remove_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("a{}", key_id), "value".to_owned())?;
+        store.set(format!("b{}", key_id), "value".to_owned())?;
+    }
+    assert_eq!(store.remove_prefix("a1")?, 11);
+    assert_eq!(store.remove_prefix("a1")?, 0);
+    assert_eq!(store.count_prefix("a"), 89);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a10".to_owned())?, None);
+    assert_eq!(store.count_prefix("a"), 89);
+    assert_eq!(store.remove_prefix("")?, 189);
+    assert_eq!(store.count_prefix(""), 0);
+
+    Ok(())
+}
+
+// Keys and values with NUL, CR, LF and quotes should survive a reopen
+#[test]
+fn adversarial_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let keys = ["nul\0inside", "\r\n", "\"]}{\"S\":[", "\\", "ünïcödé"];
+    for key in keys.iter() {
+        store.set(key.to_string(), format!("{}\0\r\n", key))?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key in keys.iter() {
+        assert_eq!(store.get(key.to_string())?, Some(format!("{}\0\r\n", key)));
+    }
+    store.compact()?;
+    for key in keys.iter() {
+        assert_eq!(store.get(key.to_string())?, Some(format!("{}\0\r\n", key)));
+    }
+
+    Ok(())
+}
+
+// retain_scan should return the pairs with the prefix kept by the predicate
+#[test]
+fn retain_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("a{}", key_id), format!("{}", key_id % 3))?;
+        store.set(format!("b{}", key_id), "0".to_owned())?;
+    }
+    store.remove("a3".to_owned())?;
+
+    let pairs = store.retain_scan("a", |_, val| val == "0")?;
+    assert_eq!(pairs.len(), 33);
+    assert!(pairs
+        .iter()
+        .all(|(key, val)| key.starts_with('a') && val == "0"));
+    assert!(pairs.windows(2).all(|w| w[0].0 < w[1].0));
+    assert_eq!(store.retain_scan("a1", |key, _| key.len() == 3)?.len(), 10);
+    assert!(store.retain_scan("c", |_, _| true)?.is_empty());
+
+    Ok(())
+}
+
+// iter_log_order should yield the live pairs in the order of their last writes
+#[test]
+fn iter_log_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["a", "b", "c"] {
+        store.set(key.to_string(), format!("{}1", key))?;
+    }
+    store.set("a".to_owned(), "a2".to_owned())?;
+    store.remove("b".to_owned())?;
+    store.set("d".to_owned(), "d1".to_owned())?;
+
+    let pairs =
+        |store: &KvStore| -> Result<Vec<(String, String)>> { store.iter_log_order().collect() };
+    let expected = vec![
+        ("c".to_owned(), "c1".to_owned()),
+        ("a".to_owned(), "a2".to_owned()),
+        ("d".to_owned(), "d1".to_owned()),
+    ];
+    assert_eq!(pairs(&store)?, expected);
+
+    // Compaction rewrites the merged pairs in no particular order.
+    store.compact()?;
+    store.set("e".to_owned(), "e1".to_owned())?;
+    let mut after = pairs(&store)?;
+    assert_eq!(after.pop(), Some(("e".to_owned(), "e1".to_owned())));
+    after.sort();
+    let mut expected = expected;
+    expected.sort();
+    assert_eq!(after, expected);
+
+    Ok(())
+}
+
+// Compaction should only rewrite the files with enough garbage, and keep
+// the keys removed in them removed
+#[test]
+fn compact_min_garbage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data = |id: u64| temp_dir.path().join(format!("{}.data", id));
+    let store = KvStoreBuilder::new(temp_dir.path())
+        .compact_min_garbage(0.5)
+        .build()?;
+    for key_id in 0..100 {
+        store.set(format!("cold{}", key_id), "value".to_owned())?;
+    }
+    store.set("gone".to_owned(), "value".to_owned())?;
+    for iter in 0..200 {
+        store.set("hot".to_owned(), format!("{}", iter))?;
+    }
+    store.compact()?;
+    assert!(!data(1).exists());
+    assert!(data(2).exists());
+
+    // 2.data holds little garbage, 3.data mostly garbage.
+    store.remove("gone".to_owned())?;
+    for iter in 0..200 {
+        store.set("hot".to_owned(), format!("{}", iter + 200))?;
+    }
+    store.compact()?;
+    assert!(data(2).exists());
+    assert!(!data(3).exists());
+    assert!(data(4).exists());
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("cold{}", key_id))?,
+                Some("value".to_owned())
+            );
+        }
+        assert_eq!(store.get("hot".to_owned())?, Some("399".to_owned()));
+        assert_eq!(store.get("gone".to_owned())?, None);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+
+    // The value of "gone" in 2.data must not come back.
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// A preallocated active file should be written after its last record, and
+// truncated once it is no longer active
+#[test]
+fn preallocate() -> Result<()> {
+    const PREALLOC: u64 = 1 << 20;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data = |id: u64| temp_dir.path().join(format!("{}.data", id));
+    let len = |id: u64| fs::metadata(data(id)).map(|m| m.len()).unwrap_or(0);
+    let open = || {
+        KvStoreBuilder::new(temp_dir.path())
+            .preallocate(PREALLOC)
+            .build()
+    };
+
+    let store = open()?;
+    assert_eq!(len(1), PREALLOC);
+    for iter in 0..100 {
+        store.set(format!("key{}", iter % 10), format!("{}", iter))?;
+    }
+    store.remove("key0".to_owned())?;
+    store.compact()?;
+    assert!(!data(1).exists());
+    assert!(len(2) < PREALLOC);
+    assert_eq!(len(3), PREALLOC);
+    store.set("key1".to_owned(), "new".to_owned())?;
+    drop(store);
+    assert!(len(3) < PREALLOC);
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+        for key_id in 2..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{}", 90 + key_id))
+            );
+        }
+        Ok(())
+    };
+    let store = open()?;
+    assert_eq!(len(3), PREALLOC);
+    check(&store)?;
+    store.set("key2".to_owned(), "92".to_owned())?;
+    check(&store)?;
+    drop(store);
+
+    // As left by a crash, with the unused space and an active file of
+    // zeros created just before it.
+    fs::OpenOptions::new()
+        .write(true)
+        .open(data(3))?
+        .set_len(PREALLOC)?;
+    fs::File::create(data(4))?.set_len(PREALLOC)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!data(4).exists());
+    check(&store)?;
+    let pairs: Vec<_> = store.iter_log_order().collect::<Result<_>>()?;
+    assert_eq!(pairs.len(), 9);
+
+    Ok(())
+}
+
+// set_many should set every pair, bump the versions, and write nothing
+// when a key is rejected
+#[test]
+fn set_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new(temp_dir.path())
+        .reject_empty_keys(true)
+        .build()?;
+    store.set("a".to_owned(), "1".to_owned())?;
+
+    store.set_many(vec![
+        ("a".to_owned(), "2".to_owned()),
+        ("b".to_owned(), "3".to_owned()),
+        ("a".to_owned(), "4".to_owned()),
+    ])?;
+    assert_eq!(store.get("a".to_owned())?, Some("4".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, Some("3".to_owned()));
+    assert_eq!(
+        store.get_with_version("a".to_owned())?.map(|(_, v)| v),
+        Some(3)
+    );
+
+    let rejected = store.set_many(vec![
+        ("c".to_owned(), "5".to_owned()),
+        ("".to_owned(), "6".to_owned()),
+    ]);
+    assert!(rejected.is_err());
+    assert_eq!(store.get("c".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("4".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, Some("3".to_owned()));
+    assert_eq!(store.count_prefix(""), 2);
+
+    Ok(())
+}
+
+// set_many_get should return the previous values, and never interleave
+// with another batch
+#[test]
+fn set_many_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+
+    let old = store.set_many_get(vec![
+        ("a".to_owned(), "2".to_owned()),
+        ("b".to_owned(), "3".to_owned()),
+        ("a".to_owned(), "4".to_owned()),
+    ])?;
+    assert_eq!(old, vec![Some("1".to_owned()), None, Some("2".to_owned())]);
+    assert_eq!(store.get("a".to_owned())?, Some("4".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, Some("3".to_owned()));
+    assert_eq!(
+        store.get_with_version("a".to_owned())?.map(|(_, v)| v),
+        Some(3)
+    );
+
+    // Start from equal values, each batch must keep them equal.
+    store.set("b".to_owned(), "4".to_owned())?;
+    let handles: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for iter in 0..100 {
+                    let val = format!("{}-{}", thread_id, iter);
+                    let old = store
+                        .set_many_get(vec![("a".to_owned(), val.clone()), ("b".to_owned(), val)])
+                        .unwrap();
+                    assert_eq!(old[0], old[1]);
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("a".to_owned())?, store.get("b".to_owned())?);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, store.get("b".to_owned())?);
+
+    Ok(())
+}
+
+// build_with_status should tell a new database from an existing one
+#[test]
+fn build_with_status() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (store, status) = KvStoreBuilder::new(temp_dir.path()).build_with_status()?;
+    assert_eq!(status, OpenStatus::Created);
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let (store, status) = KvStoreBuilder::new(temp_dir.path()).build_with_status()?;
+    assert_eq!(status, OpenStatus::Opened);
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Reads should return the right value while compactions move the records
+// and delete their files
+#[test]
+fn get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut key_id = thread_id;
+                while !done.load(Ordering::SeqCst) {
+                    key_id = (key_id + 7) % 200;
+                    assert_eq!(
+                        store.get(format!("key{}", key_id)).unwrap(),
+                        Some(format!("value{}", key_id))
+                    );
+                }
+            })
+        })
+        .collect();
+    for _ in 0..50 {
+        store.compact()?;
+    }
+    done.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    Ok(())
+}
+
+// Compaction in key order should write the merged records sorted by key
+#[test]
+fn compact_key_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new(temp_dir.path())
+        .compact_key_order(true)
+        .build()?;
+    for key_id in (0..100).rev() {
+        store.set(format!("key{:03}", key_id), "value".to_owned())?;
+    }
+    store.compact()?;
+
+    let keys: Vec<_> = store
+        .iter_log_order()
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    let expected: Vec<_> = (0..100).map(|key_id| format!("key{:03}", key_id)).collect();
+    assert_eq!(keys, expected);
+
+    Ok(())
+}
+
+// Paused background compactions should let garbage pile up, and run once
+// resumed
+#[test]
+fn pause_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let finished = Arc::new(AtomicUsize::new(0));
+    let counter = finished.clone();
+    let store = KvStoreBuilder::new(temp_dir.path())
+        .compact_threshold(1024)
+        .on_compaction(move |event| {
+            if let CompactionEvent::Finished { .. } = event {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+        .build()?;
+
+    store.pause_compaction();
+    assert!(store.compaction_health().paused);
+    for iter in 0..20 {
+        store.set("key".to_owned(), format!("value{}", iter).repeat(100))?;
+    }
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(finished.load(Ordering::SeqCst), 0);
+
+    store.resume_compaction();
+    assert!(!store.compaction_health().paused);
+    for _ in 0..50 {
+        if finished.load(Ordering::SeqCst) > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(finished.load(Ordering::SeqCst), 1);
+    assert_eq!(store.get("key".to_owned())?, Some("value19".repeat(100)));
+
+    Ok(())
+}
+
+// Every hasher of the index should find the keys, across compactions and
+// reopens with another hasher
+#[test]
+fn index_hasher() -> Result<()> {
+    let hashers = [
+        IndexHasher::Sip,
+        #[cfg(feature = "ahash")]
+        IndexHasher::AHash,
+    ];
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for (round, hasher) in hashers.iter().chain(hashers.iter()).enumerate() {
+        let store = KvStoreBuilder::new(temp_dir.path())
+            .index_hasher(*hasher)
+            .build()?;
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                if round == 0 {
+                    None
+                } else {
+                    Some(format!("value{}", round - 1))
+                }
+            );
+            store.set(format!("key{}", key_id), format!("value{}", round))?;
+        }
+        store.remove("key0".to_owned())?;
+        store.set("key0".to_owned(), format!("value{}", round))?;
+        store.compact()?;
+        assert_eq!(store.keys().len(), 100);
+    }
+
+    Ok(())
+}
+
+// Should get the TTL with the value, and set a new one without changing
+// the value
+#[test]
+fn get_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let ttl = Duration::from_millis(300);
+
+    assert_eq!(store.get_with_ttl("key".to_owned())?, None);
+    assert_eq!(store.get_and_expire("key".to_owned(), ttl)?, None);
+    assert_eq!(store.get("key".to_owned())?, None);
+
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(
+        store.get_with_ttl("key".to_owned())?,
+        Some(("value".to_owned(), None))
+    );
+    let meta = store.meta("key".to_owned())?.unwrap();
+    assert_eq!(
+        store.get_and_expire("key".to_owned(), ttl)?,
+        Some("value".to_owned())
+    );
+    let after = store.meta("key".to_owned())?.unwrap();
+    assert_eq!(after.version, meta.version);
+    assert_eq!(after.created_at, meta.created_at);
+    assert_eq!(after.updated_at, meta.updated_at);
+    let (val, left) = store.get_with_ttl("key".to_owned())?.unwrap();
+    assert_eq!(val, "value");
+    assert!(left.unwrap() <= ttl);
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(store.get_with_ttl("key".to_owned())?, None);
+    assert_eq!(store.get_and_expire("key".to_owned(), ttl)?, None);
+
+    // The TTL survives a restart, and the next write of a value clears it.
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.get_and_expire("key".to_owned(), Duration::from_secs(60))?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let (_, left) = store.get_with_ttl("key".to_owned())?.unwrap();
+    assert!(left.unwrap() > Duration::from_secs(30));
+    store.set("key".to_owned(), "other".to_owned())?;
+    assert_eq!(
+        store.get_with_ttl("key".to_owned())?,
+        Some(("other".to_owned(), None))
+    );
+
+    Ok(())
+}
+
+// Should remove the expired keys only, for good
+#[test]
+fn purge_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let ttl = Duration::from_millis(200);
+
+    for key_id in 0..5 {
+        store.increment_with_ttl(format!("rate{}", key_id), 1, ttl)?;
+    }
+    store.increment_with_ttl("long".to_owned(), 1, Duration::from_secs(60))?;
+    store.set("plain".to_owned(), "1".to_owned())?;
+    // An older value without TTL is still on disk.
+    store.set("cache".to_owned(), "old".to_owned())?;
+    store.get_and_expire("cache".to_owned(), ttl)?;
+    assert_eq!(store.purge_expired()?, 0);
+    thread::sleep(Duration::from_millis(300));
+
+    assert_eq!(store.purge_expired()?, 6);
+    assert_eq!(store.purge_expired()?, 0);
+    let mut keys = store.keys();
+    keys.sort();
+    assert_eq!(keys, vec!["long", "plain"]);
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("cache".to_owned())?, None);
+    assert_eq!(store.get("rate0".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.purge_expired()?, 0);
+
+    Ok(())
+}
+
+// Should count the bytes of the records written by the writes and the
+// compactions, and read by the reads
+#[test]
+fn io_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.io_stats(), IoStats::default());
+    assert_eq!(store.io_stats().write_amplification(), 0.0);
+
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            let key = format!("key{}", key_id);
+            store.set(key, format!("value{}", iter))?;
+        }
+    }
+    let stats = store.io_stats();
+    assert!(stats.bytes_written > 1000 * "value0".len() as u64);
+    assert_eq!(stats.bytes_read, 0);
+
+    let big = "x".repeat(10_000);
+    store.set("big".to_owned(), big.clone())?;
+    let before = store.io_stats().bytes_read;
+    assert_eq!(store.get("big".to_owned())?, Some(big));
+    let read = store.io_stats().bytes_read - before;
+    assert!(read > 10_000, "{}", read);
+    store.get_capped("big".to_owned(), 10)?;
+    let read = store.io_stats().bytes_read - before - read;
+    assert!(read < 100, "{}", read);
+
+    // Another handle shares the counters.
+    let other = store.clone();
+    other.compact()?;
+    let stats = store.io_stats();
+    assert_eq!(other.io_stats(), stats);
+    // Only the live records are read, and copied as they are.
+    assert_eq!(stats.compaction_read, stats.compaction_written);
+    assert!(stats.compaction_written > 100 * "value9".len() as u64);
+    assert!(stats.write_amplification() > 1.0);
+    assert!(stats.lines().contains("io_compaction_read="));
+    drop(other);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.io_stats(), IoStats::default());
+
+    Ok(())
+}
+
+#[test]
+fn hot_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.hot_keys(10), vec![]);
+
+    for i in 0..50 {
+        store.set("hammered".to_owned(), format!("value{}", i))?;
+    }
+    for i in 0..5 {
+        store.set("warm".to_owned(), format!("value{}", i))?;
+    }
+    store.set("cold".to_owned(), "value".to_owned())?;
+    store.set("removed".to_owned(), "value".to_owned())?;
+    store.remove("removed".to_owned())?;
+
+    let hot = store.hot_keys(10);
+    let keys: Vec<&str> = hot.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, vec!["hammered", "warm", "removed"]);
+    assert!(hot[0].1 > 10 * hot[1].1, "{:?}", hot);
+    assert_eq!(store.hot_keys(1).len(), 1);
+
+    // The counters are shared by the handles and survive compactions,
+    // but not a restart.
+    let other = store.clone();
+    other.compact()?;
+    assert_eq!(other.hot_keys(10), hot);
+    drop(other);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.hot_keys(10), vec![]);
+
+    Ok(())
+}
+
+#[test]
+fn size_histogram() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.size_histogram(), SizeHistogram::default());
+    assert_eq!(store.size_histogram().lines(), "size_histogram=\n");
+
+    for i in 0..100 {
+        store.set(format!("small{}", i), "x".to_owned())?;
+    }
+    store.set("big".to_owned(), "x".repeat(100_000))?;
+    // Overwritten and removed records aren't counted.
+    store.set("small0".to_owned(), "y".to_owned())?;
+    store.set("gone".to_owned(), "x".repeat(100_000))?;
+    store.remove("gone".to_owned())?;
+
+    let hist = store.size_histogram();
+    assert_eq!(hist.count(), 101);
+    // The record holds the key and metadata too.
+    assert_eq!(hist.buckets.len(), 17);
+    assert_eq!(hist.buckets[16], 1);
+    let small = hist.buckets.iter().position(|n| *n > 0).unwrap();
+    assert!(small < 8, "{:?}", hist);
+    assert_eq!(hist.buckets[small..small + 2].iter().sum::<u64>(), 100);
+    let line = hist.lines();
+    assert!(line.starts_with("size_histogram="), "{}", line);
+    assert!(line.ends_with(",65536:1\n"), "{}", line);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.size_histogram(), hist);
+
+    Ok(())
+}
+
+// Lists should keep their items in order, and neither be read as strings
+// nor be pushed to strings
+#[test]
+fn lists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = || "list".to_owned();
+
+    assert_eq!(store.list_len(key())?, 0);
+    assert_eq!(store.list_range(key(), 0, -1)?, Vec::<String>::new());
+    assert_eq!(store.push_back(key(), "b".to_owned())?, 1);
+    assert_eq!(store.push_back(key(), "c".to_owned())?, 2);
+    assert_eq!(store.push_front(key(), "a".to_owned())?, 3);
+    assert_eq!(store.list_range(key(), 0, -1)?, vec!["a", "b", "c"]);
+    assert_eq!(store.list_range(key(), -2, 10)?, vec!["b", "c"]);
+    assert_eq!(store.list_range(key(), 2, 1)?, Vec::<String>::new());
+
+    store.set("string".to_owned(), "value".to_owned())?;
+    for res in [
+        store
+            .push_back("string".to_owned(), "x".to_owned())
+            .map(|_| ()),
+        store.list_len("string".to_owned()).map(|_| ()),
+    ] {
+        match res {
+            Err(e) => match e.downcast_ref::<KvsError>() {
+                Some(KvsError::WrongType(key)) => assert_eq!(key, "string"),
+                _ => panic!("unexpected error: {}", e),
+            },
+            Ok(_) => panic!("a string used as a list"),
+        }
+    }
+    assert_eq!(store.get("string".to_owned())?, Some("value".to_owned()));
+
+    // Nor a list as a string, nor a string that looks like a list.
+    for res in [
+        store.get(key()).map(|_| ()),
+        store.get_range(key(), 0, 3).map(|_| ()),
+    ] {
+        match res {
+            Err(e) => match e.downcast_ref::<KvsError>() {
+                Some(KvsError::WrongType(key)) => assert_eq!(key, "list"),
+                _ => panic!("unexpected error: {}", e),
+            },
+            Ok(_) => panic!("a list used as a string"),
+        }
+    }
+    store.set("tagged".to_owned(), "\u{0}list\u{0}[]".to_owned())?;
+    assert!(store.list_len("tagged".to_owned()).is_err());
+
+    // A TTL keeps the kind of the value, GETEX only reads strings.
+    assert!(store.expire(key(), Duration::from_secs(60))?);
+    assert!(store.persist(key())?);
+    assert_eq!(store.list_len(key())?, 3);
+    assert!(store
+        .get_and_expire(key(), Duration::from_secs(60))
+        .is_err());
+
+    // Concurrent pushes are not lost.
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..25 {
+                    store.push_back("shared".to_owned(), format!("{}-{}", t, i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.list_len("shared".to_owned())?, 100);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.list_range(key(), 0, -1)?, vec!["a", "b", "c"]);
+
+    Ok(())
+}
+
+#[test]
+fn min_replicas_ack() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    match KvStoreBuilder::new(temp_dir.path())
+        .min_replicas_ack(1)
+        .build()
+    {
+        Err(e) => match e.downcast_ref::<KvsError>() {
+            Some(KvsError::ReplicationOff) => {}
+            _ => panic!("unexpected error: {}", e),
+        },
+        Ok(_) => panic!("waiting for followers without replication"),
+    }
+
+    let store = KvStoreBuilder::new(temp_dir.path())
+        .replication_backlog(1 << 20)
+        .min_replicas_ack(1)
+        .replica_ack_timeout(Duration::from_millis(50))
+        .build()?;
+    let not_acked = |res: Result<()>| match res {
+        Err(e) => match e.downcast_ref::<KvsError>() {
+            Some(KvsError::ReplicasNotAcked { acked, needed }) => {
+                assert_eq!((*acked, *needed), (0, 1));
+            }
+            _ => panic!("unexpected error: {}", e),
+        },
+        Ok(_) => panic!("acknowledged without followers"),
+    };
+
+    // Done locally all the same.
+    not_acked(store.set("key1".to_owned(), "value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // A follower behind the write doesn't count.
+    store.ack_replica("replica1", 1)?;
+    not_acked(store.set("key2".to_owned(), "value2".to_owned()));
+    store.ack_replica("replica1", 2)?;
+    not_acked(store.remove("key2".to_owned()));
+    store.ack_replica("replica1", 4)?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.forget_replica("replica1");
+    not_acked(store.set("key4".to_owned(), "value4".to_owned()));
+    drop(store);
+
+    // A write waits for the acknowledgment.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new(temp_dir.path())
+        .replication_backlog(1 << 20)
+        .min_replicas_ack(1)
+        .replica_ack_timeout(Duration::from_secs(10))
+        .build()?;
+    let acker = {
+        let store = store.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            store.ack_replica("replica1", 1)
+        })
+    };
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    acker.join().unwrap()?;
+
+    Ok(())
+}
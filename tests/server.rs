@@ -0,0 +1,1394 @@
+use std::net::SocketAddr;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use tempfile::TempDir;
+use tokio::prelude::*;
+
+use kvs::slog::{Discard, Level};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    CachingClient, Follower, KvStore, KvStoreBuilder, KvsClient, KvsServer, LevelSwitch, Quota,
+    ShardedClient,
+};
+
+type Server = KvsServer<KvStore, SharedQueueThreadPool>;
+
+fn start_server(addr: &str) -> (Server, JoinHandle<Result<(), i32>>, TempDir) {
+    start_server_with(addr, |server| server)
+}
+
+fn start_server_with<F>(addr: &str, config: F) -> (Server, JoinHandle<Result<(), i32>>, TempDir)
+where
+    F: FnOnce(Server) -> Server,
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let server = config(KvsServer::new(store, pool, addr.parse().unwrap(), None));
+    let handle = server.spawn_background().unwrap();
+    (server, handle, temp_dir)
+}
+
+fn client(addr: &str) -> KvsClient {
+    let addr: SocketAddr = addr.parse().unwrap();
+    KvsClient::new(addr, None).unwrap()
+}
+
+// A retried set with the same token should not be applied again
+#[test]
+fn set_with_token() {
+    let addr = "127.0.0.1:4100";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+
+    let key = "key1".to_owned();
+    let token = "token1".to_owned();
+    client
+        .set_with_token(key.clone(), "value1".to_owned(), token.clone())
+        .wait()
+        .unwrap();
+    client.set(key.clone(), "value2".to_owned()).wait().unwrap();
+    client
+        .set_with_token(key.clone(), "value3".to_owned(), token)
+        .wait()
+        .unwrap();
+    assert_eq!(
+        client.get(key.clone()).wait(),
+        Ok(Some("value2".to_owned()))
+    );
+
+    client
+        .set_with_token(key.clone(), "value4".to_owned(), "token2".to_owned())
+        .wait()
+        .unwrap();
+    assert_eq!(client.get(key).wait(), Ok(Some("value4".to_owned())));
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// INCRBYFLOAT should return and store the new value
+#[test]
+fn incrbyfloat() {
+    let addr = "127.0.0.1:4102";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+
+    assert_eq!(client.incrbyfloat("key1".to_owned(), 1.5).wait(), Ok(1.5));
+    assert_eq!(client.incrbyfloat("key1".to_owned(), 0.5).wait(), Ok(2.0));
+    assert_eq!(
+        client.get("key1".to_owned()).wait(),
+        Ok(Some("2".to_owned()))
+    );
+    client
+        .set("key2".to_owned(), "abc".to_owned())
+        .wait()
+        .unwrap();
+    assert!(client.incrbyfloat("key2".to_owned(), 1.0).wait().is_err());
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// INCRTTL should count in a window that ends after the TTL
+#[test]
+fn incr_with_ttl() {
+    let addr = "127.0.0.1:4136";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+    let ttl = Duration::from_millis(300);
+    let incr = |delta| client.incr_with_ttl("rate".to_owned(), delta, ttl).wait();
+
+    assert_eq!(incr(1), Ok(1));
+    assert_eq!(incr(5), Ok(6));
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(client.get("rate".to_owned()).wait(), Ok(None));
+    assert_eq!(incr(1), Ok(1));
+    assert!(client
+        .incr_with_ttl("rate".to_owned(), 1, Duration::from_micros(10))
+        .wait()
+        .is_err());
+    client
+        .set("text".to_owned(), "abc".to_owned())
+        .wait()
+        .unwrap();
+    assert!(client
+        .incr_with_ttl("text".to_owned(), 1, ttl)
+        .wait()
+        .is_err());
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// GETEX should return the TTL with the value and slide it when given one
+#[test]
+fn getex() {
+    let addr = "127.0.0.1:4137";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+    let ttl = Duration::from_millis(300);
+    let getex = |ttl| client.getex("cache".to_owned(), ttl).wait();
+
+    assert_eq!(getex(None), Ok(None));
+    assert_eq!(getex(Some(ttl)), Ok(None));
+    client
+        .set("cache".to_owned(), "a".to_owned())
+        .wait()
+        .unwrap();
+    assert_eq!(getex(None), Ok(Some(("a".to_owned(), None))));
+    assert_eq!(getex(Some(ttl)), Ok(Some(("a".to_owned(), Some(ttl)))));
+    match getex(None) {
+        Ok(Some((ref val, Some(left)))) if val == "a" => assert!(left <= ttl),
+        res => panic!("unexpected GETEX reply: {:?}", res),
+    }
+    // Read within the TTL, the key outlives it.
+    for _ in 0..3 {
+        thread::sleep(Duration::from_millis(150));
+        assert!(getex(Some(ttl)).unwrap().is_some());
+    }
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(getex(None), Ok(None));
+    assert!(getex(Some(Duration::from_micros(10))).is_err());
+
+    // PURGEEXPIRED should count the expired keys it removed.
+    client
+        .incr_with_ttl("rate".to_owned(), 1, Duration::from_millis(100))
+        .wait()
+        .unwrap();
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(client.purge_expired().wait(), Ok(2));
+    assert_eq!(client.purge_expired().wait(), Ok(0));
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// Keys should be spread over the servers and found again
+#[test]
+fn sharded_client() {
+    let addrs = ["127.0.0.1:4103", "127.0.0.1:4104"];
+    let (server1, handle1, _dir1) = start_server(addrs[0]);
+    let (server2, handle2, _dir2) = start_server(addrs[1]);
+    let sharded =
+        ShardedClient::new(addrs.iter().map(|a| a.parse().unwrap()).collect(), None).unwrap();
+
+    let keys: Vec<_> = (0..20).map(|i| format!("key{}", i)).collect();
+    for key in keys.iter() {
+        sharded
+            .set(key.clone(), format!("{}-value", key))
+            .wait()
+            .unwrap();
+    }
+    for key in keys.iter() {
+        assert_eq!(
+            sharded.get(key.clone()).wait(),
+            Ok(Some(format!("{}-value", key)))
+        );
+    }
+
+    let mut query = keys.clone();
+    query.insert(3, "missing".to_owned());
+    let vals = sharded.mget(query.clone()).wait().unwrap();
+    assert_eq!(vals.len(), query.len());
+    for (key, val) in query.iter().zip(vals) {
+        if key == "missing" {
+            assert_eq!(val, None);
+        } else {
+            assert_eq!(val, Some(format!("{}-value", key)));
+        }
+    }
+
+    let n1 = client(addrs[0]).count("key".to_owned()).wait().unwrap();
+    let n2 = client(addrs[1]).count("key".to_owned()).wait().unwrap();
+    assert!(n1 > 0 && n2 > 0);
+    assert_eq!(n1 + n2, 20);
+    assert_eq!(sharded.count("key".to_owned()).wait(), Ok(20));
+
+    server1.shutdown();
+    server2.shutdown();
+    handle1.join().unwrap().unwrap();
+    handle2.join().unwrap().unwrap();
+}
+
+// CLIENTS should list the open connections
+#[test]
+fn clients() {
+    let addr = "127.0.0.1:4105";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+
+    let idle = std::net::TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let list = client.clients().wait().unwrap();
+    assert_eq!(list.lines().count(), 2);
+    assert!(list.contains(&format!("addr={} ", idle.local_addr().unwrap())));
+
+    drop(idle);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(client.clients().wait().unwrap().lines().count(), 1);
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// SET ... VERSION should only apply on the current version
+#[test]
+fn set_if_version() {
+    let addr = "127.0.0.1:4106";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+
+    let key = "key1".to_owned();
+    assert_eq!(client.get_with_version(key.clone()).wait(), Ok(None));
+    assert_eq!(
+        client
+            .set_if_version(key.clone(), "value1".to_owned(), 0)
+            .wait(),
+        Ok(1)
+    );
+    assert!(client
+        .set_if_version(key.clone(), "value2".to_owned(), 0)
+        .wait()
+        .is_err());
+    assert_eq!(
+        client.get_with_version(key.clone()).wait(),
+        Ok(Some(("value1".to_owned(), 1)))
+    );
+    assert_eq!(
+        client.set_if_version(key, "value2".to_owned(), 1).wait(),
+        Ok(2)
+    );
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// SET ... NX and SET ... XX should only apply on an absent or a present key
+#[test]
+fn set_nx_xx() {
+    use std::io::{BufRead, BufReader, Write};
+
+    let addr = "127.0.0.1:4129";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+
+    let key = "key1".to_owned();
+    let set_xx = |val: &str| client.set_if_present(key.clone(), val.to_owned()).wait();
+    let set_nx = |val: &str| client.set_if_absent(key.clone(), val.to_owned()).wait();
+    assert_eq!(set_xx("value1"), Ok(false));
+    assert_eq!(client.get(key.clone()).wait(), Ok(None));
+    assert_eq!(set_nx("value1"), Ok(true));
+    assert_eq!(set_nx("value2"), Ok(false));
+    assert_eq!(set_xx("value3"), Ok(true));
+    assert_eq!(
+        client.get(key.clone()).wait(),
+        Ok(Some("value3".to_owned()))
+    );
+
+    let mut sock = std::net::TcpStream::connect(addr).unwrap();
+    let mut rdr = BufReader::new(sock.try_clone().unwrap());
+    let mut reply = String::new();
+    sock.write_all(b"*5\r\n+SET\r\n$4\r\nkey1\r\n$1\r\nv\r\n$2\r\nnx\r\n$2\r\nXX\r\n")
+        .unwrap();
+    rdr.read_line(&mut reply).unwrap();
+    assert!(reply.starts_with('-'), "{}", reply);
+    assert_eq!(client.get(key).wait(), Ok(Some("value3".to_owned())));
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// WAIT should reply once the writes are synced
+#[test]
+fn wait() {
+    let addr = "127.0.0.1:4130";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+
+    assert_eq!(client.wait().wait(), Ok(()));
+    for i in 0..10 {
+        client
+            .set(format!("key{}", i), "value".to_owned())
+            .wait()
+            .unwrap();
+    }
+    assert_eq!(client.wait().wait(), Ok(()));
+    assert_eq!(
+        client.get("key9".to_owned()).wait(),
+        Ok(Some("value".to_owned()))
+    );
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// MSETNX should report the outcome of each pair, errors included
+#[test]
+fn mset_nx() {
+    let addr = "127.0.0.1:4131";
+    let dir = TempDir::new().unwrap();
+    let store = KvStoreBuilder::new(dir.path())
+        .reject_empty_keys(true)
+        .build()
+        .unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let server = KvsServer::new(store, pool, addr.parse().unwrap(), None);
+    let handle = server.spawn_background().unwrap();
+    let client = client(addr);
+
+    client.set("b".to_owned(), "old".to_owned()).wait().unwrap();
+    let pairs = vec![
+        ("a".to_owned(), "1".to_owned()),
+        ("b".to_owned(), "2".to_owned()),
+        ("".to_owned(), "3".to_owned()),
+        ("a".to_owned(), "4".to_owned()),
+        ("c".to_owned(), "5".to_owned()),
+    ];
+    assert_eq!(
+        client.mset_nx(pairs).wait(),
+        Ok(vec![
+            Ok(true),
+            Ok(false),
+            Err("empty key".to_owned()),
+            Ok(false),
+            Ok(true)
+        ])
+    );
+    assert_eq!(client.get("a".to_owned()).wait(), Ok(Some("1".to_owned())));
+    assert_eq!(
+        client.get("b".to_owned()).wait(),
+        Ok(Some("old".to_owned()))
+    );
+    assert_eq!(client.get("c".to_owned()).wait(), Ok(Some("5".to_owned())));
+    assert!(client.mset_nx(vec![]).wait().is_err());
+    assert_eq!(server.errors().engine, 0);
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// COMPACT PAUSE and RESUME should switch the background compactions
+#[test]
+fn compact_pause_resume() {
+    let addr = "127.0.0.1:4132";
+    let dir = TempDir::new().unwrap();
+    let store = KvStoreBuilder::new(dir.path()).build().unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let server = KvsServer::new(store.clone(), pool, addr.parse().unwrap(), None);
+    let handle = server.spawn_background().unwrap();
+    let client = client(addr);
+
+    assert_eq!(client.pause_compaction().wait(), Ok(()));
+    assert!(store.compaction_health().paused);
+    assert_eq!(client.resume_compaction().wait(), Ok(()));
+    assert!(!store.compaction_health().paused);
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// KEYS should return the keys matching a glob
+#[test]
+fn keys_glob() {
+    let addr = "127.0.0.1:4133";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+
+    for key in ["user:1:session", "user:2:session", "user:1:profile"].iter() {
+        client
+            .set(key.to_string(), "value".to_owned())
+            .wait()
+            .unwrap();
+    }
+    let mut keys = client
+        .keys_matching("user:*:session".to_owned())
+        .wait()
+        .unwrap();
+    keys.sort();
+    assert_eq!(keys, vec!["user:1:session", "user:2:session"]);
+    assert_eq!(client.keys_matching("none:*".to_owned()).wait(), Ok(vec![]));
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// RENAMENX and COPY should report whether they applied
+#[test]
+fn rename_nx_and_copy() {
+    let addr = "127.0.0.1:4107";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+
+    client
+        .set("key1".to_owned(), "value1".to_owned())
+        .wait()
+        .unwrap();
+    client
+        .set("key2".to_owned(), "value2".to_owned())
+        .wait()
+        .unwrap();
+    assert_eq!(
+        client
+            .rename_nx("key1".to_owned(), "key2".to_owned())
+            .wait(),
+        Ok(false)
+    );
+    assert_eq!(
+        client
+            .rename_nx("key1".to_owned(), "key3".to_owned())
+            .wait(),
+        Ok(true)
+    );
+    assert!(client
+        .rename_nx("key1".to_owned(), "key4".to_owned())
+        .wait()
+        .is_err());
+    assert_eq!(
+        client
+            .copy("key3".to_owned(), "key2".to_owned(), false)
+            .wait(),
+        Ok(false)
+    );
+    assert_eq!(
+        client
+            .copy("key3".to_owned(), "key2".to_owned(), true)
+            .wait(),
+        Ok(true)
+    );
+    assert_eq!(
+        client.get("key2".to_owned()).wait(),
+        Ok(Some("value1".to_owned()))
+    );
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// Requests after `swap_store` should be served by the new store
+#[test]
+fn swap_store() {
+    let addr = "127.0.0.1:4108";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+
+    client
+        .set("key1".to_owned(), "old".to_owned())
+        .wait()
+        .unwrap();
+    let new_dir = TempDir::new().expect("unable to create temporary working directory");
+    let new_store = KvStore::open(new_dir.path()).unwrap();
+    new_store.set("key2".to_owned(), "new".to_owned()).unwrap();
+    server.swap_store(new_store);
+
+    assert_eq!(client.get("key1".to_owned()).wait(), Ok(None));
+    assert_eq!(
+        client.get("key2".to_owned()).wait(),
+        Ok(Some("new".to_owned()))
+    );
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// PSUBSCRIBE should receive the removes of the matching keys until shutdown
+#[test]
+fn psubscribe_del() {
+    let addr = "127.0.0.1:4109";
+    let (server, handle, _dir) = start_server(addr);
+    let mut client = client(addr);
+
+    client
+        .set("user:1".to_owned(), "value1".to_owned())
+        .wait()
+        .unwrap();
+    client
+        .set("other:1".to_owned(), "value2".to_owned())
+        .wait()
+        .unwrap();
+    let sub = client.psubscribe("user:".to_owned());
+    let listener = thread::spawn(move || sub.wait().collect::<Vec<_>>());
+    thread::sleep(Duration::from_millis(200));
+
+    client.rm("other:1".to_owned()).wait().unwrap();
+    client
+        .expire("user:1".to_owned(), Duration::from_secs(60))
+        .wait()
+        .unwrap();
+    client.rm("user:1".to_owned()).wait().unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+    assert_eq!(
+        listener.join().unwrap(),
+        vec![
+            Ok(("expire".to_owned(), "user:1".to_owned())),
+            Ok(("del".to_owned(), "user:1".to_owned())),
+        ]
+    );
+}
+
+// OBJECT should return the metadata of a key
+#[test]
+fn object() {
+    let addr = "127.0.0.1:4111";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+
+    assert_eq!(client.object("key1".to_owned()).wait(), Ok(None));
+    client
+        .set("key1".to_owned(), "value1".to_owned())
+        .wait()
+        .unwrap();
+    client
+        .set("key1".to_owned(), "value2".to_owned())
+        .wait()
+        .unwrap();
+    let meta = client.object("key1".to_owned()).wait().unwrap().unwrap();
+    assert!(meta.created_at.is_some());
+    assert!(meta.updated_at >= meta.created_at);
+    assert!(meta.size > "value2".len());
+    assert_eq!(meta.version, 2);
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// RANDOMKEY should return nil on an empty store and a live key otherwise
+#[test]
+fn random_key() {
+    let addr = "127.0.0.1:4112";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+
+    assert_eq!(client.random_key().wait(), Ok(None));
+    for key_id in 0..10 {
+        client
+            .set(format!("key{}", key_id), "value".to_owned())
+            .wait()
+            .unwrap();
+    }
+    let key = client.random_key().wait().unwrap().unwrap();
+    assert_eq!(client.get(key).wait(), Ok(Some("value".to_owned())));
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// QUIT should be acknowledged with OK, then the server closes the connection
+#[test]
+fn quit() {
+    use std::io::{Read, Write};
+
+    let addr = "127.0.0.1:4113";
+    let (server, handle, _dir) = start_server(addr);
+    assert_eq!(client(addr).quit().wait(), Ok(()));
+
+    let mut sock = std::net::TcpStream::connect(addr).unwrap();
+    sock.write_all(b"*1\r\n+QUIT\r\n").unwrap();
+    let mut reply = Vec::new();
+    sock.read_to_end(&mut reply).unwrap();
+    assert_eq!(reply, b"+OK\r\n");
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// After HELLO MSGPACK, the reply should be a msgpack frame
+#[test]
+fn hello_msgpack() {
+    use bytes::BytesMut;
+    use kvs::{MsgPackCodec, Proto};
+    use std::io::{Read, Write};
+    use tokio::codec::Decoder;
+
+    let addr = "127.0.0.1:4138";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+    client.set("a".to_owned(), "1".to_owned()).wait().unwrap();
+    let command = |hello: &[u8], cmd: &[u8]| {
+        let mut sock = std::net::TcpStream::connect(addr).unwrap();
+        sock.write_all(hello).unwrap();
+        sock.write_all(cmd).unwrap();
+        let mut reply = Vec::new();
+        sock.read_to_end(&mut reply).unwrap();
+        reply
+    };
+    let hello = b"*2\r\n$5\r\nHELLO\r\n$7\r\nMSGPACK\r\n";
+
+    let reply = command(hello, b"*2\r\n$6\r\nGETVER\r\n$1\r\na\r\n");
+    assert_eq!(&reply[..5], b"+OK\r\n");
+    let mut frames = BytesMut::from(&reply[5..]);
+    match MsgPackCodec::new().decode(&mut frames).unwrap() {
+        Some(Proto::Seq(ref items)) => match items.as_slice() {
+            [Proto::Bulk(val), Proto::Int(1)] => assert_eq!(val, b"1"),
+            x => panic!("unexpected items: {:?}", x),
+        },
+        x => panic!("unexpected reply: {:?}", x),
+    }
+    assert!(frames.is_empty());
+    let reply = command(hello, b"*2\r\n$3\r\nGET\r\n$1\r\nb\r\n");
+    assert_eq!(&reply[5..], b"\x00\x00\x00\x01\xc0");
+    let reply = command(hello, b"*1\r\n$5\r\nNOCMD\r\n");
+    match MsgPackCodec::new().decode(&mut BytesMut::from(&reply[5..])) {
+        Ok(Some(Proto::Err(_))) => {}
+        x => panic!("unexpected reply: {:?}", x),
+    }
+
+    let hello = b"*2\r\n$5\r\nHELLO\r\n$4\r\nRESP\r\n";
+    let reply = command(hello, b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+    assert_eq!(reply, b"+OK\r\n$1\r\n1\r\n");
+    let reply = command(b"*2\r\n$5\r\nHELLO\r\n$4\r\nJSON\r\n", b"");
+    assert_eq!(reply[0], b'-');
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// SCARD and SINTER should treat the keys with a prefix as a set
+#[test]
+fn set_commands() {
+    let addr = "127.0.0.1:4139";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+    for key in &["tags:x:red", "tags:x:blue", "tags:y:blue", "tags:y:green"] {
+        client.set(key.to_string(), "1".to_owned()).wait().unwrap();
+    }
+
+    assert_eq!(client.scard("tags:x:".to_owned()).wait(), Ok(2));
+    assert_eq!(client.scard("tags:z:".to_owned()).wait(), Ok(0));
+    assert_eq!(
+        client
+            .sinter("tags:x:".to_owned(), "tags:y:".to_owned())
+            .wait(),
+        Ok(vec!["blue".to_owned()])
+    );
+    assert_eq!(
+        client
+            .sinter("tags:x:".to_owned(), "tags:z:".to_owned())
+            .wait(),
+        Ok(vec![])
+    );
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn hot_keys() {
+    let addr = "127.0.0.1:4140";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+    for i in 0..3 {
+        client.set("a".to_owned(), i.to_string()).wait().unwrap();
+    }
+    client.set("b".to_owned(), "1".to_owned()).wait().unwrap();
+    client.set("b".to_owned(), "2".to_owned()).wait().unwrap();
+
+    let hot = client.hot_keys(10).wait().unwrap();
+    let keys: Vec<&str> = hot.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, vec!["a", "b"]);
+    assert!(hot[0].1 > hot[1].1);
+    assert_eq!(client.hot_keys(1).wait().unwrap().len(), 1);
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn setinfo() {
+    let addr = "127.0.0.1:4141";
+    let (server, handle, _dir) = start_server(addr);
+    let mut client = client(addr);
+    let key = || "key".to_owned();
+
+    assert_eq!(client.set_created(key(), "1".to_owned()).wait(), Ok(true));
+    assert_eq!(client.set_created(key(), "2".to_owned()).wait(), Ok(false));
+    assert_eq!(client.get(key()).wait(), Ok(Some("2".to_owned())));
+    client.rm(key()).wait().unwrap();
+    assert_eq!(client.set_created(key(), "3".to_owned()).wait(), Ok(true));
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// A command declaring too many arguments should get an error without
+// the server waiting for them
+#[test]
+fn max_array_len() {
+    use std::io::{Read, Write};
+
+    let addr = "127.0.0.1:4142";
+    let (server, handle, _dir) = start_server_with(addr, |s| s.max_array_len(3));
+    let client = client(addr);
+
+    let mut sock = std::net::TcpStream::connect(addr).unwrap();
+    sock.write_all(b"*1000000\r\n").unwrap();
+    let mut reply = Vec::new();
+    sock.read_to_end(&mut reply).unwrap();
+    let reply = String::from_utf8_lossy(&reply);
+    assert!(reply.starts_with("-"), "{}", reply);
+    assert!(reply.contains("array too long: 1000000 items"), "{}", reply);
+    assert_eq!(server.errors().decode, 1);
+
+    client
+        .set("key".to_owned(), "value".to_owned())
+        .wait()
+        .unwrap();
+    assert_eq!(
+        client.get("key".to_owned()).wait(),
+        Ok(Some("value".to_owned()))
+    );
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn lists() {
+    let addr = "127.0.0.1:4143";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+    let key = || "list".to_owned();
+
+    assert_eq!(client.llen(key()).wait(), Ok(0));
+    assert_eq!(client.rpush(key(), "b".to_owned()).wait(), Ok(1));
+    assert_eq!(client.rpush(key(), "c".to_owned()).wait(), Ok(2));
+    assert_eq!(client.lpush(key(), "a".to_owned()).wait(), Ok(3));
+    assert_eq!(client.llen(key()).wait(), Ok(3));
+    assert_eq!(
+        client.lrange(key(), 0, -1).wait(),
+        Ok(vec!["a".to_owned(), "b".to_owned(), "c".to_owned()])
+    );
+    assert_eq!(
+        client.lrange(key(), -1, -1).wait(),
+        Ok(vec!["c".to_owned()])
+    );
+
+    client
+        .set("string".to_owned(), "value".to_owned())
+        .wait()
+        .unwrap();
+    assert_eq!(
+        client.rpush("string".to_owned(), "x".to_owned()).wait(),
+        Err(90)
+    );
+    assert_eq!(client.lrange("string".to_owned(), 0, -1).wait(), Err(92));
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// VERSION should report the crate version, the engine and the uptime
+#[test]
+fn server_version() {
+    let addr = "127.0.0.1:4114";
+    let (server, handle, _dir) = start_server(addr);
+    // The uptime is in whole seconds.
+    thread::sleep(Duration::from_secs(1));
+
+    let version = client(addr).server_version().wait().unwrap();
+    assert!(version.starts_with(&format!(
+        "version={} engine=kvs uptime=",
+        env!("CARGO_PKG_VERSION")
+    )));
+    let uptime: u64 = version.rsplit('=').next().unwrap().parse().unwrap();
+    assert!(uptime >= 1);
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// SWAP should exchange two values and fail if a key is missing
+#[test]
+fn swap() {
+    let addr = "127.0.0.1:4115";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+
+    client.set("a".to_owned(), "1".to_owned()).wait().unwrap();
+    client.set("b".to_owned(), "2".to_owned()).wait().unwrap();
+    client.swap("a".to_owned(), "b".to_owned()).wait().unwrap();
+    assert_eq!(client.get("a".to_owned()).wait(), Ok(Some("2".to_owned())));
+    assert_eq!(client.get("b".to_owned()).wait(), Ok(Some("1".to_owned())));
+    assert!(client
+        .swap("a".to_owned(), "missing".to_owned())
+        .wait()
+        .is_err());
+    assert_eq!(client.get("a".to_owned()).wait(), Ok(Some("2".to_owned())));
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// DELPREFIX should remove only the keys with the prefix and count them
+#[test]
+fn del_prefix() {
+    let addr = "127.0.0.1:4116";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+
+    for key in &["tenant1:a", "tenant1:b", "tenant2:a", "tenant10:a"] {
+        client.set(key.to_string(), "v".to_owned()).wait().unwrap();
+    }
+    assert_eq!(client.del_prefix("tenant1:".to_owned()).wait(), Ok(2));
+    assert_eq!(client.del_prefix("tenant1:".to_owned()).wait(), Ok(0));
+    assert_eq!(client.get("tenant1:a".to_owned()).wait(), Ok(None));
+    assert_eq!(client.count("tenant".to_owned()).wait(), Ok(2));
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// SCANVALUE should return only the pairs with the prefix whose value
+// contains the substring
+#[test]
+fn scan_value() {
+    let addr = "127.0.0.1:4118";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+
+    let pairs = [
+        ("config:a", "host=db1 port=1"),
+        ("config:b", "host=db2 port=2"),
+        ("config:c", "mentions db1 too"),
+        ("other:a", "host=db1"),
+    ];
+    for (key, val) in pairs.iter() {
+        client.set(key.to_string(), val.to_string()).wait().unwrap();
+    }
+    assert_eq!(
+        client
+            .scan_value("config:".to_owned(), "db1".to_owned())
+            .wait(),
+        Ok(vec![
+            ("config:a".to_owned(), "host=db1 port=1".to_owned()),
+            ("config:c".to_owned(), "mentions db1 too".to_owned()),
+        ])
+    );
+    assert_eq!(
+        client
+            .scan_value("config:".to_owned(), "db3".to_owned())
+            .wait(),
+        Ok(vec![])
+    );
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// MSETGET should set all the pairs and return the previous values
+#[test]
+fn mset_get() {
+    let addr = "127.0.0.1:4120";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+
+    client.set("a".to_owned(), "1".to_owned()).wait().unwrap();
+    let pairs = vec![
+        ("a".to_owned(), "2".to_owned()),
+        ("b".to_owned(), "3".to_owned()),
+    ];
+    assert_eq!(
+        client.mset_get(pairs).wait(),
+        Ok(vec![Some("1".to_owned()), None])
+    );
+    assert_eq!(client.get("a".to_owned()).wait(), Ok(Some("2".to_owned())));
+    assert_eq!(client.get("b".to_owned()).wait(), Ok(Some("3".to_owned())));
+    assert!(client.mset_get(vec![]).wait().is_err());
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// A get sent after a set succeeded, on another connection, should see it
+// even with other clients writing concurrently
+#[test]
+fn read_your_writes() {
+    let addr = "127.0.0.1:4121";
+    let (server, handle, _dir) = start_server(addr);
+
+    let writers: Vec<_> = (0..4)
+        .map(|thread_id| {
+            thread::spawn(move || {
+                let client = client(addr);
+                let key = format!("key{}", thread_id);
+                for iter in 0..50 {
+                    let val = format!("{}", iter);
+                    client.set(key.clone(), val.clone()).wait().unwrap();
+                    assert_eq!(client.get(key.clone()).wait(), Ok(Some(val)));
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// INFO and KvsServer::errors should count the failed requests by category
+#[test]
+fn error_counters() {
+    use std::io::{Read, Write};
+
+    let addr = "127.0.0.1:4122";
+    let (server, handle, _dir) = start_server(addr);
+    let mut client = client(addr);
+
+    let send = |req: &[u8]| {
+        let mut sock = std::net::TcpStream::connect(addr).unwrap();
+        sock.write_all(req).unwrap();
+        let mut reply = Vec::new();
+        let _ = sock.read_to_end(&mut reply);
+    };
+    send(b"*1\r\n+NOSUCHCMD\r\n");
+    send(b"*1\r\n+GET\r\n");
+    send(b"*2\r\n+GET\r\n:1\r\n");
+    assert!(client.rm("missing".to_owned()).wait().is_err());
+
+    let errors = server.errors();
+    assert_eq!(errors.unknown_command, 1);
+    assert_eq!(errors.bad_args, 1);
+    assert_eq!(errors.decode, 1);
+    assert_eq!(errors.engine, 1);
+    assert_eq!(errors.busy, 0);
+    let info = client.info().wait().unwrap();
+    assert!(info.contains("errors_decode=1\n"), "{}", info);
+    assert!(info.contains("errors_unknown_command=1\n"), "{}", info);
+    assert!(info.contains("errors_engine=1\n"), "{}", info);
+    assert!(info.contains("io_bytes_written="), "{}", info);
+    assert!(info.contains("size_histogram="), "{}", info);
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// TOUCH should count the existing keys
+#[test]
+fn touch() {
+    let addr = "127.0.0.1:4123";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+
+    client.set("a".to_owned(), "1".to_owned()).wait().unwrap();
+    client.set("b".to_owned(), "2".to_owned()).wait().unwrap();
+    let keys = vec!["a".to_owned(), "missing".to_owned(), "b".to_owned()];
+    assert_eq!(client.touch(keys).wait(), Ok(2));
+    assert!(client.touch(vec![]).wait().is_err());
+    assert_eq!(client.get("a".to_owned()).wait(), Ok(Some("1".to_owned())));
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// A follower should copy the pairs of the primary, then follow its writes
+// until the primary shuts down
+#[test]
+fn replication() {
+    let addr = "127.0.0.1:4124";
+    let primary_dir = TempDir::new().unwrap();
+    let store = KvStoreBuilder::new(primary_dir.path())
+        .replication_backlog(1 << 20)
+        .build()
+        .unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let server = KvsServer::new(store, pool, addr.parse().unwrap(), None);
+    let handle = server.spawn_background().unwrap();
+    let mut client = client(addr);
+    client.set("a".to_owned(), "1".to_owned()).wait().unwrap();
+    client.set("b".to_owned(), "2".to_owned()).wait().unwrap();
+
+    let replica_dir = TempDir::new().unwrap();
+    let replica = KvStore::open(replica_dir.path()).unwrap();
+    replica.set("stale".to_owned(), "x".to_owned()).unwrap();
+    let mut follower = Follower::new(addr.parse().unwrap(), replica.clone(), None);
+    let following = thread::spawn(move || {
+        let res = follower.run();
+        (follower, res)
+    });
+    thread::sleep(Duration::from_millis(200));
+    client.set("c".to_owned(), "3".to_owned()).wait().unwrap();
+    client.rm("a".to_owned()).wait().unwrap();
+
+    for _ in 0..50 {
+        if replica.get("a".to_owned()).unwrap().is_none() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(replica.get("a".to_owned()).unwrap(), None);
+    assert_eq!(replica.get("b".to_owned()).unwrap(), Some("2".to_owned()));
+    assert_eq!(replica.get("c".to_owned()).unwrap(), Some("3".to_owned()));
+    assert_eq!(replica.get("stale".to_owned()).unwrap(), None);
+
+    server.shutdown();
+    let (follower, res) = following.join().unwrap();
+    assert_eq!(res, Ok(()));
+    let (id, offset) = follower.position();
+    assert_ne!(id, "?");
+    assert_eq!(offset, 4);
+    handle.join().unwrap().unwrap();
+}
+
+// With min_replicas_ack, a write should return once the follower applied it
+#[test]
+fn replica_ack() {
+    let addr = "127.0.0.1:4144";
+    let primary_dir = TempDir::new().unwrap();
+    let store = KvStoreBuilder::new(primary_dir.path())
+        .replication_backlog(1 << 20)
+        .min_replicas_ack(1)
+        .replica_ack_timeout(Duration::from_millis(200))
+        .build()
+        .unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let server = KvsServer::new(store, pool, addr.parse().unwrap(), None);
+    let handle = server.spawn_background().unwrap();
+    let client = client(addr);
+
+    // Without follower, the write is done but not acknowledged.
+    assert!(client.set("a".to_owned(), "1".to_owned()).wait().is_err());
+    assert_eq!(client.get("a".to_owned()).wait(), Ok(Some("1".to_owned())));
+
+    let replica_dir = TempDir::new().unwrap();
+    let replica = KvStore::open(replica_dir.path()).unwrap();
+    let mut follower = Follower::new(addr.parse().unwrap(), replica.clone(), None);
+    let following = thread::spawn(move || follower.run());
+    thread::sleep(Duration::from_millis(200));
+    for i in 0..10 {
+        client.set("b".to_owned(), i.to_string()).wait().unwrap();
+        assert_eq!(replica.get("b".to_owned()).unwrap(), Some(i.to_string()));
+    }
+    assert_eq!(replica.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+
+    server.shutdown();
+    assert_eq!(following.join().unwrap(), Ok(()));
+    handle.join().unwrap().unwrap();
+}
+
+// An empty value should round-trip through the protocol
+#[test]
+fn empty_value() {
+    let addr = "127.0.0.1:4125";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+
+    client.set("key".to_owned(), "".to_owned()).wait().unwrap();
+    assert_eq!(client.get("key".to_owned()).wait(), Ok(Some("".to_owned())));
+    assert_eq!(client.get("missing".to_owned()).wait(), Ok(None));
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// LOGLEVEL should read and change the level given to the server
+#[test]
+fn log_level() {
+    let addr = "127.0.0.1:4126";
+    let (_drain, level) = LevelSwitch::new(Discard, Level::Warning);
+    let handle_level = level.clone();
+    let (server, handle, _dir) = start_server_with(addr, |server| server.log_level(handle_level));
+    let client = client(addr);
+
+    assert_eq!(client.log_level(None).wait(), Ok("warning".to_owned()));
+    assert_eq!(
+        client.log_level(Some("DEBUG".to_owned())).wait(),
+        Ok("debug".to_owned())
+    );
+    assert_eq!(level.get(), Level::Debug);
+    assert!(client.log_level(Some("loud".to_owned())).wait().is_err());
+    assert_eq!(client.log_level(None).wait(), Ok("debug".to_owned()));
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// CONFIG GET should report the settings of the server and of its engine
+#[test]
+fn config_get() {
+    let addr = "127.0.0.1:4145";
+    let (_drain, level) = LevelSwitch::new(Discard, Level::Info);
+    let (server, handle, _dir) = start_server_with(addr, |server| {
+        server.log_level(level).dedup_window(Duration::from_secs(3))
+    });
+    let client = client(addr);
+
+    let config = client.config_get("*".to_owned()).wait().unwrap();
+    let get = |name: &str| {
+        config
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, val)| val.as_str())
+    };
+    assert_eq!(get("engine"), Some("kvs"));
+    assert_eq!(get("threads"), Some("2"));
+    assert_eq!(get("dedup_window"), Some("3000"));
+    assert_eq!(get("log_level"), Some("info"));
+    assert_eq!(get("record_codec"), Some("json"));
+    assert!(get("compact_threshold").is_some());
+
+    let compact = client.config_get("COMPACT_*".to_owned()).wait().unwrap();
+    assert!(!compact.is_empty());
+    assert!(compact.iter().all(|(name, _)| name.starts_with("compact_")));
+    assert_eq!(client.config_get("nothing".to_owned()).wait(), Ok(vec![]));
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// QUOTA should report the usage of a namespace and limit its growth
+#[test]
+fn quota() {
+    let addr = "127.0.0.1:4127";
+    let dir = TempDir::new().unwrap();
+    let store = KvStoreBuilder::new(dir.path())
+        .namespaces(':')
+        .build()
+        .unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let server = KvsServer::new(store, pool, addr.parse().unwrap(), None);
+    let handle = server.spawn_background().unwrap();
+    let client = client(addr);
+
+    client.set("a:1".to_owned(), "1".to_owned()).wait().unwrap();
+    let (usage, quota) = client.quota("a".to_owned()).wait().unwrap();
+    assert_eq!(usage.keys, 1);
+    assert_eq!(quota, Quota::default());
+
+    let limit = Quota {
+        max_keys: Some(1),
+        max_bytes: None,
+    };
+    let (_, quota) = client.set_quota("a".to_owned(), limit).wait().unwrap();
+    assert_eq!(quota, limit);
+    assert!(client.set("a:2".to_owned(), "2".to_owned()).wait().is_err());
+    client.set("b:2".to_owned(), "2".to_owned()).wait().unwrap();
+    assert_eq!(client.get("a:2".to_owned()).wait(), Ok(None));
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// GETRANGE should return a slice of the value, nil for a missing key
+#[test]
+fn getrange() {
+    let addr = "127.0.0.1:4128";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+
+    client
+        .set("key".to_owned(), "This is a string".to_owned())
+        .wait()
+        .unwrap();
+    let range = |start, end| client.getrange("key".to_owned(), start, end).wait();
+    assert_eq!(range(0, 3), Ok(Some("This".to_owned())));
+    assert_eq!(range(-3, -1), Ok(Some("ing".to_owned())));
+    assert_eq!(range(10, 100), Ok(Some("string".to_owned())));
+    assert_eq!(range(5, 2), Ok(Some("".to_owned())));
+    assert_eq!(
+        client.getrange("missing".to_owned(), 0, -1).wait(),
+        Ok(None)
+    );
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// GET with CAP should cut the value and tell so
+#[test]
+fn get_cap() {
+    let addr = "127.0.0.1:4134";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+
+    client
+        .set("key".to_owned(), "This is a string".to_owned())
+        .wait()
+        .unwrap();
+    let capped = |key: &str, max| client.get_capped(key.to_owned(), max).wait();
+    assert_eq!(capped("key", 4), Ok(Some(("This".to_owned(), true))));
+    assert_eq!(
+        capped("key", 100),
+        Ok(Some(("This is a string".to_owned(), false)))
+    );
+    assert_eq!(capped("missing", 4), Ok(None));
+    assert_eq!(
+        client.get("key".to_owned()).wait(),
+        Ok(Some("This is a string".to_owned()))
+    );
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// CachingClient should answer repeated gets from its cache, and see the
+// writes of other clients once their events arrive
+#[test]
+fn caching_client() {
+    let addr = "127.0.0.1:4119";
+    let (server, handle, _dir) = start_server(addr);
+    let mut writer = client(addr);
+    let cached = CachingClient::new(addr.parse().unwrap(), "user:".to_owned(), None)
+        .unwrap()
+        .max_age(Duration::from_secs(60));
+    thread::sleep(Duration::from_millis(200));
+
+    writer
+        .set("user:1".to_owned(), "v1".to_owned())
+        .wait()
+        .unwrap();
+    writer
+        .set("other:1".to_owned(), "v1".to_owned())
+        .wait()
+        .unwrap();
+    thread::sleep(Duration::from_millis(200));
+    for _ in 0..3 {
+        assert_eq!(
+            cached.get("user:1".to_owned()).wait(),
+            Ok(Some("v1".to_owned()))
+        );
+        assert_eq!(cached.get("user:2".to_owned()).wait(), Ok(None));
+        assert_eq!(
+            cached.get("other:1".to_owned()).wait(),
+            Ok(Some("v1".to_owned()))
+        );
+    }
+    assert_eq!(cached.cached(), 2);
+
+    // Invalidated by the events of the writes.
+    let eventually = |key: &str, expected: Option<&str>| {
+        for _ in 0..50 {
+            if cached.get(key.to_owned()).wait() == Ok(expected.map(str::to_owned)) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("{} never became {:?}", key, expected);
+    };
+    writer
+        .set("user:1".to_owned(), "v2".to_owned())
+        .wait()
+        .unwrap();
+    writer
+        .set("user:2".to_owned(), "v2".to_owned())
+        .wait()
+        .unwrap();
+    eventually("user:1", Some("v2"));
+    eventually("user:2", Some("v2"));
+    writer.rm("user:1".to_owned()).wait().unwrap();
+    eventually("user:1", None);
+    writer.del_prefix("user".to_owned()).wait().unwrap();
+    eventually("user:2", None);
+
+    drop(cached);
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// Keys with NUL, CR, LF and protocol-like bytes should round-trip intact,
+// non-UTF-8 ones should get an error reply
+#[test]
+fn adversarial_keys() {
+    use std::io::{Read, Write};
+
+    let addr = "127.0.0.1:4117";
+    let (server, handle, _dir) = start_server(addr);
+    let mut client = client(addr);
+
+    let keys = vec![
+        "nul\0inside",
+        "\0",
+        "line\r\nbreak",
+        "\r",
+        "\n",
+        "\r\n",
+        "*2\r\n$3\r\nGET\r\n",
+        "-ERR not an error",
+        "ünïcödé ключ 键",
+    ];
+    for (i, key) in keys.iter().enumerate() {
+        client
+            .set(key.to_string(), format!("value{}\0\r\n", i))
+            .wait()
+            .unwrap();
+    }
+    for (i, key) in keys.iter().enumerate() {
+        assert_eq!(
+            client.get(key.to_string()).wait(),
+            Ok(Some(format!("value{}\0\r\n", i)))
+        );
+    }
+    assert_eq!(client.count("\r".to_owned()).wait(), Ok(2));
+    client.rm("\0".to_owned()).wait().unwrap();
+    assert_eq!(client.get("\0".to_owned()).wait(), Ok(None));
+    assert_eq!(
+        client.get("nul\0inside".to_owned()).wait(),
+        Ok(Some("value0\0\r\n".to_owned()))
+    );
+
+    let mut sock = std::net::TcpStream::connect(addr).unwrap();
+    sock.write_all(b"*2\r\n+GET\r\n$2\r\n\xff\xfe\r\n").unwrap();
+    let mut reply = Vec::new();
+    sock.read_to_end(&mut reply).unwrap();
+    assert!(reply.starts_with(b"-"), "{:?}", reply);
+    assert!(String::from_utf8_lossy(&reply).contains("UTF-8"));
+    assert_eq!(client.count("".to_owned()).wait(), Ok(keys.len() - 1));
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// The server should work with a custom backlog and nodelay sockets
+#[test]
+fn socket_options() {
+    let addr = "127.0.0.1:4101";
+    let (server, handle, _dir) = start_server_with(addr, |s| s.backlog(16).nodelay(true));
+    let client = client(addr);
+
+    client
+        .set("key1".to_owned(), "value1".to_owned())
+        .wait()
+        .unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).wait(),
+        Ok(Some("value1".to_owned()))
+    );
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+// EXPIRE should give a key a TTL, and PERSIST should remove it
+#[test]
+fn expire_persist() {
+    let addr = "127.0.0.1:4146";
+    let (server, handle, _dir) = start_server(addr);
+    let client = client(addr);
+    let key = || "cache".to_owned();
+    let ttl = Duration::from_millis(200);
+
+    assert_eq!(client.expire(key(), ttl).wait(), Ok(false));
+    assert_eq!(client.persist(key()).wait(), Ok(false));
+    client.set(key(), "a".to_owned()).wait().unwrap();
+    assert_eq!(client.persist(key()).wait(), Ok(false));
+    assert_eq!(client.expire(key(), ttl).wait(), Ok(true));
+    assert_eq!(client.persist(key()).wait(), Ok(true));
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(client.get(key()).wait(), Ok(Some("a".to_owned())));
+
+    client.expire(key(), ttl).wait().unwrap();
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(client.get(key()).wait(), Ok(None));
+    assert!(client
+        .expire(key(), Duration::from_millis(0))
+        .wait()
+        .is_err());
+
+    server.shutdown();
+    handle.join().unwrap().unwrap();
+}
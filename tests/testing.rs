@@ -0,0 +1,29 @@
+// Run with `cargo test --features testing`.
+#![cfg(feature = "testing")]
+
+use kvs::testing::spawn_test_server;
+use kvs::{KvStore, KvsClient};
+use tempfile::TempDir;
+use tokio::prelude::*;
+
+// Should serve on a port of its own as soon as it returns, until shut down
+#[test]
+fn test_servers() {
+    let dirs = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let (first, addr1) = spawn_test_server(KvStore::open(dirs.0.path()).unwrap());
+    let (second, addr2) = spawn_test_server(KvStore::open(dirs.1.path()).unwrap());
+    assert_ne!(addr1.port(), 0);
+    assert_ne!(addr1, addr2);
+    assert_eq!(first.server().local_addr(), Some(addr1));
+
+    let client1 = KvsClient::new(addr1, None).unwrap();
+    let client2 = KvsClient::new(addr2, None).unwrap();
+    client1.set("a".to_owned(), "1".to_owned()).wait().unwrap();
+    assert_eq!(client1.get("a".to_owned()).wait(), Ok(Some("1".to_owned())));
+    assert_eq!(client2.get("a".to_owned()).wait(), Ok(None));
+
+    assert_eq!(first.shutdown(), Ok(()));
+    assert!(client1.get("a".to_owned()).wait().is_err());
+    drop(second);
+    assert!(client2.get("a".to_owned()).wait().is_err());
+}
@@ -68,3 +68,33 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+// `try_spawn` should give the job back once the capacity is reached
+#[test]
+fn bounded_thread_pool_try_spawn() -> Result<()> {
+    let pool = BoundedThreadPool::with_capacity(SharedQueueThreadPool::new(2)?, 2);
+    let (release, rx) = crossbeam_channel::unbounded::<()>();
+    for _ in 0..2 {
+        let rx = rx.clone();
+        assert!(pool
+            .try_spawn(move || {
+                let _ = rx.recv();
+            })
+            .is_ok());
+    }
+    assert_eq!(pool.outstanding(), 2);
+    assert!(pool.try_spawn(|| ()).is_err());
+
+    drop(release);
+    while pool.outstanding() > 0 {
+        std::thread::yield_now();
+    }
+    assert!(pool.try_spawn(|| ()).is_ok());
+    spawn_counter(pool)
+}
+
+#[test]
+fn bounded_rayon_thread_pool_spawn_counter() -> Result<()> {
+    let pool = BoundedThreadPool::<RayonThreadPool>::new(4)?;
+    spawn_counter(pool)
+}